@@ -0,0 +1,63 @@
+// ============================================================================
+//  FUZZ TARGET - TLS CLIENTHELLO PARSER
+// ============================================================================
+// Feeds arbitrary bytes to `parse_client_hello`. Besides never panicking:
+// 1. A ClientHello that parses gives the same result when its handshake is split
+//    into records of another size (first input byte = record size - 1)
+// 2. Every cut-off prefix of it asks for more data instead of failing
+//
+// Run from this folder: cargo fuzz run client_hello
+
+#![no_main]
+
+#[allow(dead_code)] // The async reader and the alert aren't fuzzed
+#[path = "../../src/windows/client_hello.rs"]
+mod client_hello;
+
+use client_hello::{parse_client_hello, HelloError};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&size, input)) = data.split_first() else {
+        return;
+    };
+    let Ok(hello) = parse_client_hello(input) else {
+        return;
+    };
+
+    let records = refragment(input, size as usize + 1);
+    assert_eq!(parse_client_hello(&records), Ok(hello));
+    for end in 0..records.len() {
+        assert_eq!(
+            parse_client_hello(&records[..end]),
+            Err(HelloError::Incomplete)
+        );
+    }
+});
+
+// The handshake bytes of `input`'s records, put in new records of `size` bytes.
+// Only called on input that parsed, so the record headers are valid.
+fn refragment(input: &[u8], size: usize) -> Vec<u8> {
+    let mut handshake = Vec::new();
+    let mut rest = input;
+    while rest.len() >= 5 && rest[0] == 22 {
+        let length = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+        let Some(fragment) = rest.get(5..5 + length) else {
+            break;
+        };
+        handshake.extend_from_slice(fragment);
+        rest = &rest[5 + length..];
+    }
+
+    // Just the ClientHello, the parser ignores whatever follows it
+    let length = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+    handshake.truncate(4 + length);
+
+    let mut records = Vec::new();
+    for chunk in handshake.chunks(size) {
+        records.extend_from_slice(&[22, 3, 1]);
+        records.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+        records.extend_from_slice(chunk);
+    }
+    records
+}
@@ -1,8 +1,9 @@
-use chrono::Utc;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::net::SocketAddr;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConnectRequestLog {
     pub client_addr: SocketAddr,
     pub target_host: String,
@@ -11,7 +12,7 @@ pub struct ConnectRequestLog {
     pub headers: HashMap<String, String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HttpRequestLog {
     pub client_addr: SocketAddr,
     pub method: String,
@@ -27,15 +28,37 @@ impl ProxyLogger {
         println!("Proxy running at http://{}", addr);
     }
 
-    pub fn log_connection_established(target: &str) {
-        println!("Connection established to {}", target);
+    pub fn log_error(context: &str, error: &dyn std::fmt::Display) {
+        eprintln!("Error in {}: {}", context, error);
     }
 
-    pub fn log_connection_failed(target: &str, error: &str) {
-        println!("Failed to connect to {}: {}", target, error);
+    // Filtering lifted with a parent's code, from `source` (the app, the block page)
+    pub fn log_parent_override(scope: &str, until: DateTime<Utc>, source: &str) {
+        println!(
+            "[{}] 🔓 Parent override from the {}: {} unlocked until {}",
+            Utc::now().to_rfc3339(),
+            source,
+            scope,
+            until.to_rfc3339()
+        );
     }
 
-    pub fn log_error(context: &str, error: &dyn std::fmt::Display) {
-        eprintln!("Error in {}: {}", context, error);
+    // The screen-time budgets started over for `day`
+    pub fn log_screen_time_reset(day: NaiveDate) {
+        println!(
+            "[{}] ⏰ Screen time budgets reset for {}",
+            Utc::now().to_rfc3339(),
+            day
+        );
+    }
+
+    // A parent code that was refused (wrong, reused, too many attempts, ...)
+    pub fn log_parent_code_refused(source: &str, error: &dyn std::fmt::Display) {
+        eprintln!(
+            "[{}] ⚠️ Parent code refused on the {}: {}",
+            Utc::now().to_rfc3339(),
+            source,
+            error
+        );
     }
 }
@@ -4,7 +4,14 @@
 mod logger;
 mod windows;
 
-use windows::proxy::{disable_system_proxy, enable_system_proxy, get_proxy_status, restart_proxy};
+use windows::certificate::{certificate_status, export_ca_certificate};
+use windows::proxy::{
+    disable_system_proxy, enable_system_proxy, end_parent_override, forget_pinned_host,
+    get_access_requests, get_activity, get_filter_mode, get_intercept_bypass,
+    get_parent_overrides, get_proxy_status, get_screen_time, get_tls_interception,
+    restart_proxy, set_filter_mode, set_intercept_bypass, set_tls_interception,
+    unlock_with_parent_code,
+};
 use windows::screen_record::start_screen_record;
 use windows::system::{is_user_admin, system_check};
 
@@ -20,13 +27,32 @@ async fn main() {
             restart_proxy,
             enable_system_proxy,
             disable_system_proxy,
+            set_filter_mode,
+            get_filter_mode,
+            get_screen_time,
+            get_access_requests,
+            unlock_with_parent_code,
+            end_parent_override,
+            get_parent_overrides,
+            get_activity,
+            set_tls_interception,
+            get_tls_interception,
+            get_intercept_bypass,
+            set_intercept_bypass,
+            forget_pinned_host,
+            certificate_status,
+            export_ca_certificate,
             is_user_admin,
             system_check,
             start_screen_record,
         ])
         .setup(|_app| {
-            // Ensure root CA exists and is installed in Root store
+            // Ensure root CA exists and is installed in Root store, and rotate it
+            // ahead of its expiry while the app runs
             windows::certificate::ensure_certificate_installed();
+            windows::certificate::watch_certificate_lifecycle(
+                windows::proxy::reload_tls_interception,
+            );
 
             Ok(())
         })
@@ -0,0 +1,415 @@
+// ============================================================================
+//  ACCESS REQUESTS - "ASK A PARENT" FROM THE BLOCK PAGE
+// ============================================================================
+// The child can ask for a blocked site from the block page (see local_site.rs):
+// 1. The request (host and an optional reason) is queued here and saved to
+//    `ACCESS_REQUESTS_PATH`, so it isn't lost while the device is offline
+// 2. The updater sends it to the backend, then asks for the parent's decision
+//    after every sync (see http_service/access.rs)
+// 3. An approval comes with a signed grant: an allow rule that overrides the
+//    blocklist (and allowlist mode) until it expires, or for good
+// 4. A denial is kept, so the child sees the answer
+//
+// Grants stop counting on their own once they expire: the policy checks the time
+// on every request. Denied and expired requests are forgotten after `KEEP_DECIDED`.
+
+use crate::windows::domain_matcher::{normalize_host, DomainMatcher};
+use crate::windows::http_service::access::{AccessDecision, AccessGrant};
+use crate::windows::http_service::signature::KeySet;
+use crate::windows::key_protector::write_atomic;
+use crate::windows::policy::AllowException;
+use crate::windows::schedule::{Clock, SystemClock};
+use chrono::{DateTime, Duration as TimeDelta, Utc};
+use openssl::rand::rand_bytes;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::{fmt, fs, io, path::Path};
+
+pub const ACCESS_REQUESTS_PATH: &str = "C:\\ProgramData\\GuardNest\\access_requests.json";
+
+// Requests waiting for a parent at the same time. More are refused, so a child (or
+// a page posting to the local site) can't flood the parent.
+const MAX_PENDING: usize = 20;
+
+// Longest reason kept, in characters
+pub const MAX_REASON_CHARS: usize = 280;
+
+// How long denied and expired requests stay visible
+const KEEP_DECIDED: TimeDelta = TimeDelta::days(7);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessStatus {
+    Pending,  // Waiting for a parent (or to be sent)
+    Approved, // The grant lets the site through
+    Denied,   // A parent said no
+    Expired,  // Was approved, the grant has run out
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccessRequest {
+    pub id: String, // Random, 32 hex digits
+    pub host: String,
+    pub reason: Option<String>,
+    pub requested_at: DateTime<Utc>,
+    pub status: AccessStatus,
+    #[serde(default)]
+    pub sent: bool, // Accepted by the backend
+    #[serde(default)]
+    pub decided_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub grant: Option<AccessGrant>, // Set once approved
+}
+
+impl AccessRequest {
+    // The status at `now`: an approval whose grant has run out is expired
+    fn status_at(&self, now: DateTime<Utc>) -> AccessStatus {
+        match self.grant.as_ref().and_then(|grant| grant.expires_at) {
+            Some(expires_at) if self.status == AccessStatus::Approved && expires_at <= now => {
+                AccessStatus::Expired
+            }
+            _ => self.status,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SubmitError {
+    InvalidHost,
+    TooManyPending, // `MAX_PENDING` requests are already waiting
+    Io(io::Error),  // No random ID could be made
+}
+
+impl fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubmitError::InvalidHost => write!(f, "Invalid host"),
+            SubmitError::TooManyPending => write!(f, "Too many requests are waiting"),
+            SubmitError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SubmitError {}
+
+// Layout of the file at `ACCESS_REQUESTS_PATH`
+#[derive(Serialize, Deserialize, Default)]
+struct SavedRequests {
+    #[serde(default)]
+    requests: Vec<AccessRequest>,
+}
+
+pub struct AccessRequests {
+    requests: Vec<AccessRequest>, // Oldest first
+    clock: Arc<dyn Clock>,
+    dirty: bool, // Changed since the last save
+}
+
+impl Default for AccessRequests {
+    fn default() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+}
+
+impl AccessRequests {
+    // No requests, reading the time from `clock` (e.g. a fixed clock in tests)
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        AccessRequests {
+            requests: Vec::new(),
+            clock,
+            dirty: false,
+        }
+    }
+
+    // Queues a request for `host`. Asking again while a request for the same host
+    // is waiting returns that request.
+    pub fn submit(
+        &mut self,
+        host: &str,
+        reason: Option<&str>,
+    ) -> Result<AccessRequest, SubmitError> {
+        let host = normalize_host(host).ok_or(SubmitError::InvalidHost)?;
+        let pending = || {
+            self.requests
+                .iter()
+                .filter(|request| request.status == AccessStatus::Pending)
+        };
+        if let Some(waiting) = pending().find(|request| request.host == host) {
+            return Ok(waiting.clone());
+        }
+        if pending().count() >= MAX_PENDING {
+            return Err(SubmitError::TooManyPending);
+        }
+
+        let mut id = [0u8; 16];
+        rand_bytes(&mut id).map_err(|e| SubmitError::Io(io::Error::other(e)))?;
+        let request = AccessRequest {
+            id: id.iter().map(|b| format!("{:02x}", b)).collect(),
+            host,
+            reason: reason
+                .map(|reason| reason.trim().chars().take(MAX_REASON_CHARS).collect())
+                .filter(|reason: &String| !reason.is_empty()),
+            requested_at: self.clock.now(),
+            status: AccessStatus::Pending,
+            sent: false,
+            decided_at: None,
+            grant: None,
+        };
+        self.requests.push(request.clone());
+        self.dirty = true;
+        Ok(request)
+    }
+
+    // Every request, newest first, with its status as of now
+    pub fn requests(&self) -> Vec<AccessRequest> {
+        let now = self.clock.now();
+        self.requests
+            .iter()
+            .rev()
+            .map(|request| AccessRequest {
+                status: request.status_at(now),
+                ..request.clone()
+            })
+            .collect()
+    }
+
+    // The latest request for `host`, with its status as of now
+    pub fn latest_for(&self, host: &str) -> Option<AccessRequest> {
+        let host = normalize_host(host)?;
+        self.requests()
+            .into_iter()
+            .find(|request| request.host == host)
+    }
+
+    // Requests the backend doesn't have yet
+    pub fn unsent(&self) -> Vec<AccessRequest> {
+        self.requests
+            .iter()
+            .filter(|request| !request.sent)
+            .cloned()
+            .collect()
+    }
+
+    // Marks requests as accepted by the backend
+    pub fn mark_sent(&mut self, ids: &[String]) {
+        for request in &mut self.requests {
+            if ids.contains(&request.id) && !request.sent {
+                request.sent = true;
+                self.dirty = true;
+            }
+        }
+    }
+
+    // IDs of the requests the backend has, still waiting for a parent
+    pub fn awaiting_decision(&self) -> Vec<String> {
+        self.requests
+            .iter()
+            .filter(|request| request.sent && request.status == AccessStatus::Pending)
+            .map(|request| request.id.clone())
+            .collect()
+    }
+
+    // Records the parent's decisions (grants already verified). Returns true if
+    // a request was approved, i.e. the allow rules changed.
+    pub fn apply_decisions(&mut self, decisions: Vec<AccessDecision>) -> bool {
+        let now = self.clock.now();
+        let mut approved = false;
+
+        for decision in decisions {
+            let id = match &decision {
+                AccessDecision::Approved(grant) => &grant.request_id,
+                AccessDecision::Denied(id) => id,
+            };
+            let Some(request) = self
+                .requests
+                .iter_mut()
+                .find(|request| request.id == *id && request.status == AccessStatus::Pending)
+            else {
+                continue; // Unknown, or decided already
+            };
+
+            request.decided_at = Some(now);
+            match decision {
+                AccessDecision::Approved(grant) => {
+                    request.status = AccessStatus::Approved;
+                    request.grant = Some(grant);
+                    approved = true;
+                }
+                AccessDecision::Denied(_) => request.status = AccessStatus::Denied,
+            }
+            self.dirty = true;
+        }
+        approved
+    }
+
+    // The allow rules of the approved requests, expired ones included: the policy
+    // checks the expiry on every request
+    pub fn exceptions(&self) -> Vec<AllowException> {
+        self.requests
+            .iter()
+            .filter(|request| request.status == AccessStatus::Approved)
+            .filter_map(|request| request.grant.as_ref())
+            .map(|grant| AllowException {
+                rules: Some(DomainMatcher::from_rules([grant.rule.as_str()])),
+                until: grant.expires_at,
+            })
+            .collect()
+    }
+
+    // Forgets the requests denied or expired more than `KEEP_DECIDED` ago.
+    // Returns true if the allow rules changed.
+    pub fn prune(&mut self) -> bool {
+        let now = self.clock.now();
+        let before = self.requests.len();
+        let mut expired_grants = false;
+
+        self.requests.retain(|request| {
+            let ended = match request.status_at(now) {
+                AccessStatus::Denied => request.decided_at,
+                AccessStatus::Expired => request.grant.as_ref().and_then(|grant| grant.expires_at),
+                AccessStatus::Pending | AccessStatus::Approved => None,
+            };
+            let keep = ended.is_none_or(|ended| now - ended < KEEP_DECIDED);
+            expired_grants |= !keep && request.grant.is_some();
+            keep
+        });
+
+        self.dirty |= self.requests.len() != before;
+        expired_grants
+    }
+
+    // Restores the requests saved by `save`. Approvals whose grant isn't signed by
+    // one of `keys` for `child_id`, the child the device is registered for (e.g.
+    // an edited file, or one copied from another child's device), are dropped, and
+    // all of them if the device isn't registered.
+    pub fn load(&mut self, path: &Path, keys: &KeySet, child_id: Option<i32>) -> io::Result<()> {
+        let data = fs::read(path)?;
+        let saved: SavedRequests = serde_json::from_slice(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        self.requests = saved
+            .requests
+            .into_iter()
+            .filter(|request| match (&request.grant, request.status) {
+                (Some(grant), AccessStatus::Approved) => {
+                    child_id.is_some_and(|child_id| grant.verify(keys, child_id).is_ok())
+                        && grant.request_id == request.id
+                }
+                (None, AccessStatus::Approved) => false,
+                _ => true,
+            })
+            .collect();
+        self.dirty = false;
+        Ok(())
+    }
+
+    // Writes the requests to disk if they changed since the last save
+    pub fn save(&mut self, path: &Path) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let saved = SavedRequests {
+            requests: self.requests.clone(),
+        };
+        let data = serde_json::to_vec_pretty(&saved)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        write_atomic(path, &data)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::windows::http_service::cache::tests::signing_key;
+    use crate::windows::http_service::signature::PayloadSignature;
+    use crate::windows::schedule::tests::FixedClock;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use openssl::pkey::{PKey, Private};
+    use openssl::sign::Signer;
+
+    fn requests() -> AccessRequests {
+        let now = DateTime::parse_from_rfc3339("2026-10-17T08:30:00Z").unwrap();
+        AccessRequests::with_clock(Arc::new(FixedClock(now.to_utc())))
+    }
+
+    // An approval of `request_id` for `child_id`, signed the way the backend does
+    fn grant(key: &PKey<Private>, child_id: i32, request_id: &str) -> AccessGrant {
+        let mut grant = AccessGrant {
+            child_id,
+            request_id: request_id.to_string(),
+            rule: "wikipedia.org".to_string(),
+            expires_at: None,
+            signature: PayloadSignature {
+                key_id: "test-key".to_string(),
+                signature: String::new(),
+            },
+        };
+        let signature = Signer::new_without_digest(key)
+            .unwrap()
+            .sign_oneshot_to_vec(&grant.signed_bytes().unwrap())
+            .unwrap();
+        grant.signature.signature = STANDARD.encode(signature);
+        grant
+    }
+
+    // Saves an approval for child 7, and loads it back as `child_id`
+    fn approved_for(child_id: Option<i32>) -> Vec<AccessRequest> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access_requests.json");
+        let (key, keys) = signing_key();
+
+        let mut saved = requests();
+        let id = saved.submit("wikipedia.org", Some("homework")).unwrap().id;
+        saved.submit("games.example", None).unwrap();
+        assert!(saved.apply_decisions(vec![AccessDecision::Approved(grant(&key, 7, &id))]));
+        saved.save(&path).unwrap();
+        assert!(!path.with_extension("tmp").exists());
+
+        let mut loaded = requests();
+        loaded.load(&path, &keys, child_id).unwrap();
+        loaded.requests()
+    }
+
+    #[test]
+    fn approvals_are_bound_to_the_registered_child() {
+        let loaded = approved_for(Some(7));
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[1].status, AccessStatus::Approved);
+        assert_eq!(loaded[1].reason.as_deref(), Some("homework"));
+
+        // Signed for child 7, but this device is now another child's
+        let loaded = approved_for(Some(8));
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].host, "games.example");
+
+        // Not registered: no grant can be checked
+        assert_eq!(approved_for(None).len(), 1);
+    }
+
+    #[test]
+    fn edited_approvals_are_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access_requests.json");
+        let (key, keys) = signing_key();
+
+        let mut saved = requests();
+        let id = saved.submit("wikipedia.org", None).unwrap().id;
+        saved.apply_decisions(vec![AccessDecision::Approved(grant(&key, 7, &id))]);
+        saved.save(&path).unwrap();
+
+        // The rule widened after signing
+        let data = fs::read_to_string(&path).unwrap();
+        fs::write(&path, data.replace("\"wikipedia.org\"", "\"org\"")).unwrap();
+        let mut loaded = requests();
+        loaded.load(&path, &keys, Some(7)).unwrap();
+        assert!(loaded
+            .requests()
+            .iter()
+            .all(|r| r.status != AccessStatus::Approved));
+        assert!(loaded.exceptions().is_empty());
+    }
+}
@@ -0,0 +1,607 @@
+// ============================================================================
+//  ACTIVITY - WHAT THE PROXY DID WITH EVERY REQUEST
+// ============================================================================
+// Every decision the proxy takes is recorded as an `ActivityEvent`: the request
+// (a CONNECT tunnel or a plain/decrypted HTTP request, see logger.rs), where it
+// went, whether it was allowed, blocked or failed, the rule that blocked it, the
+// bytes each way and how long it took.
+//
+// Events are handed to a bounded channel and written by a thread of their own, so
+// a slow disk or a full queue never holds up a connection:
+// 1. The proxy calls `ActivityLog::emit`, which never waits. When the channel is
+//    full (`CHANNEL_CAPACITY` events behind) the event is dropped and counted.
+// 2. The writer thread takes the events in batches and passes each batch to every
+//    sink: the console (`StdoutSink`), a JSON-lines file (`FileSink`) and the
+//    activity history on disk, which the updater uploads to the backend from
+//    (see activity_store.rs).
+//
+// Paths are recorded without their query string (see `logged_path`), and the
+// headers that carry credentials not at all.
+//
+// The CONNECT event of an intercepted tunnel has no byte counts: its requests are
+// recorded one by one, with theirs.
+//
+// Parent codes typed on the device, taken or refused, are recorded the same way
+// (see parent_override.rs), so they show up in the history next to the requests
+// they let through.
+
+use crate::logger::{ConnectRequestLog, HttpRequestLog};
+use crate::windows::block_page::BlockCause;
+use crate::windows::http_parser::HttpRequestHead;
+use crate::windows::key_protector::append_restricted;
+use crate::windows::parent_override::{OverrideScope, OverrideSource};
+use crate::windows::policy::BlockReason;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+pub const ACTIVITY_LOG_PATH: &str = "C:\\ProgramData\\GuardNest\\activity.log";
+
+// Size the activity file may reach before it is moved to "activity.log.1"
+pub const MAX_LOG_FILE_SIZE: u64 = 10 * 1024 * 1024;
+
+// Events waiting for the writer thread. More are dropped rather than slowing down
+// the proxy.
+pub const CHANNEL_CAPACITY: usize = 4096;
+
+// Most events handed to the sinks at once
+const BATCH_SIZE: usize = 256;
+
+// Headers that carry credentials, never recorded
+const SECRET_HEADERS: [&str; 3] = ["authorization", "cookie", "proxy-authorization"];
+
+// Longest path recorded, in characters; longer ones are cut and end with "…"
+const MAX_LOGGED_PATH: usize = 256;
+
+// The path of a request target (or a Referer URL) as recorded: without its query
+// string and fragment, which often carry search terms, e-mail addresses or login
+// tokens, and cut at `MAX_LOGGED_PATH` characters
+fn logged_path(target: &str) -> String {
+    let path = target.split(['?', '#']).next().unwrap_or("");
+    match path.char_indices().nth(MAX_LOGGED_PATH) {
+        Some((end, _)) => format!("{}…", &path[..end]),
+        None => path.to_string(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    Allowed, // Forwarded to the server (or answered by the local site)
+    Blocked, // Refused by the filter or screen time
+    Failed,  // Malformed request, server unreachable or the connection broke off
+    Timeout, // The server didn't answer in time
+}
+
+impl Decision {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Decision::Allowed => "allowed",
+            Decision::Blocked => "blocked",
+            Decision::Failed => "failed",
+            Decision::Timeout => "timeout",
+        }
+    }
+
+    // The other way round from `as_str`
+    pub fn parse(text: &str) -> Option<Self> {
+        [
+            Decision::Allowed,
+            Decision::Blocked,
+            Decision::Failed,
+            Decision::Timeout,
+        ]
+        .into_iter()
+        .find(|decision| decision.as_str() == text)
+    }
+}
+
+// The request as the browser sent it, credentials left out
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RequestLog {
+    Connect(ConnectRequestLog),
+    Http(HttpRequestLog),
+    ParentCode(ParentCodeLog),
+}
+
+// A parent's code typed on the device. The code itself is never recorded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ParentCodeLog {
+    pub source: OverrideSource,
+    #[serde(flatten)]
+    pub scope: OverrideScope, // What the code was typed for
+    pub minutes: u32,
+    pub until: Option<DateTime<Utc>>, // End of the override, if the code was taken
+    pub refused: Option<String>,      // Why it wasn't
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ActivityEvent {
+    pub at: DateTime<Utc>, // When the request arrived
+    pub host: String,      // As far as it could be told; the raw target if it couldn't
+    pub port: u16,         // 0 if it couldn't be told
+    pub decision: Decision,
+    pub rule: Option<String>, // What blocked the request, e.g. "||games.com" or "allowlist"
+    pub bytes_up: u64,        // Browser to server, heads included
+    pub bytes_down: u64,      // Server to browser, heads included
+    pub duration_ms: u64,     // Until the response (or tunnel) ended
+    pub request: RequestLog,
+}
+
+impl ActivityEvent {
+    // "CONNECT", "GET", ..., "PARENT-CODE"
+    pub fn method(&self) -> &str {
+        match &self.request {
+            RequestLog::Connect(_) => "CONNECT",
+            RequestLog::Http(log) => &log.method,
+            RequestLog::ParentCode(_) => "PARENT-CODE",
+        }
+    }
+
+    // A parent code typed at `at`: allowed if it was taken, blocked if not, with
+    // where it was typed as the rule. The host is "*" for a code for every host.
+    pub fn parent_code(at: DateTime<Utc>, log: ParentCodeLog) -> Self {
+        let (decision, rule) = match &log.refused {
+            None => (Decision::Allowed, format!("parent code ({})", log.source)),
+            Some(refused) => (
+                Decision::Blocked,
+                format!("parent code refused ({}): {}", log.source, refused),
+            ),
+        };
+        ActivityEvent {
+            at,
+            host: match &log.scope {
+                OverrideScope::All => "*".to_string(),
+                OverrideScope::Host { host } => host.clone(),
+            },
+            port: 0,
+            decision,
+            rule: Some(rule),
+            bytes_up: 0,
+            bytes_down: 0,
+            duration_ms: 0,
+            request: RequestLog::ParentCode(log),
+        }
+    }
+}
+
+// What the proxy did with a request, filled in while handling it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    pub decision: Decision,
+    pub rule: Option<String>,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+impl Outcome {
+    // No rule, no bytes (failures, refusals without a cause)
+    pub fn new(decision: Decision) -> Self {
+        Outcome {
+            decision,
+            rule: None,
+            bytes_up: 0,
+            bytes_down: 0,
+        }
+    }
+
+    pub fn allowed(bytes_up: u64, bytes_down: u64) -> Self {
+        Outcome {
+            bytes_up,
+            bytes_down,
+            ..Outcome::new(Decision::Allowed)
+        }
+    }
+
+    pub fn blocked(cause: &BlockCause) -> Self {
+        Outcome {
+            rule: Some(rule_of(cause)),
+            ..Outcome::new(Decision::Blocked)
+        }
+    }
+}
+
+// The rule recorded for a block: the matching domain or path rule (and its
+// scheduled group), "allowlist", or the screen-time category
+fn rule_of(cause: &BlockCause) -> String {
+    match cause {
+        BlockCause::Policy(BlockReason::Listed { rule, group: None }) => rule.to_string(),
+        BlockCause::Policy(BlockReason::Listed {
+            rule,
+            group: Some(group),
+        }) => format!("{} ({})", rule, group),
+        BlockCause::Policy(BlockReason::ListedPath { rule, group: None }) => rule.to_string(),
+        BlockCause::Policy(BlockReason::ListedPath {
+            rule,
+            group: Some(group),
+        }) => format!("{} ({})", rule, group),
+        BlockCause::Policy(BlockReason::NotAllowed) => "allowlist".to_string(),
+        BlockCause::ScreenTime(category) => format!("screen time: {}", category),
+        BlockCause::Unavailable => "filter unavailable".to_string(),
+    }
+}
+
+// A request being handled. Started when its head has been read, finished into an
+// event once the proxy is done with it.
+pub struct RequestActivity {
+    at: DateTime<Utc>,
+    started: Instant,
+    host: String,
+    port: u16,
+    request: RequestLog,
+}
+
+impl RequestActivity {
+    pub fn new(client_addr: SocketAddr, host: &str, port: u16, head: &HttpRequestHead) -> Self {
+        let mut log = head.to_log(client_addr);
+        log.headers
+            .retain(|name, _| !SECRET_HEADERS.contains(&name.as_str()));
+        log.path = logged_path(&log.path);
+        if let Some(referer) = log.headers.get_mut("referer") {
+            *referer = logged_path(referer);
+        }
+
+        let request = if head.method == "CONNECT" {
+            RequestLog::Connect(ConnectRequestLog {
+                client_addr,
+                target_host: host.to_string(),
+                target_port: port,
+                http_version: log.http_version,
+                headers: log.headers,
+            })
+        } else {
+            RequestLog::Http(log)
+        };
+
+        RequestActivity {
+            at: Utc::now(),
+            started: Instant::now(),
+            host: host.to_string(),
+            port,
+            request,
+        }
+    }
+
+    // The event for this request. Without an outcome the request broke off
+    // (the browser or server went away) and counts as failed.
+    pub fn finish(self, outcome: Option<Outcome>) -> ActivityEvent {
+        let outcome = outcome.unwrap_or(Outcome::new(Decision::Failed));
+        ActivityEvent {
+            at: self.at,
+            host: self.host,
+            port: self.port,
+            decision: outcome.decision,
+            rule: outcome.rule,
+            bytes_up: outcome.bytes_up,
+            bytes_down: outcome.bytes_down,
+            duration_ms: self.started.elapsed().as_millis() as u64,
+            request: self.request,
+        }
+    }
+}
+
+// Where events end up. Sinks run on the writer thread, so they may block.
+pub trait ActivitySink: Send {
+    // Shown when the sink fails
+    fn name(&self) -> &'static str;
+
+    // Takes a batch of events, oldest first
+    fn record(&mut self, events: &[ActivityEvent]) -> io::Result<()>;
+}
+
+// Sends events to the sinks without ever waiting. Clones share the same writer
+// thread, which ends once every clone is dropped.
+#[derive(Clone)]
+pub struct ActivityLog {
+    sender: mpsc::Sender<ActivityEvent>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl ActivityLog {
+    // Starts the writer thread for `sinks`
+    pub fn start(sinks: Vec<Box<dyn ActivitySink>>) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        std::thread::Builder::new()
+            .name("activity-log".to_string())
+            .spawn(move || write_events(receiver, sinks))?;
+        Ok(ActivityLog {
+            sender,
+            dropped: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    // Queues an event. It is dropped (and counted) if the writer is too far behind.
+    pub fn emit(&self, event: ActivityEvent) {
+        match self.sender.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped % 1000 == 1 {
+                    eprintln!("⚠️ Activity log is behind, {} events dropped", dropped);
+                }
+            }
+            Err(TrySendError::Closed(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // Events lost because the channel was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+// The writer thread: waits for events and hands them to the sinks in batches
+fn write_events(
+    mut receiver: mpsc::Receiver<ActivityEvent>,
+    mut sinks: Vec<Box<dyn ActivitySink>>,
+) {
+    while let Some(first) = receiver.blocking_recv() {
+        let mut batch = vec![first];
+        while batch.len() < BATCH_SIZE {
+            match receiver.try_recv() {
+                Ok(event) => batch.push(event),
+                Err(_) => break,
+            }
+        }
+
+        for sink in &mut sinks {
+            if let Err(e) = sink.record(&batch) {
+                eprintln!("Error in activity sink {}: {}", sink.name(), e);
+            }
+        }
+    }
+}
+
+// One line per event on the console
+pub struct StdoutSink;
+
+impl ActivitySink for StdoutSink {
+    fn name(&self) -> &'static str {
+        "stdout"
+    }
+
+    fn record(&mut self, events: &[ActivityEvent]) -> io::Result<()> {
+        let mut out = io::stdout().lock();
+        for event in events {
+            let icon = match event.decision {
+                Decision::Allowed => "✅",
+                Decision::Blocked => "🚫",
+                Decision::Failed => "❌",
+                Decision::Timeout => "⌛",
+            };
+            write!(
+                out,
+                "{} {} {} {}:{} {}",
+                icon,
+                event.at.to_rfc3339(),
+                event.method(),
+                event.host,
+                event.port,
+                event.decision.as_str()
+            )?;
+            if let Some(rule) = &event.rule {
+                write!(out, " by {}", rule)?;
+            }
+            writeln!(
+                out,
+                " (↑{} B, ↓{} B, {} ms)",
+                event.bytes_up, event.bytes_down, event.duration_ms
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Events as JSON lines, appended to a file that is rotated once it reaches
+// `max_size`: the full file becomes "<path>.1", replacing the previous one. The
+// file is created readable by administrators only (see key_protector.rs), like
+// the history: it lists every site the child opened.
+pub struct FileSink {
+    path: PathBuf,
+    max_size: u64,
+    file: Option<File>, // Opened on the first batch
+    size: u64,
+}
+
+impl FileSink {
+    pub fn new(path: impl Into<PathBuf>, max_size: u64) -> Self {
+        FileSink {
+            path: path.into(),
+            max_size,
+            file: None,
+            size: 0,
+        }
+    }
+
+    fn open(&mut self) -> io::Result<&mut File> {
+        if self.file.is_none() {
+            if let Some(dir) = self.path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let file = append_restricted(&self.path)?;
+            self.size = file.metadata()?.len();
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&self.path, rotated)
+    }
+}
+
+impl ActivitySink for FileSink {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn record(&mut self, events: &[ActivityEvent]) -> io::Result<()> {
+        let mut lines = Vec::new();
+        for event in events {
+            serde_json::to_writer(&mut lines, event)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            lines.push(b'\n');
+        }
+
+        self.open()?;
+        if self.size > 0 && self.size + lines.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.open()?.write_all(&lines)?;
+        self.size += lines.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(target: &str, headers: &[(&str, &str)]) -> RequestLog {
+        let head = HttpRequestHead {
+            method: "GET".to_string(),
+            target: target.to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        };
+        let client_addr = "127.0.0.1:50000".parse().unwrap();
+        RequestActivity::new(client_addr, "example.com", 80, &head).request
+    }
+
+    fn http(log: RequestLog) -> HttpRequestLog {
+        match log {
+            RequestLog::Http(log) => log,
+            other => panic!("not an HTTP request: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn queries_are_not_recorded() {
+        let log = http(request(
+            "http://example.com/search?q=secret&token=abc#results",
+            &[
+                ("Host", "example.com"),
+                (
+                    "Referer",
+                    "https://mail.example.com/inbox?email=kid@example.com",
+                ),
+                ("Cookie", "session=1"),
+                ("Authorization", "Bearer x"),
+            ],
+        ));
+        assert_eq!(log.path, "http://example.com/search");
+        assert_eq!(log.headers["referer"], "https://mail.example.com/inbox");
+        assert_eq!(log.headers["host"], "example.com");
+        assert!(!log.headers.contains_key("cookie"));
+        assert!(!log.headers.contains_key("authorization"));
+
+        assert_eq!(http(request("/page#top", &[])).path, "/page");
+        assert_eq!(http(request("/?q=x", &[])).path, "/");
+    }
+
+    #[test]
+    fn long_paths_are_cut() {
+        let long = format!("/{}", "é".repeat(MAX_LOGGED_PATH * 2));
+        let path = http(request(&long, &[])).path;
+        assert_eq!(path.chars().count(), MAX_LOGGED_PATH + 1);
+        assert!(path.ends_with('…'));
+        assert!(long.starts_with(path.trim_end_matches('…')));
+
+        let fits = format!("/{}", "a".repeat(MAX_LOGGED_PATH - 1));
+        assert_eq!(http(request(&fits, &[])).path, fits);
+    }
+
+    #[test]
+    fn file_sink_rotates() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logs").join("activity.log");
+        let event = || {
+            let head = HttpRequestHead {
+                method: "GET".to_string(),
+                target: "/page?q=x".to_string(),
+                version: "HTTP/1.1".to_string(),
+                headers: Vec::new(),
+            };
+            let client_addr = "127.0.0.1:50000".parse().unwrap();
+            RequestActivity::new(client_addr, "example.com", 80, &head)
+                .finish(Some(Outcome::allowed(10, 20)))
+        };
+        // Room for two events (give or take a digit of `duration_ms`), not three
+        let line = serde_json::to_vec(&event()).unwrap().len() as u64 + 1;
+        let mut sink = FileSink::new(&path, 2 * line + 8);
+
+        sink.record(&[event()]).unwrap();
+        sink.record(&[event()]).unwrap();
+        let first = fs::read_to_string(&path).unwrap();
+        assert_eq!(first.lines().count(), 2);
+        for line in first.lines() {
+            let value: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(value["request"]["path"], "/page");
+        }
+
+        // The next batch doesn't fit: the full file moves aside
+        sink.record(&[event()]).unwrap();
+        let rotated = dir.path().join("logs").join("activity.log.1");
+        assert_eq!(fs::read_to_string(&rotated).unwrap(), first);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_is_restricted() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("activity.log");
+        let mut sink = FileSink::new(&path, 1);
+        let event = ActivityEvent::parent_code(
+            Utc::now(),
+            ParentCodeLog {
+                source: OverrideSource::BlockPage,
+                scope: OverrideScope::All,
+                minutes: 30,
+                until: None,
+                refused: None,
+            },
+        );
+        let mode = |path: &std::path::Path| fs::metadata(path).unwrap().permissions().mode();
+
+        sink.record(std::slice::from_ref(&event)).unwrap();
+        assert_eq!(mode(&path) & 0o777, 0o600);
+        // The file that replaces a rotated one too
+        sink.record(&[event]).unwrap();
+        assert_eq!(mode(&path) & 0o777, 0o600);
+
+        // An existing file is appended to, not replaced
+        let before = fs::read(&path).unwrap();
+        let mut sink = FileSink::new(&path, MAX_LOG_FILE_SIZE);
+        sink.record(&[ActivityEvent::parent_code(
+            Utc::now(),
+            ParentCodeLog {
+                source: OverrideSource::BlockPage,
+                scope: OverrideScope::All,
+                minutes: 30,
+                until: None,
+                refused: Some("wrong code".to_string()),
+            },
+        )])
+        .unwrap();
+        assert!(fs::read(&path).unwrap().starts_with(&before));
+    }
+}
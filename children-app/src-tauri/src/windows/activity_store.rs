@@ -0,0 +1,717 @@
+// ============================================================================
+//  ACTIVITY STORE - THE ACTIVITY HISTORY ON DISK
+// ============================================================================
+// Keeps every activity event (see activity.rs) in a SQLite database at
+// `ACTIVITY_DB_PATH`, so parents get the history even for the time the backend
+// couldn't be reached:
+// 1. The activity log's writer thread inserts the events, a batch per transaction
+// 2. The app reads them with `get_activity`: a time range, an optional domain
+//    (subdomains included) and a page, newest first
+// 3. The updater uploads the events the backend doesn't have yet, oldest first,
+//    and marks them uploaded once it accepted them
+// 4. Events older than `Retention::max_age` are removed, and the oldest ones
+//    too while the database takes more than `Retention::max_size`
+//
+// The schema is changed through `MIGRATIONS` only; the database's `user_version`
+// counts the ones applied.
+
+use crate::windows::activity::{ActivityEvent, ActivitySink, Decision, RequestLog};
+use chrono::{DateTime, Duration as TimeDelta, Utc};
+use rusqlite::types::{Type, Value};
+use rusqlite::{params, params_from_iter, Connection, Row};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::{fmt, fs, io, path::Path};
+
+pub const ACTIVITY_DB_PATH: &str = "C:\\ProgramData\\GuardNest\\activity.db";
+
+// Page size when the app doesn't ask for one, and the largest it may ask for
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 500;
+
+// Schema changes, oldest first. One that has shipped is never edited: changes go
+// in a new one.
+const MIGRATIONS: &[&str] = &[
+    // 1: the events. `reversed_host` holds the labels right to left
+    //    ("com.example.www"), so a domain and its subdomains are one index range.
+    "CREATE TABLE events (
+        id            INTEGER PRIMARY KEY AUTOINCREMENT,
+        at            INTEGER NOT NULL, -- Unix time in milliseconds
+        host          TEXT    NOT NULL,
+        reversed_host TEXT    NOT NULL,
+        port          INTEGER NOT NULL,
+        method        TEXT    NOT NULL,
+        decision      TEXT    NOT NULL,
+        rule          TEXT,
+        bytes_up      INTEGER NOT NULL,
+        bytes_down    INTEGER NOT NULL,
+        duration_ms   INTEGER NOT NULL,
+        request       TEXT    NOT NULL, -- The RequestLog, as JSON
+        uploaded      INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX events_at ON events (at);
+    CREATE INDEX events_host_at ON events (reversed_host, at);
+    CREATE INDEX events_not_uploaded ON events (id) WHERE uploaded = 0;",
+];
+
+#[derive(Debug)]
+pub enum StoreError {
+    Sqlite(rusqlite::Error),
+    Io(io::Error),    // The database's folder couldn't be created
+    NewerSchema(i64), // Written by a newer version of the app, left alone
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Sqlite(e) => write!(f, "SQLite error: {}", e),
+            StoreError::Io(e) => write!(f, "I/O error: {}", e),
+            StoreError::NewerSchema(version) => write!(
+                f,
+                "Activity database has schema version {}, this version knows {}",
+                version,
+                MIGRATIONS.len()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        StoreError::Sqlite(e)
+    }
+}
+
+// How much history is kept
+#[derive(Debug, Clone)]
+pub struct Retention {
+    pub max_age: TimeDelta,
+    pub max_size: u64, // Bytes the database may take up
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            max_age: TimeDelta::days(90),
+            max_size: 256 * 1024 * 1024,
+        }
+    }
+}
+
+// Events from `from` (included) to `to` (excluded). Open ends when not set.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TimeRange {
+    #[serde(default)]
+    pub from: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub to: Option<DateTime<Utc>>,
+}
+
+// Which page of the results, the first being 0
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PageRequest {
+    pub index: u32,
+    pub size: u32, // Up to `MAX_PAGE_SIZE`
+}
+
+impl Default for PageRequest {
+    fn default() -> Self {
+        PageRequest {
+            index: 0,
+            size: DEFAULT_PAGE_SIZE,
+        }
+    }
+}
+
+// An event as stored, with its row ID
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredEvent {
+    pub id: i64,
+    #[serde(flatten)]
+    pub event: ActivityEvent,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ActivityPage {
+    pub events: Vec<StoredEvent>, // Newest first
+    pub total: u64,               // Events matching, on every page
+    pub index: u32,
+    pub size: u32,
+}
+
+pub struct ActivityStore {
+    conn: Connection,
+}
+
+impl ActivityStore {
+    // Opens the database at `path`, creating it (and its folder) if needed and
+    // bringing its schema up to date
+    pub fn open(path: &Path) -> Result<Self, StoreError> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(StoreError::Io)?;
+        }
+        let mut conn = Connection::open(path)?;
+
+        // `auto_vacuum` only takes effect on a new database, before the first table;
+        // it lets `prune` give the space of removed events back. With WAL the app
+        // can read while events are written.
+        conn.execute_batch(
+            "PRAGMA auto_vacuum = INCREMENTAL;
+             PRAGMA journal_mode = WAL;
+             PRAGMA synchronous = NORMAL;",
+        )?;
+        migrate(&mut conn)?;
+        Ok(ActivityStore { conn })
+    }
+
+    // Adds a batch of events, all or none
+    pub fn insert(&mut self, events: &[ActivityEvent]) -> Result<(), StoreError> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT INTO events (at, host, reversed_host, port, method, decision, rule,
+                                     bytes_up, bytes_down, duration_ms, request)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            )?;
+            for event in events {
+                let request = serde_json::to_string(&event.request)
+                    .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+                insert.execute(params![
+                    event.at.timestamp_millis(),
+                    event.host,
+                    reverse_host(&event.host),
+                    event.port,
+                    event.method(),
+                    event.decision.as_str(),
+                    event.rule,
+                    event.bytes_up as i64,
+                    event.bytes_down as i64,
+                    event.duration_ms as i64,
+                    request,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    // One page of the events within `range`, for `domain` and its subdomains if set
+    // (already normalized), newest first
+    pub fn query(
+        &self,
+        range: &TimeRange,
+        domain: Option<&str>,
+        page: &PageRequest,
+    ) -> Result<ActivityPage, StoreError> {
+        let mut filter = String::from("at >= ? AND at < ?");
+        let mut values = vec![
+            Value::Integer(range.from.map_or(i64::MIN, |from| from.timestamp_millis())),
+            Value::Integer(range.to.map_or(i64::MAX, |to| to.timestamp_millis())),
+        ];
+        if let Some(domain) = domain {
+            // "com.example" itself, or anything from "com.example." to "com.example/"
+            // ('/' comes right after '.')
+            let reversed = reverse_host(domain);
+            filter
+                .push_str(" AND (reversed_host = ? OR (reversed_host > ? AND reversed_host < ?))");
+            values.push(Value::Text(reversed.clone()));
+            values.push(Value::Text(format!("{}.", reversed)));
+            values.push(Value::Text(format!("{}/", reversed)));
+        }
+
+        let total: i64 = self.conn.query_row(
+            &format!("SELECT COUNT(*) FROM events WHERE {}", filter),
+            params_from_iter(&values),
+            |row| row.get(0),
+        )?;
+
+        let size = page.size.clamp(1, MAX_PAGE_SIZE);
+        values.push(Value::Integer(i64::from(size)));
+        values.push(Value::Integer(i64::from(page.index) * i64::from(size)));
+        let mut select = self.conn.prepare(&format!(
+            "SELECT {} FROM events WHERE {} ORDER BY at DESC, id DESC LIMIT ? OFFSET ?",
+            COLUMNS, filter
+        ))?;
+        let events = select
+            .query_map(params_from_iter(&values), read_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ActivityPage {
+            events,
+            total: total as u64,
+            index: page.index,
+            size,
+        })
+    }
+
+    // Up to `max` of the oldest events the backend doesn't have yet
+    pub fn pending_upload(&self, max: usize) -> Result<Vec<StoredEvent>, StoreError> {
+        let mut select = self.conn.prepare_cached(&format!(
+            "SELECT {} FROM events WHERE uploaded = 0 ORDER BY id LIMIT ?1",
+            COLUMNS
+        ))?;
+        let events = select
+            .query_map([max as i64], read_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(events)
+    }
+
+    // Marks the events up to row `last` as accepted by the backend
+    pub fn mark_uploaded(&mut self, last: i64) -> Result<(), StoreError> {
+        self.conn.execute(
+            "UPDATE events SET uploaded = 1 WHERE id <= ?1 AND uploaded = 0",
+            [last],
+        )?;
+        Ok(())
+    }
+
+    // Removes the events older than the retention, then the oldest ones while the
+    // database is larger than allowed. The size taken per event is estimated from
+    // the database, so it may take a few rounds to get under the limit. Returns
+    // the number of events removed.
+    pub fn prune(
+        &mut self,
+        now: DateTime<Utc>,
+        retention: &Retention,
+    ) -> Result<usize, StoreError> {
+        let cutoff = (now - retention.max_age).timestamp_millis();
+        let mut removed = self
+            .conn
+            .execute("DELETE FROM events WHERE at < ?1", [cutoff])?;
+
+        let used = self.used_size()?;
+        if used > retention.max_size {
+            let count: i64 = self
+                .conn
+                .query_row("SELECT COUNT(*) FROM events", [], |row| row.get(0))?;
+            if count > 0 {
+                let per_event = (used / count as u64).max(1);
+                let excess = (used - retention.max_size).div_ceil(per_event) as i64;
+                removed += self.conn.execute(
+                    "DELETE FROM events WHERE id IN
+                         (SELECT id FROM events ORDER BY at, id LIMIT ?1)",
+                    [excess],
+                )?;
+            }
+        }
+
+        if removed > 0 {
+            self.conn.execute_batch("PRAGMA incremental_vacuum;")?;
+        }
+        Ok(removed)
+    }
+
+    // Bytes of the database in use (pages holding data, not free ones)
+    fn used_size(&self) -> Result<u64, StoreError> {
+        let pragma = |name: &str| -> Result<u64, StoreError> {
+            let value: i64 = self
+                .conn
+                .query_row(&format!("PRAGMA {}", name), [], |row| row.get(0))?;
+            Ok(value as u64)
+        };
+        let pages = pragma("page_count")? - pragma("freelist_count")?;
+        Ok(pages * pragma("page_size")?)
+    }
+}
+
+// The activity log's writer thread adds every batch
+impl ActivitySink for Arc<Mutex<ActivityStore>> {
+    fn name(&self) -> &'static str {
+        "activity store"
+    }
+
+    fn record(&mut self, events: &[ActivityEvent]) -> io::Result<()> {
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(events)
+            .map_err(io::Error::other)
+    }
+}
+
+// Brings the schema up to date, one migration per transaction
+fn migrate(conn: &mut Connection) -> Result<(), StoreError> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > MIGRATIONS.len() as i64 {
+        return Err(StoreError::NewerSchema(version));
+    }
+
+    for (applied, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.execute_batch(&format!("PRAGMA user_version = {};", applied + 1))?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+// "www.example.com" -> "com.example.www"
+fn reverse_host(host: &str) -> String {
+    host.rsplit('.').collect::<Vec<_>>().join(".")
+}
+
+// Columns read back by `read_row`, in its order
+const COLUMNS: &str =
+    "id, at, host, port, decision, rule, bytes_up, bytes_down, duration_ms, request";
+
+fn read_row(row: &Row<'_>) -> rusqlite::Result<StoredEvent> {
+    let invalid = |column: usize, e: String| {
+        rusqlite::Error::FromSqlConversionFailure(column, Type::Text, e.into())
+    };
+
+    let at: i64 = row.get(1)?;
+    let at =
+        DateTime::from_timestamp_millis(at).ok_or_else(|| invalid(1, format!("time {}", at)))?;
+    let decision: String = row.get(4)?;
+    let decision =
+        Decision::parse(&decision).ok_or_else(|| invalid(4, format!("decision {}", decision)))?;
+    let request: String = row.get(9)?;
+    let request: RequestLog =
+        serde_json::from_str(&request).map_err(|e| invalid(9, e.to_string()))?;
+
+    Ok(StoredEvent {
+        id: row.get(0)?,
+        event: ActivityEvent {
+            at,
+            host: row.get(2)?,
+            port: row.get(3)?,
+            decision,
+            rule: row.get(5)?,
+            bytes_up: row.get::<_, i64>(6)? as u64,
+            bytes_down: row.get::<_, i64>(7)? as u64,
+            duration_ms: row.get::<_, i64>(8)? as u64,
+            request,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::ConnectRequestLog;
+    use std::collections::HashMap;
+    use tempfile::TempDir;
+
+    fn start() -> DateTime<Utc> {
+        "2026-10-17T08:00:00Z".parse().unwrap()
+    }
+
+    fn event(host: &str, at: DateTime<Utc>) -> ActivityEvent {
+        ActivityEvent {
+            at,
+            host: host.to_string(),
+            port: 443,
+            decision: Decision::Allowed,
+            rule: None,
+            bytes_up: 100,
+            bytes_down: 2000,
+            duration_ms: 30,
+            request: RequestLog::Connect(ConnectRequestLog {
+                client_addr: "127.0.0.1:50000".parse().unwrap(),
+                target_host: host.to_string(),
+                target_port: 443,
+                http_version: "HTTP/1.1".to_string(),
+                headers: HashMap::from([("host".to_string(), format!("{}:443", host))]),
+            }),
+        }
+    }
+
+    fn open(dir: &TempDir) -> ActivityStore {
+        ActivityStore::open(&dir.path().join("activity.db")).unwrap()
+    }
+
+    fn user_version(conn: &Connection) -> i64 {
+        conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    fn hosts(page: &ActivityPage) -> Vec<&str> {
+        page.events
+            .iter()
+            .map(|stored| stored.event.host.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn migrations_are_applied_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history").join("activity.db");
+        let mut store = ActivityStore::open(&path).unwrap();
+        assert_eq!(user_version(&store.conn), MIGRATIONS.len() as i64);
+        let indexes: i64 = store
+            .conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND tbl_name = 'events'
+                     AND name LIKE 'events_%'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(indexes, 3);
+        store.insert(&[event("example.com", start())]).unwrap();
+        drop(store);
+
+        // Opening again applies nothing and keeps the events
+        let store = ActivityStore::open(&path).unwrap();
+        assert_eq!(user_version(&store.conn), MIGRATIONS.len() as i64);
+        let page = store
+            .query(&TimeRange::default(), None, &PageRequest::default())
+            .unwrap();
+        assert_eq!(hosts(&page), ["example.com"]);
+    }
+
+    #[test]
+    fn newer_schema_is_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("activity.db");
+        let newer = MIGRATIONS.len() as i64 + 1;
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(&format!(
+            "CREATE TABLE future (x INTEGER); PRAGMA user_version = {};",
+            newer
+        ))
+        .unwrap();
+        drop(conn);
+
+        match ActivityStore::open(&path) {
+            Err(StoreError::NewerSchema(version)) => assert_eq!(version, newer),
+            other => panic!("expected NewerSchema, got {:?}", other.err()),
+        }
+        let conn = Connection::open(&path).unwrap();
+        assert_eq!(user_version(&conn), newer);
+        let events: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE name = 'events'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(events, 0);
+    }
+
+    #[test]
+    fn events_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open(&dir);
+        let mut blocked = event("games.com", start());
+        blocked.decision = Decision::Blocked;
+        blocked.rule = Some("||games.com (school nights)".to_string());
+        blocked.bytes_up = u64::from(u32::MAX) + 1;
+        store.insert(&[blocked.clone()]).unwrap();
+
+        let page = store
+            .query(&TimeRange::default(), None, &PageRequest::default())
+            .unwrap();
+        let stored = &page.events[0].event;
+        assert_eq!(
+            serde_json::to_value(stored).unwrap(),
+            serde_json::to_value(&blocked).unwrap()
+        );
+    }
+
+    #[test]
+    fn domains_include_their_subdomains() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open(&dir);
+        let hosts_seen = [
+            "example.com",
+            "www.example.com",
+            "a.b.example.com",
+            "notexample.com",
+            "example.com.evil.org",
+            "example-cdn.com",
+            "example.co",
+            "example.org",
+        ];
+        let events: Vec<_> = hosts_seen
+            .iter()
+            .enumerate()
+            .map(|(i, host)| event(host, start() + TimeDelta::minutes(i as i64)))
+            .collect();
+        store.insert(&events).unwrap();
+
+        let range = TimeRange::default();
+        let page = PageRequest::default();
+        let found = store.query(&range, Some("example.com"), &page).unwrap();
+        assert_eq!(
+            hosts(&found),
+            ["a.b.example.com", "www.example.com", "example.com"]
+        );
+        assert_eq!(found.total, 3);
+
+        let found = store.query(&range, Some("b.example.com"), &page).unwrap();
+        assert_eq!(hosts(&found), ["a.b.example.com"]);
+        let found = store.query(&range, Some("com"), &page).unwrap();
+        assert_eq!(found.total, 5);
+        let found = store.query(&range, Some("xample.com"), &page).unwrap();
+        assert_eq!(found.total, 0);
+        assert!(found.events.is_empty());
+    }
+
+    #[test]
+    fn time_ranges_and_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open(&dir);
+        let events: Vec<_> = (0..10)
+            .map(|i| {
+                event(
+                    &format!("s{}.example.com", i),
+                    start() + TimeDelta::minutes(i),
+                )
+            })
+            .collect();
+        store.insert(&events).unwrap();
+        // Same time as s9: the later row comes first
+        store
+            .insert(&[event("tie.example.com", start() + TimeDelta::minutes(9))])
+            .unwrap();
+
+        // `from` included, `to` excluded
+        let range = TimeRange {
+            from: Some(start() + TimeDelta::minutes(2)),
+            to: Some(start() + TimeDelta::minutes(5)),
+        };
+        let found = store.query(&range, None, &PageRequest::default()).unwrap();
+        assert_eq!(
+            hosts(&found),
+            ["s4.example.com", "s3.example.com", "s2.example.com"]
+        );
+
+        let all = TimeRange::default();
+        let page = |index, size| PageRequest { index, size };
+        let first = store.query(&all, Some("example.com"), &page(0, 4)).unwrap();
+        assert_eq!(first.total, 11);
+        assert_eq!(
+            hosts(&first),
+            [
+                "tie.example.com",
+                "s9.example.com",
+                "s8.example.com",
+                "s7.example.com"
+            ]
+        );
+        let last = store.query(&all, Some("example.com"), &page(2, 4)).unwrap();
+        assert_eq!(
+            hosts(&last),
+            ["s2.example.com", "s1.example.com", "s0.example.com"]
+        );
+        let past_the_end = store.query(&all, None, &page(3, 4)).unwrap();
+        assert!(past_the_end.events.is_empty());
+        assert_eq!(past_the_end.total, 11);
+
+        // Page sizes are kept between 1 and `MAX_PAGE_SIZE`
+        let found = store.query(&all, None, &page(0, 0)).unwrap();
+        assert_eq!((found.size, found.events.len()), (1, 1));
+        let found = store.query(&all, None, &page(0, u32::MAX)).unwrap();
+        assert_eq!((found.size, found.events.len()), (MAX_PAGE_SIZE, 11));
+    }
+
+    #[test]
+    fn uploads_resume_after_the_last_accepted() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open(&dir);
+        let events: Vec<_> = (0..5)
+            .map(|i| event(&format!("s{}.com", i), start() + TimeDelta::minutes(i)))
+            .collect();
+        store.insert(&events).unwrap();
+
+        let batch = store.pending_upload(3).unwrap();
+        assert_eq!(batch.len(), 3);
+        store.mark_uploaded(batch[2].id).unwrap();
+        let rest = store.pending_upload(3).unwrap();
+        assert_eq!(
+            rest.iter()
+                .map(|stored| stored.event.host.as_str())
+                .collect::<Vec<_>>(),
+            ["s3.com", "s4.com"]
+        );
+        store.mark_uploaded(rest[1].id).unwrap();
+        assert!(store.pending_upload(3).unwrap().is_empty());
+    }
+
+    #[test]
+    fn prune_removes_old_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open(&dir);
+        let now = start();
+        let retention = Retention::default();
+        store
+            .insert(&[
+                event("old.com", now - TimeDelta::days(91)),
+                event("edge.com", now - retention.max_age),
+                event("recent.com", now - TimeDelta::days(1)),
+            ])
+            .unwrap();
+
+        assert_eq!(store.prune(now, &retention).unwrap(), 1);
+        let page = store
+            .query(&TimeRange::default(), None, &PageRequest::default())
+            .unwrap();
+        assert_eq!(hosts(&page), ["recent.com", "edge.com"]);
+        assert_eq!(store.prune(now, &retention).unwrap(), 0);
+    }
+
+    #[test]
+    fn prune_keeps_the_database_under_its_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open(&dir);
+        let now = start();
+        let events: Vec<_> = (0..2000)
+            .map(|i| {
+                let mut event = event(&format!("s{}.com", i), now - TimeDelta::seconds(2000 - i));
+                event.rule = Some("x".repeat(200));
+                event
+            })
+            .collect();
+        store.insert(&events).unwrap();
+        let full = store.used_size().unwrap();
+        let pages = |store: &ActivityStore| -> i64 {
+            store
+                .conn
+                .query_row("PRAGMA page_count", [], |row| row.get(0))
+                .unwrap()
+        };
+        let full_pages = pages(&store);
+
+        // Plenty of room: nothing to do
+        let roomy = Retention {
+            max_size: full * 2,
+            ..Retention::default()
+        };
+        assert_eq!(store.prune(now, &roomy).unwrap(), 0);
+
+        let half = Retention {
+            max_size: full / 2,
+            ..Retention::default()
+        };
+        let mut removed = 0;
+        for _ in 0..5 {
+            removed += store.prune(now, &half).unwrap();
+            if store.used_size().unwrap() <= half.max_size {
+                break;
+            }
+        }
+        assert!(store.used_size().unwrap() <= half.max_size);
+        assert!(removed > 0 && removed < 2000, "removed {}", removed);
+        // The space is given back to the file system
+        assert!(pages(&store) < full_pages);
+
+        // The oldest went first
+        let page = store
+            .query(
+                &TimeRange::default(),
+                None,
+                &PageRequest {
+                    index: 0,
+                    size: MAX_PAGE_SIZE,
+                },
+            )
+            .unwrap();
+        assert_eq!(page.total, 2000 - removed as u64);
+        assert_eq!(page.events[0].event.host, "s1999.com");
+        let oldest = store.pending_upload(1).unwrap();
+        assert_eq!(oldest[0].event.host, format!("s{}.com", removed));
+    }
+}
@@ -0,0 +1,644 @@
+// ============================================================================
+//  BLOCK PAGE - WHAT THE BROWSER SHOWS INSTEAD OF A BLOCKED SITE
+// ============================================================================
+// A bare "403 Forbidden" shows up as a browser error, and looks like the internet
+// is broken. Requests the proxy refuses get an HTML page instead:
+// 1. Which host was blocked, why (the matching rule and its scheduled group, the
+//    allowlist, or a used-up screen-time budget) and when
+// 2. A "request access" button, leading to the local site (see local_site.rs),
+//    where the child sends a request to a parent and later sees the answer, or
+//    a parent standing by types their code (see parent_override.rs)
+// 3. In the browser's language when it's one we have (Accept-Language), English
+//    otherwise
+//
+// The templates are in templates/ and embedded in the binary. Every value put in
+// them is HTML-escaped.
+//
+// The page can be shown for plain HTTP and for intercepted HTTPS. A blocked tunnel
+// that can't be intercepted only gets a 403: browsers never show what a proxy
+// answers to CONNECT.
+
+use crate::windows::access_request::{AccessRequest, AccessStatus, MAX_REASON_CHARS};
+use crate::windows::local_site::{LocalResponse, LOCAL_HOST};
+use crate::windows::parent_override::{Override, OverrideError, OverrideScope};
+use crate::windows::policy::BlockReason;
+use chrono::{DateTime, Local, Utc};
+
+const BLOCK_PAGE_TEMPLATE: &str = include_str!("templates/block_page.html");
+const REQUEST_ACCESS_TEMPLATE: &str = include_str!("templates/request_access.html");
+const REQUEST_STATUS_TEMPLATE: &str = include_str!("templates/request_status.html");
+
+// Why a request was refused
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockCause {
+    Policy(BlockReason), // The blocklist or allowlist
+    ScreenTime(String),  // The daily budget of this category is used up
+    Unavailable,         // The filter couldn't be read, refused to be safe
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    English,
+    Portuguese,
+}
+
+impl Language {
+    // The language the browser prefers among ours, from its Accept-Language header
+    // (e.g. "pt-PT,pt;q=0.9,en;q=0.8")
+    pub fn from_accept_language(header: Option<&str>) -> Self {
+        let mut ranges: Vec<(&str, f32)> = header
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let tag = parts.next()?.trim();
+                let quality = parts
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.trim().parse().ok())?;
+                Some((tag, quality))
+            })
+            .filter(|(tag, quality)| !tag.is_empty() && *quality > 0.0)
+            .collect();
+        // Stable, so ranges of equal quality keep the browser's order
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        ranges
+            .iter()
+            .find_map(|(tag, _)| {
+                let primary = tag.split('-').next().unwrap_or_default();
+                match primary.to_ascii_lowercase().as_str() {
+                    "en" => Some(Language::English),
+                    "pt" => Some(Language::Portuguese),
+                    _ => None,
+                }
+            })
+            .unwrap_or(Language::English)
+    }
+
+    fn code(self) -> &'static str {
+        match self {
+            Language::English => "en",
+            Language::Portuguese => "pt",
+        }
+    }
+
+    fn messages(self) -> &'static Messages {
+        match self {
+            Language::English => &ENGLISH,
+            Language::Portuguese => &PORTUGUESE,
+        }
+    }
+}
+
+// The texts of the pages in one language. "{}" is replaced by a value.
+struct Messages {
+    title: &'static str,
+    heading: &'static str,
+    message: &'static str,
+    host_label: &'static str,
+    reason_label: &'static str,
+    time_label: &'static str,
+    request_access: &'static str,
+    listed: &'static str,           // {} = rule
+    listed_scheduled: &'static str, // {} = rule, {} = group
+    not_allowed: &'static str,
+    screen_time: &'static str, // {} = category
+    unavailable: &'static str,
+    request_heading: &'static str,
+    request_message: &'static str,
+    reason_prompt: &'static str,
+    send: &'static str,
+    status_heading: &'static str,
+    pending_unsent: &'static str, // {} = time
+    pending: &'static str,        // {} = time
+    approved: &'static str,
+    approved_until: &'static str, // {} = time
+    denied: &'static str,
+    expired: &'static str, // {} = time
+    too_many: &'static str,
+    parent_code: &'static str,
+    parent_heading: &'static str,
+    code_label: &'static str,
+    duration_label: &'static str,
+    scope_host: &'static str,
+    scope_all: &'static str,
+    unlock: &'static str,
+    unlocked_host: &'static str, // {} = time
+    unlocked_all: &'static str,  // {} = time
+    wrong_code: &'static str,
+    code_rate_limited: &'static str, // {} = time
+    codes_not_set_up: &'static str,
+    invalid_unlock: &'static str,
+    code_unchecked: &'static str,
+    time_format: &'static str,
+}
+
+const ENGLISH: Messages = Messages {
+    title: "Site blocked - GuardNest",
+    heading: "This site is blocked",
+    message: "GuardNest blocked this page on this computer.",
+    host_label: "Site",
+    reason_label: "Reason",
+    time_label: "Time",
+    request_access: "Request access",
+    listed: "Blocked by the rule {}",
+    listed_scheduled: "Blocked by the rule {} of \"{}\", at this time of day",
+    not_allowed: "Not on the list of allowed sites",
+    screen_time: "Today's time for \"{}\" is used up",
+    unavailable: "The filter couldn't be checked",
+    request_heading: "Ask a parent",
+    request_message:
+        "Send a request: a parent gets it in the GuardNest app and can allow this site.",
+    reason_prompt: "Why do you need it? (optional)",
+    send: "Send request",
+    status_heading: "Access request",
+    pending_unsent: "Saved at {}. It goes to a parent as soon as this computer is online.",
+    pending: "Sent to a parent at {}, waiting for an answer. Reload this page to see it.",
+    approved: "Allowed. Reload the site to open it.",
+    approved_until: "Allowed until {}. Reload the site to open it.",
+    denied: "A parent said no.",
+    expired: "The access ended at {}.",
+    too_many:
+        "Too many requests are waiting for an answer. Try again once a parent has answered them.",
+    parent_code: "I have a parent code",
+    parent_heading: "Parent code",
+    code_label: "Code shown in the parent's GuardNest app",
+    duration_label: "Unlock for",
+    scope_host: "This site",
+    scope_all: "All sites",
+    unlock: "Unlock",
+    unlocked_host: "Unlocked until {}. Reload the site to open it.",
+    unlocked_all: "Filtering is off until {}.",
+    wrong_code: "Wrong code, or it was used already. Wait for the next one and try again.",
+    code_rate_limited: "Too many wrong codes. Try again after {}.",
+    codes_not_set_up: "Parent codes aren't set up on this computer.",
+    invalid_unlock: "Choose this site or all sites, for up to 24 hours.",
+    code_unchecked: "The code couldn't be checked.",
+    time_format: "%Y-%m-%d %H:%M",
+};
+
+const PORTUGUESE: Messages = Messages {
+    title: "Site bloqueado - GuardNest",
+    heading: "Este site está bloqueado",
+    message: "O GuardNest bloqueou esta página neste computador.",
+    host_label: "Site",
+    reason_label: "Motivo",
+    time_label: "Hora",
+    request_access: "Pedir acesso",
+    listed: "Bloqueado pela regra {}",
+    listed_scheduled: "Bloqueado pela regra {} de \"{}\", a esta hora do dia",
+    not_allowed: "Não está na lista de sites permitidos",
+    screen_time: "O tempo de hoje para \"{}\" esgotou",
+    unavailable: "Não foi possível verificar o filtro",
+    request_heading: "Pede a um dos pais",
+    request_message: "Envia um pedido: um dos pais recebe-o na app GuardNest e pode permitir este site.",
+    reason_prompt: "Para que precisas? (opcional)",
+    send: "Enviar pedido",
+    status_heading: "Pedido de acesso",
+    pending_unsent: "Guardado em {}. Segue para um dos pais assim que este computador estiver online.",
+    pending: "Enviado a um dos pais em {}, à espera de resposta. Volta a carregar esta página para a ver.",
+    approved: "Permitido. Volta a carregar o site para o abrir.",
+    approved_until: "Permitido até {}. Volta a carregar o site para o abrir.",
+    denied: "Um dos pais disse que não.",
+    expired: "O acesso terminou em {}.",
+    too_many: "Há demasiados pedidos à espera de resposta. Tenta de novo quando um dos pais lhes responder.",
+    parent_code: "Tenho um código dos pais",
+    parent_heading: "Código dos pais",
+    code_label: "Código mostrado na app GuardNest dos pais",
+    duration_label: "Desbloquear durante",
+    scope_host: "Este site",
+    scope_all: "Todos os sites",
+    unlock: "Desbloquear",
+    unlocked_host: "Desbloqueado até {}. Volta a carregar o site para o abrir.",
+    unlocked_all: "O filtro está desligado até {}.",
+    wrong_code: "Código errado, ou já foi usado. Espera pelo próximo e tenta de novo.",
+    code_rate_limited: "Demasiados códigos errados. Tenta de novo depois de {}.",
+    codes_not_set_up: "Os códigos dos pais não estão configurados neste computador.",
+    invalid_unlock: "Escolhe este site ou todos os sites, até 24 horas.",
+    code_unchecked: "Não foi possível verificar o código.",
+    time_format: "%d/%m/%Y %H:%M",
+};
+
+// The block page for a request to `host`, refused at `at` because of `cause`
+pub fn block_page(
+    host: &str,
+    cause: &BlockCause,
+    at: DateTime<Local>,
+    accept_language: Option<&str>,
+) -> LocalResponse {
+    let language = Language::from_accept_language(accept_language);
+    let messages = language.messages();
+
+    let reason = match cause {
+        BlockCause::Policy(BlockReason::Listed { rule, group: None }) => {
+            messages.listed.replacen("{}", &rule.to_string(), 1)
+        }
+        BlockCause::Policy(BlockReason::Listed {
+            rule,
+            group: Some(group),
+        }) => messages
+            .listed_scheduled
+            .replacen("{}", &rule.to_string(), 1)
+            .replacen("{}", group, 1),
+        BlockCause::Policy(BlockReason::ListedPath { rule, group: None }) => {
+            messages.listed.replacen("{}", &rule.to_string(), 1)
+        }
+        BlockCause::Policy(BlockReason::ListedPath {
+            rule,
+            group: Some(group),
+        }) => messages
+            .listed_scheduled
+            .replacen("{}", &rule.to_string(), 1)
+            .replacen("{}", group, 1),
+        BlockCause::Policy(BlockReason::NotAllowed) => messages.not_allowed.to_string(),
+        BlockCause::ScreenTime(category) => messages.screen_time.replacen("{}", category, 1),
+        BlockCause::Unavailable => messages.unavailable.to_string(),
+    };
+    let request_url = format!(
+        "http://{}/request-access?host={}",
+        LOCAL_HOST,
+        encode_query(host)
+    );
+
+    let html = render(
+        BLOCK_PAGE_TEMPLATE,
+        &[
+            ("lang", language.code()),
+            ("title", messages.title),
+            ("heading", messages.heading),
+            ("message", messages.message),
+            ("host_label", messages.host_label),
+            ("host", host),
+            ("reason_label", messages.reason_label),
+            ("reason", &reason),
+            ("time_label", messages.time_label),
+            ("time", &at.format(messages.time_format).to_string()),
+            ("request_access", messages.request_access),
+            ("request_url", &request_url),
+            ("parent_code", messages.parent_code),
+            ("parent_url", &format!("{}#parent", request_url)),
+        ],
+    );
+    LocalResponse::new(
+        403,
+        "Forbidden",
+        "text/html; charset=utf-8",
+        html.into_bytes(),
+    )
+}
+
+// The page the "request access" button leads to: a form asking a parent for `host`.
+// The answer to the `previous` request for it, if any, is shown above the form.
+pub fn request_access_page(
+    host: &str,
+    previous: Option<&AccessRequest>,
+    accept_language: Option<&str>,
+) -> LocalResponse {
+    let language = Language::from_accept_language(accept_language);
+    let messages = language.messages();
+    let message = match previous {
+        Some(previous) => format!(
+            "{} {}",
+            status_text(previous, messages),
+            messages.request_message
+        ),
+        None => messages.request_message.to_string(),
+    };
+
+    let max_reason = MAX_REASON_CHARS.to_string();
+    let mut values = vec![
+        ("lang", language.code()),
+        ("title", messages.title),
+        ("heading", messages.request_heading),
+        ("host", host),
+        ("message", &message),
+        ("reason_label", messages.reason_prompt),
+        ("max_reason", &max_reason),
+        ("send", messages.send),
+    ];
+    values.extend(parent_form(messages));
+    let html = render(REQUEST_ACCESS_TEMPLATE, &values);
+    LocalResponse::new(200, "OK", "text/html; charset=utf-8", html.into_bytes())
+}
+
+// Where a request stands, shown once it was sent
+pub fn access_request_page(
+    request: &AccessRequest,
+    accept_language: Option<&str>,
+) -> LocalResponse {
+    let language = Language::from_accept_language(accept_language);
+    let messages = language.messages();
+    let status = status_text(request, messages);
+    status_page(
+        200,
+        "OK",
+        messages.status_heading,
+        &request.host,
+        &status,
+        language,
+    )
+}
+
+// Refusal of a new request while too many are waiting for a parent
+pub fn too_many_requests_page(host: &str, accept_language: Option<&str>) -> LocalResponse {
+    let language = Language::from_accept_language(accept_language);
+    let messages = language.messages();
+    status_page(
+        429,
+        "Too Many Requests",
+        messages.status_heading,
+        host,
+        messages.too_many,
+        language,
+    )
+}
+
+// The answer to a parent code typed for `host` on the local site
+pub fn parent_override_page(
+    host: &str,
+    result: &Result<Override, OverrideError>,
+    accept_language: Option<&str>,
+) -> LocalResponse {
+    let language = Language::from_accept_language(accept_language);
+    let messages = language.messages();
+    let time = |at: DateTime<Utc>| {
+        at.with_timezone(&Local)
+            .format(messages.time_format)
+            .to_string()
+    };
+
+    let (status, reason, text) = match result {
+        Ok(granted) => {
+            let text = match granted.scope {
+                OverrideScope::All => messages.unlocked_all,
+                OverrideScope::Host { .. } => messages.unlocked_host,
+            };
+            (200, "OK", text.replacen("{}", &time(granted.until), 1))
+        }
+        Err(OverrideError::WrongCode) => (403, "Forbidden", messages.wrong_code.to_string()),
+        Err(OverrideError::RateLimited { retry_at }) => (
+            429,
+            "Too Many Requests",
+            messages
+                .code_rate_limited
+                .replacen("{}", &time(*retry_at), 1),
+        ),
+        Err(OverrideError::NotConfigured) => (
+            503,
+            "Service Unavailable",
+            messages.codes_not_set_up.to_string(),
+        ),
+        Err(OverrideError::InvalidDuration | OverrideError::InvalidHost) => {
+            (400, "Bad Request", messages.invalid_unlock.to_string())
+        }
+        Err(OverrideError::Crypto(_)) => (
+            500,
+            "Internal Server Error",
+            messages.code_unchecked.to_string(),
+        ),
+    };
+    status_page(
+        status,
+        reason,
+        messages.parent_heading,
+        host,
+        &text,
+        language,
+    )
+}
+
+fn status_text(request: &AccessRequest, messages: &Messages) -> String {
+    let time = |at: DateTime<Utc>| {
+        at.with_timezone(&Local)
+            .format(messages.time_format)
+            .to_string()
+    };
+
+    let expires_at = request.grant.as_ref().and_then(|grant| grant.expires_at);
+    match (request.status, expires_at) {
+        (AccessStatus::Pending, _) if !request.sent => {
+            messages
+                .pending_unsent
+                .replacen("{}", &time(request.requested_at), 1)
+        }
+        (AccessStatus::Pending, _) => {
+            messages
+                .pending
+                .replacen("{}", &time(request.requested_at), 1)
+        }
+        (AccessStatus::Approved, None) => messages.approved.to_string(),
+        (AccessStatus::Approved, Some(until)) => {
+            messages.approved_until.replacen("{}", &time(until), 1)
+        }
+        (AccessStatus::Denied, _) => messages.denied.to_string(),
+        (AccessStatus::Expired, until) => {
+            messages
+                .expired
+                .replacen("{}", &until.map(time).unwrap_or_default(), 1)
+        }
+    }
+}
+
+fn status_page(
+    status: u16,
+    reason: &'static str,
+    heading: &str,
+    host: &str,
+    text: &str,
+    language: Language,
+) -> LocalResponse {
+    let messages = language.messages();
+    let mut values = vec![
+        ("lang", language.code()),
+        ("title", messages.title),
+        ("heading", heading),
+        ("host", host),
+        ("status", text),
+    ];
+    values.extend(parent_form(messages));
+    let html = render(REQUEST_STATUS_TEMPLATE, &values);
+    LocalResponse::new(
+        status,
+        reason,
+        "text/html; charset=utf-8",
+        html.into_bytes(),
+    )
+}
+
+// The texts of the parent code form, at the bottom of the local site's pages
+fn parent_form(messages: &Messages) -> [(&'static str, &'static str); 6] {
+    [
+        ("parent_heading", messages.parent_heading),
+        ("code_label", messages.code_label),
+        ("duration_label", messages.duration_label),
+        ("scope_host", messages.scope_host),
+        ("scope_all", messages.scope_all),
+        ("unlock", messages.unlock),
+    ]
+}
+
+// Replaces each "{{name}}" in `template` with its value, HTML-escaped. Done in
+// one pass, so values are never searched for placeholders themselves.
+fn render(template: &str, values: &[(&str, &str)]) -> String {
+    let mut html = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        html.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            rest = &rest[start..];
+            break;
+        };
+        let name = &after[..end];
+        match values.iter().find(|(key, _)| *key == name) {
+            Some((_, value)) => html.push_str(&escape_html(value)),
+            None => html.push_str(&rest[start..start + 2 + end + 2]),
+        }
+        rest = &after[end + 2..];
+    }
+    html.push_str(rest);
+    html
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Percent-encodes `text` for a query string value
+fn encode_query(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::windows::domain_matcher::DomainRule;
+    use chrono::TimeZone;
+
+    fn body(response: &LocalResponse) -> &str {
+        std::str::from_utf8(&response.body).unwrap()
+    }
+
+    #[test]
+    fn values_are_escaped() {
+        assert_eq!(
+            escape_html(r#"<script>alert("a" & 'b')</script>"#),
+            "&lt;script&gt;alert(&quot;a&quot; &amp; &#39;b&#39;)&lt;/script&gt;"
+        );
+        assert_eq!(escape_html("café &amp;"), "café &amp;amp;");
+
+        let html = render(
+            r#"<a href="{{url}}">{{host}}</a> {{missing}} {{host"#,
+            &[("host", "{{url}}<b>"), ("url", "/x?a=1&b=\"2\"")],
+        );
+        // Values are never expanded themselves, unknown and unclosed placeholders stay
+        assert_eq!(
+            html,
+            r#"<a href="/x?a=1&amp;b=&quot;2&quot;">{{url}}&lt;b&gt;</a> {{missing}} {{host"#
+        );
+    }
+
+    #[test]
+    fn query_values_are_percent_encoded() {
+        assert_eq!(encode_query("games.example"), "games.example");
+        assert_eq!(encode_query("A-z_0.9~"), "A-z_0.9~");
+        assert_eq!(
+            encode_query("a b&c=d/é?#+"),
+            "a%20b%26c%3Dd%2F%C3%A9%3F%23%2B"
+        );
+    }
+
+    #[test]
+    fn language_from_accept_language() {
+        let language = Language::from_accept_language;
+        assert_eq!(language(None), Language::English);
+        assert_eq!(language(Some("")), Language::English);
+        assert_eq!(
+            language(Some("pt-PT,pt;q=0.9,en;q=0.8")),
+            Language::Portuguese
+        );
+        assert_eq!(language(Some("PT-br")), Language::Portuguese);
+
+        // Ours aren't all listed first: by quality, then in the browser's order
+        assert_eq!(language(Some("en;q=0.5, pt-BR")), Language::Portuguese);
+        assert_eq!(language(Some("pt-BR;q=0.8, en;q=0.9")), Language::English);
+        assert_eq!(language(Some("pt;q=0.7, en;q=0.7")), Language::Portuguese);
+        assert_eq!(
+            language(Some("fr-FR, de;q=0.9, pt;q=0.1")),
+            Language::Portuguese
+        );
+
+        // Unknown, refused (q=0) or malformed ranges fall back to English
+        assert_eq!(language(Some("fr-FR, de;q=0.9")), Language::English);
+        assert_eq!(language(Some("pt;q=0, *")), Language::English);
+        assert_eq!(language(Some("pt;q=high")), Language::English);
+        assert_eq!(language(Some(" ; q=1, ,")), Language::English);
+    }
+
+    #[test]
+    fn block_page_shows_host_rule_and_time() {
+        let at = Local.with_ymd_and_hms(2026, 10, 17, 21, 5, 0).unwrap();
+        let rule = DomainRule::parse("||games.example").unwrap();
+        let cause = BlockCause::Policy(BlockReason::Listed {
+            rule: rule.clone(),
+            group: Some("<School> \"nights\"".to_string()),
+        });
+
+        let page = block_page("games.example", &cause, at, None);
+        assert_eq!((page.status, page.reason), (403, "Forbidden"));
+        let html = body(&page);
+        assert!(html.contains(r#"<html lang="en">"#));
+        assert!(html.contains("<dd>games.example</dd>"));
+        assert!(html.contains(&format!(
+            "Blocked by the rule {} of &quot;&lt;School&gt; &quot;nights&quot;&quot;",
+            rule
+        )));
+        assert!(html.contains("<dd>2026-10-17 21:05</dd>"));
+        assert!(html.contains(r#"href="http://guardnest.local/request-access?host=games.example""#));
+        assert!(!html.contains("<School>"));
+
+        let page = block_page(
+            "games.example",
+            &BlockCause::ScreenTime("vídeo".to_string()),
+            at,
+            Some("pt-PT,pt;q=0.9"),
+        );
+        let html = body(&page);
+        assert!(html.contains(r#"<html lang="pt">"#));
+        assert!(html.contains("<dd>17/10/2026 21:05</dd>"));
+        assert!(html.contains("vídeo"));
+    }
+
+    #[test]
+    fn hostile_hosts_stay_text() {
+        let at = Local.with_ymd_and_hms(2026, 10, 17, 9, 0, 0).unwrap();
+        let host = r#"x.example"><script>alert('x')</script>"#;
+        let page = block_page(host, &BlockCause::Unavailable, at, None);
+        let html = body(&page);
+        assert!(!html.contains("<script>"));
+        assert!(html.contains(
+            "<dd>x.example&quot;&gt;&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt;</dd>"
+        ));
+        // In the link it is percent-encoded first, so nothing is left to escape
+        assert!(html.contains(&format!("request-access?host={}\"", encode_query(host))));
+    }
+}
@@ -0,0 +1,544 @@
+// ============================================================================
+//  INTERCEPTION BYPASS - HOSTS THAT ARE ALWAYS TUNNELED, NEVER DECRYPTED
+// ============================================================================
+// Some connections break when TLS interception forges their certificate: banking
+// sites, OS updates, and apps that pin their server's certificate. Those hosts
+// are passed through as plain tunnels (still checked against the blocklist and
+// the SNI, see proxy.rs) when:
+// 1. They are covered by an enabled built-in category ("banking", "os_updates",
+//    "pinned_apps")
+// 2. They match one of the custom domain rules (same syntax as the blocklist:
+//    "example.com", "=example.com", "*.example.com")
+// 3. They were learned as pinned: the client aborted the TLS handshake against
+//    our leaf certificate `LEARN_AFTER_FAILURES` times within `LEARN_WINDOW`.
+//    Learned hosts are reported to the parent dashboard and expire after
+//    `LEARNED_FOR`: any client can abort a handshake on purpose, so a host
+//    learned that way can't stay exempt from interception for good.
+//
+// The settings and learned hosts are saved to `INTERCEPT_BYPASS_PATH`.
+
+use crate::windows::domain_matcher::{normalize_host, DomainMatcher};
+use crate::windows::key_protector::write_atomic;
+use crate::windows::schedule::{Clock, SystemClock};
+use chrono::{DateTime, Duration as TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+use std::{fs, io, path::Path};
+
+pub const INTERCEPT_BYPASS_PATH: &str = "C:\\ProgramData\\GuardNest\\intercept_bypass.json";
+
+// Handshake failures for one host within `LEARN_WINDOW` before it counts as pinned.
+// More than one, so a single closed tab or network hiccup doesn't turn it off.
+const LEARN_AFTER_FAILURES: u32 = 3;
+const LEARN_WINDOW: TimeDelta = TimeDelta::minutes(10);
+
+// How long a learned host is tunneled. A host that really pins its certificate
+// fails again and is learned again; one learned from aborts staged by the child
+// is intercepted again after this.
+const LEARNED_FOR: TimeDelta = TimeDelta::days(7);
+
+// Built-in categories and the rules they cover
+const CATEGORIES: &[(&str, &[&str])] = &[
+    (
+        "banking",
+        &[
+            "paypal.com",
+            "stripe.com",
+            "revolut.com",
+            "wise.com",
+            "n26.com",
+            "chase.com",
+            "bankofamerica.com",
+            "wellsfargo.com",
+            "hsbc.com",
+            "cgd.pt",
+            "millenniumbcp.pt",
+            "novobanco.pt",
+            "santander.pt",
+            "bancobpi.pt",
+            "activobank.pt",
+            "montepio.pt",
+            "creditoagricola.pt",
+        ],
+    ),
+    (
+        "os_updates",
+        &[
+            "windowsupdate.com",
+            "update.microsoft.com",
+            "delivery.mp.microsoft.com",
+            "officecdn.microsoft.com",
+            "msftconnecttest.com",
+            "swcdn.apple.com",
+            "mesu.apple.com",
+            "update.googleapis.com",
+            "gvt1.com",
+            "aus5.mozilla.org",
+        ],
+    ),
+    (
+        "pinned_apps",
+        &[
+            "whatsapp.com",
+            "whatsapp.net",
+            "signal.org",
+            "dropbox.com",
+            "dropboxapi.com",
+            "push.apple.com",
+            "login.microsoftonline.com",
+            "events.data.microsoft.com",
+        ],
+    ),
+];
+
+// Names of the built-in categories
+pub fn category_names() -> Vec<&'static str> {
+    CATEGORIES.iter().map(|(name, _)| *name).collect()
+}
+
+// What the bypass list is made of, as set from the app
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BypassConfig {
+    pub categories: BTreeSet<String>, // Enabled built-in categories
+    pub domains: Vec<String>,         // Custom domain rules
+}
+
+impl Default for BypassConfig {
+    // All built-in categories, no custom rules
+    fn default() -> Self {
+        BypassConfig {
+            categories: category_names().into_iter().map(String::from).collect(),
+            domains: Vec::new(),
+        }
+    }
+}
+
+// A host learned as pinned from its handshake failures
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PinnedHost {
+    pub host: String,
+    pub detected_at: DateTime<Utc>,
+    #[serde(default)]
+    pub reported: bool, // Sent to the parent dashboard
+}
+
+// The bypass list as shown in the app
+#[derive(Debug, Clone, Serialize)]
+pub struct BypassStatus {
+    pub available_categories: Vec<&'static str>, // Built-in categories that can be enabled
+    pub config: BypassConfig,
+    pub learned: Vec<PinnedHost>,
+}
+
+// Layout of the file at `INTERCEPT_BYPASS_PATH`
+#[derive(Serialize, Deserialize, Default)]
+struct SavedBypass {
+    #[serde(default)]
+    config: BypassConfig,
+    #[serde(default)]
+    learned: Vec<PinnedHost>,
+}
+
+// Handshake failures of a host not (yet) learned as pinned
+struct Failures {
+    count: u32,
+    since: DateTime<Utc>, // First failure of the current window
+}
+
+pub struct InterceptBypass {
+    config: BypassConfig,
+    rules: DomainMatcher, // Enabled categories + custom rules
+    learned: BTreeMap<String, PinnedHost>,
+    failures: HashMap<String, Failures>,
+    clock: Arc<dyn Clock>,
+    dirty: bool, // Changed since the last save
+}
+
+impl Default for InterceptBypass {
+    fn default() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+}
+
+impl InterceptBypass {
+    // Default settings, reading the time from `clock` (e.g. a fixed clock in tests)
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        let config = BypassConfig::default();
+        InterceptBypass {
+            rules: build_rules(&config),
+            config,
+            learned: BTreeMap::new(),
+            failures: HashMap::new(),
+            clock,
+            dirty: false,
+        }
+    }
+
+    pub fn config(&self) -> &BypassConfig {
+        &self.config
+    }
+
+    // Replaces the categories and custom rules. Learned hosts are kept.
+    pub fn set_config(&mut self, config: BypassConfig) -> Result<(), String> {
+        let known = category_names();
+        if let Some(unknown) = config
+            .categories
+            .iter()
+            .find(|category| !known.contains(&category.as_str()))
+        {
+            return Err(format!("Unknown bypass category \"{}\"", unknown));
+        }
+        if let Some(invalid) = config
+            .domains
+            .iter()
+            .find(|rule| DomainMatcher::from_rules([rule.as_str()]).is_empty())
+        {
+            return Err(format!("Invalid domain rule \"{}\"", invalid));
+        }
+
+        self.rules = build_rules(&config);
+        self.config = config;
+        self.dirty = true;
+        Ok(())
+    }
+
+    // Whether connections to `host` must not be intercepted
+    pub fn bypasses(&self, host: &str) -> bool {
+        if self.rules.matches(host) {
+            return true;
+        }
+        let now = self.clock.now();
+        normalize_host(host).is_some_and(|host| {
+            self.learned
+                .get(&host)
+                .is_some_and(|pinned| is_current(pinned, now))
+        })
+    }
+
+    // Records that a client aborted the TLS handshake against our leaf for `host`.
+    // Returns true when this makes the host learned as pinned.
+    pub fn handshake_failed(&mut self, host: &str) -> bool {
+        let Some(host) = normalize_host(host) else {
+            return false;
+        };
+        let now = self.clock.now();
+        self.drop_expired(now);
+        if self.learned.contains_key(&host) {
+            return false;
+        }
+
+        // Failures from before the window can't add up to anything anymore
+        self.failures
+            .retain(|_, failures| now - failures.since <= LEARN_WINDOW);
+
+        let failures = self.failures.entry(host.clone()).or_insert(Failures {
+            count: 0,
+            since: now,
+        });
+        failures.count += 1;
+        if failures.count < LEARN_AFTER_FAILURES {
+            return false;
+        }
+
+        self.failures.remove(&host);
+        self.learned.insert(
+            host.clone(),
+            PinnedHost {
+                host,
+                detected_at: now,
+                reported: false,
+            },
+        );
+        self.dirty = true;
+        true
+    }
+
+    // A completed handshake clears the host's failures
+    pub fn handshake_succeeded(&mut self, host: &str) {
+        if let Some(host) = normalize_host(host) {
+            self.failures.remove(&host);
+        }
+    }
+
+    pub fn status(&self) -> BypassStatus {
+        BypassStatus {
+            available_categories: category_names(),
+            config: self.config.clone(),
+            learned: self.learned(),
+        }
+    }
+
+    // Hosts learned as pinned that haven't expired, in name order
+    pub fn learned(&self) -> Vec<PinnedHost> {
+        let now = self.clock.now();
+        self.learned
+            .values()
+            .filter(|pinned| is_current(pinned, now))
+            .cloned()
+            .collect()
+    }
+
+    // Drops a learned host, so it's intercepted again. Returns false if it wasn't learned.
+    pub fn forget(&mut self, host: &str) -> bool {
+        let removed = normalize_host(host).is_some_and(|host| self.learned.remove(&host).is_some());
+        self.dirty |= removed;
+        removed
+    }
+
+    // Learned hosts the parent dashboard hasn't been told about yet
+    pub fn unreported(&self) -> Vec<PinnedHost> {
+        self.learned()
+            .into_iter()
+            .filter(|pinned| !pinned.reported)
+            .collect()
+    }
+
+    // Marks learned hosts as sent to the parent dashboard
+    pub fn mark_reported(&mut self, hosts: &[PinnedHost]) {
+        for reported in hosts {
+            if let Some(pinned) = self.learned.get_mut(&reported.host) {
+                pinned.reported = true;
+                self.dirty = true;
+            }
+        }
+    }
+
+    // Restores the settings and learned hosts saved by `save`
+    pub fn load(&mut self, path: &Path) -> io::Result<()> {
+        let data = fs::read(path)?;
+        let saved: SavedBypass = serde_json::from_slice(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        self.set_config(saved.config)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.learned = saved
+            .learned
+            .into_iter()
+            .map(|pinned| (pinned.host.clone(), pinned))
+            .collect();
+        self.failures.clear();
+        self.dirty = false;
+        Ok(())
+    }
+
+    // Writes the settings and learned hosts to disk if they changed since the last save
+    pub fn save(&mut self, path: &Path) -> io::Result<()> {
+        self.drop_expired(self.clock.now());
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let saved = SavedBypass {
+            config: self.config.clone(),
+            learned: self.learned(),
+        };
+        let data = serde_json::to_vec_pretty(&saved)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        write_atomic(path, &data)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    // Forgets the learned hosts whose time is up, so they are intercepted again
+    fn drop_expired(&mut self, now: DateTime<Utc>) {
+        let count = self.learned.len();
+        self.learned.retain(|_, pinned| is_current(pinned, now));
+        self.dirty |= self.learned.len() != count;
+    }
+}
+
+// Whether a learned host is still tunneled at `now`
+fn is_current(pinned: &PinnedHost, now: DateTime<Utc>) -> bool {
+    now - pinned.detected_at < LEARNED_FOR
+}
+
+// One trie for the enabled categories and the custom rules
+fn build_rules(config: &BypassConfig) -> DomainMatcher {
+    let mut rules = DomainMatcher::from_rules(&config.domains);
+    for (name, domains) in CATEGORIES {
+        if config.categories.contains(*name) {
+            for domain in *domains {
+                rules.insert(domain);
+            }
+        }
+    }
+    rules
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::windows::screen_time::tests::SteppingClock;
+    use chrono::TimeZone;
+
+    // A bypass list and its clock, which moves one second per reading
+    fn bypass_list() -> (InterceptBypass, Arc<SteppingClock>) {
+        let clock = Arc::new(SteppingClock::starting_at(
+            Utc.with_ymd_and_hms(2026, 10, 12, 9, 0, 0).unwrap(),
+        ));
+        (InterceptBypass::with_clock(clock.clone()), clock)
+    }
+
+    fn advance(clock: &SteppingClock, by: TimeDelta) {
+        *clock.0.lock().unwrap() += by;
+    }
+
+    // Fails `times` handshakes for `host`, returns whether the last one learned it
+    fn fail(bypass: &mut InterceptBypass, host: &str, times: u32) -> bool {
+        let mut learned = false;
+        for _ in 0..times {
+            learned = bypass.handshake_failed(host);
+        }
+        learned
+    }
+
+    #[test]
+    fn categories_and_custom_rules() {
+        let (mut bypass, _) = bypass_list();
+        assert!(bypass.bypasses("www.paypal.com"));
+        assert!(!bypass.bypasses("example.com"));
+
+        bypass
+            .set_config(BypassConfig {
+                categories: ["os_updates".to_string()].into(),
+                domains: vec!["=intranet.example".to_string()],
+            })
+            .unwrap();
+        assert!(!bypass.bypasses("www.paypal.com"));
+        assert!(bypass.bypasses("update.microsoft.com"));
+        assert!(bypass.bypasses("intranet.example"));
+        assert!(!bypass.bypasses("www.intranet.example"));
+    }
+
+    #[test]
+    fn set_config_rejects_unknown_settings() {
+        let (mut bypass, _) = bypass_list();
+        let unknown = BypassConfig {
+            categories: ["games".to_string()].into(),
+            domains: Vec::new(),
+        };
+        assert_eq!(
+            bypass.set_config(unknown),
+            Err("Unknown bypass category \"games\"".to_string())
+        );
+        let invalid = BypassConfig {
+            categories: BTreeSet::new(),
+            domains: vec!["example.com".to_string(), "http://".to_string()],
+        };
+        assert_eq!(
+            bypass.set_config(invalid),
+            Err("Invalid domain rule \"http://\"".to_string())
+        );
+        assert_eq!(bypass.config(), &BypassConfig::default());
+    }
+
+    #[test]
+    fn learned_after_repeated_failures() {
+        let (mut bypass, _) = bypass_list();
+        assert!(!fail(&mut bypass, "Api.Bank.Example", 2));
+        assert!(!bypass.bypasses("api.bank.example"));
+        assert!(bypass.handshake_failed("api.bank.example."));
+        assert!(bypass.bypasses("API.bank.example"));
+
+        // Already learned
+        assert!(!bypass.handshake_failed("api.bank.example"));
+        let learned = bypass.learned();
+        assert_eq!(learned.len(), 1);
+        assert_eq!(learned[0].host, "api.bank.example");
+        assert!(!learned[0].reported);
+    }
+
+    #[test]
+    fn failures_only_add_up_within_the_window() {
+        let (mut bypass, clock) = bypass_list();
+        fail(&mut bypass, "example.com", 2);
+        advance(&clock, LEARN_WINDOW);
+        assert!(!bypass.handshake_failed("example.com"));
+        assert!(!fail(&mut bypass, "example.com", 1));
+        assert!(bypass.handshake_failed("example.com"));
+    }
+
+    #[test]
+    fn a_completed_handshake_resets_the_count() {
+        let (mut bypass, _) = bypass_list();
+        fail(&mut bypass, "example.com", 2);
+        bypass.handshake_succeeded("example.com");
+        assert!(!fail(&mut bypass, "example.com", 2));
+        assert!(bypass.handshake_failed("example.com"));
+    }
+
+    #[test]
+    fn learned_hosts_expire() {
+        let (mut bypass, clock) = bypass_list();
+        fail(&mut bypass, "example.com", LEARN_AFTER_FAILURES);
+        advance(&clock, LEARNED_FOR - TimeDelta::minutes(1));
+        assert!(bypass.bypasses("example.com"));
+
+        advance(&clock, TimeDelta::minutes(1));
+        assert!(!bypass.bypasses("example.com"));
+        assert!(bypass.learned().is_empty());
+        assert!(bypass.unreported().is_empty());
+
+        // Learned again if it keeps failing
+        assert!(fail(&mut bypass, "example.com", LEARN_AFTER_FAILURES));
+        assert!(bypass.bypasses("example.com"));
+    }
+
+    #[test]
+    fn forget_and_report() {
+        let (mut bypass, _) = bypass_list();
+        fail(&mut bypass, "a.example", LEARN_AFTER_FAILURES);
+        fail(&mut bypass, "b.example", LEARN_AFTER_FAILURES);
+
+        let unreported = bypass.unreported();
+        assert_eq!(unreported.len(), 2);
+        bypass.mark_reported(&unreported[..1]);
+        let unreported = bypass.unreported();
+        assert_eq!(unreported.len(), 1);
+        assert_eq!(unreported[0].host, "b.example");
+
+        assert!(bypass.forget("A.example"));
+        assert!(!bypass.forget("a.example"));
+        assert!(!bypass.bypasses("a.example"));
+        assert!(bypass.bypasses("b.example"));
+    }
+
+    #[test]
+    fn saved_and_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("GuardNest").join("intercept_bypass.json");
+        let (mut bypass, _) = bypass_list();
+        let config = BypassConfig {
+            categories: ["banking".to_string()].into(),
+            domains: vec!["*.corp.example".to_string()],
+        };
+        bypass.set_config(config.clone()).unwrap();
+        fail(&mut bypass, "api.bank.example", LEARN_AFTER_FAILURES);
+        bypass.mark_reported(&bypass.unreported());
+        bypass.save(&path).unwrap();
+        assert!(!path.with_extension("tmp").exists());
+
+        // Nothing changed, nothing written
+        fs::remove_file(&path).unwrap();
+        bypass.save(&path).unwrap();
+        assert!(!path.exists());
+        bypass.forget("nothing.example");
+        bypass.save(&path).unwrap();
+        assert!(!path.exists());
+
+        bypass.dirty = true;
+        bypass.save(&path).unwrap();
+        let (mut loaded, _) = bypass_list();
+        loaded.load(&path).unwrap();
+        assert_eq!(loaded.config(), &config);
+        assert_eq!(loaded.learned(), bypass.learned());
+        assert!(loaded.bypasses("api.bank.example"));
+        assert!(loaded.bypasses("git.corp.example"));
+
+        fs::write(&path, r#"{"config":{"categories":["games"],"domains":[]}}"#).unwrap();
+        let error = loaded.load(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,179 +1,1199 @@
 use std::fs;
-use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use rcgen::{
-    BasicConstraints, CertificateParams, DnType, IsCa, KeyPair, KeyUsagePurpose,
-    PKCS_ECDSA_P256_SHA256,
+use crate::windows::domain_matcher::normalize_host;
+use crate::windows::key_protector::{
+    default_protector, read_sealed, write_restricted, write_sealed, KeyProtector,
 };
+use crate::windows::local_site::LOCAL_HOST;
+use crate::windows::trust_store::{default_trust_stores, TrustStore};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Duration as TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
 
-// We use OpenSSL only to package the rcgen-generated key/cert into a PKCS#12 (.p12)
+// We use OpenSSL to build the CA certificates, to package them with their keys
+// into PKCS#12 (.p12) files, and to sign the per-host leaf certificates used for
+// TLS interception
+use openssl::asn1::{Asn1Object, Asn1OctetString, Asn1Time, Asn1TimeRef};
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
 use openssl::pkcs12::Pkcs12;
-use openssl::pkey::PKey;
-use openssl::x509::X509;
-use std::process::Command;
+use openssl::pkey::{PKey, Private};
+use openssl::rand::rand_bytes;
+use openssl::x509::extension::{
+    AuthorityKeyIdentifier, BasicConstraints as X509BasicConstraints, ExtendedKeyUsage, KeyUsage,
+    SubjectAlternativeName, SubjectKeyIdentifier,
+};
+use openssl::x509::{X509Builder, X509Extension, X509NameBuilder, X509};
+use std::io;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Where the CA files are stored.
+pub const CA_DIR: &str = "C:\\ProgramData\\GuardNest";
+
+/// How the CA is issued (see `CaConfig`), set by the deployment. Missing: the defaults.
+pub const CA_CONFIG_PATH: &str = "C:\\ProgramData\\GuardNest\\ca_config.json";
+
+/// Random bytes in the PKCS#12 password (base64-encoded).
+const CA_PASSWORD_BYTES: usize = 32;
+
+/// Lifecycle defaults, see `CaConfig`.
+const DEFAULT_VALIDITY_DAYS: u32 = 3650;
+const DEFAULT_RENEW_BEFORE_DAYS: u32 = 30;
+const DEFAULT_OVERLAP_DAYS: u32 = 7;
+
+/// How often the CA's lifecycle is checked while the app runs.
+const LIFECYCLE_CHECK_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// How long a leaf certificate minted for a host is valid.
+const LEAF_VALIDITY_DAYS: u32 = 30;
 
-/// Generate a self-signed Root CA certificate and save it as a PKCS#12 file
-/// at C:\\ProgramData\\GuardNest\\certificate.p12.
+/// Common names of the CA certificates. The root's is what trust stores are
+/// searched for.
+pub const ROOT_NAME: &str = "GuardNest Local CA";
+const INTERMEDIATE_NAME: &str = "GuardNest Intermediate CA";
+
+/// How the GuardNest CA is issued and replaced.
 ///
-/// This root will later be used to sign per-host leaf certificates for MITM.
-pub fn generate_certificate() {
-    println!("Generating GuardNest Root CA (PKCS#12)...");
+/// The CA signing the leaves always has a path length of 0: its key can sign
+/// certificates for hosts, but not another CA.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CaConfig {
+    /// Sign the leaves with an intermediate CA generated with its own key for this
+    /// install. The root (path length 1) then only ever signs the intermediate.
+    pub intermediate: bool,
+    /// Domains the CA may issue certificates for, subdomains included, written
+    /// into the CA certificates as X.509 name constraints (IP addresses are then
+    /// excluded). Hosts outside them are tunneled, never intercepted.
+    /// Empty: any name.
+    pub permitted_domains: Vec<String>,
+    /// How long a new root and intermediate are valid.
+    pub validity_days: u32,
+    /// The CA is replaced by a new one this long before it expires.
+    pub renew_before_days: u32,
+    /// How long a replaced root stays trusted next to the new one, so leaves it
+    /// signed and open connections keep working during the switch.
+    pub overlap_days: u32,
+}
 
-    let output_path = Path::new("C:\\ProgramData\\GuardNest\\certificate.p12");
-    if let Some(parent) = output_path.parent() {
-        if let Err(e) = fs::create_dir_all(parent) {
-            eprintln!("❌ Failed to create directory {:?}: {}", parent, e);
-            return;
+impl Default for CaConfig {
+    fn default() -> Self {
+        CaConfig {
+            intermediate: false,
+            permitted_domains: Vec::new(),
+            validity_days: DEFAULT_VALIDITY_DAYS,
+            renew_before_days: DEFAULT_RENEW_BEFORE_DAYS,
+            overlap_days: DEFAULT_OVERLAP_DAYS,
+        }
+    }
+}
+
+impl CaConfig {
+    /// Read the config at `path`; a missing file gives the defaults.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let config: CaConfig = match fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        config
+            .normalized()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// The same config with the permitted domains lowercased, without trailing
+    /// dots and in punycode, as they must appear in the certificate. Fails if a
+    /// domain or the lifecycle settings are invalid.
+    pub fn normalized(&self) -> Result<Self, String> {
+        if self.validity_days == 0 || self.renew_before_days >= self.validity_days {
+            return Err(format!(
+                "renew_before_days ({}) must be shorter than validity_days ({})",
+                self.renew_before_days, self.validity_days
+            ));
+        }
+
+        let mut permitted_domains = Vec::with_capacity(self.permitted_domains.len());
+        for domain in &self.permitted_domains {
+            // DNS names only: no IP addresses, no wildcards
+            match normalize_host(domain) {
+                Some(normalized)
+                    if normalized.parse::<IpAddr>().is_err()
+                        && normalized
+                            .bytes()
+                            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.') =>
+                {
+                    permitted_domains.push(normalized)
+                }
+                _ => return Err(format!("Invalid permitted domain \"{}\"", domain)),
+            }
+        }
+        permitted_domains.sort();
+        permitted_domains.dedup();
+        Ok(CaConfig {
+            permitted_domains,
+            ..self.clone()
+        })
+    }
+
+    /// Whether CAs issued with `self` and `other` are signed and constrained the
+    /// same way. The lifecycle settings only apply to the next CA, changing them
+    /// doesn't require a new one.
+    pub fn issues_like(&self, other: &CaConfig) -> bool {
+        self.intermediate == other.intermediate && self.permitted_domains == other.permitted_domains
+    }
+
+    /// Whether a CA issued with this config can sign a certificate for `name` (a
+    /// host, or a wildcard like "*.example.com").
+    pub fn permits(&self, name: &str) -> bool {
+        if self.permitted_domains.is_empty() {
+            return true;
+        }
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if name.parse::<IpAddr>().is_ok() || name.contains(':') {
+            return false;
+        }
+        self.permitted_domains.iter().any(|domain| {
+            name == *domain
+                || name
+                    .strip_suffix(domain.as_str())
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+        })
+    }
+}
+
+/// Where a CA is stored.
+pub struct CaFiles {
+    pub root_pfx: PathBuf,            // Root certificate + key (PKCS#12)
+    pub root_secret: PathBuf,         // Its password, sealed
+    pub root_cert: PathBuf,           // Root certificate alone (DER), for trust stores and export
+    pub intermediate_pfx: PathBuf,    // Intermediate certificate + key, if any
+    pub intermediate_secret: PathBuf, // Its password, sealed
+    pub info: PathBuf,                // The `CaConfig` the CA was issued with
+}
+
+impl CaFiles {
+    pub fn in_dir(dir: &Path) -> Self {
+        CaFiles {
+            root_pfx: dir.join("certificate.p12"),
+            root_secret: dir.join("certificate.secret"),
+            root_cert: dir.join("certificate.crt"),
+            intermediate_pfx: dir.join("intermediate.p12"),
+            intermediate_secret: dir.join("intermediate.secret"),
+            info: dir.join("certificate.json"),
         }
     }
+}
+
+impl Default for CaFiles {
+    fn default() -> Self {
+        Self::in_dir(Path::new(CA_DIR))
+    }
+}
+
+/// A root replaced by a newer one, still trusted for a while so what it signed
+/// keeps working during the switch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SupersededRoot {
+    pub thumbprint: String,           // SHA-1, how the Root store identifies it
+    pub trusted_until: DateTime<Utc>, // Removed from the trust stores after this
+}
+
+/// What the app shows about the current CA.
+#[derive(Debug, Clone, Serialize)]
+pub struct CertificateStatus {
+    pub subject: String,
+    pub fingerprint: String, // SHA-256 of the root certificate
+    pub thumbprint: String,  // SHA-1, as shown by Windows
+    pub not_before: DateTime<Utc>,
+    pub not_after: DateTime<Utc>,
+    pub next_rotation: DateTime<Utc>,
+    pub intermediate: bool,
+    pub permitted_domains: Vec<String>,
+    pub superseded: Vec<SupersededRoot>, // Previous roots still trusted
+}
+
+/// The root certificate alone, for installing it by hand in a store
+/// `default_trust_stores` doesn't cover. Never holds the private key.
+#[derive(Debug, Clone, Serialize)]
+pub struct RootExport {
+    pub pem: String,
+    pub der: String,          // Base64
+    pub fingerprint: String,  // SHA-256, to compare with what the browser shows
+    pub thumbprint: String,   // SHA-1
+    pub download_url: String, // Where browsers of this machine can download it
+}
+
+impl RootExport {
+    pub fn new(root: &X509) -> Result<Self, ErrorStack> {
+        Ok(RootExport {
+            pem: String::from_utf8_lossy(&root.to_pem()?).into_owned(),
+            der: STANDARD.encode(root.to_der()?),
+            fingerprint: fingerprint(root)?,
+            thumbprint: thumbprint(root)?,
+            download_url: format!("http://{}/ca.pem", LOCAL_HOST),
+        })
+    }
+}
 
-    // 1) Build CA certificate parameters
-    let mut params = CertificateParams::default();
+/// Layout of `CaFiles::info`.
+#[derive(Serialize, Deserialize)]
+struct CaInfo {
+    config: CaConfig,
+    #[serde(default)]
+    superseded: Vec<SupersededRoot>,
+}
 
-    // Mark as a CA with unconstrained path length
-    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+/// Generate a new GuardNest CA as set by `config` and save it to the default
+/// `CaFiles`: a self-signed Root CA and, if enabled, an intermediate CA below it.
+/// The root of `previous`, the CA being replaced, stays trusted for
+/// `overlap_days`.
+///
+/// Each PKCS#12 file is protected by a random password generated for this device,
+/// stored sealed by the platform's `KeyProtector`. Both are only readable by
+/// administrators. The password is never logged.
+///
+/// This CA will later be used to sign per-host leaf certificates for MITM.
+pub fn generate_certificate(
+    config: &CaConfig,
+    previous: Option<&CertificateAuthority>,
+) -> io::Result<CertificateAuthority> {
+    println!("Generating GuardNest Root CA (PKCS#12)...");
 
-    // Key usages appropriate for a CA
-    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    let ca = match previous {
+        Some(previous) => previous.succeed(config, Utc::now()),
+        None => CertificateAuthority::generate(config),
+    }
+    .map_err(io::Error::other)?;
+    let protector = default_protector();
+    ca.save_to(&CaFiles::default(), &*protector)?;
 
-    // Set a readable subject
-    params
-        .distinguished_name
-        .push(DnType::CommonName, "GuardNest Local CA");
+    println!(
+        "✅ Generated GuardNest Root CA{} in {} (passwords sealed with {})",
+        if config.intermediate {
+            " and intermediate CA"
+        } else {
+            ""
+        },
+        CA_DIR,
+        protector.name()
+    );
+    Ok(ca)
+}
 
-    // 2) Generate a keypair and create the self-signed CA certificate
-    let key_pair = match KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256) {
-        Ok(kp) => kp,
+/// Ensure the GuardNest Root CA is installed in the trust stores of this machine
+/// (see `default_trust_stores`), and replace it when needed. Returns true if a
+/// new CA was generated.
+/// - Generates a new CA if the current one can't be loaded (missing, damaged, or
+///   written by an older version under the fixed password, so its key must be
+///   considered public), was issued with another `CaConfig`, or is due for
+///   rotation (`renew_before_days` before it expires)
+/// - Installs the certificate (not the key) in each store that lacks it
+/// - Removes the other GuardNest roots from the stores, except the ones replaced
+///   less than `overlap_days` ago
+pub fn ensure_certificate_installed() -> bool {
+    let config = match CaConfig::load(Path::new(CA_CONFIG_PATH)) {
+        Ok(config) => config,
         Err(e) => {
-            eprintln!("❌ Failed to generate key pair: {}", e);
-            return;
+            eprintln!("❌ Invalid CA config {}: {}", CA_CONFIG_PATH, e);
+            return false;
         }
     };
+    let now = Utc::now();
 
-    let certified = match params.self_signed(&key_pair) {
-        Ok(c) => c,
+    // 1) Whether the current CA can stay
+    let current = match CertificateAuthority::load() {
+        Ok(ca) => Some(ca),
         Err(e) => {
-            eprintln!("❌ Failed to create self-signed CA: {}", e);
-            return;
+            println!("🔄 GuardNest Root CA can't be loaded ({})", e);
+            None
         }
     };
+    let replace_because = match &current {
+        None => Some("missing or unreadable"),
+        Some(ca) if !ca.config().issues_like(&config) => Some("CA config changed"),
+        Some(ca) if ca.next_rotation(&config).map_or(true, |at| at <= now) => {
+            Some("due for rotation")
+        }
+        Some(_) => None,
+    };
 
-    // 3) Extract DER for certificate and private key (PKCS#8)
-    let ca_cert_der = certified.der();
-    let ca_key_der = key_pair.serialize_der();
+    // 2) Replace it. One that couldn't be loaded isn't kept trusted in the overlap.
+    let ca = match (current, replace_because) {
+        (Some(ca), None) => ca,
+        (previous, reason) => {
+            println!(
+                "🔄 Generating and importing a new GuardNest Root CA ({})...",
+                reason.unwrap_or_default()
+            );
+            match generate_certificate(&config, previous.as_ref()) {
+                Ok(ca) => ca,
+                Err(e) => {
+                    eprintln!("❌ Failed to generate the GuardNest Root CA: {}", e);
+                    return false;
+                }
+            }
+        }
+    };
 
-    // 4) Convert to OpenSSL types
-    let x509 = match X509::from_der(&ca_cert_der) {
-        Ok(x) => x,
-        Err(e) => {
-            eprintln!("❌ Failed to parse DER certificate with OpenSSL: {}", e);
+    // 3) Bring the trust stores in line with it
+    sync_trust_stores(&ca, &default_trust_stores(), now);
+    replace_because.is_some()
+}
+
+/// Run `ensure_certificate_installed` every `LIFECYCLE_CHECK_INTERVAL` on a
+/// background thread, so the CA is rotated while the app keeps running.
+/// `on_new_ca` is called after a new CA was generated.
+pub fn watch_certificate_lifecycle(on_new_ca: fn()) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(LIFECYCLE_CHECK_INTERVAL);
+        if ensure_certificate_installed() {
+            on_new_ca();
+        }
+    });
+}
+
+/// A Tauri command that returns the current CA: subject, fingerprint, validity
+/// and when it will be rotated.
+#[tauri::command]
+pub fn certificate_status() -> Result<CertificateStatus, String> {
+    let config = CaConfig::load(Path::new(CA_CONFIG_PATH))
+        .map_err(|e| format!("Invalid CA config: {}", e))?;
+    let ca = CertificateAuthority::load()
+        .map_err(|e| format!("Failed to load the GuardNest Root CA: {}", e))?;
+    ca.status(&config, Utc::now())
+        .map_err(|e| format!("Failed to read the GuardNest Root CA: {}", e))
+}
+
+/// A Tauri command that returns the root certificate in PEM and DER with its
+/// fingerprint, and the URL devices using the proxy can download it from.
+#[tauri::command]
+pub fn export_ca_certificate() -> Result<RootExport, String> {
+    let root = load_root_certificate(&CaFiles::default())
+        .map_err(|e| format!("Failed to read the GuardNest Root CA: {}", e))?;
+    RootExport::new(&root).map_err(|e| format!("Failed to export the GuardNest Root CA: {}", e))
+}
+
+/// Load the root certificate alone from `files.root_cert`. Only the public
+/// certificate is read, the PKCS#12 files holding the keys are left alone.
+pub fn load_root_certificate(files: &CaFiles) -> io::Result<X509> {
+    X509::from_der(&fs::read(&files.root_cert)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Install the root of `ca` in each of `stores` that lacks it, and remove the
+/// GuardNest roots that are neither it nor still in their overlap period.
+fn sync_trust_stores(
+    ca: &CertificateAuthority,
+    stores: &[Box<dyn TrustStore>],
+    now: DateTime<Utc>,
+) {
+    let (current, keep) = match (fingerprint(ca.root()), ca.trusted_thumbprints(now)) {
+        (Ok(current), Ok(keep)) => (current, keep),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("❌ Failed to read the GuardNest Root CA: {}", e);
             return;
         }
     };
 
-    let pkey = match PKey::private_key_from_pkcs8(&ca_key_der) {
-        Ok(k) => k,
-        Err(_) => match PKey::private_key_from_der(&ca_key_der) {
-            Ok(k) => k,
+    for store in stores {
+        let installed = match store.installed() {
+            Ok(installed) => installed,
             Err(e) => {
-                eprintln!("❌ Failed to parse private key with OpenSSL: {}", e);
-                return;
+                eprintln!("❌ Failed to list the {}: {}", store.name(), e);
+                continue;
             }
-        },
-    };
+        };
 
-    // 5) Build PKCS#12 (PFX). Use a friendly name and a password.
-    let friendly_name = "GuardNest Root CA";
-    let password = "guardnest321";
-
-    let pkcs12 = match {
-        let mut builder = Pkcs12::builder();
-        builder.name(friendly_name);
-        builder.pkey(&pkey);
-        builder.cert(&x509);
-        builder.build2(password)
-    } {
-        Ok(p) => p,
-        Err(e) => {
-            eprintln!("❌ Failed to build PKCS#12: {}", e);
-            return;
+        let mut present = false;
+        for root in &installed {
+            let (Ok(root_fingerprint), Ok(root_thumbprint)) = (fingerprint(root), thumbprint(root))
+            else {
+                continue;
+            };
+            if root_fingerprint == current {
+                present = true;
+            } else if !keep.contains(&root_thumbprint) {
+                match store.uninstall(&root_fingerprint) {
+                    Ok(_) => println!(
+                        "🗑️ Removed superseded GuardNest Root CA {} from the {}",
+                        root_thumbprint,
+                        store.name()
+                    ),
+                    Err(e) => eprintln!(
+                        "❌ Failed to remove {} from the {}: {}",
+                        root_thumbprint,
+                        store.name(),
+                        e
+                    ),
+                }
+            }
         }
-    };
 
-    let pfx_der = match pkcs12.to_der() {
-        Ok(d) => d,
-        Err(e) => {
-            eprintln!("❌ Failed to serialize PKCS#12 to DER: {}", e);
-            return;
+        if present {
+            println!(
+                "✅ GuardNest Root CA present and valid in the {}",
+                store.name()
+            );
+        } else {
+            match store.install(ca.root()) {
+                Ok(()) => println!("✅ GuardNest Root CA installed to the {}", store.name()),
+                Err(e) => eprintln!(
+                    "❌ Failed to install the GuardNest Root CA to the {}: {}",
+                    store.name(),
+                    e
+                ),
+            }
         }
-    };
+    }
+}
 
-    // 6) Write to C:\\ProgramData\\GuardNest\\certificate.p12
-    let mut file = match fs::File::create(&output_path) {
-        Ok(f) => f,
-        Err(e) => {
-            eprintln!("❌ Failed to create {:?}: {}", output_path, e);
-            return;
+/// The GuardNest CA, loaded with its private key so it can sign leaf
+/// certificates for intercepted hosts.
+pub struct CertificateAuthority {
+    cert: X509, // Signs the leaves: the root, or the intermediate
+    key: PKey<Private>,
+    root: Option<(X509, PKey<Private>)>, // The root above `cert`, if it's an intermediate
+    config: CaConfig,
+    superseded: Vec<SupersededRoot>, // Previous roots, possibly still trusted
+}
+
+/// A certificate for one host, signed by the GuardNest CA.
+pub struct LeafCertificate {
+    pub cert: X509,
+    pub key: PKey<Private>,
+}
+
+impl CertificateAuthority {
+    /// Use an existing CA certificate and key (e.g. a throwaway CA in tests).
+    pub fn new(cert: X509, key: PKey<Private>) -> Self {
+        CertificateAuthority {
+            cert,
+            key,
+            root: None,
+            config: CaConfig::default(),
+            superseded: Vec::new(),
+        }
+    }
+
+    /// Generate a new CA with fresh keys, as set by `config`.
+    pub fn generate(config: &CaConfig) -> Result<Self, ErrorStack> {
+        if !config.intermediate {
+            let (cert, key) = build_ca(ROOT_NAME, None, 0, config)?;
+            return Ok(CertificateAuthority {
+                config: config.clone(),
+                ..Self::new(cert, key)
+            });
+        }
+
+        let (root_cert, root_key) = build_ca(ROOT_NAME, None, 1, config)?;
+        let (cert, key) = build_ca(INTERMEDIATE_NAME, Some((&root_cert, &root_key)), 0, config)?;
+        Ok(CertificateAuthority {
+            cert,
+            key,
+            root: Some((root_cert, root_key)),
+            config: config.clone(),
+            superseded: Vec::new(),
+        })
+    }
+
+    /// Generate the CA replacing this one, with new keys as set by `config`. This
+    /// root stays trusted for `overlap_days` from `now`, but not past its expiry.
+    pub fn succeed(&self, config: &CaConfig, now: DateTime<Utc>) -> Result<Self, ErrorStack> {
+        let mut next = Self::generate(config)?;
+        let overlap_end = now + TimeDelta::days(config.overlap_days.into());
+        next.superseded = self.superseded_at(now).cloned().collect();
+        next.superseded.push(SupersededRoot {
+            thumbprint: thumbprint(self.root())?,
+            trusted_until: overlap_end.min(to_datetime(self.root().not_after())?),
+        });
+        Ok(next)
+    }
+
+    /// Load the CA from the files written by `generate_certificate`.
+    pub fn load() -> io::Result<Self> {
+        Self::load_from(&CaFiles::default(), &*default_protector())
+    }
+
+    /// Load a CA from `files`, the PKCS#12 passwords being sealed by `protector`.
+    pub fn load_from(files: &CaFiles, protector: &dyn KeyProtector) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+        let info: CaInfo = serde_json::from_slice(&fs::read(&files.info)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let (root_cert, root_key) = load_pkcs12(&files.root_pfx, &files.root_secret, protector)?;
+        if !info.config.intermediate {
+            return Ok(CertificateAuthority {
+                config: info.config,
+                superseded: info.superseded,
+                ..Self::new(root_cert, root_key)
+            });
+        }
+
+        // The intermediate must have been signed by this root, not a previous one
+        let (cert, key) = load_pkcs12(
+            &files.intermediate_pfx,
+            &files.intermediate_secret,
+            protector,
+        )?;
+        let signed_by_root = root_cert
+            .public_key()
+            .and_then(|root_key| cert.verify(&root_key))
+            .map_err(io::Error::other)?;
+        if !signed_by_root {
+            return Err(invalid("Intermediate CA isn't signed by the root"));
+        }
+        Ok(CertificateAuthority {
+            cert,
+            key,
+            root: Some((root_cert, root_key)),
+            config: info.config,
+            superseded: info.superseded,
+        })
+    }
+
+    /// Write the CA to `files`, each key under a new random password sealed by
+    /// `protector`.
+    pub fn save_to(&self, files: &CaFiles, protector: &dyn KeyProtector) -> io::Result<()> {
+        match &self.root {
+            Some((root_cert, root_key)) => {
+                save_pkcs12(
+                    &files.intermediate_pfx,
+                    &files.intermediate_secret,
+                    protector,
+                    "GuardNest Intermediate CA",
+                    &self.cert,
+                    &self.key,
+                )?;
+                save_pkcs12(
+                    &files.root_pfx,
+                    &files.root_secret,
+                    protector,
+                    "GuardNest Root CA",
+                    root_cert,
+                    root_key,
+                )?;
+            }
+            None => {
+                save_pkcs12(
+                    &files.root_pfx,
+                    &files.root_secret,
+                    protector,
+                    "GuardNest Root CA",
+                    &self.cert,
+                    &self.key,
+                )?;
+                // Left over from a previous config
+                let _ = fs::remove_file(&files.intermediate_pfx);
+                let _ = fs::remove_file(&files.intermediate_secret);
+            }
+        }
+
+        // The root certificate alone, for the Root store (public, no key)
+        fs::write(
+            &files.root_cert,
+            self.root().to_der().map_err(io::Error::other)?,
+        )?;
+
+        let info = serde_json::to_vec_pretty(&CaInfo {
+            config: self.config.clone(),
+            superseded: self.superseded.clone(),
+        })
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write_restricted(&files.info, &info)
+    }
+
+    /// The certificate signing the leaves: the intermediate, or the root itself.
+    pub fn certificate(&self) -> &X509 {
+        &self.cert
+    }
+
+    /// The root certificate, the one browsers must trust.
+    pub fn root(&self) -> &X509 {
+        self.root.as_ref().map_or(&self.cert, |(cert, _)| cert)
+    }
+
+    /// The config the CA was issued with.
+    pub fn config(&self) -> &CaConfig {
+        &self.config
+    }
+
+    /// Whether the CA can sign a leaf for `name`, see `CaConfig::permits`.
+    pub fn permits(&self, name: &str) -> bool {
+        self.config.permits(name)
+    }
+
+    /// Previous roots still trusted at `now`.
+    pub fn superseded_at(&self, now: DateTime<Utc>) -> impl Iterator<Item = &SupersededRoot> {
+        self.superseded
+            .iter()
+            .filter(move |superseded| superseded.trusted_until > now)
+    }
+
+    /// Thumbprints of the roots that belong in the trust stores at `now`: this one
+    /// and the previous ones still in their overlap period.
+    pub fn trusted_thumbprints(&self, now: DateTime<Utc>) -> Result<Vec<String>, ErrorStack> {
+        let mut trusted = vec![thumbprint(self.root())?];
+        trusted.extend(
+            self.superseded_at(now)
+                .map(|superseded| superseded.thumbprint.clone()),
+        );
+        Ok(trusted)
+    }
+
+    /// When the CA must be replaced: `renew_before_days` of `config` before the
+    /// root or the intermediate expires, whichever is first.
+    pub fn next_rotation(&self, config: &CaConfig) -> Result<DateTime<Utc>, ErrorStack> {
+        let expiry = to_datetime(self.cert.not_after())?.min(to_datetime(self.root().not_after())?);
+        Ok(expiry - TimeDelta::days(config.renew_before_days.into()))
+    }
+
+    /// The current root as shown in the app, rotated as set by `config`.
+    pub fn status(
+        &self,
+        config: &CaConfig,
+        now: DateTime<Utc>,
+    ) -> Result<CertificateStatus, ErrorStack> {
+        let root = self.root();
+        let mut subject = Vec::new();
+        for entry in root.subject_name().entries() {
+            subject.push(format!(
+                "{}={}",
+                entry.object().nid().short_name()?,
+                String::from_utf8_lossy(entry.data().as_slice())
+            ));
+        }
+
+        Ok(CertificateStatus {
+            subject: subject.join(", "),
+            fingerprint: fingerprint(root)?,
+            thumbprint: thumbprint(root)?,
+            not_before: to_datetime(root.not_before())?,
+            not_after: to_datetime(root.not_after())?,
+            next_rotation: self.next_rotation(config)?,
+            intermediate: self.root.is_some(),
+            permitted_domains: self.config.permitted_domains.clone(),
+            superseded: self.superseded_at(now).cloned().collect(),
+        })
+    }
+
+    /// Mint a leaf certificate for `host` (a DNS name or an IP address).
+    ///
+    /// The leaf gets a fresh P-256 key, the host as its only subject alternative
+    /// name, and is only valid for TLS servers.
+    pub fn issue_leaf(&self, host: &str) -> Result<LeafCertificate, ErrorStack> {
+        // 1) Fresh key pair for the leaf
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+
+        // 2) Random serial number, so two leaves for the same host never clash
+        let mut serial = BigNum::new()?;
+        serial.rand(127, MsbOption::MAYBE_ZERO, false)?;
+
+        // 3) Subject: the host as common name (limited to 64 characters)
+        let mut name = X509NameBuilder::new()?;
+        if host.len() <= 64 {
+            name.append_entry_by_nid(Nid::COMMONNAME, host)?;
         }
+        let name = name.build();
+
+        // 4) Valid from a day ago (clock skew between machines) for LEAF_VALIDITY_DAYS
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() as i64);
+        let not_before = Asn1Time::from_unix(now - 24 * 60 * 60)?;
+        let not_after = Asn1Time::days_from_now(LEAF_VALIDITY_DAYS)?;
+
+        let mut builder = X509Builder::new()?;
+        builder.set_version(2)?;
+        builder.set_serial_number(&*serial.to_asn1_integer()?)?;
+        builder.set_subject_name(&name)?;
+        builder.set_issuer_name(self.cert.subject_name())?;
+        builder.set_pubkey(&key)?;
+        builder.set_not_before(&not_before)?;
+        builder.set_not_after(&not_after)?;
+
+        // 5) Extensions: not a CA, TLS server only, valid for this host only
+        builder.append_extension(X509BasicConstraints::new().critical().build()?)?;
+        builder.append_extension(KeyUsage::new().critical().digital_signature().build()?)?;
+        builder.append_extension(ExtendedKeyUsage::new().server_auth().build()?)?;
+
+        let context = builder.x509v3_context(Some(&self.cert), None);
+        let mut san = SubjectAlternativeName::new();
+        match host.parse::<IpAddr>() {
+            Ok(_) => san.ip(host),
+            Err(_) => san.dns(host),
+        };
+        let san = san.build(&context)?;
+        let subject_key_id = SubjectKeyIdentifier::new().build(&context)?;
+        let authority_key_id = AuthorityKeyIdentifier::new().keyid(false).build(&context)?;
+        builder.append_extension(san)?;
+        builder.append_extension(subject_key_id)?;
+        builder.append_extension(authority_key_id)?;
+
+        // 6) Sign with the CA key
+        builder.sign(&self.key, MessageDigest::sha256())?;
+
+        Ok(LeafCertificate {
+            cert: builder.build(),
+            key,
+        })
+    }
+}
+
+/// Build a CA certificate named `common_name` with a fresh P-256 key, signed by
+/// `issuer` (certificate + key), or self-signed without one.
+fn build_ca(
+    common_name: &str,
+    issuer: Option<(&X509, &PKey<Private>)>,
+    path_len: u32,
+    config: &CaConfig,
+) -> Result<(X509, PKey<Private>), ErrorStack> {
+    // 1) Fresh key pair and random serial number
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+    let mut serial = BigNum::new()?;
+    serial.rand(127, MsbOption::MAYBE_ZERO, false)?;
+
+    // 2) Readable subject, valid from a day ago (clock skew) for `validity_days`
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, common_name)?;
+    let name = name.build();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as i64);
+
+    let mut builder = X509Builder::new()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(&*serial.to_asn1_integer()?)?;
+    builder.set_subject_name(&name)?;
+    match issuer {
+        Some((issuer_cert, _)) => builder.set_issuer_name(issuer_cert.subject_name())?,
+        None => builder.set_issuer_name(&name)?,
+    }
+    builder.set_pubkey(&key)?;
+    builder.set_not_before(&*Asn1Time::from_unix(now - 24 * 60 * 60)?)?;
+    builder.set_not_after(&*Asn1Time::days_from_now(config.validity_days)?)?;
+
+    // 3) Extensions: a CA with a bounded path length, key usages appropriate for a
+    //    CA, and the name constraints if any
+    builder.append_extension(
+        X509BasicConstraints::new()
+            .critical()
+            .ca()
+            .pathlen(path_len)
+            .build()?,
+    )?;
+    builder.append_extension(
+        KeyUsage::new()
+            .critical()
+            .key_cert_sign()
+            .crl_sign()
+            .build()?,
+    )?;
+    if !config.permitted_domains.is_empty() {
+        builder.append_extension(name_constraints(&config.permitted_domains)?)?;
+    }
+
+    let context = builder.x509v3_context(issuer.map(|(issuer_cert, _)| &**issuer_cert), None);
+    let subject_key_id = SubjectKeyIdentifier::new().build(&context)?;
+    let authority_key_id = match issuer {
+        Some(_) => Some(AuthorityKeyIdentifier::new().keyid(false).build(&context)?),
+        None => None,
     };
+    builder.append_extension(subject_key_id)?;
+    if let Some(authority_key_id) = authority_key_id {
+        builder.append_extension(authority_key_id)?;
+    }
+
+    // 4) Sign with the issuer's key, or its own
+    let signing_key = issuer.map_or(&key, |(_, issuer_key)| issuer_key);
+    builder.sign(signing_key, MessageDigest::sha256())?;
+    Ok((builder.build(), key))
+}
 
-    if let Err(e) = file.write_all(&pfx_der) {
-        eprintln!("❌ Failed to write PKCS#12 file: {}", e);
-        return;
+/// Critical NameConstraints extension (RFC 5280 4.2.1.10) permitting the DNS
+/// names under `domains` and excluding all IPv4 and IPv6 addresses, which DNS
+/// constraints alone would leave unconstrained.
+fn name_constraints(domains: &[String]) -> Result<X509Extension, ErrorStack> {
+    // GeneralSubtree ::= SEQUENCE { base GeneralName }, dNSName is [2], iPAddress
+    // is [7] (address + mask, all zero for "any address")
+    let permitted: Vec<u8> = domains
+        .iter()
+        .flat_map(|domain| der(0x30, &der(0x82, domain.as_bytes())))
+        .collect();
+    let mut excluded = der(0x30, &der(0x87, &[0u8; 8]));
+    excluded.extend(der(0x30, &der(0x87, &[0u8; 32])));
+
+    // NameConstraints ::= SEQUENCE { permittedSubtrees [0], excludedSubtrees [1] }
+    let mut constraints = der(0xA0, &permitted);
+    constraints.extend(der(0xA1, &excluded));
+    let value = Asn1OctetString::new_from_bytes(&der(0x30, &constraints))?;
+    let oid = Asn1Object::from_str("2.5.29.30")?;
+    X509Extension::new_from_der(&oid, true, &value)
+}
+
+/// One DER element: tag, length, content.
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    if content.len() < 0x80 {
+        out.push(content.len() as u8);
+    } else {
+        let length = content.len().to_be_bytes();
+        let length = &length[length.iter().take_while(|&&b| b == 0).count()..];
+        out.push(0x80 | length.len() as u8);
+        out.extend_from_slice(length);
     }
+    out.extend_from_slice(content);
+    out
+}
 
-    println!(
-        "✅ Generated CA PKCS#12 at {:?} (friendly name: \"{}\")",
-        output_path, friendly_name
-    );
+/// Write `cert` and `key` to a PKCS#12 file at `pfx_path`, under a new random
+/// password sealed by `protector` at `secret_path`.
+fn save_pkcs12(
+    pfx_path: &Path,
+    secret_path: &Path,
+    protector: &dyn KeyProtector,
+    friendly_name: &str,
+    cert: &X509,
+    key: &PKey<Private>,
+) -> io::Result<()> {
+    let mut password = [0u8; CA_PASSWORD_BYTES];
+    rand_bytes(&mut password).map_err(io::Error::other)?;
+    let password = STANDARD.encode(password);
+
+    let mut builder = Pkcs12::builder();
+    builder.name(friendly_name);
+    builder.pkey(key);
+    builder.cert(cert);
+    let pfx_der = builder
+        .build2(&password)
+        .and_then(|pkcs12| pkcs12.to_der())
+        .map_err(io::Error::other)?;
+
+    // The password first: a PKCS#12 file without its password can't be loaded
+    // and gets replaced, a password without its file is simply unused
+    write_sealed(secret_path, protector, password.as_bytes())?;
+    write_restricted(pfx_path, &pfx_der)
+}
+
+/// Read a certificate and key written by `save_pkcs12`.
+fn load_pkcs12(
+    pfx_path: &Path,
+    secret_path: &Path,
+    protector: &dyn KeyProtector,
+) -> io::Result<(X509, PKey<Private>)> {
+    let password = read_sealed(secret_path, protector)?;
+    let password = String::from_utf8(password)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Sealed password isn't text"))?;
+
+    let data = fs::read(pfx_path)?;
+    let parsed = Pkcs12::from_der(&data)
+        .and_then(|pkcs12| pkcs12.parse2(&password))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    match (parsed.cert, parsed.pkey) {
+        (Some(cert), Some(key)) => Ok((cert, key)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "PKCS#12 file has no certificate or key",
+        )),
+    }
 }
 
-/// Ensure the GuardNest Root CA is installed in the LocalMachine Root store.
-/// - Generates the PFX if missing
-/// - Imports to Root via certutil if not present
-pub fn ensure_certificate_installed() {
-    let pfx_path = Path::new("C:\\ProgramData\\GuardNest\\certificate.p12");
-
-    if !pfx_path.exists() {
-        generate_certificate();
-    }
-
-    // Validate installed cert: present and within validity window (NotBefore/NotAfter)
-    let check_status = Command::new("powershell")
-        .args(&[
-            "-NoProfile",
-            "-NonInteractive",
-            "-ExecutionPolicy",
-            "Bypass",
-            "-Command",
-            r#"$now=Get-Date; $c=Get-ChildItem Cert:\\LocalMachine\\Root | Where-Object { $_.Subject -like '*GuardNest Local CA*' } | Sort-Object NotAfter -Descending | Select-Object -First 1; if ($c -and $c.NotBefore -le $now -and $c.NotAfter -gt $now) { exit 0 } else { exit 1 }"#,
-        ])
-        .status();
-
-    let is_valid = matches!(check_status, Ok(s) if s.success());
-
-    if !is_valid {
-        println!("🔄 Generating and importing a new GuardNest Root CA (existing one missing or invalid)...");
-        generate_certificate();
-
-        let import_status = Command::new("certutil")
-            .args(&[
-                "-f",
-                "-p",
-                "guardnest321",
-                "-importpfx",
-                "Root",
-                "C:\\ProgramData\\GuardNest\\certificate.p12",
-            ])
-            .status();
-
-        match import_status {
-            Ok(s) if s.success() => println!("✅ GuardNest Root CA installed to Root store"),
-            Ok(s) => eprintln!("❌ certutil failed with status: {:?}", s.code()),
-            Err(e) => eprintln!("❌ Failed to run certutil: {}", e),
+/// SHA-1 of a certificate in uppercase hex, as the Windows certificate stores
+/// show and identify it.
+pub fn thumbprint(cert: &X509) -> Result<String, ErrorStack> {
+    Ok(hex(&cert.digest(MessageDigest::sha1())?))
+}
+
+/// SHA-256 of a certificate in lowercase hex, how trust stores are checked for a
+/// root (see trust_store.rs).
+pub fn fingerprint(cert: &X509) -> Result<String, ErrorStack> {
+    Ok(hex(&cert.digest(MessageDigest::sha256())?).to_ascii_lowercase())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// An ASN.1 time as a UTC date and time.
+fn to_datetime(time: &Asn1TimeRef) -> Result<DateTime<Utc>, ErrorStack> {
+    let since_epoch = Asn1Time::from_unix(0)?.diff(time)?;
+    let seconds = i64::from(since_epoch.days) * 24 * 60 * 60 + i64::from(since_epoch.secs);
+    Ok(DateTime::from_timestamp(seconds, 0).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::stack::Stack;
+    use openssl::x509::store::X509StoreBuilder;
+    use openssl::x509::{X509StoreContext, X509VerifyResult};
+
+    fn constrained(domains: &[&str], intermediate: bool) -> CertificateAuthority {
+        let config = CaConfig {
+            intermediate,
+            permitted_domains: domains.iter().map(|domain| domain.to_string()).collect(),
+            ..CaConfig::default()
+        };
+        CertificateAuthority::generate(&config.normalized().unwrap()).unwrap()
+    }
+
+    // Verifies a leaf for `host` against the CA's root alone, as a browser would
+    fn verify_leaf(ca: &CertificateAuthority, host: &str) -> X509VerifyResult {
+        let mut store = X509StoreBuilder::new().unwrap();
+        store.add_cert(ca.root().clone()).unwrap();
+        let store = store.build();
+        let mut chain = Stack::new().unwrap();
+        if ca.root.is_some() {
+            chain.push(ca.certificate().clone()).unwrap();
+        }
+
+        let leaf = ca.issue_leaf(host).unwrap();
+        let mut context = X509StoreContext::new().unwrap();
+        context
+            .init(&store, &leaf.cert, &chain, |context| {
+                context.verify_cert()?;
+                Ok(context.error())
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn der_lengths() {
+        assert_eq!(der(0x04, b""), [0x04, 0x00]);
+        assert_eq!(der(0x82, b"a.b"), [0x82, 0x03, b'a', b'.', b'b']);
+
+        // 127 bytes is the longest short form, then the length gets its own bytes
+        assert_eq!(der(0x30, &[7; 127])[..2], [0x30, 0x7F]);
+        assert_eq!(der(0x30, &[7; 128])[..3], [0x30, 0x81, 0x80]);
+        assert_eq!(der(0x30, &[7; 255])[..3], [0x30, 0x81, 0xFF]);
+        assert_eq!(der(0x30, &[7; 256])[..4], [0x30, 0x82, 0x01, 0x00]);
+        assert_eq!(der(0x30, &[7; 70000])[..5], [0x30, 0x83, 0x01, 0x11, 0x70]);
+        assert_eq!(der(0x30, &[7; 300]).len(), 4 + 300);
+    }
+
+    #[test]
+    fn name_constraints_encoding() {
+        let mut expected = vec![0x30, 0x43, 0xA0, 0x0F, 0x30, 0x0D, 0x82, 0x0B];
+        expected.extend_from_slice(b"example.com");
+        expected.extend_from_slice(&[0xA1, 0x30, 0x30, 0x0A, 0x87, 0x08]);
+        expected.extend_from_slice(&[0; 8]);
+        expected.extend_from_slice(&[0x30, 0x22, 0x87, 0x20]);
+        expected.extend_from_slice(&[0; 32]);
+
+        // The extension value as written into the certificate
+        let ca = constrained(&["Example.COM."], false);
+        let cert = ca.certificate().to_der().unwrap();
+        assert!(cert
+            .windows(expected.len())
+            .any(|window| window == expected));
+
+        let text = String::from_utf8(ca.certificate().to_text().unwrap()).unwrap();
+        assert!(
+            text.contains("X509v3 Name Constraints: critical"),
+            "{}",
+            text
+        );
+        assert!(text.contains("DNS:example.com"), "{}", text);
+        assert!(text.contains("IP:0.0.0.0/0.0.0.0"), "{}", text);
+        assert!(text.contains("Excluded:"), "{}", text);
+
+        // No domains, no constraints
+        let text = constrained(&[], false).certificate().to_text().unwrap();
+        assert!(!String::from_utf8(text)
+            .unwrap()
+            .contains("Name Constraints"));
+    }
+
+    #[test]
+    fn long_constraint_lists() {
+        // More than 127 bytes of subtrees, so the lengths take the long form
+        let domains: Vec<String> = (0..40)
+            .map(|n| format!("school-{}.example.org", n))
+            .collect();
+        let domains: Vec<&str> = domains.iter().map(String::as_str).collect();
+        let ca = constrained(&domains, false);
+
+        let text = String::from_utf8(ca.certificate().to_text().unwrap()).unwrap();
+        for domain in &domains {
+            assert!(text.contains(&format!("DNS:{}\n", domain)), "{}", domain);
+        }
+        assert_eq!(
+            verify_leaf(&ca, "www.school-39.example.org"),
+            X509VerifyResult::OK
+        );
+    }
+
+    #[test]
+    fn constraints_are_enforced() {
+        for intermediate in [false, true] {
+            let ca = constrained(&["example.com", "school.org"], intermediate);
+            for host in ["example.com", "www.example.com", "a.b.school.org"] {
+                assert!(ca.permits(host), "{}", host);
+                assert_eq!(verify_leaf(&ca, host), X509VerifyResult::OK, "{}", host);
+            }
+
+            // Outside the permitted names, and IP addresses, are refused by the
+            // verifier too, not only by `permits`
+            for host in ["example.org", "notexample.com", "10.0.0.1", "::1"] {
+                assert!(!ca.permits(host), "{}", host);
+                assert_ne!(verify_leaf(&ca, host), X509VerifyResult::OK, "{}", host);
+            }
+        }
+
+        // Unconstrained, anything goes
+        let ca = constrained(&[], true);
+        assert_eq!(verify_leaf(&ca, "example.org"), X509VerifyResult::OK);
+        assert_eq!(verify_leaf(&ca, "10.0.0.1"), X509VerifyResult::OK);
+    }
+
+    fn lifecycle(validity_days: u32, renew_before_days: u32, overlap_days: u32) -> CaConfig {
+        CaConfig {
+            validity_days,
+            renew_before_days,
+            overlap_days,
+            ..CaConfig::default()
+        }
+    }
+
+    #[test]
+    fn next_rotation() {
+        let config = lifecycle(90, 30, 7);
+        for intermediate in [false, true] {
+            let ca = CertificateAuthority::generate(&CaConfig {
+                intermediate,
+                ..config.clone()
+            })
+            .unwrap();
+            let expiry = to_datetime(ca.root().not_after()).unwrap();
+            assert!(
+                (expiry - Utc::now() - TimeDelta::days(90))
+                    .num_seconds()
+                    .abs()
+                    < 60
+            );
+            assert_eq!(
+                ca.next_rotation(&config).unwrap(),
+                expiry - TimeDelta::days(30)
+            );
+            // The config passed in decides, not the one the CA was issued with
+            assert_eq!(
+                ca.next_rotation(&lifecycle(90, 60, 7)).unwrap(),
+                expiry - TimeDelta::days(60)
+            );
+        }
+
+        // Renewing as long as the CA is valid is refused
+        assert!(lifecycle(30, 30, 7).normalized().is_err());
+        assert!(lifecycle(0, 0, 0).normalized().is_err());
+    }
+
+    #[test]
+    fn overlap_on_succession() {
+        let config = lifecycle(90, 30, 7);
+        let now = Utc::now();
+        let first = CertificateAuthority::generate(&config).unwrap();
+        let first_thumbprint = thumbprint(first.root()).unwrap();
+
+        let second = first.succeed(&config, now).unwrap();
+        assert_ne!(thumbprint(second.root()).unwrap(), first_thumbprint);
+        assert_eq!(
+            second.superseded,
+            [SupersededRoot {
+                thumbprint: first_thumbprint.clone(),
+                trusted_until: now + TimeDelta::days(7),
+            }]
+        );
+
+        // Both roots are trusted during the overlap, only the new one after it
+        let during = second
+            .trusted_thumbprints(now + TimeDelta::days(6))
+            .unwrap();
+        assert_eq!(during.len(), 2);
+        assert!(during.contains(&first_thumbprint));
+        let after = second
+            .trusted_thumbprints(now + TimeDelta::days(7))
+            .unwrap();
+        assert_eq!(after, [thumbprint(second.root()).unwrap()]);
+
+        let status = second.status(&config, now).unwrap();
+        assert_eq!(status.superseded.len(), 1);
+        assert_eq!(status.thumbprint, thumbprint(second.root()).unwrap());
+        assert!(!second
+            .status(&config, now + TimeDelta::days(8))
+            .unwrap()
+            .superseded
+            .iter()
+            .any(|root| root.thumbprint == first_thumbprint));
+    }
+
+    #[test]
+    fn overlaps_of_successive_rotations() {
+        let config = lifecycle(90, 30, 7);
+        let now = Utc::now();
+        let first = CertificateAuthority::generate(&config).unwrap();
+        let second = first.succeed(&config, now).unwrap();
+
+        // Replaced again within the overlap: both previous roots stay trusted
+        let third = second.succeed(&config, now + TimeDelta::days(2)).unwrap();
+        assert_eq!(third.superseded.len(), 2);
+        assert_eq!(third.trusted_thumbprints(now).unwrap().len(), 3);
+        assert_eq!(
+            third
+                .trusted_thumbprints(now + TimeDelta::days(8))
+                .unwrap()
+                .len(),
+            2
+        );
+
+        // Replaced after it: the first root is dropped for good
+        let fourth = second.succeed(&config, now + TimeDelta::days(10)).unwrap();
+        assert_eq!(
+            fourth.superseded,
+            [SupersededRoot {
+                thumbprint: thumbprint(second.root()).unwrap(),
+                trusted_until: now + TimeDelta::days(17),
+            }]
+        );
+    }
+
+    #[test]
+    fn overlap_ends_with_the_old_root() {
+        let first = CertificateAuthority::generate(&lifecycle(10, 3, 7)).unwrap();
+        let expiry = to_datetime(first.root().not_after()).unwrap();
+
+        // Replaced two days before it expires: trusted until then, not 7 days on
+        let second = first
+            .succeed(&lifecycle(90, 30, 7), expiry - TimeDelta::days(2))
+            .unwrap();
+        assert_eq!(second.superseded[0].trusted_until, expiry);
+
+        // Without an overlap it's dropped at once
+        let third = second
+            .succeed(&lifecycle(90, 30, 0), expiry - TimeDelta::days(2))
+            .unwrap();
+        let trusted = third
+            .trusted_thumbprints(expiry - TimeDelta::days(2))
+            .unwrap();
+        assert!(!trusted.contains(&thumbprint(second.root()).unwrap()));
+        assert!(trusted.contains(&thumbprint(first.root()).unwrap()));
+    }
+
+    #[test]
+    fn invalid_domains() {
+        for domain in ["10.0.0.1", "*.example.com", "exa mple.com", ""] {
+            let config = CaConfig {
+                permitted_domains: vec![domain.to_string()],
+                ..CaConfig::default()
+            };
+            assert!(config.normalized().is_err(), "{}", domain);
         }
-    } else {
-        println!("✅ GuardNest Root CA present and valid in Root store");
     }
 }
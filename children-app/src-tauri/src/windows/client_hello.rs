@@ -0,0 +1,480 @@
+// ============================================================================
+//  TLS CLIENTHELLO PARSER - SERVER NAME OF A TUNNEL WITHOUT DECRYPTING IT
+// ============================================================================
+// The host in "CONNECT host:443" is whatever the client chose to write there: an
+// IP address, or a name that isn't the site the TLS session is really for. The
+// first message of the TLS handshake (the ClientHello) names the real site in its
+// SNI extension, in clear text. This parser:
+// 1. Buffers the TLS records the ClientHello arrives in, however it's split over
+//    records and TCP segments
+// 2. Reads the server name (SNI) and the offered application protocols (ALPN)
+// 3. Leaves the buffered bytes alone, so they can be sent on to the server as-is
+//
+// It only ever reads input from the network, so every length is checked against
+// the bytes actually there (see the fuzz target in `fuzz/`).
+//
+// With Encrypted Client Hello the SNI seen here is the provider's public name
+// (e.g. "cloudflare-ech.com"), not the site.
+
+use std::{fmt, io};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// The largest ClientHello accepted. Real ones are below 2 KiB, or up to a few KiB
+// with post-quantum key shares.
+pub const MAX_CLIENT_HELLO_SIZE: usize = 16 * 1024;
+
+// TLS alert sent in place of the ServerHello when a tunnel is refused:
+// record type 21 (alert), TLS 1.2, length 2, fatal, access_denied (49)
+pub const ACCESS_DENIED_ALERT: &[u8] = &[0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x31];
+
+const RECORD_HANDSHAKE: u8 = 22;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const EXTENSION_ALPN: u16 = 16;
+const MAX_RECORD_SIZE: usize = 16 * 1024; // Plaintext records are at most 2^14 bytes
+
+// What the ClientHello says about the session
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ClientHello {
+    pub server_name: Option<String>, // SNI host name, lowercase, no trailing dot
+    pub alpn: Vec<String>,           // Offered protocols, e.g. ["h2", "http/1.1"]
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HelloError {
+    Incomplete,              // More bytes are needed (or the client stopped sending)
+    NotTls,                  // The tunnel doesn't start with a TLS handshake record
+    NotClientHello,          // A handshake message other than ClientHello came first
+    TooLarge,                // Bigger than MAX_CLIENT_HELLO_SIZE
+    Malformed(&'static str), // Not a valid ClientHello
+}
+
+impl fmt::Display for HelloError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HelloError::Incomplete => write!(f, "Incomplete ClientHello"),
+            HelloError::NotTls => write!(f, "Not a TLS connection"),
+            HelloError::NotClientHello => write!(f, "First handshake message isn't a ClientHello"),
+            HelloError::TooLarge => write!(f, "ClientHello too large"),
+            HelloError::Malformed(msg) => write!(f, "Malformed ClientHello: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for HelloError {}
+
+// Reads from `stream` into `buf` until it holds a complete ClientHello, and parses
+// it. `buf` may already hold the start of it, and keeps everything read, so the
+// caller can forward it. A client that closes early gets `Incomplete`.
+pub async fn read_client_hello<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    buf: &mut Vec<u8>,
+) -> io::Result<Result<ClientHello, HelloError>> {
+    loop {
+        match parse_client_hello(buf) {
+            Err(HelloError::Incomplete) => {}
+            result => return Ok(result),
+        }
+
+        let mut chunk = [0u8; 4096];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(Err(HelloError::Incomplete));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+// Parses the ClientHello at the start of `data` (TLS records as sent by the client)
+pub fn parse_client_hello(data: &[u8]) -> Result<ClientHello, HelloError> {
+    let mut handshake: Vec<u8> = Vec::new();
+    let mut rest = data;
+
+    // Step 1: Join the handshake records until the ClientHello is complete
+    loop {
+        if let Some(body) = complete_message(&handshake)? {
+            return parse_body(body);
+        }
+
+        if rest.is_empty() {
+            return Err(HelloError::Incomplete);
+        }
+        if rest[0] != RECORD_HANDSHAKE {
+            // Anything else in the middle of a ClientHello isn't allowed
+            return Err(if handshake.is_empty() && rest.len() == data.len() {
+                HelloError::NotTls
+            } else {
+                HelloError::Malformed("unexpected record type")
+            });
+        }
+        if rest.len() < 5 {
+            return Err(HelloError::Incomplete);
+        }
+        if rest[1] != 3 {
+            return Err(HelloError::NotTls); // Record version major is 3 for SSL 3.0 to TLS 1.3
+        }
+
+        let length = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+        if length == 0 || length > MAX_RECORD_SIZE {
+            return Err(HelloError::Malformed("bad record length"));
+        }
+        if rest.len() < 5 + length {
+            return Err(HelloError::Incomplete);
+        }
+        handshake.extend_from_slice(&rest[5..5 + length]);
+        rest = &rest[5 + length..];
+    }
+}
+
+// The ClientHello body once `handshake` holds all of it, None while it doesn't
+fn complete_message(handshake: &[u8]) -> Result<Option<&[u8]>, HelloError> {
+    if handshake.is_empty() {
+        return Ok(None);
+    }
+    if handshake[0] != HANDSHAKE_CLIENT_HELLO {
+        return Err(HelloError::NotClientHello);
+    }
+    if handshake.len() < 4 {
+        return Ok(None);
+    }
+
+    let length = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+    if length > MAX_CLIENT_HELLO_SIZE {
+        return Err(HelloError::TooLarge);
+    }
+    Ok(handshake.get(4..4 + length))
+}
+
+// Cursor over a byte slice that fails instead of reading past the end
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], HelloError> {
+        if self.data.len() < n {
+            return Err(HelloError::Malformed("length past the end"));
+        }
+        let (taken, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, HelloError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, HelloError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    // A vector prefixed with its length in one byte
+    fn vec8(&mut self) -> Result<Reader<'a>, HelloError> {
+        let length = self.u8()? as usize;
+        Ok(Reader {
+            data: self.take(length)?,
+        })
+    }
+
+    // A vector prefixed with its length in two bytes
+    fn vec16(&mut self) -> Result<Reader<'a>, HelloError> {
+        let length = self.u16()? as usize;
+        Ok(Reader {
+            data: self.take(length)?,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+fn parse_body(body: &[u8]) -> Result<ClientHello, HelloError> {
+    let mut reader = Reader { data: body };
+
+    // Step 2: Fixed fields before the extensions
+    reader.take(2)?; // legacy_version
+    reader.take(32)?; // random
+    if reader.vec8()?.data.len() > 32 {
+        return Err(HelloError::Malformed("session ID too long"));
+    }
+    let cipher_suites = reader.vec16()?;
+    if cipher_suites.is_empty() || cipher_suites.data.len() % 2 != 0 {
+        return Err(HelloError::Malformed("bad cipher suite list"));
+    }
+    if reader.vec8()?.is_empty() {
+        return Err(HelloError::Malformed("no compression methods"));
+    }
+
+    let mut hello = ClientHello::default();
+    if reader.is_empty() {
+        return Ok(hello); // Very old clients send no extensions at all
+    }
+
+    // Step 3: Extensions, each type at most once
+    let mut extensions = reader.vec16()?;
+    if !reader.is_empty() {
+        return Err(HelloError::Malformed("data after the extensions"));
+    }
+    let mut seen: Vec<u16> = Vec::new();
+    while !extensions.is_empty() {
+        let extension_type = extensions.u16()?;
+        let mut data = extensions.vec16()?;
+        if seen.contains(&extension_type) {
+            return Err(HelloError::Malformed("duplicate extension"));
+        }
+        seen.push(extension_type);
+
+        match extension_type {
+            EXTENSION_SERVER_NAME => hello.server_name = parse_server_name(&mut data)?,
+            EXTENSION_ALPN => hello.alpn = parse_alpn(&mut data)?,
+            _ => {}
+        }
+    }
+    Ok(hello)
+}
+
+// server_name extension: a list of (type, name), type 0 being a DNS host name
+fn parse_server_name(data: &mut Reader<'_>) -> Result<Option<String>, HelloError> {
+    let mut names = data.vec16()?;
+    if !data.is_empty() || names.is_empty() {
+        return Err(HelloError::Malformed("bad server name list"));
+    }
+
+    let mut host_name = None;
+    while !names.is_empty() {
+        let name_type = names.u8()?;
+        let name = names.vec16()?.data;
+        if name_type == 0 && host_name.is_none() {
+            host_name = Some(normalize_host_name(name)?);
+        }
+    }
+    Ok(host_name)
+}
+
+// The SNI host name as a lowercase string, refusing anything that isn't a plain
+// DNS name (IP addresses aren't allowed in SNI either)
+fn normalize_host_name(name: &[u8]) -> Result<String, HelloError> {
+    let name = name.strip_suffix(b".").unwrap_or(name);
+    let valid = !name.is_empty()
+        && name.len() <= 253
+        && name
+            .iter()
+            .all(|&b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_')
+        && name.split(|&b| b == b'.').all(|label| !label.is_empty());
+    if !valid {
+        return Err(HelloError::Malformed("invalid server name"));
+    }
+    Ok(String::from_utf8_lossy(name).to_ascii_lowercase())
+}
+
+// ALPN extension: a list of protocol names, each prefixed with its length
+fn parse_alpn(data: &mut Reader<'_>) -> Result<Vec<String>, HelloError> {
+    let mut protocols = data.vec16()?;
+    if !data.is_empty() || protocols.is_empty() {
+        return Err(HelloError::Malformed("bad ALPN list"));
+    }
+
+    let mut alpn = Vec::new();
+    while !protocols.is_empty() {
+        let protocol = protocols.vec8()?.data;
+        if protocol.is_empty() {
+            return Err(HelloError::Malformed("empty ALPN protocol"));
+        }
+        alpn.push(String::from_utf8_lossy(protocol).into_owned());
+    }
+    Ok(alpn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ssl::{HandshakeError, SslConnector, SslMethod};
+    use std::io::{Read, Write};
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::ReadBuf;
+
+    // Collects what a TLS client writes; reads block, so the handshake stops
+    // right after the ClientHello
+    #[derive(Default)]
+    struct Wire {
+        written: Vec<u8>,
+    }
+
+    impl Read for Wire {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl Write for Wire {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // The ClientHello OpenSSL sends for `host`, optionally without SNI and with
+    // ALPN protocols in wire format (e.g. b"\x02h2")
+    fn real_client_hello(host: &str, sni: bool, alpn: &[u8]) -> Vec<u8> {
+        let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
+        if !alpn.is_empty() {
+            builder.set_alpn_protos(alpn).unwrap();
+        }
+        let mut config = builder.build().configure().unwrap();
+        config.set_use_server_name_indication(sni);
+        config.set_verify_hostname(sni);
+
+        match config.connect(host, Wire::default()) {
+            Err(HandshakeError::WouldBlock(handshake)) => handshake.get_ref().written.clone(),
+            _ => panic!("handshake didn't stop after the ClientHello"),
+        }
+    }
+
+    // Re-frames the handshake messages in `records` into records of at most
+    // `size` bytes, the way a client may split a large ClientHello
+    fn split_records(records: &[u8], size: usize) -> Vec<u8> {
+        let (header, body) = records.split_at(5);
+        assert_eq!(
+            u16::from_be_bytes([header[3], header[4]]) as usize,
+            body.len()
+        );
+
+        let mut split = Vec::new();
+        for fragment in body.chunks(size) {
+            split.extend_from_slice(&header[..3]);
+            split.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            split.extend_from_slice(fragment);
+        }
+        split
+    }
+
+    // Hands out `step` bytes per read, like a slow TCP connection
+    struct Trickle {
+        data: Vec<u8>,
+        step: usize,
+    }
+
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            out: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let n = self.step.min(self.data.len()).min(out.remaining());
+            out.put_slice(&self.data[..n]);
+            self.data.drain(..n);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    async fn read_trickled(data: &[u8], step: usize) -> (Result<ClientHello, HelloError>, Vec<u8>) {
+        let mut stream = Trickle {
+            data: data.to_vec(),
+            step,
+        };
+        let mut buf = Vec::new();
+        let hello = read_client_hello(&mut stream, &mut buf).await.unwrap();
+        (hello, buf)
+    }
+
+    #[test]
+    fn server_name() {
+        let data = real_client_hello("Example.COM", true, b"");
+        assert_eq!(data[0], RECORD_HANDSHAKE);
+        let hello = parse_client_hello(&data).unwrap();
+        assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+        assert!(hello.alpn.is_empty());
+    }
+
+    #[test]
+    fn no_server_name() {
+        let data = real_client_hello("example.com", false, b"");
+        assert_eq!(parse_client_hello(&data).unwrap(), ClientHello::default());
+    }
+
+    #[test]
+    fn alpn() {
+        let data = real_client_hello("example.com", true, b"\x02h2\x08http/1.1");
+        let hello = parse_client_hello(&data).unwrap();
+        assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+        assert_eq!(hello.alpn, ["h2", "http/1.1"]);
+    }
+
+    #[test]
+    fn split_over_records() {
+        let data = real_client_hello("example.com", true, b"\x02h2\x08http/1.1");
+        let expected = parse_client_hello(&data).unwrap();
+
+        for size in [1, 3, 64, 200] {
+            let split = split_records(&data, size);
+            assert!(split.len() > data.len());
+            assert_eq!(parse_client_hello(&split), Ok(expected.clone()), "{}", size);
+
+            // Every prefix is incomplete, including ones ending inside a record header
+            for end in [1, 4, 5, 6, split.len() - 1] {
+                assert_eq!(
+                    parse_client_hello(&split[..end]),
+                    Err(HelloError::Incomplete),
+                    "{} {}",
+                    size,
+                    end
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn split_over_reads() {
+        let data = split_records(&real_client_hello("example.com", true, b"\x02h2"), 100);
+        // Application data already sent behind the ClientHello stays in the buffer
+        let mut sent = data.clone();
+        sent.extend_from_slice(&[23, 3, 3, 0, 1, 0]);
+
+        for step in [1, 2, 5, 7, 4096] {
+            let (hello, buf) = read_trickled(&sent, step).await;
+            let hello = hello.unwrap();
+            assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+            assert_eq!(hello.alpn, ["h2"]);
+            assert!(buf.starts_with(&data) && sent.starts_with(&buf), "{}", step);
+        }
+
+        // A client that closes mid-way
+        let (hello, buf) = read_trickled(&data[..data.len() - 10], 7).await;
+        assert_eq!(hello, Err(HelloError::Incomplete));
+        assert_eq!(buf, data[..data.len() - 10]);
+    }
+
+    #[test]
+    fn not_a_client_hello() {
+        assert_eq!(
+            parse_client_hello(b"GET / HTTP/1.1\r\n"),
+            Err(HelloError::NotTls)
+        );
+        assert_eq!(parse_client_hello(b""), Err(HelloError::Incomplete));
+
+        // A ServerHello (handshake type 2) in place of a ClientHello
+        let mut data = real_client_hello("example.com", true, b"");
+        data[5] = 2;
+        assert_eq!(parse_client_hello(&data), Err(HelloError::NotClientHello));
+
+        // Another record type between the fragments of a ClientHello
+        let mut split = split_records(&real_client_hello("example.com", true, b""), 50);
+        split[55] = 23;
+        assert_eq!(
+            parse_client_hello(&split),
+            Err(HelloError::Malformed("unexpected record type"))
+        );
+
+        // A ClientHello claiming more than the limit
+        let mut data = real_client_hello("example.com", true, b"");
+        data[6..9].copy_from_slice(&[0, 0x40, 0x01]);
+        assert_eq!(parse_client_hello(&data), Err(HelloError::TooLarge));
+    }
+}
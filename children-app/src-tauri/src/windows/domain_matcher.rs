@@ -0,0 +1,372 @@
+// ============================================================================
+//  DOMAIN MATCHER - REVERSED-LABEL TRIE FOR BLOCKLIST LOOKUPS
+// ============================================================================
+// Stores domain rules in a trie keyed by labels from right to left:
+//
+//     com ─┬─ example ─── www
+//          └─ games
+//
+// A lookup for "cdn.example.com" walks "com" → "example" → "cdn" and stops at
+// the first rule that covers the host, so it costs one step per label no
+// matter how many rules are loaded.
+//
+// Supported rule syntax:
+// - "example.com" or "||example.com": the domain and all its subdomains
+// - "=example.com":                    only "example.com" itself
+// - "*.example.com":                   only subdomains, not "example.com" itself
+// Rules with a path ("example.com/games") are path rules, see url_matcher.rs.
+
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RuleKind {
+    Suffix,   // The domain and every subdomain
+    Exact,    // Only the domain itself
+    Wildcard, // Every subdomain, but not the domain itself
+}
+
+// A single parsed rule, e.g. `||example.com`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DomainRule {
+    pub kind: RuleKind,
+    pub domain: String, // Normalized (lowercase, ASCII/punycode, no trailing dot)
+}
+
+impl DomainRule {
+    // Parses a rule line. Returns None for empty lines, comments, path rules and
+    // invalid domains.
+    pub fn parse(rule: &str) -> Option<DomainRule> {
+        let rule = rule.trim();
+        if rule.is_empty() || rule.starts_with('#') || rule.starts_with('!') || rule.contains('/') {
+            return None;
+        }
+
+        let (kind, domain) = if let Some(rest) = rule.strip_prefix("||") {
+            // Adblock style rules may end with "^" (separator)
+            (RuleKind::Suffix, rest.trim_end_matches('^'))
+        } else if let Some(rest) = rule.strip_prefix('=') {
+            (RuleKind::Exact, rest)
+        } else if let Some(rest) = rule.strip_prefix("*.") {
+            (RuleKind::Wildcard, rest)
+        } else {
+            (RuleKind::Suffix, rule)
+        };
+
+        Some(DomainRule {
+            kind,
+            domain: normalize_host(domain)?,
+        })
+    }
+
+    // Whether the rule covers `host` (already normalized). The trie answers this for
+    // many rules at once, this is for a single rule.
+    pub fn covers(&self, host: &str) -> bool {
+        let below = host
+            .strip_suffix(self.domain.as_str())
+            .is_some_and(|rest| rest.ends_with('.'));
+        match self.kind {
+            RuleKind::Suffix => host == self.domain || below,
+            RuleKind::Exact => host == self.domain,
+            RuleKind::Wildcard => below,
+        }
+    }
+}
+
+impl fmt::Display for DomainRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            RuleKind::Suffix => write!(f, "||{}", self.domain),
+            RuleKind::Exact => write!(f, "={}", self.domain),
+            RuleKind::Wildcard => write!(f, "*.{}", self.domain),
+        }
+    }
+}
+
+// Normalizes a host name so equivalent spellings compare equal:
+// - surrounding whitespace and trailing dots are removed ("Example.COM." → "example.com")
+// - internationalized names are converted to punycode ("bücher.de" → "xn--bcher-kva.de")
+// - IPv6 literals keep their form, only lowercased and without brackets
+// Returns None if the name is not a valid host.
+pub fn normalize_host(host: &str) -> Option<String> {
+    let host = host.trim().trim_end_matches('.');
+    if host.is_empty() {
+        return None;
+    }
+
+    // IPv6 literal ("[::1]" or "::1")
+    let unbracketed = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    if unbracketed.contains(':') {
+        return Some(unbracketed.to_ascii_lowercase());
+    }
+
+    // PERFORMANCE OPTIMIZATION: plain ASCII names (the common case) only need lowercasing,
+    // the IDNA conversion is only run for Unicode names or punycode labels
+    let plain_ascii = host
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_');
+    if plain_ascii && !host.to_ascii_lowercase().contains("xn--") {
+        if host.split('.').any(|label| label.is_empty()) {
+            return None;
+        }
+        return Some(host.to_ascii_lowercase());
+    }
+
+    idna::domain_to_ascii(host).ok().filter(|h| !h.is_empty())
+}
+
+#[derive(Debug, Default)]
+struct TrieNode {
+    children: HashMap<Box<str>, TrieNode>,
+    suffix: bool,   // A Suffix rule ends here
+    exact: bool,    // An Exact rule ends here
+    wildcard: bool, // A Wildcard rule ends here
+}
+
+impl TrieNode {
+    fn flag_mut(&mut self, kind: RuleKind) -> &mut bool {
+        match kind {
+            RuleKind::Suffix => &mut self.suffix,
+            RuleKind::Exact => &mut self.exact,
+            RuleKind::Wildcard => &mut self.wildcard,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.children.is_empty() && !self.suffix && !self.exact && !self.wildcard
+    }
+
+    // Clears the rule at the end of `labels`, removing nodes that become empty.
+    // Returns true if the rule existed.
+    fn remove(&mut self, labels: &[&str], kind: RuleKind) -> bool {
+        match labels.split_last() {
+            None => std::mem::replace(self.flag_mut(kind), false),
+            Some((label, rest)) => {
+                let Some(child) = self.children.get_mut(*label) else {
+                    return false;
+                };
+                let removed = child.remove(rest, kind);
+                if child.is_empty() {
+                    self.children.remove(*label);
+                }
+                removed
+            }
+        }
+    }
+}
+
+// The set of domain rules used by the proxy to decide what is blocked.
+#[derive(Debug, Default)]
+pub struct DomainMatcher {
+    root: TrieNode,
+    len: usize, // Number of rules stored
+}
+
+impl DomainMatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Builds a matcher from rule lines, skipping the ones that don't parse.
+    pub fn from_rules<I, S>(rules: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut matcher = Self::new();
+        for rule in rules {
+            matcher.insert(rule.as_ref());
+        }
+        matcher
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Adds a rule. Returns false if it is invalid or already present.
+    pub fn insert(&mut self, rule: &str) -> bool {
+        match DomainRule::parse(rule) {
+            Some(rule) => self.insert_rule(&rule),
+            None => false,
+        }
+    }
+
+    pub fn insert_rule(&mut self, rule: &DomainRule) -> bool {
+        let mut node = &mut self.root;
+        for label in rule.domain.rsplit('.') {
+            node = node.children.entry(label.into()).or_default();
+        }
+
+        let flag = node.flag_mut(rule.kind);
+        if *flag {
+            return false;
+        }
+        *flag = true;
+        self.len += 1;
+        true
+    }
+
+    // Removes a rule. Returns false if it was not present.
+    pub fn remove(&mut self, rule: &str) -> bool {
+        let Some(rule) = DomainRule::parse(rule) else {
+            return false;
+        };
+        let labels: Vec<&str> = rule.domain.split('.').collect();
+        let removed = self.root.remove(&labels, rule.kind);
+        if removed {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    // Returns the first rule (from the top-level domain down) that covers `host`.
+    pub fn find(&self, host: &str) -> Option<DomainRule> {
+        let host = normalize_host(host)?;
+        let labels: Vec<&str> = host.rsplit('.').collect();
+
+        let mut node = &self.root;
+        for (depth, label) in labels.iter().enumerate() {
+            node = node.children.get(*label)?;
+
+            let is_last = depth + 1 == labels.len();
+            let kind = if node.suffix {
+                Some(RuleKind::Suffix)
+            } else if !is_last && node.wildcard {
+                // "*.example.com" covers anything below "example.com"
+                Some(RuleKind::Wildcard)
+            } else if is_last && node.exact {
+                Some(RuleKind::Exact)
+            } else {
+                None
+            };
+
+            if let Some(kind) = kind {
+                // Rebuild the rule's domain from the labels walked so far
+                let domain = labels[..=depth]
+                    .iter()
+                    .rev()
+                    .copied()
+                    .collect::<Vec<_>>()
+                    .join(".");
+                return Some(DomainRule { kind, domain });
+            }
+        }
+        None
+    }
+
+    pub fn matches(&self, host: &str) -> bool {
+        self.find(host).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_hosts() {
+        assert_eq!(
+            normalize_host(" Example.COM. ").as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(
+            normalize_host("bücher.de").as_deref(),
+            Some("xn--bcher-kva.de")
+        );
+        assert_eq!(
+            normalize_host("XN--BCHER-KVA.de").as_deref(),
+            Some("xn--bcher-kva.de")
+        );
+        assert_eq!(
+            normalize_host("BÜCHER.DE").as_deref(),
+            Some("xn--bcher-kva.de")
+        );
+        assert_eq!(normalize_host("[::1]").as_deref(), Some("::1"));
+        assert_eq!(normalize_host("FE80::1").as_deref(), Some("fe80::1"));
+        assert_eq!(normalize_host("a..com"), None);
+        assert_eq!(normalize_host("."), None);
+        assert_eq!(normalize_host(""), None);
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rule = |text| DomainRule::parse(text).map(|rule| rule.to_string());
+        assert_eq!(rule("Example.com").as_deref(), Some("||example.com"));
+        assert_eq!(rule("||example.com^").as_deref(), Some("||example.com"));
+        assert_eq!(rule("=example.com").as_deref(), Some("=example.com"));
+        assert_eq!(rule("*.bücher.de").as_deref(), Some("*.xn--bcher-kva.de"));
+        assert_eq!(rule("# comment"), None);
+        assert_eq!(rule("! adblock comment"), None);
+        assert_eq!(rule("   "), None);
+    }
+
+    #[test]
+    fn matches_by_kind() {
+        let matcher = DomainMatcher::from_rules([
+            "example.com",
+            "=exact.org",
+            "*.wild.net",
+            "a..b",
+        ]);
+        assert_eq!(matcher.len(), 3);
+
+        assert!(matcher.matches("example.com"));
+        assert!(matcher.matches("cdn.www.EXAMPLE.com."));
+        assert!(!matcher.matches("notexample.com"));
+        assert!(!matcher.matches("example.com.evil"));
+
+        assert!(matcher.matches("exact.org"));
+        assert!(!matcher.matches("www.exact.org"));
+
+        assert!(!matcher.matches("wild.net"));
+        assert!(matcher.matches("a.b.wild.net"));
+
+        // The rule that matched, rebuilt from the trie
+        let rule = matcher.find("a.www.example.com").unwrap();
+        assert_eq!(
+            (rule.kind, rule.domain.as_str()),
+            (RuleKind::Suffix, "example.com")
+        );
+        let rule = matcher.find("x.wild.net").unwrap();
+        assert_eq!(rule.to_string(), "*.wild.net");
+    }
+
+    #[test]
+    fn idna_hosts_match_rules() {
+        let matcher = DomainMatcher::from_rules(["bücher.de"]);
+        assert!(matcher.matches("xn--bcher-kva.de"));
+        assert!(matcher.matches("shop.BÜCHER.de"));
+        let matcher = DomainMatcher::from_rules(["xn--bcher-kva.de"]);
+        assert!(matcher.matches("bücher.de"));
+    }
+
+    #[test]
+    fn insert_and_remove() {
+        let mut matcher = DomainMatcher::new();
+        assert!(matcher.insert("a.example.com"));
+        assert!(!matcher.insert("||A.example.com"), "same rule twice");
+        assert!(matcher.insert("=example.com"));
+        assert!(matcher.insert("example.com"));
+        assert_eq!(matcher.len(), 3);
+
+        assert!(matcher.remove("example.com"));
+        assert!(!matcher.remove("example.com"));
+        assert!(!matcher.remove("other.com"));
+        assert!(matcher.matches("example.com")); // The exact rule is still there
+        assert!(!matcher.matches("b.example.com"));
+        assert!(matcher.matches("x.a.example.com"));
+
+        assert!(matcher.remove("=example.com"));
+        assert!(matcher.remove("a.example.com"));
+        assert!(matcher.is_empty());
+        assert!(matcher.root.is_empty(), "empty nodes are pruned");
+    }
+}
@@ -0,0 +1,633 @@
+// ============================================================================
+//  HTTP/1.1 MESSAGE FRAMING
+// ============================================================================
+// Helpers used by the proxy to carry several HTTP requests over one client
+// connection (keep-alive and pipelining). To do that we must know exactly
+// where each message ends:
+// 1. Read a message head (request or response) up to the blank line
+// 2. Work out how the body is delimited (Content-Length, chunked, or close)
+// 3. Forward exactly that body, leaving the next message in the buffer
+
+use std::{io, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::timeout,
+};
+
+// How long we wait for the next bytes of a message head before giving up. Bodies
+// have no such limit: a long-poll or streaming response may stay quiet for minutes
+// and still be in use.
+const HEAD_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Largest response head we accept (status line + headers)
+pub const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+// Longest chunk-size or trailer line we accept inside a chunked body
+const MAX_LINE_SIZE: usize = 8 * 1024;
+
+// Most hex digits in a chunk size. Keeps a chunk under 2^60 bytes, so adding up
+// the sizes of a body can't overflow.
+const MAX_CHUNK_SIZE_DIGITS: usize = 15;
+
+// How the body of an HTTP message is delimited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyLength {
+    Empty,              // No body at all
+    ContentLength(u64), // Exactly N bytes
+    Chunked,            // "Transfer-Encoding: chunked", ends with a zero-size chunk
+    UntilClose,         // Body ends when the server closes the connection (responses only)
+}
+
+// Parses "Name: value" header lines (everything after the first line of the head).
+pub fn parse_headers(head: &str) -> Vec<(String, String)> {
+    head.lines()
+        .skip(1) // Skip the request/status line
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+// Returns the value of the first header with this name (case-insensitive).
+pub fn header_value<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+// Checks whether a comma separated header (e.g. "Connection: keep-alive, Upgrade")
+// contains the given token.
+pub fn has_header_token(headers: &[(String, String)], name: &str, token: &str) -> bool {
+    headers
+        .iter()
+        .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+        .flat_map(|(_, value)| value.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+// Decides if the connection can stay open after this message.
+// HTTP/1.1 is persistent unless "close" is sent, HTTP/1.0 only with "keep-alive".
+pub fn wants_keep_alive(version: &str, headers: &[(String, String)]) -> bool {
+    if has_header_token(headers, "connection", "close")
+        || has_header_token(headers, "proxy-connection", "close")
+    {
+        return false;
+    }
+    if version.eq_ignore_ascii_case("HTTP/1.1") {
+        return true;
+    }
+    has_header_token(headers, "connection", "keep-alive")
+        || has_header_token(headers, "proxy-connection", "keep-alive")
+}
+
+// Error returned for message framing we refuse to forward
+fn invalid_framing(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Parses the Content-Length header. Several different values are an error,
+// since the two ends could disagree on where the body stops.
+fn content_length(headers: &[(String, String)]) -> io::Result<Option<u64>> {
+    let mut length = None;
+    for (_, value) in headers
+        .iter()
+        .filter(|(key, _)| key.eq_ignore_ascii_case("content-length"))
+    {
+        for part in value.split(',') {
+            // Digits only: `parse` would also take a sign ("+5")
+            let part = part.trim();
+            if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(invalid_framing("Invalid Content-Length"));
+            }
+            let n: u64 = part
+                .parse()
+                .map_err(|_| invalid_framing("Invalid Content-Length"))?;
+            match length {
+                Some(existing) if existing != n => {
+                    return Err(invalid_framing("Conflicting Content-Length values"))
+                }
+                _ => length = Some(n),
+            }
+        }
+    }
+    Ok(length)
+}
+
+// Checks if "chunked" is the final transfer coding.
+fn is_chunked(headers: &[(String, String)]) -> Option<bool> {
+    let codings: Vec<&str> = headers
+        .iter()
+        .filter(|(key, _)| key.eq_ignore_ascii_case("transfer-encoding"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|t| t.trim())
+        .filter(|t| !t.is_empty())
+        .collect();
+
+    codings
+        .last()
+        .map(|last| last.eq_ignore_ascii_case("chunked"))
+}
+
+// Body framing of a request. Returns Err for framing we refuse to forward
+// (conflicting lengths, or Transfer-Encoding together with Content-Length),
+// which would let a request be read differently by us and by the server.
+pub fn request_body_length(headers: &[(String, String)]) -> io::Result<BodyLength> {
+    let length = content_length(headers)?;
+
+    match is_chunked(headers) {
+        Some(true) if length.is_none() => Ok(BodyLength::Chunked),
+        Some(true) => Err(invalid_framing("Both Transfer-Encoding and Content-Length")),
+        Some(false) => Err(invalid_framing("Unsupported request Transfer-Encoding")),
+        None => Ok(match length {
+            Some(0) | None => BodyLength::Empty,
+            Some(n) => BodyLength::ContentLength(n),
+        }),
+    }
+}
+
+// Body framing of a response (RFC 9112 section 6.3).
+pub fn response_body_length(
+    request_method: &str,
+    status: u16,
+    headers: &[(String, String)],
+) -> io::Result<BodyLength> {
+    // Responses to HEAD and 1xx/204/304 never have a body
+    if request_method.eq_ignore_ascii_case("HEAD")
+        || (100..200).contains(&status)
+        || status == 204
+        || status == 304
+    {
+        return Ok(BodyLength::Empty);
+    }
+
+    match is_chunked(headers) {
+        Some(true) => Ok(BodyLength::Chunked),
+        Some(false) => Ok(BodyLength::UntilClose),
+        None => Ok(match content_length(headers)? {
+            Some(0) => BodyLength::Empty,
+            Some(n) => BodyLength::ContentLength(n),
+            None => BodyLength::UntilClose,
+        }),
+    }
+}
+
+// Reads more bytes from `reader` into `buf`. Returns how many were read (0 = closed).
+pub async fn fill_buf<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+) -> io::Result<usize> {
+    let mut chunk = [0u8; 8192];
+    let n = reader.read(&mut chunk).await?;
+    buf.extend_from_slice(&chunk[..n]);
+    Ok(n)
+}
+
+// Reads until a full message head ("...\r\n\r\n") is in `buf`, then removes it
+// from the buffer and returns it without the final blank line.
+// Bytes after the head stay in `buf` (the body, or the next pipelined request).
+// Returns Ok(None) if the peer closed the connection before sending anything,
+// an `InvalidData` error if the head is longer than `max_size` and a `TimedOut`
+// error if the peer goes quiet for `HEAD_READ_TIMEOUT` in the middle of it.
+pub async fn read_head<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    max_size: usize,
+) -> io::Result<Option<Vec<u8>>> {
+    // Where to resume looking for "\r\n\r\n", so each byte is only scanned once
+    // (minus 3 bytes, in case the terminator was split across two reads)
+    let mut scanned = 0;
+
+    loop {
+        if let Some(pos) = buf[scanned..].windows(4).position(|w| w == b"\r\n\r\n") {
+            let end = scanned + pos;
+            if end > max_size {
+                break;
+            }
+            let head = buf[..end].to_vec();
+            buf.drain(..end + 4);
+            return Ok(Some(head));
+        }
+        scanned = buf.len().saturating_sub(3);
+
+        if buf.len() > max_size {
+            break;
+        }
+
+        let read = match timeout(HEAD_READ_TIMEOUT, fill_buf(reader, buf)).await {
+            Ok(result) => result?,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "Read timeout")),
+        };
+        if read == 0 {
+            if buf.is_empty() {
+                return Ok(None);
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Connection closed in the middle of a message head",
+            ));
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Message head too large",
+    ))
+}
+
+// Forwards exactly `len` bytes, starting with what is already buffered.
+async fn copy_exact<R, W>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    writer: &mut W,
+    mut len: u64,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    while len > 0 {
+        if buf.is_empty() && fill_buf(reader, buf).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Body ended early",
+            ));
+        }
+        let take = buf.len().min(len as usize);
+        writer.write_all(&buf[..take]).await?;
+        buf.drain(..take);
+        len -= take as u64;
+    }
+    Ok(())
+}
+
+// Removes one "\r\n" terminated line from the buffer (reading more if needed).
+async fn take_line<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut Vec<u8>) -> io::Result<Vec<u8>> {
+    loop {
+        if let Some(pos) = buf.windows(2).position(|w| w == b"\r\n") {
+            return Ok(buf.drain(..pos + 2).collect());
+        }
+        if buf.len() > MAX_LINE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Chunk line too long",
+            ));
+        }
+        if fill_buf(reader, buf).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Body ended early",
+            ));
+        }
+    }
+}
+
+// Removes the "\r\n" that must follow the data of a chunk, reading more if needed.
+// Anything else means the two ends could disagree on where the chunk stops.
+async fn take_chunk_end<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut Vec<u8>) -> io::Result<()> {
+    while buf.len() < 2 {
+        if fill_buf(reader, buf).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Body ended early",
+            ));
+        }
+    }
+    if &buf[..2] != b"\r\n" {
+        return Err(invalid_framing("Chunk data not followed by CRLF"));
+    }
+    buf.drain(..2);
+    Ok(())
+}
+
+// Parses the size at the start of a chunk line: "1a2b;name=value\r\n".
+// Only hex digits are accepted (no sign, no spaces inside), and at most
+// `MAX_CHUNK_SIZE_DIGITS` of them.
+fn parse_chunk_size(line: &[u8]) -> io::Result<u64> {
+    let text = String::from_utf8_lossy(line);
+    let size = text.trim_end().split(';').next().unwrap_or("").trim();
+    if size.is_empty()
+        || size.len() > MAX_CHUNK_SIZE_DIGITS
+        || !size.bytes().all(|b| b.is_ascii_hexdigit())
+    {
+        return Err(invalid_framing("Invalid chunk size"));
+    }
+    u64::from_str_radix(size, 16).map_err(|_| invalid_framing("Invalid chunk size"))
+}
+
+// Forwards one message body from `reader` to `writer` using the given framing.
+// Chunked bodies are passed through unchanged (sizes, extensions and trailers),
+// we only parse them to find where the body ends.
+// Returns the number of bytes forwarded.
+pub async fn copy_body<R, W>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    writer: &mut W,
+    length: BodyLength,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut copied = 0;
+    match length {
+        BodyLength::Empty => {}
+        BodyLength::ContentLength(n) => {
+            copy_exact(reader, buf, writer, n).await?;
+            copied = n;
+        }
+        BodyLength::Chunked => loop {
+            let line = take_line(reader, buf).await?;
+            writer.write_all(&line).await?;
+            copied += line.len() as u64;
+            let size = parse_chunk_size(&line)?;
+
+            if size == 0 {
+                // Last chunk: forward trailer lines up to and including the blank line
+                loop {
+                    let trailer = take_line(reader, buf).await?;
+                    writer.write_all(&trailer).await?;
+                    copied += trailer.len() as u64;
+                    if trailer == b"\r\n" {
+                        break;
+                    }
+                }
+                break;
+            }
+
+            // Chunk data followed by its "\r\n", checked before it is passed on
+            copy_exact(reader, buf, writer, size).await?;
+            take_chunk_end(reader, buf).await?;
+            writer.write_all(b"\r\n").await?;
+            copied += size + 2;
+        },
+        BodyLength::UntilClose => loop {
+            if !buf.is_empty() {
+                writer.write_all(buf).await?;
+                copied += buf.len() as u64;
+                buf.clear();
+            }
+            if fill_buf(reader, buf).await? == 0 {
+                break;
+            }
+        },
+    }
+    writer.flush().await?;
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::ReadBuf;
+
+    fn headers(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    // Hands out its data a few bytes per read, like a slow TCP connection
+    struct Trickle {
+        data: Vec<u8>,
+        step: usize,
+    }
+
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            out: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let n = self.step.min(self.data.len()).min(out.remaining());
+            out.put_slice(&self.data[..n]);
+            self.data.drain(..n);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    // Copies a chunked body from `input`, returning what was forwarded and what is
+    // left in the buffer
+    async fn copy_chunked(input: &[u8], step: usize) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let mut reader = Trickle {
+            data: input.to_vec(),
+            step,
+        };
+        let mut buf = Vec::new();
+        let mut out = Vec::new();
+        let copied = copy_body(&mut reader, &mut buf, &mut out, BodyLength::Chunked).await?;
+        assert_eq!(copied, out.len() as u64);
+        buf.extend_from_slice(&reader.data);
+        Ok((out, buf))
+    }
+
+    #[test]
+    fn request_framing() {
+        let length = |list: &[(&str, &str)]| request_body_length(&headers(list));
+        assert_eq!(length(&[]).unwrap(), BodyLength::Empty);
+        assert_eq!(
+            length(&[("Content-Length", "12")]).unwrap(),
+            BodyLength::ContentLength(12)
+        );
+        assert_eq!(
+            length(&[("Content-Length", "5"), ("content-length", "5, 5")]).unwrap(),
+            BodyLength::ContentLength(5)
+        );
+        assert_eq!(
+            length(&[("Transfer-Encoding", "gzip, chunked")]).unwrap(),
+            BodyLength::Chunked
+        );
+        assert!(length(&[("Content-Length", "5"), ("Content-Length", "6")]).is_err());
+        assert!(length(&[("Content-Length", "+5")]).is_err());
+        assert!(length(&[("Transfer-Encoding", "chunked"), ("Content-Length", "5")]).is_err());
+        assert!(length(&[("Transfer-Encoding", "chunked, gzip")]).is_err());
+    }
+
+    #[test]
+    fn response_framing() {
+        let length = |method, status, list: &[(&str, &str)]| {
+            response_body_length(method, status, &headers(list)).unwrap()
+        };
+        let sized = [("Content-Length", "10")];
+        assert_eq!(length("HEAD", 200, &sized), BodyLength::Empty);
+        assert_eq!(length("GET", 204, &sized), BodyLength::Empty);
+        assert_eq!(length("GET", 304, &sized), BodyLength::Empty);
+        assert_eq!(length("GET", 200, &sized), BodyLength::ContentLength(10));
+        assert_eq!(length("GET", 200, &[]), BodyLength::UntilClose);
+        assert_eq!(
+            length("GET", 200, &[("Transfer-Encoding", "gzip")]),
+            BodyLength::UntilClose
+        );
+    }
+
+    #[test]
+    fn keep_alive() {
+        assert!(wants_keep_alive("HTTP/1.1", &[]));
+        assert!(!wants_keep_alive(
+            "HTTP/1.1",
+            &headers(&[("Connection", "Upgrade, close")])
+        ));
+        assert!(!wants_keep_alive("HTTP/1.0", &[]));
+        assert!(wants_keep_alive(
+            "HTTP/1.0",
+            &headers(&[("Proxy-Connection", "keep-alive")])
+        ));
+    }
+
+    #[test]
+    fn chunk_sizes() {
+        assert_eq!(parse_chunk_size(b"1a\r\n").unwrap(), 0x1a);
+        assert_eq!(parse_chunk_size(b"FF;name=value\r\n").unwrap(), 0xff);
+        assert_eq!(parse_chunk_size(b"0\r\n").unwrap(), 0);
+        for bad in [
+            &b"\r\n"[..],
+            b"+1a\r\n",
+            b"-1\r\n",
+            b"1 a\r\n",
+            b"0x1a\r\n",
+            b"ffffffffffffffff\r\n",
+            b"1000000000000000\r\n",
+        ] {
+            assert!(parse_chunk_size(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn chunked_body_across_reads() {
+        let body = b"4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\nExpires: never\r\n\r\n";
+        let next = b"GET /next HTTP/1.1\r\n\r\n";
+        let input = [&body[..], &next[..]].concat();
+        for step in [1, 3, input.len()] {
+            let (out, rest) = copy_chunked(&input, step).await.unwrap();
+            assert_eq!(out, body);
+            assert_eq!(rest, next); // The pipelined request is left for the next read
+        }
+    }
+
+    #[tokio::test]
+    async fn chunked_body_smuggling() {
+        // Chunk data longer than its size: a request hidden after the chunk
+        let smuggled = b"3\r\nabcGET /blocked HTTP/1.1\r\n\r\n0\r\n\r\n";
+        let e = copy_chunked(smuggled, 4).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        // A size that would wrap around when the CRLF is added
+        let e = copy_chunked(b"ffffffffffffffff\r\nabc\r\n0\r\n\r\n", 64)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        // Nothing after the data
+        let e = copy_chunked(b"3\r\nabc", 64).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+        // A chunk line that never ends
+        let long = vec![b'1'; MAX_LINE_SIZE + 10];
+        let e = copy_chunked(&long, 4096).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn content_length_body() {
+        let mut reader = Trickle {
+            data: b"lo worldGET".to_vec(),
+            step: 2,
+        };
+        let mut buf = b"hel".to_vec();
+        let mut out = Vec::new();
+        let length = BodyLength::ContentLength(11);
+        assert_eq!(
+            copy_body(&mut reader, &mut buf, &mut out, length)
+                .await
+                .unwrap(),
+            11
+        );
+        assert_eq!(out, b"hello world");
+        buf.extend_from_slice(&reader.data);
+        assert_eq!(buf, b"GET");
+
+        let mut short = Trickle {
+            data: b"abc".to_vec(),
+            step: 8,
+        };
+        let length = BodyLength::ContentLength(10);
+        let e = copy_body(&mut short, &mut Vec::new(), &mut Vec::new(), length)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn heads() {
+        let input = b"HTTP/1.1 200 OK\r\nA: b\r\n\r\nbody";
+        let mut reader = Trickle {
+            data: input.to_vec(),
+            step: 1,
+        };
+        let mut buf = Vec::new();
+        let head = read_head(&mut reader, &mut buf, 1024)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(head, b"HTTP/1.1 200 OK\r\nA: b");
+        let text = String::from_utf8(head).unwrap();
+        assert_eq!(parse_headers(&text), headers(&[("A", "b")]));
+
+        let mut empty = Trickle {
+            data: Vec::new(),
+            step: 1,
+        };
+        assert!(read_head(&mut empty, &mut Vec::new(), 1024)
+            .await
+            .unwrap()
+            .is_none());
+
+        let mut large = Trickle {
+            data: vec![b'a'; 2048],
+            step: 100,
+        };
+        let e = read_head(&mut large, &mut Vec::new(), 1024)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quiet_bodies_are_not_cut_off() {
+        let bodies = [
+            (
+                BodyLength::UntilClose,
+                &b"data: 1\n\n"[..],
+                &b"data: 2\n\n"[..],
+            ),
+            (BodyLength::Chunked, b"5\r\nfirst\r\n", b"0\r\n\r\n"),
+            (BodyLength::ContentLength(10), b"first", b"later"),
+        ];
+        for (length, first, later) in bodies {
+            // Part of the body, then nothing for longer than a head may take
+            let (mut server, mut proxy) = tokio::io::duplex(64);
+            tokio::spawn(async move {
+                server.write_all(first).await.unwrap();
+                tokio::time::sleep(HEAD_READ_TIMEOUT * 4).await;
+                server.write_all(later).await.unwrap();
+            });
+            let mut out = Vec::new();
+            copy_body(&mut proxy, &mut Vec::new(), &mut out, length)
+                .await
+                .unwrap();
+            assert_eq!(out, [first, later].concat(), "{:?}", length);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_heads_time_out() {
+        let (mut server, mut proxy) = tokio::io::duplex(64);
+        server.write_all(b"HTTP/1.1 200 OK\r\n").await.unwrap();
+        let e = read_head(&mut proxy, &mut Vec::new(), 1024)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        drop(server);
+    }
+}
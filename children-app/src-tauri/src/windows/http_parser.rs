@@ -0,0 +1,352 @@
+// ============================================================================
+//  HTTP REQUEST HEAD PARSER
+// ============================================================================
+// Reads and validates the head of a request sent by the browser:
+// 1. Buffers incoming bytes until the blank line that ends the head,
+//    however many TCP segments it arrives in
+// 2. Enforces a maximum head size and a maximum number of headers
+// 3. Parses the request line and headers into an `HttpRequestHead`
+// 4. Leaves any bytes after the head (body, next request) in the buffer
+//
+// Errors map to the response the browser should get:
+// malformed input -> 400 Bad Request, too large -> 431.
+
+use crate::logger::HttpRequestLog;
+use crate::windows::http_framing::{header_value, read_head};
+use std::collections::HashMap;
+use std::{fmt, io, net::SocketAddr};
+use tokio::io::AsyncRead;
+
+// Size limits for a request head. Requests above these are refused with 431.
+#[derive(Debug, Clone, Copy)]
+pub struct HeadLimits {
+    pub max_head_size: usize, // Request line + all headers, in bytes
+    pub max_headers: usize,   // Number of header lines
+}
+
+impl Default for HeadLimits {
+    fn default() -> Self {
+        HeadLimits {
+            max_head_size: 64 * 1024, // Large enough for big cookies
+            max_headers: 100,
+        }
+    }
+}
+
+// A parsed request head: "METHOD TARGET VERSION" followed by the headers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpRequestHead {
+    pub method: String,                 // e.g. "GET" or "CONNECT"
+    pub target: String,                 // e.g. "http://example.com/page" or "example.com:443"
+    pub version: String,                // e.g. "HTTP/1.1"
+    pub headers: Vec<(String, String)>, // In the order they were sent, duplicates kept
+}
+
+impl HttpRequestHead {
+    // Returns the value of the first header with this name (case-insensitive).
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header_value(&self.headers, name)
+    }
+
+    // Converts the request into the log record used by the proxy logger.
+    // Repeated headers are joined with ", " as allowed by RFC 9110.
+    pub fn to_log(&self, client_addr: SocketAddr) -> HttpRequestLog {
+        let mut headers: HashMap<String, String> = HashMap::new();
+        for (key, value) in &self.headers {
+            headers
+                .entry(key.to_ascii_lowercase())
+                .and_modify(|existing| {
+                    existing.push_str(", ");
+                    existing.push_str(value);
+                })
+                .or_insert_with(|| value.clone());
+        }
+
+        HttpRequestLog {
+            client_addr,
+            method: self.method.clone(),
+            path: self.target.clone(),
+            http_version: self.version.clone(),
+            headers,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HeadParseError {
+    Malformed(&'static str), // Not a valid HTTP/1.x request head
+    HeadTooLarge,            // More bytes than `max_head_size`
+    TooManyHeaders,          // More headers than `max_headers`
+    Io(io::Error),           // The connection failed while reading
+}
+
+impl HeadParseError {
+    // The response to send before closing the connection, if any
+    pub fn response(&self) -> Option<&'static [u8]> {
+        match self {
+            HeadParseError::Malformed(_) => {
+                Some(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n")
+            }
+            HeadParseError::HeadTooLarge | HeadParseError::TooManyHeaders => {
+                Some(b"HTTP/1.1 431 Request Header Fields Too Large\r\nConnection: close\r\n\r\n")
+            }
+            HeadParseError::Io(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for HeadParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeadParseError::Malformed(msg) => write!(f, "Malformed request: {}", msg),
+            HeadParseError::HeadTooLarge => write!(f, "Request head too large"),
+            HeadParseError::TooManyHeaders => write!(f, "Too many request headers"),
+            HeadParseError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HeadParseError {}
+
+// Characters allowed in a method or header name ("token" in RFC 9110)
+fn is_token_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
+fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(is_token_char)
+}
+
+// "HTTP/1.0" or "HTTP/1.1" - the only versions a plain-text request can use
+fn is_http1_version(s: &str) -> bool {
+    matches!(s, "HTTP/1.0" | "HTTP/1.1")
+}
+
+// Splits a head into its "\r\n" separated lines
+fn split_crlf(data: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    while let Some(pos) = data[start..].windows(2).position(|w| w == b"\r\n") {
+        lines.push(&data[start..start + pos]);
+        start += pos + 2;
+    }
+    lines.push(&data[start..]);
+    lines
+}
+
+// Parses a complete request head (without the final blank line).
+pub fn parse_request_head(
+    head: &[u8],
+    limits: &HeadLimits,
+) -> Result<HttpRequestHead, HeadParseError> {
+    if head.len() > limits.max_head_size {
+        return Err(HeadParseError::HeadTooLarge);
+    }
+
+    // Browsers may send an extra CRLF after a request body; skip empty lines first
+    let mut head = head;
+    while let Some(rest) = head.strip_prefix(b"\r\n") {
+        head = rest;
+    }
+
+    let mut lines = split_crlf(head).into_iter();
+
+    // Step 1: Request line "METHOD TARGET VERSION"
+    let request_line = lines.next().unwrap_or_default();
+    let request_line = std::str::from_utf8(request_line)
+        .map_err(|_| HeadParseError::Malformed("request line is not ASCII"))?;
+    if request_line
+        .bytes()
+        .any(|b| !b.is_ascii() || b.is_ascii_control())
+    {
+        return Err(HeadParseError::Malformed(
+            "invalid characters in request line",
+        ));
+    }
+
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v), None) => (m, t, v),
+        _ => {
+            return Err(HeadParseError::Malformed(
+                "request line must have three parts",
+            ))
+        }
+    };
+
+    if !is_token(method) {
+        return Err(HeadParseError::Malformed("invalid method"));
+    }
+    if target.is_empty() {
+        return Err(HeadParseError::Malformed("empty request target"));
+    }
+    if !is_http1_version(version) {
+        return Err(HeadParseError::Malformed("unsupported HTTP version"));
+    }
+
+    // Step 2: Header lines "Name: value"
+    let mut headers = Vec::new();
+    for line in lines {
+        // Lines must end with CRLF; a bare CR or LF could be read differently by the server
+        if line.contains(&b'\r') || line.contains(&b'\n') {
+            return Err(HeadParseError::Malformed("bare CR or LF in header"));
+        }
+        // Obsolete line folding (continuation lines) is rejected, see RFC 9112 section 5.2
+        if line.starts_with(b" ") || line.starts_with(b"\t") {
+            return Err(HeadParseError::Malformed("folded header line"));
+        }
+
+        let colon = line
+            .iter()
+            .position(|&b| b == b':')
+            .ok_or(HeadParseError::Malformed("header without colon"))?;
+
+        // No whitespace is allowed between the name and the colon
+        let name = std::str::from_utf8(&line[..colon])
+            .ok()
+            .filter(|name| is_token(name))
+            .ok_or(HeadParseError::Malformed("invalid header name"))?;
+
+        let value = &line[colon + 1..];
+        if value.contains(&0) {
+            return Err(HeadParseError::Malformed("invalid header value"));
+        }
+        // Values may contain non-UTF-8 bytes (obs-text), keep them readable
+        let value = String::from_utf8_lossy(value).trim().to_string();
+
+        headers.push((name.to_string(), value));
+        if headers.len() > limits.max_headers {
+            return Err(HeadParseError::TooManyHeaders);
+        }
+    }
+
+    Ok(HttpRequestHead {
+        method: method.to_string(),
+        target: target.to_string(),
+        version: version.to_string(),
+        headers,
+    })
+}
+
+// Reads the next request head from the browser.
+// `buf` holds bytes already read but not handled yet; after the call it holds
+// whatever followed the head (request body, or the next pipelined request).
+// Returns Ok(None) if the browser closed the connection between requests.
+pub async fn read_request_head<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    limits: &HeadLimits,
+) -> Result<Option<HttpRequestHead>, HeadParseError> {
+    let head = match read_head(reader, buf, limits.max_head_size).await {
+        Ok(Some(head)) => head,
+        Ok(None) => return Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            return Err(HeadParseError::HeadTooLarge)
+        }
+        Err(e) => return Err(HeadParseError::Io(e)),
+    };
+
+    parse_request_head(&head, limits).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(head: &[u8]) -> Result<HttpRequestHead, HeadParseError> {
+        parse_request_head(head, &HeadLimits::default())
+    }
+
+    fn status(error: &HeadParseError) -> &'static [u8] {
+        &error.response().unwrap()[..12]
+    }
+
+    #[test]
+    fn parses_request() {
+        let request = parse(
+            b"\r\nGET http://example.com/a?b HTTP/1.1\r\nHost: example.com\r\nX-A:  1 \r\nx-a: 2",
+        )
+        .unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.target, "http://example.com/a?b");
+        assert_eq!(request.version, "HTTP/1.1");
+        assert_eq!(request.header("HOST"), Some("example.com"));
+        assert_eq!(request.header("x-a"), Some("1"));
+        assert_eq!(request.headers.len(), 3);
+
+        let log = request.to_log("127.0.0.1:5000".parse().unwrap());
+        assert_eq!(log.headers["x-a"], "1, 2");
+
+        let connect = parse(b"CONNECT example.com:443 HTTP/1.0").unwrap();
+        assert!(connect.headers.is_empty());
+    }
+
+    #[test]
+    fn malformed_is_400() {
+        for head in [
+            &b"GET /"[..],
+            b"GET  / HTTP/1.1",
+            b"GET / HTTP/2.0",
+            b"G(T / HTTP/1.1",
+            b"GET /\x01 HTTP/1.1",
+            b"GET / HTTP/1.1\r\nHost example.com",
+            b"GET / HTTP/1.1\r\nHost : example.com",
+            b"GET / HTTP/1.1\r\nA: 1\r\n folded",
+            b"GET / HTTP/1.1\r\nA: 1\nB: 2",
+            b"GET / HTTP/1.1\r\nA: 1\0",
+        ] {
+            let error = parse(head).unwrap_err();
+            assert!(matches!(error, HeadParseError::Malformed(_)), "{:?}", head);
+            assert_eq!(status(&error), b"HTTP/1.1 400");
+        }
+    }
+
+    #[test]
+    fn limits_are_431() {
+        let limits = HeadLimits {
+            max_head_size: 64,
+            max_headers: 2,
+        };
+        let error = parse_request_head(&[b'a'; 65], &limits).unwrap_err();
+        assert!(matches!(error, HeadParseError::HeadTooLarge));
+        assert_eq!(status(&error), b"HTTP/1.1 431");
+
+        let three = b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3";
+        let error = parse_request_head(three, &limits).unwrap_err();
+        assert!(matches!(error, HeadParseError::TooManyHeaders));
+        assert_eq!(status(&error), b"HTTP/1.1 431");
+        let two = b"GET / HTTP/1.1\r\nA: 1\r\nB: 2";
+        assert!(parse_request_head(two, &limits).is_ok());
+    }
+
+    #[tokio::test]
+    async fn reads_pipelined_requests() {
+        let mut input: &[u8] =
+            b"GET /1 HTTP/1.1\r\nHost: a\r\n\r\nGET /2 HTTP/1.1\r\nHost: a\r\n\r\n";
+        let mut buf = Vec::new();
+        let limits = HeadLimits::default();
+        let first = read_request_head(&mut input, &mut buf, &limits)
+            .await
+            .unwrap();
+        assert_eq!(first.unwrap().target, "/1");
+        let second = read_request_head(&mut input, &mut buf, &limits)
+            .await
+            .unwrap();
+        assert_eq!(second.unwrap().target, "/2");
+        let closed = read_request_head(&mut input, &mut buf, &limits)
+            .await
+            .unwrap();
+        assert!(closed.is_none());
+
+        // A head that never ends within the limit
+        let mut endless: &[u8] = &[b'a'; 200];
+        let limits = HeadLimits {
+            max_head_size: 100,
+            max_headers: 10,
+        };
+        let error = read_request_head(&mut endless, &mut Vec::new(), &limits)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, HeadParseError::HeadTooLarge));
+    }
+}
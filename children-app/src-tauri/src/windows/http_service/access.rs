@@ -0,0 +1,180 @@
+// ============================================================================
+//  ACCESS REQUESTS - ASKING A PARENT FOR A BLOCKED SITE
+// ============================================================================
+// Requests the child made from the block page (see access_request.rs) go to the
+// backend, which shows them to the parent. The updater sends new requests and
+// asks for the parent's decisions after each sync:
+//
+//     POST /api/children/{child_id}/access-requests
+//     {"requests":[{"id":"3f0a…","host":"wikipedia.org","reason":"homework",
+//                   "requested_at":"2026-10-17T08:30:00Z"}]}
+//
+//     GET /api/children/{child_id}/access-requests?ids=3f0a…,9c1e…
+//     {"decisions":[{"request_id":"3f0a…","status":"approved","grant":{...}},
+//                   {"request_id":"9c1e…","status":"denied"}]}
+//
+// An approval lets a site through, so it is signed like the rule sets (see
+// signature.rs). The grant is bound to the child and to the request it answers;
+// a grant for another request can't be replayed, and its expiry can't be moved.
+//
+// Signed message (compact JSON, fields in declaration order, `expires_at` in
+// RFC 3339 UTC with "Z", null when the approval doesn't expire):
+//
+//     GUARDNEST-ACCESS-GRANT-SIGNATURE 1\n{"child_id":7,"request_id":"3f0a…",
+//     "rule":"wikipedia.org","expires_at":"2026-10-17T09:00:00Z"}
+
+use crate::windows::domain_matcher::DomainRule;
+use crate::windows::http_service::client::{BackendClient, BackendError};
+use crate::windows::http_service::signature::{KeySet, PayloadSignature, VerifyError};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+// Put in front of every signed grant, so a rule set signature (or any other) can
+// never be passed off as an approval
+const GRANT_SIGNATURE_CONTEXT: &[u8] = b"GUARDNEST-ACCESS-GRANT-SIGNATURE 1\n";
+
+// A request as sent to the backend
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SentRequest<'a> {
+    pub id: &'a str,
+    pub host: &'a str,
+    pub reason: Option<&'a str>,
+    pub requested_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct SentRequests<'a> {
+    requests: &'a [SentRequest<'a>],
+}
+
+// The parent's approval of a request, signed by the backend
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccessGrant {
+    pub child_id: i32,
+    pub request_id: String,
+    pub rule: String, // Let through, in blocklist rule syntax (e.g. "wikipedia.org")
+    pub expires_at: Option<DateTime<Utc>>, // None: until the rules change
+    pub signature: PayloadSignature,
+}
+
+// The part of a grant the signature covers, in signing order
+#[derive(Serialize)]
+struct SignedGrant<'a> {
+    child_id: i32,
+    request_id: &'a str,
+    rule: &'a str,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl AccessGrant {
+    // The exact bytes the backend signs
+    pub fn signed_bytes(&self) -> Result<Vec<u8>, VerifyError> {
+        let content = SignedGrant {
+            child_id: self.child_id,
+            request_id: &self.request_id,
+            rule: &self.rule,
+            expires_at: self.expires_at,
+        };
+        let mut message = GRANT_SIGNATURE_CONTEXT.to_vec();
+        serde_json::to_writer(&mut message, &content)
+            .map_err(|e| VerifyError::Malformed(e.to_string()))?;
+        Ok(message)
+    }
+
+    // Checks the signature and that the grant was issued for `child_id`
+    pub fn verify(&self, keys: &KeySet, child_id: i32) -> Result<(), VerifyError> {
+        if self.child_id != child_id {
+            return Err(VerifyError::WrongChild {
+                expected: child_id,
+                received: self.child_id,
+            });
+        }
+        keys.verify(&self.signed_bytes()?, &self.signature)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum DecisionStatus {
+    Pending,
+    Approved,
+    Denied,
+}
+
+#[derive(Deserialize)]
+struct ReceivedDecision {
+    request_id: String,
+    status: DecisionStatus,
+    #[serde(default)]
+    grant: Option<AccessGrant>,
+}
+
+#[derive(Deserialize)]
+struct ReceivedDecisions {
+    decisions: Vec<ReceivedDecision>,
+}
+
+// What the parent decided, after the grant was verified
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccessDecision {
+    Approved(AccessGrant),
+    Denied(String), // Request ID
+}
+
+// Hands new requests to the backend
+pub async fn send_access_requests(
+    client: &BackendClient,
+    requests: &[SentRequest<'_>],
+) -> Result<(), BackendError> {
+    let path = format!("/api/children/{}/access-requests", client.child_id());
+    client.post_json(&path, &SentRequests { requests }).await?;
+    Ok(())
+}
+
+// Asks the backend what the parent decided about the requests `ids`. Requests
+// still waiting for an answer are left out. Nothing is returned unless every
+// approval is signed, for this child, for the request it answers and for a valid
+// rule.
+pub async fn fetch_access_decisions(
+    client: &BackendClient,
+    keys: &KeySet,
+    ids: &[String],
+) -> Result<Vec<AccessDecision>, BackendError> {
+    let path = format!(
+        "/api/children/{}/access-requests?ids={}",
+        client.child_id(),
+        ids.join(",")
+    );
+    let received: ReceivedDecisions = client.get_json(&path).await?;
+
+    let mut decisions = Vec::new();
+    for decision in received.decisions {
+        match (decision.status, decision.grant) {
+            (DecisionStatus::Pending, _) => {}
+            (DecisionStatus::Denied, _) => {
+                decisions.push(AccessDecision::Denied(decision.request_id))
+            }
+            (DecisionStatus::Approved, Some(grant)) => {
+                if grant.request_id != decision.request_id
+                    || DomainRule::parse(&grant.rule).is_none()
+                {
+                    return Err(BackendError::MalformedPayload(format!(
+                        "grant for request {} doesn't match it",
+                        decision.request_id
+                    )));
+                }
+                grant
+                    .verify(keys, client.child_id())
+                    .map_err(BackendError::Unverified)?;
+                decisions.push(AccessDecision::Approved(grant));
+            }
+            (DecisionStatus::Approved, None) => {
+                return Err(BackendError::MalformedPayload(format!(
+                    "approval of request {} without a grant",
+                    decision.request_id
+                )))
+            }
+        }
+    }
+    Ok(decisions)
+}
@@ -0,0 +1,100 @@
+// ============================================================================
+//  BACKOFF - WAIT TIMES BETWEEN FAILED REQUESTS
+// ============================================================================
+// After a failed request the wait before the next attempt doubles each time
+// (5s, 10s, 20s, ... up to a cap). Half of each wait is random ("jitter"), so
+// many devices that lost the backend at the same moment don't all come back
+// at the same second.
+
+use rand::Rng;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration, // Wait after the first failure
+    max: Duration,  // Longest wait
+    failures: u32,  // Failures in a row
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Backoff {
+            base,
+            max,
+            failures: 0,
+        }
+    }
+
+    // Call after a successful request
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    // Call after a failed request, returns how long to wait before the next one
+    pub fn next_delay(&mut self) -> Duration {
+        let exponent = self.failures.min(16); // 2^16 × base is far above any cap
+        self.failures = self.failures.saturating_add(1);
+
+        let delay = self.base.saturating_mul(1 << exponent).min(self.max);
+
+        // "Equal jitter": half fixed, half random
+        let half = delay / 2;
+        let jitter = rand::thread_rng().gen_range(0..=half.as_millis() as u64);
+        half + Duration::from_millis(jitter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: Duration = Duration::from_secs(5);
+    const MAX: Duration = Duration::from_secs(300);
+
+    // Between half of `delay` and all of it
+    fn assert_within(delay: Duration, full: Duration) {
+        assert!(
+            delay >= full / 2 && delay <= full,
+            "{:?} not within {:?}",
+            delay,
+            full
+        );
+    }
+
+    #[test]
+    fn doubles_up_to_the_cap() {
+        let mut backoff = Backoff::new(BASE, MAX);
+        for secs in [5, 10, 20, 40, 80, 160, 300, 300, 300] {
+            assert_within(backoff.next_delay(), Duration::from_secs(secs));
+        }
+
+        // Many failures later the wait is still capped
+        for _ in 0..100 {
+            assert_within(backoff.next_delay(), MAX);
+        }
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut backoff = Backoff::new(BASE, MAX);
+        for _ in 0..5 {
+            backoff.next_delay();
+        }
+        backoff.reset();
+        assert_within(backoff.next_delay(), BASE);
+        assert_within(backoff.next_delay(), BASE * 2);
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let mut delays = std::collections::HashSet::new();
+        for _ in 0..200 {
+            let mut backoff = Backoff::new(BASE, MAX);
+            let delay = backoff.next_delay();
+            assert_within(delay, BASE);
+            delays.insert(delay);
+        }
+        // 2501 possible waits: 200 draws can't all be the same
+        assert!(delays.len() > 1);
+    }
+}
@@ -0,0 +1,458 @@
+// ============================================================================
+//  BLOCKLIST CACHE - LAST GOOD RULE SET ON DISK
+// ============================================================================
+// Keeps the last rule set received from the backend, so the proxy filters from
+// the first connection after a restart, even when the device is offline.
+//
+// File layout (`BLOCKLIST_CACHE_PATH`):
+//
+//     GUARDNEST-BLOCKLIST 2           <- magic + format version
+//     sha256:<64 hex digits>          <- checksum of the JSON below
+//     {"blocklist":{...},"etag":...}  <- the cached rule set
+//
+// The file is written to a temporary file first and then renamed over the old
+// one, so a crash or power loss leaves either the old or the new cache, never
+// half of each. The checksum catches damaged files, the backend's signature on
+// the rule set catches edited ones. A file that fails any check is reported
+// and ignored.
+//
+// An older cache file is still validly signed, so copying one back would undo the
+// parent's recent changes. The highest version accepted for the child is kept in a
+// second, sealed file only administrators can write (`VersionFloor`), and a cache
+// below it is refused.
+
+use crate::windows::http_service::fetch::SyncCursor;
+use crate::windows::http_service::signature::{KeySet, SignedBlocklist, VerifyError};
+use crate::windows::key_protector::{read_sealed, write_sealed, KeyProtector};
+use openssl::sha::sha256;
+use serde::{Deserialize, Serialize};
+use std::{fmt, io, path::Path};
+use tokio::io::AsyncWriteExt;
+
+pub const BLOCKLIST_CACHE_PATH: &str = "C:\\ProgramData\\GuardNest\\blocklist.cache";
+
+// Sealed `VersionFloor`, written next to the cache
+pub const VERSION_FLOOR_PATH: &str = "C:\\ProgramData\\GuardNest\\blocklist.floor";
+
+// First line of the file. Bump the number when the layout changes.
+const CACHE_MAGIC: &str = "GUARDNEST-BLOCKLIST 2";
+
+#[derive(Debug)]
+pub enum CacheError {
+    Io(io::Error),           // The file can't be read or written
+    BadHeader,               // Not a cache file, or written by another format version
+    ChecksumMismatch,        // The content was changed or cut off
+    Malformed(String),       // The checksum is fine but the JSON isn't
+    Unverified(VerifyError), // The rules aren't signed by a pinned key
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheError::Io(e) => write!(f, "I/O error: {}", e),
+            CacheError::BadHeader => write!(f, "Unknown cache format"),
+            CacheError::ChecksumMismatch => write!(f, "Checksum mismatch"),
+            CacheError::Malformed(msg) => write!(f, "Malformed cache: {}", msg),
+            CacheError::Unverified(e) => write!(f, "Untrusted cache: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CacheError {}
+
+// The rule set plus where the sync left off, so a delta sync can resume
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedBlocklist {
+    pub blocklist: SignedBlocklist, // Rules as signed by the backend
+    pub etag: Option<String>,       // ETag of the last response
+}
+
+impl CachedBlocklist {
+    pub fn cursor(&self) -> SyncCursor {
+        SyncCursor {
+            version: Some(self.blocklist.version),
+            etag: self.etag.clone(),
+        }
+    }
+}
+
+// The highest rule set version accepted for a child. Only goes up, unless the
+// device is paired with another child.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionFloor {
+    pub child_id: i32,
+    pub version: u64,
+}
+
+impl VersionFloor {
+    pub fn load(path: &Path, protector: &dyn KeyProtector) -> io::Result<VersionFloor> {
+        let data = read_sealed(path, protector)?;
+        serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save(&self, path: &Path, protector: &dyn KeyProtector) -> io::Result<()> {
+        let data = serde_json::to_vec(self).map_err(io::Error::other)?;
+        write_sealed(path, protector, &data)
+    }
+
+    // Refuses a rule set for the same child older than the floor
+    pub fn check(&self, blocklist: &SignedBlocklist) -> Result<(), VerifyError> {
+        if blocklist.child_id == self.child_id && blocklist.version < self.version {
+            return Err(VerifyError::Rollback {
+                current: self.version,
+                received: blocklist.version,
+            });
+        }
+        Ok(())
+    }
+
+    // Moves the floor up to an accepted rule set. Returns whether it changed (and
+    // needs saving).
+    pub fn raise(&mut self, blocklist: &SignedBlocklist) -> bool {
+        if blocklist.child_id == self.child_id && blocklist.version <= self.version {
+            return false;
+        }
+        *self = VersionFloor {
+            child_id: blocklist.child_id,
+            version: blocklist.version,
+        };
+        true
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Serializes the cache into the file layout described above
+pub fn encode(cache: &CachedBlocklist) -> Result<Vec<u8>, CacheError> {
+    let body = serde_json::to_vec(cache).map_err(|e| CacheError::Malformed(e.to_string()))?;
+
+    let mut data = format!("{}\nsha256:{}\n", CACHE_MAGIC, to_hex(&sha256(&body))).into_bytes();
+    data.extend_from_slice(&body);
+    Ok(data)
+}
+
+// Checks and parses a cache file's content
+pub fn decode(data: &[u8]) -> Result<CachedBlocklist, CacheError> {
+    let mut parts = data.splitn(3, |&b| b == b'\n');
+    let (magic, checksum, body) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(magic), Some(checksum), Some(body)) => (magic, checksum, body),
+        _ => return Err(CacheError::BadHeader),
+    };
+
+    if magic != CACHE_MAGIC.as_bytes() {
+        return Err(CacheError::BadHeader);
+    }
+    let expected = checksum
+        .strip_prefix(b"sha256:")
+        .ok_or(CacheError::BadHeader)?;
+    if expected != to_hex(&sha256(body)).as_bytes() {
+        return Err(CacheError::ChecksumMismatch);
+    }
+
+    serde_json::from_slice(body).map_err(|e| CacheError::Malformed(e.to_string()))
+}
+
+// Reads the cache at startup and checks the signature on the rules, and that they
+// aren't older than `floor`. Blocking, but only runs once before the proxy starts.
+pub fn load(
+    path: &Path,
+    keys: &KeySet,
+    floor: &VersionFloor,
+) -> Result<CachedBlocklist, CacheError> {
+    let data = std::fs::read(path).map_err(CacheError::Io)?;
+    let cached = decode(&data)?;
+    cached
+        .blocklist
+        .verify(keys)
+        .and_then(|()| floor.check(&cached.blocklist))
+        .map_err(CacheError::Unverified)?;
+    Ok(cached)
+}
+
+// Writes the cache atomically: temporary file, flush to disk, then rename.
+pub async fn save(path: &Path, cache: &CachedBlocklist) -> Result<(), CacheError> {
+    let data = encode(cache)?;
+    let tmp_path = path.with_extension("tmp");
+
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .map_err(CacheError::Io)?;
+    }
+
+    let write = async {
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        file.write_all(&data).await?;
+        file.sync_all().await?; // Make sure the data is on disk before the rename
+        drop(file);
+        tokio::fs::rename(&tmp_path, path).await // Replaces the old cache in one step
+    };
+
+    if let Err(e) = write.await {
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+        return Err(CacheError::Io(e));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::windows::http_service::fetch::BlocklistPayload;
+    use crate::windows::http_service::signature::PayloadSignature;
+    use crate::windows::key_protector::FileKeyProtector;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use openssl::pkey::{PKey, Private};
+    use openssl::sign::Signer;
+
+    // A throwaway signing key and the key set that trusts it
+    // (also used by the tests of the version floor and of access requests)
+    pub fn signing_key() -> (PKey<Private>, KeySet) {
+        let key = PKey::generate_ed25519().unwrap();
+        let public = STANDARD.encode(key.raw_public_key().unwrap());
+        let keys = KeySet::from_raw(&[("test-key", &public)]).unwrap();
+        (key, keys)
+    }
+
+    // A rule set for `child_id` signed the way the backend does
+    pub fn signed(
+        key: &PKey<Private>,
+        child_id: i32,
+        version: u64,
+        rules: &[&str],
+    ) -> SignedBlocklist {
+        let mut blocklist = SignedBlocklist {
+            child_id,
+            version,
+            payload: BlocklistPayload {
+                rules: rules.iter().map(|rule| rule.to_string()).collect(),
+                ..Default::default()
+            },
+            signature: PayloadSignature {
+                key_id: "test-key".to_string(),
+                signature: String::new(),
+            },
+        };
+        let signature = Signer::new_without_digest(key)
+            .unwrap()
+            .sign_oneshot_to_vec(&blocklist.signed_bytes().unwrap())
+            .unwrap();
+        blocklist.signature.signature = STANDARD.encode(signature);
+        blocklist
+    }
+
+    fn cached(blocklist: SignedBlocklist) -> CachedBlocklist {
+        CachedBlocklist {
+            blocklist,
+            etag: Some("\"v3\"".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn saved_and_loaded() {
+        let (key, keys) = signing_key();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state").join("blocklist.cache");
+        let cache = cached(signed(&key, 7, 3, &["example.com"]));
+
+        save(&path, &cache).await.unwrap();
+        save(&path, &cache).await.unwrap(); // Replaces the old file
+        assert_eq!(load(&path, &keys, &VersionFloor::default()).unwrap(), cache);
+        assert_eq!(cache.cursor().version, Some(3));
+
+        let files: Vec<_> = std::fs::read_dir(path.parent().unwrap()).unwrap().collect();
+        assert_eq!(files.len(), 1, "temporary file left behind");
+    }
+
+    #[test]
+    fn damaged_files_are_refused() {
+        let (key, _) = signing_key();
+        let data = encode(&cached(signed(&key, 7, 3, &["example.com"]))).unwrap();
+
+        // Cut off
+        let result = decode(&data[..data.len() - 1]);
+        assert!(
+            matches!(result, Err(CacheError::ChecksumMismatch)),
+            "{:?}",
+            result
+        );
+        // Changed
+        let mut changed = data.clone();
+        let last = changed.len() - 2;
+        changed[last] ^= 0x20;
+        let result = decode(&changed);
+        assert!(
+            matches!(result, Err(CacheError::ChecksumMismatch)),
+            "{:?}",
+            result
+        );
+        // Another format version, or not a cache at all
+        let mut old = data.clone();
+        old[CACHE_MAGIC.len() - 1] = b'1';
+        assert!(matches!(decode(&old), Err(CacheError::BadHeader)));
+        assert!(matches!(decode(b""), Err(CacheError::BadHeader)));
+        assert!(matches!(
+            decode(b"{\"blocklist\":{}}"),
+            Err(CacheError::BadHeader)
+        ));
+        let no_prefix = format!("{}\n{}\n{{}}", CACHE_MAGIC, to_hex(&sha256(b"{}")));
+        assert!(matches!(
+            decode(no_prefix.as_bytes()),
+            Err(CacheError::BadHeader)
+        ));
+        // Checksum fine, JSON isn't
+        let body = b"{\"blocklist\":";
+        let mut broken =
+            format!("{}\nsha256:{}\n", CACHE_MAGIC, to_hex(&sha256(body))).into_bytes();
+        broken.extend_from_slice(body);
+        assert!(matches!(decode(&broken), Err(CacheError::Malformed(_))));
+    }
+
+    #[test]
+    fn edited_rules_are_refused() {
+        let (key, keys) = signing_key();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocklist.cache");
+
+        // Rules changed and the checksum fixed up: only the signature notices
+        let mut cache = cached(signed(&key, 7, 3, &["example.com"]));
+        cache.blocklist.payload.rules.clear();
+        std::fs::write(&path, encode(&cache).unwrap()).unwrap();
+        let result = load(&path, &keys, &VersionFloor::default());
+        assert!(
+            matches!(
+                result,
+                Err(CacheError::Unverified(VerifyError::BadSignature))
+            ),
+            "{:?}",
+            result
+        );
+
+        // Signed by a key the app doesn't pin
+        let (other_key, _) = signing_key();
+        let cache = cached(signed(&other_key, 7, 3, &["example.com"]));
+        std::fs::write(&path, encode(&cache).unwrap()).unwrap();
+        let result = load(
+            &path,
+            &KeySet::from_raw(&[]).unwrap(),
+            &VersionFloor::default(),
+        );
+        assert!(
+            matches!(
+                result,
+                Err(CacheError::Unverified(VerifyError::UnknownKey(_)))
+            ),
+            "{:?}",
+            result
+        );
+        let result = load(&path, &keys, &VersionFloor::default());
+        assert!(
+            matches!(
+                result,
+                Err(CacheError::Unverified(VerifyError::BadSignature))
+            ),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn missing_file_is_first_run() {
+        let (_, keys) = signing_key();
+        let dir = tempfile::tempdir().unwrap();
+        match load(
+            &dir.path().join("blocklist.cache"),
+            &keys,
+            &VersionFloor::default(),
+        ) {
+            Err(CacheError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn older_cache_is_refused() {
+        let (key, keys) = signing_key();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocklist.cache");
+        let floor = VersionFloor {
+            child_id: 7,
+            version: 5,
+        };
+
+        // An old cache copied back over the current one
+        save(&path, &cached(signed(&key, 7, 4, &["example.com"])))
+            .await
+            .unwrap();
+        let result = load(&path, &keys, &floor);
+        assert!(
+            matches!(
+                result,
+                Err(CacheError::Unverified(VerifyError::Rollback {
+                    current: 5,
+                    received: 4
+                }))
+            ),
+            "{:?}",
+            result
+        );
+
+        save(&path, &cached(signed(&key, 7, 5, &["example.com"])))
+            .await
+            .unwrap();
+        assert!(load(&path, &keys, &floor).is_ok());
+        // The floor of another child doesn't apply
+        save(&path, &cached(signed(&key, 8, 1, &[]))).await.unwrap();
+        assert!(load(&path, &keys, &floor).is_ok());
+    }
+
+    #[test]
+    fn floor_only_goes_up() {
+        let (key, _) = signing_key();
+        let mut floor = VersionFloor::default();
+        assert!(floor.raise(&signed(&key, 7, 3, &[])));
+        assert!(!floor.raise(&signed(&key, 7, 3, &[])));
+        assert!(!floor.raise(&signed(&key, 7, 2, &[])));
+        assert!(floor.raise(&signed(&key, 7, 9, &[])));
+        assert_eq!(
+            floor,
+            VersionFloor {
+                child_id: 7,
+                version: 9
+            }
+        );
+
+        // Paired with another child: starts over
+        assert!(floor.raise(&signed(&key, 8, 1, &[])));
+        assert_eq!(
+            floor,
+            VersionFloor {
+                child_id: 8,
+                version: 1
+            }
+        );
+    }
+
+    #[test]
+    fn floor_is_sealed() {
+        let dir = tempfile::tempdir().unwrap();
+        let protector = FileKeyProtector::new(dir.path().join("master.key"));
+        let path = dir.path().join("blocklist.floor");
+        let floor = VersionFloor {
+            child_id: 7,
+            version: 42,
+        };
+
+        floor.save(&path, &protector).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        assert!(!String::from_utf8_lossy(&data).contains("42"));
+        assert_eq!(VersionFloor::load(&path, &protector).unwrap(), floor);
+
+        // Written in the clear instead of sealed
+        std::fs::write(&path, serde_json::to_vec(&floor).unwrap()).unwrap();
+        let error = VersionFloor::load(&path, &protector).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,535 @@
+// ============================================================================
+//  BACKEND CLIENT - AUTHENTICATED REQUESTS TO THE GUARDNEST API
+// ============================================================================
+// Talks to the GuardNest backend on behalf of this device:
+// 1. Reads the device registration (child ID + device token) from disk
+// 2. Sends the token as "Authorization: Bearer <token>" on every request
+// 3. Follows redirects for GET requests (the token is only sent again to the same
+//    origin). POST requests (reports to the dashboard) aren't redirected.
+// 4. Turns every failure into a `BackendError` instead of panicking
+//
+// The base URL can point at a plain "http://127.0.0.1:<port>" server, so the
+// client can be exercised against a local mock server. Plain HTTP is refused
+// for any other host, the device token must never travel unencrypted.
+
+use crate::windows::http_service::signature::VerifyError;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Bytes;
+use hyper::header::{HeaderMap, HeaderName};
+use hyper::{header, Request, Response, StatusCode, Uri};
+use hyper_tls::HttpsConnector;
+use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use hyper_util::rt::TokioExecutor;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs, path::Path, time::Duration};
+use tokio::time::timeout;
+
+// Production backend, used when the device registration doesn't name one
+pub const DEFAULT_API_URL: &str = "https://api.guardnest.app";
+
+// Written when the device is paired with a child in the parental dashboard
+pub const DEVICE_CONFIG_PATH: &str = "C:\\ProgramData\\GuardNest\\device.json";
+
+// Overrides the base URL (e.g. a staging server or a local mock server) in debug
+// builds and tests only. A release build ignores it: anyone who can set an
+// environment variable for the app could otherwise collect the device token and
+// the parent's data on a server of their own.
+const API_URL_ENV: &str = "GUARDNEST_API_URL";
+
+// Limits for a single request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(20);
+const MAX_REDIRECTS: usize = 5;
+const MAX_BODY_SIZE: usize = 32 * 1024 * 1024; // Category feeds can be large
+
+#[derive(Debug)]
+pub enum BackendError {
+    NotRegistered(String),    // No usable device registration on disk
+    InvalidUrl(String),       // Base URL or redirect target can't be used
+    Network(String),          // Connection, TLS or read failure
+    Timeout,                  // No complete response within `REQUEST_TIMEOUT`
+    Unauthorized,             // 401/403: the device token was rejected
+    Server(u16),              // 5xx: the backend failed
+    UnexpectedStatus(u16),    // Any other non-success status
+    TooManyRedirects,         // More than `MAX_REDIRECTS` hops
+    MalformedPayload(String), // The body isn't what we expected
+    Unverified(VerifyError),  // The rules or an access grant failed the signature checks
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::NotRegistered(msg) => write!(f, "Device not registered: {}", msg),
+            BackendError::InvalidUrl(msg) => write!(f, "Invalid URL: {}", msg),
+            BackendError::Network(msg) => write!(f, "Network error: {}", msg),
+            BackendError::Timeout => write!(f, "Request timed out"),
+            BackendError::Unauthorized => write!(f, "Device token rejected by the server"),
+            BackendError::Server(status) => write!(f, "Server error (HTTP {})", status),
+            BackendError::UnexpectedStatus(status) => {
+                write!(f, "Unexpected response (HTTP {})", status)
+            }
+            BackendError::TooManyRedirects => write!(f, "Too many redirects"),
+            BackendError::MalformedPayload(msg) => write!(f, "Malformed payload: {}", msg),
+            BackendError::Unverified(e) => write!(f, "Blocklist rejected: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+// The device registration saved at `DEVICE_CONFIG_PATH`
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceConfig {
+    #[serde(default)]
+    pub api_url: Option<String>, // Defaults to `DEFAULT_API_URL`
+    pub child_id: i32,        // `children.child_id` this device filters for
+    pub device_token: String, // Secret issued when the device was paired
+}
+
+impl DeviceConfig {
+    pub fn load(path: &Path) -> Result<DeviceConfig, BackendError> {
+        let data = fs::read(path)
+            .map_err(|e| BackendError::NotRegistered(format!("{}: {}", path.display(), e)))?;
+        serde_json::from_slice(&data)
+            .map_err(|e| BackendError::NotRegistered(format!("{}: {}", path.display(), e)))
+    }
+}
+
+// The final (non-redirect) response to a request
+#[derive(Debug)]
+pub struct BackendResponse {
+    pub status: StatusCode, // 2xx, or 304 for a conditional request
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+pub struct BackendClient {
+    base_url: String, // Without a trailing slash, e.g. "https://api.guardnest.app"
+    child_id: i32,
+    device_token: String,
+    http: Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+}
+
+impl BackendClient {
+    pub fn new(config: DeviceConfig) -> Result<BackendClient, BackendError> {
+        // Step 1: Work out the base URL (environment in debug builds, then
+        // registration, then default)
+        let from_env = if cfg!(any(test, debug_assertions)) {
+            std::env::var(API_URL_ENV).ok()
+        } else {
+            None
+        };
+        let base_url = from_env
+            .or(config.api_url)
+            .unwrap_or_else(|| DEFAULT_API_URL.to_string());
+        let base_url = base_url.trim_end_matches('/').to_string();
+        let parsed: Uri = base_url
+            .parse()
+            .map_err(|e| BackendError::InvalidUrl(format!("{}: {}", base_url, e)))?;
+        check_scheme(&parsed)?;
+
+        // Step 2: HTTPS-capable client (plain HTTP is still allowed for loopback)
+        let http = Client::builder(TokioExecutor::new()).build(HttpsConnector::new());
+
+        Ok(BackendClient {
+            base_url,
+            child_id: config.child_id,
+            device_token: config.device_token,
+            http,
+        })
+    }
+
+    // Creates a client from the registration saved at `DEVICE_CONFIG_PATH`
+    pub fn from_device_config() -> Result<BackendClient, BackendError> {
+        Self::new(DeviceConfig::load(Path::new(DEVICE_CONFIG_PATH))?)
+    }
+
+    pub fn child_id(&self) -> i32 {
+        self.child_id
+    }
+
+    // Builds the absolute URL for an API path such as "/api/children/1/blocklist"
+    fn url(&self, path: &str) -> Result<Uri, BackendError> {
+        let url = format!("{}/{}", self.base_url, path.trim_start_matches('/'));
+        url.parse()
+            .map_err(|e| BackendError::InvalidUrl(format!("{}: {}", url, e)))
+    }
+
+    // Sends a GET request with extra headers (e.g. "If-None-Match") and returns the
+    // final (non-redirect) response. 304 Not Modified counts as a success.
+    pub async fn get_with_headers(
+        &self,
+        path: &str,
+        headers: &[(HeaderName, &str)],
+    ) -> Result<BackendResponse, BackendError> {
+        let mut url = self.url(path)?;
+        let mut send_token = true;
+
+        for _ in 0..=MAX_REDIRECTS {
+            let mut request = Request::get(url.clone()).header(header::ACCEPT, "application/json");
+            for (name, value) in headers {
+                request = request.header(name, *value);
+            }
+            if send_token {
+                request = request.header(
+                    header::AUTHORIZATION,
+                    format!("Bearer {}", self.device_token),
+                );
+            }
+            let request = request
+                .body(Full::default())
+                .map_err(|e| BackendError::InvalidUrl(e.to_string()))?;
+
+            let response = match timeout(REQUEST_TIMEOUT, self.http.request(request)).await {
+                Ok(Ok(response)) => response,
+                Ok(Err(e)) => return Err(BackendError::Network(e.to_string())),
+                Err(_) => return Err(BackendError::Timeout),
+            };
+            let status = response.status();
+
+            // Redirect: continue with the Location header
+            if status.is_redirection() && status != StatusCode::NOT_MODIFIED {
+                let location = response
+                    .headers()
+                    .get(header::LOCATION)
+                    .and_then(|value| value.to_str().ok())
+                    .ok_or(BackendError::UnexpectedStatus(status.as_u16()))?;
+                let next = resolve_redirect(&url, location)?;
+
+                // The token only goes back to the origin it was issued for
+                send_token = send_token && same_origin(&url, &next);
+                url = next;
+                continue;
+            }
+
+            check_status(status)?;
+            return read_response(response).await;
+        }
+
+        Err(BackendError::TooManyRedirects)
+    }
+
+    // Sends `body` as JSON in a POST request, e.g. a report for the parent dashboard.
+    // Redirects aren't followed, the API doesn't redirect writes.
+    pub async fn post_json<T: Serialize>(
+        &self,
+        path: &str,
+        body: &T,
+    ) -> Result<BackendResponse, BackendError> {
+        let body =
+            serde_json::to_vec(body).map_err(|e| BackendError::MalformedPayload(e.to_string()))?;
+        let request = Request::post(self.url(path)?)
+            .header(header::ACCEPT, "application/json")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(
+                header::AUTHORIZATION,
+                format!("Bearer {}", self.device_token),
+            )
+            .body(Full::new(Bytes::from(body)))
+            .map_err(|e| BackendError::InvalidUrl(e.to_string()))?;
+
+        let response = match timeout(REQUEST_TIMEOUT, self.http.request(request)).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => return Err(BackendError::Network(e.to_string())),
+            Err(_) => return Err(BackendError::Timeout),
+        };
+        check_status(response.status())?;
+        read_response(response).await
+    }
+
+    // Sends a GET request and returns the body of the final (non-redirect) response.
+    pub async fn get(&self, path: &str) -> Result<Bytes, BackendError> {
+        Ok(self.get_with_headers(path, &[]).await?.body)
+    }
+
+    // Sends a GET request and parses the JSON body into `T`.
+    pub async fn get_json<T: DeserializeOwned>(&self, path: &str) -> Result<T, BackendError> {
+        let body = self.get(path).await?;
+        serde_json::from_slice(&body).map_err(|e| BackendError::MalformedPayload(e.to_string()))
+    }
+}
+
+// Turns a final (non-redirect) status into the matching error
+fn check_status(status: StatusCode) -> Result<(), BackendError> {
+    match status.as_u16() {
+        200..=299 | 304 => Ok(()),
+        401 | 403 => Err(BackendError::Unauthorized),
+        500..=599 => Err(BackendError::Server(status.as_u16())),
+        other => Err(BackendError::UnexpectedStatus(other)),
+    }
+}
+
+// Reads the body, refusing anything larger than `MAX_BODY_SIZE`
+async fn read_response(
+    response: Response<hyper::body::Incoming>,
+) -> Result<BackendResponse, BackendError> {
+    let status = response.status();
+    let (parts, body) = response.into_parts();
+    let body = Limited::new(body, MAX_BODY_SIZE);
+    let body = match timeout(REQUEST_TIMEOUT, body.collect()).await {
+        Ok(Ok(collected)) => collected.to_bytes(),
+        Ok(Err(e)) => return Err(BackendError::Network(e.to_string())),
+        Err(_) => return Err(BackendError::Timeout),
+    };
+
+    Ok(BackendResponse {
+        status,
+        headers: parts.headers,
+        body,
+    })
+}
+
+// Only HTTPS is allowed, except for loopback servers used during development and tests
+fn check_scheme(url: &Uri) -> Result<(), BackendError> {
+    match url.scheme_str() {
+        Some("https") => Ok(()),
+        Some("http") if is_loopback(url.host().unwrap_or("")) => Ok(()),
+        _ => Err(BackendError::InvalidUrl(format!(
+            "{} (HTTPS is required)",
+            url
+        ))),
+    }
+}
+
+fn is_loopback(host: &str) -> bool {
+    matches!(host, "localhost" | "127.0.0.1" | "[::1]" | "::1")
+}
+
+fn same_origin(a: &Uri, b: &Uri) -> bool {
+    a.scheme() == b.scheme() && a.authority() == b.authority()
+}
+
+// Resolves a Location header: an absolute URL, or a path on the same server
+fn resolve_redirect(current: &Uri, location: &str) -> Result<Uri, BackendError> {
+    let next: Uri = if location.starts_with('/') && !location.starts_with("//") {
+        let authority = current.authority().map(|a| a.as_str()).unwrap_or("");
+        let scheme = current.scheme_str().unwrap_or("https");
+        format!("{}://{}{}", scheme, authority, location).parse()
+    } else {
+        location.parse()
+    }
+    .map_err(|e| BackendError::InvalidUrl(format!("{}: {}", location, e)))?;
+
+    if next.scheme().is_none() || next.authority().is_none() {
+        return Err(BackendError::InvalidUrl(format!(
+            "unsupported redirect: {}",
+            location
+        )));
+    }
+    // Never follow a redirect from HTTPS down to plain HTTP
+    if current.scheme_str() == Some("https") && next.scheme_str() != Some("https") {
+        return Err(BackendError::InvalidUrl(format!(
+            "redirect to insecure URL: {}",
+            location
+        )));
+    }
+    check_scheme(&next)?;
+    Ok(next)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use hyper::body::Incoming;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper_util::rt::TokioIo;
+    use std::convert::Infallible;
+    use std::sync::{Arc, OnceLock};
+    use tokio::net::TcpListener;
+
+    // A backend on a free local port that answers every request with `route`
+    // (given the request and the port). Runs on its own thread, so it outlives the
+    // runtime of the test that started it. Returns the port.
+    // (also used by the tests of the blocklist sync)
+    pub fn mock_backend<F>(route: F) -> u16
+    where
+        F: Fn(Request<Incoming>, u16) -> Response<Full<Bytes>> + Send + Sync + 'static,
+    {
+        let route = Arc::new(route);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async move {
+                let listener = TcpListener::from_std(listener).unwrap();
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let route = route.clone();
+                    tokio::spawn(async move {
+                        let service = service_fn(move |request| {
+                            let response = route(request, port);
+                            async move { Ok::<_, Infallible>(response) }
+                        });
+                        let _ = http1::Builder::new()
+                            .serve_connection(TokioIo::new(stream), service)
+                            .await;
+                    });
+                }
+            });
+        });
+        port
+    }
+
+    // A client for `child_id` that talks to the mock backend on `port`. Goes
+    // through the registration's `api_url`: `GUARDNEST_API_URL` would point every
+    // client of the test run at the same server.
+    pub fn client_for(port: u16, child_id: i32) -> BackendClient {
+        BackendClient::new(DeviceConfig {
+            api_url: Some(format!("http://127.0.0.1:{}/", port)),
+            child_id,
+            device_token: "secret-token".to_string(),
+        })
+        .unwrap()
+    }
+
+    pub fn reply(status: u16, location: Option<String>, body: String) -> Response<Full<Bytes>> {
+        let mut response = Response::builder().status(status);
+        if let Some(location) = location {
+            response = response.header(header::LOCATION, location);
+        }
+        response.body(Full::new(Bytes::from(body))).unwrap()
+    }
+
+    // Answers by path
+    fn route(request: Request<Incoming>, port: u16) -> Response<Full<Bytes>> {
+        let path = request.uri().path().to_string();
+        match path.as_str() {
+            "/ok" => reply(200, None, r#"{"value": 1}"#.to_string()),
+            "/unauthorized" => reply(401, None, String::new()),
+            "/forbidden" => reply(403, None, String::new()),
+            "/broken" => reply(503, None, String::new()),
+            "/missing" => reply(404, None, String::new()),
+            "/malformed" => reply(200, None, "{ not json".to_string()),
+            // The Authorization header the server got
+            "/token" => {
+                let token = request
+                    .headers()
+                    .get(header::AUTHORIZATION)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or("none");
+                reply(200, None, token.to_string())
+            }
+            "/same-origin" => reply(302, Some("/token".to_string()), String::new()),
+            // Same server under another name, so another origin
+            "/other-origin" => reply(
+                302,
+                Some(format!("http://localhost:{}/token", port)),
+                String::new(),
+            ),
+            "/no-location" => reply(302, None, String::new()),
+            // "/hops/3" redirects to "/hops/2" and so on, "/hops/0" is the target
+            _ => match path.strip_prefix("/hops/").map(str::parse::<usize>) {
+                Some(Ok(0)) => reply(200, None, "arrived".to_string()),
+                Some(Ok(hops)) => reply(301, Some(format!("/hops/{}", hops - 1)), String::new()),
+                _ => reply(404, None, String::new()),
+            },
+        }
+    }
+
+    // A client of the mock backend above, shared by the tests of this file
+    fn client() -> BackendClient {
+        static PORT: OnceLock<u16> = OnceLock::new();
+        client_for(*PORT.get_or_init(|| mock_backend(route)), 7)
+    }
+
+    #[tokio::test]
+    async fn reads_json() {
+        #[derive(Deserialize)]
+        struct Value {
+            value: u32,
+        }
+        let value: Value = client().get_json("/ok").await.unwrap();
+        assert_eq!(value.value, 1);
+    }
+
+    #[tokio::test]
+    async fn statuses_become_errors() {
+        let client = client();
+        for path in ["/unauthorized", "/forbidden"] {
+            let result = client.get(path).await;
+            assert!(
+                matches!(result, Err(BackendError::Unauthorized)),
+                "{:?}",
+                result
+            );
+        }
+        let result = client.get("/broken").await;
+        assert!(
+            matches!(result, Err(BackendError::Server(503))),
+            "{:?}",
+            result
+        );
+        let result = client.get("/missing").await;
+        assert!(
+            matches!(result, Err(BackendError::UnexpectedStatus(404))),
+            "{:?}",
+            result
+        );
+        let result = client.post_json("/broken", &[1, 2]).await;
+        assert!(
+            matches!(result, Err(BackendError::Server(503))),
+            "{:?}",
+            result
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_json() {
+        let result = client().get_json::<Vec<String>>("/malformed").await;
+        assert!(
+            matches!(result, Err(BackendError::MalformedPayload(_))),
+            "{:?}",
+            result
+        );
+    }
+
+    #[tokio::test]
+    async fn redirect_limit() {
+        let client = client();
+        let body = client
+            .get(&format!("/hops/{}", MAX_REDIRECTS))
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"arrived");
+
+        let result = client.get(&format!("/hops/{}", MAX_REDIRECTS + 1)).await;
+        assert!(
+            matches!(result, Err(BackendError::TooManyRedirects)),
+            "{:?}",
+            result
+        );
+
+        let result = client.get("/no-location").await;
+        assert!(
+            matches!(result, Err(BackendError::UnexpectedStatus(302))),
+            "{:?}",
+            result
+        );
+    }
+
+    #[tokio::test]
+    async fn token_stays_with_its_origin() {
+        let client = client();
+        let body = client.get("/token").await.unwrap();
+        assert_eq!(&body[..], b"Bearer secret-token");
+        let body = client.get("/same-origin").await.unwrap();
+        assert_eq!(&body[..], b"Bearer secret-token");
+        let body = client.get("/other-origin").await.unwrap();
+        assert_eq!(&body[..], b"none");
+    }
+
+    #[test]
+    fn plain_http_only_for_loopback() {
+        assert!(check_scheme(&"https://api.guardnest.app".parse().unwrap()).is_ok());
+        assert!(check_scheme(&"http://localhost:8080".parse().unwrap()).is_ok());
+        assert!(check_scheme(&"http://api.guardnest.app".parse().unwrap()).is_err());
+
+        let current: Uri = "https://api.guardnest.app/a".parse().unwrap();
+        assert!(resolve_redirect(&current, "http://api.guardnest.app/b").is_err());
+        assert!(resolve_redirect(&current, "//evil.example/b").is_err());
+        let next = resolve_redirect(&current, "/b?x=1").unwrap();
+        assert_eq!(next.to_string(), "https://api.guardnest.app/b?x=1");
+    }
+}
@@ -1,8 +1,407 @@
-use std::collections::HashSet;
+use crate::windows::http_service::client::{BackendClient, BackendError};
+use crate::windows::http_service::signature::{KeySet, PayloadSignature, SignedBlocklist};
+use crate::windows::schedule::{Schedule, ScheduleTimeZone};
+use chrono::NaiveTime;
+use hyper::{header, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 
-pub fn proxy_fetch_blocklist() -> HashSet<String> {
-    // Simulate API call
-    vec!["example.com".to_string(), "httpforever.com".to_string()]
-        .into_iter()
-        .collect()
+// Rules that only apply while their schedule is active,
+// e.g. "games" blocked on school nights from 19:00 to 07:00
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RuleGroup {
+    pub name: String,
+    pub rules: Vec<String>,
+    pub schedule: Schedule,
+}
+
+// A daily screen-time budget for a category of sites,
+// e.g. "video" (youtube.com, twitch.tv) up to 60 minutes a day
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimeBudget {
+    pub category: String,
+    pub rules: Vec<String>,
+    pub daily_minutes: u32,
+}
+
+// Everything the proxy needs to filter for this child
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct BlocklistPayload {
+    pub rules: BTreeSet<String>, // Always active (sorted, so the signed JSON is canonical)
+    #[serde(default)]
+    pub groups: Vec<RuleGroup>, // Active only during their schedule
+    #[serde(default)]
+    pub time_zone: ScheduleTimeZone, // Time zone the schedules are written in
+    #[serde(default)]
+    pub budgets: Vec<TimeBudget>, // Daily screen-time budgets
+    #[serde(default)]
+    pub budget_reset: NaiveTime, // Local time the budgets start over (default midnight)
+}
+
+// What the server sends back for a sync request. Both kinds are signed over the
+// complete rule set they result in (see `signature.rs`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BlocklistUpdate {
+    // The complete rule set: first sync, or the server can't build a delta
+    Full {
+        child_id: i32,
+        version: u64,
+        payload: BlocklistPayload,
+        signature: PayloadSignature,
+    },
+    // Always-on rules added and removed since `base_version`
+    Delta {
+        child_id: i32,
+        version: u64,
+        base_version: u64,
+        #[serde(default)]
+        added: Vec<String>,
+        #[serde(default)]
+        removed: Vec<String>,
+        signature: PayloadSignature,
+    },
+}
+
+// Result of a sync, after the new rules have been verified
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyncOutcome {
+    // Nothing changed since the last sync (HTTP 304, no body)
+    NotModified,
+    // A complete new rule set
+    Full(SignedBlocklist),
+    // The rule set after a delta, plus the always-on rules it added and removed,
+    // so the policy can be patched instead of rebuilt
+    Delta {
+        blocklist: SignedBlocklist,
+        added: Vec<String>,
+        removed: Vec<String>,
+    },
+}
+
+// Where the last successful sync left off
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SyncCursor {
+    pub version: Option<u64>, // Rule set version we have, sent as "?since="
+    pub etag: Option<String>, // Sent as "If-None-Match"
+}
+
+impl SyncCursor {
+    // Forgets the cursor, so the next sync downloads the full rule set
+    pub fn reset(&mut self) {
+        *self = SyncCursor::default();
+    }
+}
+
+// Fetches the changes to this child's rules since the last sync.
+// The rules come from the `blocked_websites` table, keyed by `child_id`.
+//
+// 1. "If-None-Match" with the last ETag: the server answers 304 if nothing changed
+// 2. "?since=<version>": the server answers with the rules added and removed since
+//    that version, or with the full set if it no longer has that version
+// 3. The resulting rule set must be signed by a pinned key, issued for this child
+//    and not older than `current`, otherwise nothing changes
+// 4. On success the cursor moves to the new version and ETag
+pub async fn proxy_fetch_blocklist(
+    client: &BackendClient,
+    keys: &KeySet,
+    cursor: &mut SyncCursor,
+    current: Option<&SignedBlocklist>,
+) -> Result<SyncOutcome, BackendError> {
+    let mut path = format!("/api/children/{}/blocklist", client.child_id());
+    if let Some(version) = cursor.version {
+        path.push_str(&format!("?since={}", version));
+    }
+
+    let mut headers = Vec::new();
+    if let Some(etag) = &cursor.etag {
+        headers.push((header::IF_NONE_MATCH, etag.as_str()));
+    }
+
+    let response = client.get_with_headers(&path, &headers).await?;
+    if response.status == StatusCode::NOT_MODIFIED {
+        return Ok(SyncOutcome::NotModified);
+    }
+
+    let update: BlocklistUpdate = serde_json::from_slice(&response.body)
+        .map_err(|e| BackendError::MalformedPayload(e.to_string()))?;
+
+    let outcome = match update {
+        BlocklistUpdate::Full {
+            child_id,
+            version,
+            payload,
+            signature,
+        } => SyncOutcome::Full(SignedBlocklist {
+            child_id,
+            version,
+            payload,
+            signature,
+        }),
+        BlocklistUpdate::Delta {
+            child_id,
+            version,
+            base_version,
+            added,
+            removed,
+            signature,
+        } => {
+            // A delta only makes sense on top of the version we have
+            let Some(base) = current
+                .filter(|current| current.child_id == child_id && current.version == base_version)
+            else {
+                let error = BackendError::MalformedPayload(format!(
+                    "delta from version {} does not apply to {:?}",
+                    base_version,
+                    current.map(|current| current.version)
+                ));
+                cursor.reset(); // Start over with a full download
+                return Err(error);
+            };
+
+            let mut payload = base.payload.clone();
+            for rule in &removed {
+                payload.rules.remove(rule);
+            }
+            payload.rules.extend(added.iter().cloned());
+
+            SyncOutcome::Delta {
+                blocklist: SignedBlocklist {
+                    child_id,
+                    version,
+                    payload,
+                    signature,
+                },
+                added,
+                removed,
+            }
+        }
+    };
+
+    let blocklist = match &outcome {
+        SyncOutcome::Full(blocklist) | SyncOutcome::Delta { blocklist, .. } => blocklist,
+        SyncOutcome::NotModified => return Ok(outcome),
+    };
+    blocklist
+        .check_update(keys, client.child_id(), current)
+        .map_err(BackendError::Unverified)?;
+
+    cursor.version = Some(blocklist.version);
+    cursor.etag = response
+        .headers
+        .get(header::ETAG)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::windows::domain_matcher::DomainMatcher;
+    use crate::windows::http_service::cache::tests::{signed, signing_key};
+    use crate::windows::http_service::client::tests::{client_for, mock_backend, reply};
+    use crate::windows::http_service::signature::VerifyError;
+    use http_body_util::Full;
+    use hyper::body::Bytes;
+    use hyper::Response;
+
+    const V1: [&str; 3] = ["games.com", "||games.com", "news.org"];
+    const V2: [&str; 3] = ["||games.com", "news.org", "video.net"];
+
+    fn full(blocklist: &SignedBlocklist) -> String {
+        serde_json::to_string(&BlocklistUpdate::Full {
+            child_id: blocklist.child_id,
+            version: blocklist.version,
+            payload: blocklist.payload.clone(),
+            signature: blocklist.signature.clone(),
+        })
+        .unwrap()
+    }
+
+    // Version 2 as a delta from `base_version`: "games.com" goes, but "||games.com"
+    // (the same rule) stays
+    fn delta(v2: &SignedBlocklist, base_version: u64, added: &[&str]) -> String {
+        serde_json::to_string(&BlocklistUpdate::Delta {
+            child_id: v2.child_id,
+            version: v2.version,
+            base_version,
+            added: added.iter().map(|rule| rule.to_string()).collect(),
+            removed: vec!["games.com".to_string()],
+            signature: v2.signature.clone(),
+        })
+        .unwrap()
+    }
+
+    fn with_etag(body: String, etag: &str) -> Response<Full<Bytes>> {
+        let mut response = reply(200, None, body);
+        response
+            .headers_mut()
+            .insert(header::ETAG, etag.parse().unwrap());
+        response
+    }
+
+    #[tokio::test]
+    async fn not_modified_with_the_etag() {
+        let (key, keys) = signing_key();
+        let v2 = signed(&key, 7, 2, &V2);
+        let body = full(&v2);
+        let port = mock_backend(move |request, _| {
+            let etag = request
+                .headers()
+                .get(header::IF_NONE_MATCH)
+                .and_then(|value| value.to_str().ok());
+            match (request.uri().query(), etag) {
+                (None, None) => with_etag(body.clone(), "\"v2\""),
+                (Some("since=2"), Some("\"v2\"")) => reply(304, None, String::new()),
+                _ => reply(400, None, String::new()),
+            }
+        });
+        let client = client_for(port, 7);
+
+        let mut cursor = SyncCursor::default();
+        let outcome = proxy_fetch_blocklist(&client, &keys, &mut cursor, None)
+            .await
+            .unwrap();
+        assert_eq!(outcome, SyncOutcome::Full(v2.clone()));
+        assert_eq!(cursor.version, Some(2));
+        assert_eq!(cursor.etag.as_deref(), Some("\"v2\""));
+
+        let outcome = proxy_fetch_blocklist(&client, &keys, &mut cursor, Some(&v2))
+            .await
+            .unwrap();
+        assert_eq!(outcome, SyncOutcome::NotModified);
+        assert_eq!(cursor.version, Some(2));
+    }
+
+    #[tokio::test]
+    async fn deltas_apply_to_the_current_rules() {
+        let (key, keys) = signing_key();
+        let v1 = signed(&key, 7, 1, &V1);
+        let v2 = signed(&key, 7, 2, &V2);
+        let body = delta(&v2, 1, &["video.net"]);
+        let port = mock_backend(move |request, _| match request.uri().query() {
+            Some("since=1") => with_etag(body.clone(), "\"v2\""),
+            _ => reply(400, None, String::new()),
+        });
+
+        let mut cursor = SyncCursor {
+            version: Some(1),
+            etag: None,
+        };
+        let outcome = proxy_fetch_blocklist(&client_for(port, 7), &keys, &mut cursor, Some(&v1))
+            .await
+            .unwrap();
+        let SyncOutcome::Delta {
+            blocklist,
+            added,
+            removed,
+        } = outcome
+        else {
+            panic!("expected a delta, got {:?}", outcome);
+        };
+        assert_eq!(blocklist, v2);
+        assert_eq!(added, ["video.net"]);
+        assert_eq!(removed, ["games.com"]);
+        assert_eq!(cursor.version, Some(2));
+        assert_eq!(cursor.etag.as_deref(), Some("\"v2\""));
+
+        // The other spelling of the removed rule still blocks
+        assert!(DomainMatcher::from_rules(&blocklist.payload.rules).matches("games.com"));
+    }
+
+    #[tokio::test]
+    async fn delta_on_another_base_resets_the_cursor() {
+        let (key, keys) = signing_key();
+        let v1 = signed(&key, 7, 1, &V1);
+        let v2 = signed(&key, 7, 2, &V2);
+        let body = delta(&v2, 3, &["video.net"]);
+        let port = mock_backend(move |_, _| reply(200, None, body.clone()));
+
+        let mut cursor = SyncCursor {
+            version: Some(1),
+            etag: Some("\"v1\"".to_string()),
+        };
+        let result =
+            proxy_fetch_blocklist(&client_for(port, 7), &keys, &mut cursor, Some(&v1)).await;
+        assert!(
+            matches!(result, Err(BackendError::MalformedPayload(_))),
+            "{:?}",
+            result
+        );
+        assert_eq!(cursor, SyncCursor::default());
+    }
+
+    #[tokio::test]
+    async fn unverified_updates_change_nothing() {
+        let (key, keys) = signing_key();
+        let v1 = signed(&key, 7, 1, &V1);
+        let v2 = signed(&key, 7, 2, &V2);
+        let other_child = signed(&key, 8, 5, &["nothing.org"]);
+        let (old, tampered) = (full(&v1), delta(&v2, 1, &["video.net", "extra.com"]));
+        let other = full(&other_child);
+        // Child 7 gets version 1 in full, or a delta that adds a rule the signature
+        // doesn't cover; child 9 gets the rules of child 8
+        let port = mock_backend(move |request, _| {
+            let path = request.uri().path();
+            match (path, request.uri().query()) {
+                ("/api/children/7/blocklist", Some("since=1")) => {
+                    reply(200, None, tampered.clone())
+                }
+                ("/api/children/7/blocklist", _) => reply(200, None, old.clone()),
+                _ => reply(200, None, other.clone()),
+            }
+        });
+        let check = |result: Result<SyncOutcome, BackendError>,
+                     expected: fn(&VerifyError) -> bool| {
+            match result {
+                Err(BackendError::Unverified(e)) if expected(&e) => {}
+                other => panic!("unexpected {:?}", other),
+            }
+        };
+
+        // A rollback to version 1 while version 2 is in use
+        let start = SyncCursor {
+            version: Some(2),
+            etag: Some("\"v2\"".to_string()),
+        };
+        let mut cursor = SyncCursor::default();
+        let result =
+            proxy_fetch_blocklist(&client_for(port, 7), &keys, &mut cursor, Some(&v2)).await;
+        check(result, |e| {
+            matches!(
+                e,
+                VerifyError::Rollback {
+                    current: 2,
+                    received: 1
+                }
+            )
+        });
+        assert_eq!(cursor, SyncCursor::default());
+
+        // Rules issued for another child
+        let mut cursor = start.clone();
+        let result = proxy_fetch_blocklist(&client_for(port, 9), &keys, &mut cursor, None).await;
+        check(result, |e| {
+            matches!(
+                e,
+                VerifyError::WrongChild {
+                    expected: 9,
+                    received: 8
+                }
+            )
+        });
+        assert_eq!(cursor, start);
+
+        // A delta that doesn't produce the signed rule set
+        let mut cursor = SyncCursor {
+            version: Some(1),
+            etag: None,
+        };
+        let result =
+            proxy_fetch_blocklist(&client_for(port, 7), &keys, &mut cursor, Some(&v1)).await;
+        check(result, |e| matches!(e, VerifyError::BadSignature));
+        assert_eq!(cursor.version, Some(1));
+    }
 }
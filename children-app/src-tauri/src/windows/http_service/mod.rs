@@ -1 +1,7 @@
+pub mod access;
+pub mod backoff;
+pub mod cache;
+pub mod client;
 pub mod fetch;
+pub mod report;
+pub mod signature;
@@ -0,0 +1,99 @@
+// ============================================================================
+//  REPORTS - EVENTS SENT TO THE PARENT DASHBOARD
+// ============================================================================
+// Things the device found out on its own that the parent should see. Reports are
+// kept on the device until the backend has accepted them, so nothing is lost
+// while offline; the updater sends them after each sync.
+//
+// Hosts learned as pinned (see bypass.rs):
+//
+//     POST /api/children/{child_id}/pinned-hosts
+//     {"hosts":[{"host":"api.bank.example","detected_at":"2026-10-17T08:30:00Z"}]}
+//
+// What the proxy did with each request (see activity.rs), without the request
+// headers or the browser's address:
+//
+//     POST /api/children/{child_id}/activity
+//     {"events":[{"at":"2026-10-17T08:30:00Z","method":"GET","host":"games.com","port":443,
+//                 "decision":"blocked","rule":"||games.com","bytes_up":0,"bytes_down":0,
+//                 "duration_ms":3}]}
+
+use crate::windows::activity::{ActivityEvent, Decision};
+use crate::windows::bypass::PinnedHost;
+use crate::windows::http_service::client::{BackendClient, BackendError};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct PinnedHostReport<'a> {
+    hosts: Vec<ReportedHost<'a>>,
+}
+
+#[derive(Serialize)]
+struct ReportedHost<'a> {
+    host: &'a str,
+    detected_at: DateTime<Utc>,
+}
+
+// Tells the dashboard that interception is bypassed for `hosts`
+pub async fn report_pinned_hosts(
+    client: &BackendClient,
+    hosts: &[PinnedHost],
+) -> Result<(), BackendError> {
+    let report = PinnedHostReport {
+        hosts: hosts
+            .iter()
+            .map(|pinned| ReportedHost {
+                host: &pinned.host,
+                detected_at: pinned.detected_at,
+            })
+            .collect(),
+    };
+    let path = format!("/api/children/{}/pinned-hosts", client.child_id());
+    client.post_json(&path, &report).await?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ActivityReport<'a> {
+    events: Vec<ReportedEvent<'a>>,
+}
+
+#[derive(Serialize)]
+struct ReportedEvent<'a> {
+    at: DateTime<Utc>,
+    method: &'a str,
+    host: &'a str,
+    port: u16,
+    decision: Decision,
+    rule: Option<&'a str>,
+    bytes_up: u64,
+    bytes_down: u64,
+    duration_ms: u64,
+}
+
+// Sends recorded activity to the dashboard
+pub async fn report_activity(
+    client: &BackendClient,
+    events: &[ActivityEvent],
+) -> Result<(), BackendError> {
+    let report = ActivityReport {
+        events: events
+            .iter()
+            .map(|event| ReportedEvent {
+                at: event.at,
+                method: event.method(),
+                host: &event.host,
+                port: event.port,
+                decision: event.decision,
+                rule: event.rule.as_deref(),
+                bytes_up: event.bytes_up,
+                bytes_down: event.bytes_down,
+                duration_ms: event.duration_ms,
+            })
+            .collect(),
+    };
+    let path = format!("/api/children/{}/activity", client.child_id());
+    client.post_json(&path, &report).await?;
+    Ok(())
+}
@@ -0,0 +1,384 @@
+// ============================================================================
+//  BLOCKLIST SIGNATURES - ONLY RULES SIGNED BY THE BACKEND ARE TRUSTED
+// ============================================================================
+// The backend signs every rule set it sends with Ed25519. The signature covers
+// the child ID, the version and the complete rule set, so the proxy can tell if:
+// - the rules come from somewhere else (a fake server, an edited cache file)
+// - the rules were issued for another child
+// - an older rule set is being replayed to undo recent changes
+//
+// Deltas are signed over the rule set they produce, not over the change itself:
+// the proxy applies the delta to its copy and checks the result, which is what
+// ends up in the cache and can be checked again after a restart.
+//
+// Signed message:
+//
+//     GUARDNEST-BLOCKLIST-SIGNATURE 1\n{"child_id":7,"version":42,"payload":{...}}
+//
+// The proxy doesn't keep the bytes it received (a delta has none for the full set),
+// it rebuilds the message from the parsed rule set. Both sides must therefore
+// produce the same canonical form:
+// - compact JSON (no whitespace), fields in declaration order
+// - every field present, also when empty or at its default
+// - top-level `rules` sorted by byte value, no duplicates; other lists as sent
+// - times as "HH:MM:SS", days as "Mon".."Sun", the time zone as "local" or
+//   {"utc_offset_minutes":N}
+// Fields this version doesn't know are dropped before the message is built, so
+// they are neither signed nor enforced. A backend that needs a new field must
+// bump the context to version 2, which older apps refuse. The tests hold a
+// reference vector the backend's signer can be checked against.
+
+use crate::logger::ProxyLogger;
+use crate::windows::http_service::fetch::BlocklistPayload;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use openssl::pkey::{Id, PKey, Public};
+use openssl::sign::Verifier;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Public keys of the backend's signing keys, compiled into the app so they can't be
+// swapped on the device. To rotate: add the new key here one release before the
+// backend starts signing with it, and remove the old one once it is retired.
+const PINNED_KEYS: &[(&str, &str)] = &[(
+    "guardnest-blocklist-2026-1",
+    "Gh3CtGR/CdQ81JAVFxhvPxfzlC24VTIT5d+OxzbmHFU=",
+)];
+
+// Put in front of every signed message, so a signature made for something else
+// can never be passed off as a blocklist signature
+const SIGNATURE_CONTEXT: &[u8] = b"GUARDNEST-BLOCKLIST-SIGNATURE 1\n";
+
+#[derive(Debug)]
+pub enum VerifyError {
+    UnknownKey(String), // Signed with a key that isn't pinned (or was retired)
+    BadSignature,       // The signature doesn't match the rules
+    Malformed(String),  // The key or signature can't be decoded
+    Rollback { current: u64, received: u64 }, // Older than the rules we have
+    WrongChild { expected: i32, received: i32 }, // Issued for another child
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::UnknownKey(id) => write!(f, "Unknown signing key \"{}\"", id),
+            VerifyError::BadSignature => write!(f, "Invalid signature"),
+            VerifyError::Malformed(msg) => write!(f, "Malformed signature: {}", msg),
+            VerifyError::Rollback { current, received } => write!(
+                f,
+                "Version {} is older than the current version {}",
+                received, current
+            ),
+            VerifyError::WrongChild { expected, received } => write!(
+                f,
+                "Rules for child {} sent to the device of child {}",
+                received, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+// Signature attached to a rule set by the backend
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PayloadSignature {
+    pub key_id: String,    // Which of the pinned keys signed it
+    pub signature: String, // Base64 of the 64-byte Ed25519 signature
+}
+
+// The public keys signatures are checked against
+pub struct KeySet {
+    keys: Vec<(String, PKey<Public>)>,
+}
+
+impl KeySet {
+    // The keys compiled into the app
+    pub fn pinned() -> Self {
+        KeySet {
+            keys: PINNED_KEYS
+                .iter()
+                .filter_map(|(id, key)| match parse_key(id, key) {
+                    Ok(key) => Some(key),
+                    Err(e) => {
+                        ProxyLogger::log_error("loading pinned signing key", &e);
+                        None
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    // Keys given as (key ID, base64 of the 32-byte public key), e.g. a staging backend's
+    pub fn from_raw(keys: &[(&str, &str)]) -> Result<Self, VerifyError> {
+        Ok(KeySet {
+            keys: keys
+                .iter()
+                .map(|(id, key)| parse_key(id, key))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    // Checks `signature` over `message` with the key it names
+    pub fn verify(&self, message: &[u8], signature: &PayloadSignature) -> Result<(), VerifyError> {
+        let (_, key) = self
+            .keys
+            .iter()
+            .find(|(id, _)| *id == signature.key_id)
+            .ok_or_else(|| VerifyError::UnknownKey(signature.key_id.clone()))?;
+        let signature = STANDARD
+            .decode(&signature.signature)
+            .map_err(|e| VerifyError::Malformed(e.to_string()))?;
+
+        let valid = Verifier::new_without_digest(key)
+            .and_then(|mut verifier| verifier.verify_oneshot(&signature, message))
+            .unwrap_or(false); // Wrong length etc. is just a bad signature
+        if valid {
+            Ok(())
+        } else {
+            Err(VerifyError::BadSignature)
+        }
+    }
+}
+
+fn parse_key(id: &str, key: &str) -> Result<(String, PKey<Public>), VerifyError> {
+    let raw = STANDARD
+        .decode(key)
+        .map_err(|e| VerifyError::Malformed(format!("key {}: {}", id, e)))?;
+    let key = PKey::public_key_from_raw_bytes(&raw, Id::ED25519)
+        .map_err(|e| VerifyError::Malformed(format!("key {}: {}", id, e)))?;
+    Ok((id.to_string(), key))
+}
+
+// The part of a rule set the signature covers, in signing order
+#[derive(Serialize)]
+struct SignedContent<'a> {
+    child_id: i32,
+    version: u64,
+    payload: &'a BlocklistPayload,
+}
+
+// A complete rule set as signed by the backend
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SignedBlocklist {
+    pub child_id: i32, // Child the rules were issued for
+    pub version: u64,  // Rule set version, only ever goes up
+    pub payload: BlocklistPayload,
+    pub signature: PayloadSignature,
+}
+
+impl SignedBlocklist {
+    // The exact bytes the backend signs
+    pub fn signed_bytes(&self) -> Result<Vec<u8>, VerifyError> {
+        let content = SignedContent {
+            child_id: self.child_id,
+            version: self.version,
+            payload: &self.payload,
+        };
+        let mut message = SIGNATURE_CONTEXT.to_vec();
+        serde_json::to_writer(&mut message, &content)
+            .map_err(|e| VerifyError::Malformed(e.to_string()))?;
+        Ok(message)
+    }
+
+    // Checks the signature only. Child binding and rollback depend on what the
+    // device already has, see `check_update`.
+    pub fn verify(&self, keys: &KeySet) -> Result<(), VerifyError> {
+        keys.verify(&self.signed_bytes()?, &self.signature)
+    }
+
+    // Checks a rule set received for `child_id` before it replaces `current`
+    pub fn check_update(
+        &self,
+        keys: &KeySet,
+        child_id: i32,
+        current: Option<&SignedBlocklist>,
+    ) -> Result<(), VerifyError> {
+        // Step 1: Issued for this device's child
+        if self.child_id != child_id {
+            return Err(VerifyError::WrongChild {
+                expected: child_id,
+                received: self.child_id,
+            });
+        }
+
+        // Step 2: Not older than what we have for the same child
+        if let Some(current) = current.filter(|current| current.child_id == child_id) {
+            if self.version < current.version {
+                return Err(VerifyError::Rollback {
+                    current: current.version,
+                    received: self.version,
+                });
+            }
+        }
+
+        // Step 3: Signed by one of the pinned keys
+        self.verify(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::windows::http_service::cache::tests::{signed, signing_key};
+
+    // Reference vector for the canonical form, signed with the Ed25519 test key
+    // from RFC 8032 (section 7.1, test 1). The backend's signer must produce the
+    // same bytes and signature for this rule set.
+    const VECTOR_KEY: &str = "11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=";
+    const VECTOR_SIGNATURE: &str =
+        "+rKki0iSlUh4R5PVokFtG+fkIBAeh8GWpN7E64T10E2JxzDWaFg0zuYIWvNRBpTuVHDD36D9RqYGRF7cxHRPAQ==";
+    const VECTOR_MESSAGE: &str = concat!(
+        "GUARDNEST-BLOCKLIST-SIGNATURE 1\n",
+        r#"{"child_id":7,"version":42,"payload":{"rules":["example.com","||games.com"],"#,
+        r#""groups":[{"name":"school nights","rules":["youtube.com"],"schedule":{"days":["Sun","Mon"],"#,
+        r#""ranges":[{"start":"19:00:00","end":"07:00:00"}]}}],"time_zone":{"utc_offset_minutes":60},"#,
+        r#""budgets":[{"category":"video","rules":["twitch.tv"],"daily_minutes":60}],"#,
+        r#""budget_reset":"04:30:00"}}"#
+    );
+
+    // The rule set of the vector as a server might send it: other field order,
+    // whitespace, unsorted rules
+    fn vector_blocklist() -> SignedBlocklist {
+        serde_json::from_str(&format!(
+            r#"{{
+                "signature": {{ "key_id": "rfc8032", "signature": "{}" }},
+                "payload": {{
+                    "budget_reset": "04:30",
+                    "budgets": [{{ "daily_minutes": 60, "category": "video", "rules": ["twitch.tv"] }}],
+                    "time_zone": {{ "utc_offset_minutes": 60 }},
+                    "groups": [{{
+                        "schedule": {{ "ranges": [{{ "end": "07:00", "start": "19:00" }}], "days": ["Sun", "Mon"] }},
+                        "rules": ["youtube.com"],
+                        "name": "school nights"
+                    }}],
+                    "rules": ["||games.com", "example.com"]
+                }},
+                "version": 42,
+                "child_id": 7
+            }}"#,
+            VECTOR_SIGNATURE
+        ))
+        .unwrap()
+    }
+
+    fn vector_keys() -> KeySet {
+        KeySet::from_raw(&[("rfc8032", VECTOR_KEY)]).unwrap()
+    }
+
+    #[test]
+    fn canonical_form_matches_the_reference_vector() {
+        let blocklist = vector_blocklist();
+        assert_eq!(
+            String::from_utf8(blocklist.signed_bytes().unwrap()).unwrap(),
+            VECTOR_MESSAGE
+        );
+        blocklist.verify(&vector_keys()).unwrap();
+    }
+
+    #[test]
+    fn defaults_are_signed_explicitly() {
+        let blocklist: SignedBlocklist = serde_json::from_str(
+            r#"{"child_id":7,"version":1,"payload":{"rules":[]},"signature":{"key_id":"k","signature":""}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(blocklist.signed_bytes().unwrap()).unwrap(),
+            concat!(
+                "GUARDNEST-BLOCKLIST-SIGNATURE 1\n",
+                r#"{"child_id":7,"version":1,"payload":{"rules":[],"groups":[],"time_zone":"local","#,
+                r#""budgets":[],"budget_reset":"00:00:00"}}"#
+            )
+        );
+    }
+
+    #[test]
+    fn changes_break_the_signature() {
+        let keys = vector_keys();
+        let changes: [fn(&mut SignedBlocklist); 4] = [
+            |blocklist| blocklist.child_id = 8,
+            |blocklist| blocklist.version = 43,
+            |blocklist| {
+                blocklist.payload.rules.remove("||games.com");
+            },
+            |blocklist| blocklist.payload.budgets[0].daily_minutes = 600,
+        ];
+        for change in changes {
+            let mut blocklist = vector_blocklist();
+            change(&mut blocklist);
+            assert!(matches!(
+                blocklist.verify(&keys),
+                Err(VerifyError::BadSignature)
+            ));
+        }
+
+        // A field this version doesn't know is dropped before the message is built,
+        // so it can't change the signed bytes, nor what the proxy enforces
+        let mut value = serde_json::to_value(vector_blocklist()).unwrap();
+        value["payload"]["allow_all"] = true.into();
+        let blocklist: SignedBlocklist = serde_json::from_value(value).unwrap();
+        assert_eq!(blocklist, vector_blocklist());
+        blocklist.verify(&keys).unwrap();
+    }
+
+    #[test]
+    fn keys_and_signatures() {
+        let keys = vector_keys();
+        let mut blocklist = vector_blocklist();
+
+        blocklist.signature.key_id = "retired".to_string();
+        assert!(
+            matches!(blocklist.verify(&keys), Err(VerifyError::UnknownKey(id)) if id == "retired")
+        );
+
+        blocklist.signature.key_id = "rfc8032".to_string();
+        blocklist.signature.signature = "not base64!".to_string();
+        assert!(matches!(
+            blocklist.verify(&keys),
+            Err(VerifyError::Malformed(_))
+        ));
+
+        blocklist.signature.signature = STANDARD.encode([0u8; 12]);
+        assert!(matches!(
+            blocklist.verify(&keys),
+            Err(VerifyError::BadSignature)
+        ));
+
+        assert!(KeySet::from_raw(&[("short", "AAAA")]).is_err());
+        assert_eq!(KeySet::pinned().keys.len(), PINNED_KEYS.len());
+    }
+
+    #[test]
+    fn updates_are_bound_to_the_child_and_never_go_back() {
+        let (key, keys) = signing_key();
+        let current = signed(&key, 7, 5, &["games.com"]);
+
+        let older = signed(&key, 7, 4, &["games.com"]);
+        assert!(matches!(
+            older.check_update(&keys, 7, Some(&current)),
+            Err(VerifyError::Rollback {
+                current: 5,
+                received: 4
+            })
+        ));
+
+        // The same version again is fine, e.g. after a restart
+        current.check_update(&keys, 7, Some(&current)).unwrap();
+        signed(&key, 7, 6, &[])
+            .check_update(&keys, 7, Some(&current))
+            .unwrap();
+
+        // Another child's rules, even if newer
+        assert!(matches!(
+            signed(&key, 8, 9, &[]).check_update(&keys, 7, Some(&current)),
+            Err(VerifyError::WrongChild {
+                expected: 7,
+                received: 8
+            })
+        ));
+
+        // The version of another child's rules (e.g. after re-registering) doesn't count
+        older
+            .check_update(&keys, 7, Some(&signed(&key, 8, 9, &[])))
+            .unwrap();
+    }
+}
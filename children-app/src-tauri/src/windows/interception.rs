@@ -0,0 +1,427 @@
+// ============================================================================
+//  TLS INTERCEPTION - DECRYPTING CONNECT TUNNELS WITH PER-HOST CERTIFICATES
+// ============================================================================
+// Without interception a CONNECT tunnel is a blind pipe: the proxy only sees the
+// host name. With interception enabled:
+// 1. The proxy opens its own TLS connection to the real server and verifies its
+//    certificate like a browser would
+// 2. The browser's TLS session is terminated with a leaf certificate for the host,
+//    signed by the GuardNest Root CA. Leaves are minted on the first visit and
+//    reused from the leaf cache after that (see leaf_cache.rs)
+// 3. The decrypted HTTP requests go through the same checks and forwarding as plain
+//    HTTP, so rules can see the full URL and not only the host
+//
+// The browser only accepts the leaf because the GuardNest Root CA is installed in
+// the stores it trusts (see certificate.rs and trust_store.rs). Hosts that break when intercepted
+// are left alone, see bypass.rs, and so are hosts outside the CA's name
+// constraints, which it can't sign a valid leaf for.
+
+use crate::logger::ProxyLogger;
+use crate::windows::bypass::InterceptBypass;
+use crate::windows::certificate::CertificateAuthority;
+use crate::windows::leaf_cache::{leaf_name, CachedLeaf, LeafCache, DEFAULT_LEAF_CAPACITY};
+use native_tls::Certificate;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream as TokioTcpStream;
+use tokio_native_tls::{TlsAcceptor, TlsConnector, TlsStream};
+
+pub struct Interceptor {
+    ca: Arc<CertificateAuthority>, // Signs the leaf certificates shown to the browser
+    upstream: TlsConnector,        // Verifies the real servers' certificates
+    leaves: Mutex<LeafCache>,      // Leaves already minted, by leaf name
+    bypass: Arc<Mutex<InterceptBypass>>, // Hosts that are never intercepted
+}
+
+impl Interceptor {
+    // Intercepts with `ca`, trusting the system's root certificates for upstream servers
+    pub fn new(ca: CertificateAuthority) -> io::Result<Self> {
+        Self::with_upstream_roots(ca, &[])
+    }
+
+    // Same as `new`, also trusting `roots` upstream (e.g. a local test server's CA)
+    pub fn with_upstream_roots(
+        ca: CertificateAuthority,
+        roots: &[Certificate],
+    ) -> io::Result<Self> {
+        let mut builder = native_tls::TlsConnector::builder();
+        for root in roots {
+            builder.add_root_certificate(root.clone());
+        }
+        let upstream = builder.build().map_err(io::Error::other)?;
+
+        Ok(Interceptor {
+            ca: Arc::new(ca),
+            upstream: TlsConnector::from(upstream),
+            leaves: Mutex::new(LeafCache::new(DEFAULT_LEAF_CAPACITY)),
+            bypass: Arc::default(),
+        })
+    }
+
+    // Uses a bypass list shared with other interceptors, so learned hosts outlive
+    // switching interception off and on
+    pub fn with_bypass(self, bypass: Arc<Mutex<InterceptBypass>>) -> Self {
+        Interceptor { bypass, ..self }
+    }
+
+    // Whether `host` must be tunneled instead of intercepted
+    pub fn bypasses(&self, host: &str) -> bool {
+        !self.ca.permits(host) || self.lock_bypass().bypasses(host)
+    }
+
+    // The browser or app refused the leaf for `host` (e.g. certificate pinning).
+    // The host itself is only shown in the app and on the dashboard, not in the log.
+    pub fn handshake_failed(&self, host: &str) {
+        if self.lock_bypass().handshake_failed(host) {
+            println!("📌 A host refuses intercepted connections, tunneling it for a week");
+        }
+    }
+
+    pub fn handshake_succeeded(&self, host: &str) {
+        self.lock_bypass().handshake_succeeded(host);
+    }
+
+    // Replaces the in-memory leaf cache, e.g. with one saved to disk
+    pub fn with_leaf_cache(self, leaves: LeafCache) -> Self {
+        Interceptor {
+            leaves: Mutex::new(leaves),
+            ..self
+        }
+    }
+
+    // Opens the TLS connection to the real server. Fails if its certificate isn't
+    // valid for `host`, the browser must never see a forged certificate for a server
+    // it couldn't have trusted itself.
+    pub async fn connect_upstream(
+        &self,
+        host: &str,
+        stream: TokioTcpStream,
+    ) -> io::Result<TlsStream<TokioTcpStream>> {
+        self.upstream
+            .connect(host, stream)
+            .await
+            .map_err(io::Error::other)
+    }
+
+    // TLS acceptor presenting a leaf certificate for `host`, from the cache or
+    // minted now. Minting happens outside the lock, two tunnels opening at the same
+    // time for a new site may both mint one and the last one is kept. Key generation
+    // and signing are blocking work, so they run on tokio's blocking pool instead of
+    // stalling every other connection on this worker.
+    pub async fn acceptor_for(&self, host: &str) -> io::Result<TlsAcceptor> {
+        // A wildcard for the parent may fall outside the CA's name constraints
+        // ("*.example.com" when only "www.example.com" is permitted)
+        let name = Some(leaf_name(host))
+            .filter(|name| self.ca.permits(name))
+            .unwrap_or_else(|| host.trim_end_matches('.').to_ascii_lowercase());
+        if let Some(leaf) = self.lock_leaves().get(&name) {
+            return Ok(leaf.acceptor.clone());
+        }
+
+        let (ca, to_mint) = (self.ca.clone(), name.clone());
+        let leaf = tokio::task::spawn_blocking(move || mint(&ca, &to_mint))
+            .await
+            .map_err(io::Error::other)??;
+        let leaf = Arc::new(leaf);
+        self.lock_leaves().insert(&name, leaf.clone());
+        Ok(leaf.acceptor.clone())
+    }
+
+    // Mints the leaves that expire soon again, ahead of the next visit.
+    // Blocking, meant to run on a background thread.
+    pub fn refresh_expiring(&self) {
+        let expiring = self.lock_leaves().expiring();
+        for name in expiring {
+            match mint(&self.ca, &name) {
+                Ok(leaf) => self.lock_leaves().insert(&name, Arc::new(leaf)),
+                Err(e) => ProxyLogger::log_error("refreshing leaf certificate", &e),
+            }
+        }
+    }
+
+    // Saves the leaf cache if it's kept on disk and has changed
+    pub fn save_leaf_cache(&self) {
+        if let Err(e) = self.lock_leaves().save(self.ca.certificate()) {
+            ProxyLogger::log_error("saving leaf certificate cache", &e);
+        }
+    }
+
+    fn lock_leaves(&self) -> MutexGuard<'_, LeafCache> {
+        self.leaves.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock_bypass(&self) -> MutexGuard<'_, InterceptBypass> {
+        self.bypass.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// A leaf for `name` signed by `ca`, ready to present. Blocking.
+fn mint(ca: &CertificateAuthority, name: &str) -> io::Result<CachedLeaf> {
+    let leaf = ca.issue_leaf(name).map_err(io::Error::other)?;
+    CachedLeaf::new(leaf, ca.certificate())
+}
+
+// A stream that first returns bytes that were already read from it
+// (e.g. a TLS ClientHello sent right behind the CONNECT head)
+pub struct PrefixedStream<S> {
+    prefix: Vec<u8>,
+    position: usize, // How much of `prefix` has been read
+    inner: S,
+}
+
+impl<S> PrefixedStream<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+        PrefixedStream {
+            prefix,
+            position: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PrefixedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.position < self.prefix.len() {
+            let remaining = &self.prefix[self.position..];
+            let n = remaining.len().min(buf.remaining());
+            buf.put_slice(&remaining[..n]);
+            self.position += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PrefixedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::x509::extension::{BasicConstraints, KeyUsage, SubjectKeyIdentifier};
+    use openssl::x509::{X509Builder, X509NameBuilder, X509};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // A self-signed CA that only lives for one test (also used by the proxy tests)
+    pub fn throwaway_ca(common_name: &str) -> CertificateAuthority {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, common_name)
+            .unwrap();
+        let name = name.build();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+        builder.set_serial_number(&serial).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(10).unwrap())
+            .unwrap();
+        builder
+            .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+            .unwrap();
+        builder
+            .append_extension(
+                KeyUsage::new()
+                    .critical()
+                    .key_cert_sign()
+                    .crl_sign()
+                    .build()
+                    .unwrap(),
+            )
+            .unwrap();
+        let subject_key_id = SubjectKeyIdentifier::new()
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(subject_key_id).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        CertificateAuthority::new(builder.build(), key)
+    }
+
+    pub fn native_certificate(cert: &X509) -> Certificate {
+        Certificate::from_der(&cert.to_der().unwrap()).unwrap()
+    }
+
+    // A TLS server for "localhost" with a leaf from `ca`. Answers every request on a
+    // connection with "secure <path>". Returns its port.
+    pub async fn tls_origin(ca: &CertificateAuthority) -> u16 {
+        let leaf = ca.issue_leaf("localhost").unwrap();
+        let mut chain = leaf.cert.to_pem().unwrap();
+        chain.extend(ca.certificate().to_pem().unwrap());
+        let key = leaf.key.private_key_to_pem_pkcs8().unwrap();
+        let identity = native_tls::Identity::from_pkcs8(&chain, &key).unwrap();
+        let acceptor = TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(mut stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let mut buf = Vec::new();
+                    loop {
+                        let end = loop {
+                            if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                                break end;
+                            }
+                            let mut chunk = [0u8; 1024];
+                            match stream.read(&mut chunk).await {
+                                Ok(0) | Err(_) => return,
+                                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                            }
+                        };
+                        let head = String::from_utf8_lossy(&buf[..end]).into_owned();
+                        buf.drain(..end + 4);
+                        let path = head.split(' ').nth(1).unwrap_or("");
+                        let body = format!("secure {}", path);
+                        let response = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                            body.len(),
+                            body
+                        );
+                        if stream.write_all(response.as_bytes()).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    // TLS client trusting only `root`, like a browser with the GuardNest CA installed
+    pub fn browser(root: &X509) -> TlsConnector {
+        let connector = native_tls::TlsConnector::builder()
+            .disable_built_in_roots(true)
+            .add_root_certificate(native_certificate(root))
+            .build()
+            .unwrap();
+        TlsConnector::from(connector)
+    }
+
+    #[tokio::test]
+    async fn upstream_is_verified() {
+        let origin_ca = throwaway_ca("Origin Test CA");
+        let port = tls_origin(&origin_ca).await;
+
+        let interceptor = Interceptor::with_upstream_roots(
+            throwaway_ca("GuardNest Test CA"),
+            &[native_certificate(origin_ca.certificate())],
+        )
+        .unwrap();
+        let stream = TokioTcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let mut upstream = interceptor
+            .connect_upstream("localhost", stream)
+            .await
+            .unwrap();
+        upstream
+            .write_all(b"GET /a HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = [0u8; 47];
+        upstream.read_exact(&mut response).await.unwrap();
+        assert!(response.ends_with(b"secure /a"));
+
+        // A server the proxy can't trust is never connected through
+        let untrusting = Interceptor::with_upstream_roots(throwaway_ca("Other"), &[]).unwrap();
+        let stream = TokioTcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        assert!(untrusting
+            .connect_upstream("localhost", stream)
+            .await
+            .is_err());
+        // Nor one whose certificate is for another name
+        let stream = TokioTcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        assert!(interceptor
+            .connect_upstream("example.com", stream)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn minted_chain() {
+        let ca = throwaway_ca("GuardNest Test CA");
+        let root = ca.certificate().clone();
+        let interceptor = Interceptor::with_upstream_roots(ca, &[]).unwrap();
+
+        // The browser's side of an intercepted tunnel
+        let acceptor = interceptor.acceptor_for("localhost").await.unwrap();
+        let (browser_side, proxy_side) = tokio::io::duplex(16 * 1024);
+        let server = tokio::spawn(async move { acceptor.accept(proxy_side).await.is_ok() });
+        let tls = browser(&root)
+            .connect("localhost", browser_side)
+            .await
+            .unwrap();
+        assert!(server.await.unwrap());
+
+        // The leaf: for the host only, issued and signed by the CA, not a CA itself
+        let leaf = tls.get_ref().peer_certificate().unwrap().unwrap();
+        let leaf = X509::from_der(&leaf.to_der().unwrap()).unwrap();
+        assert_eq!(
+            leaf.issuer_name().to_der().unwrap(),
+            root.subject_name().to_der().unwrap()
+        );
+        assert!(leaf.verify(&root.public_key().unwrap()).unwrap());
+        let names: Vec<_> = leaf
+            .subject_alt_names()
+            .unwrap()
+            .iter()
+            .filter_map(|name| name.dnsname().map(String::from))
+            .collect();
+        assert_eq!(names, ["localhost"]);
+        assert_eq!(
+            leaf.authority_key_id().unwrap().as_slice(),
+            root.subject_key_id().unwrap().as_slice()
+        );
+
+        // A browser that doesn't trust the CA refuses the leaf
+        let acceptor = interceptor.acceptor_for("localhost").await.unwrap();
+        let (browser_side, proxy_side) = tokio::io::duplex(16 * 1024);
+        tokio::spawn(async move { acceptor.accept(proxy_side).await.is_ok() });
+        let other = throwaway_ca("Other");
+        assert!(browser(other.certificate())
+            .connect("localhost", browser_side)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,484 @@
+// ============================================================================
+//  KEY PROTECTOR - SEALING SECRETS THAT MUST NOT BE READABLE FROM DISK
+// ============================================================================
+// The Root CA key signs certificates every browser on this machine trusts. Anyone
+// who can read it can impersonate any site, so it's never written in the clear:
+// 1. Secrets are sealed by a `KeyProtector` before they're written, and unsealed
+//    after they're read
+// 2. On Windows the OS keystore seals them (DPAPI, machine scope): the blob can't
+//    be opened on another machine, e.g. from a copy of the disk or a backup. On
+//    this machine any account can open it, so point 4 is what keeps it private.
+// 3. Elsewhere they're encrypted (AES-256-GCM) with a random master key kept in a
+//    file only the owner can read
+// 4. Files holding secrets are created with their restricted permissions, there
+//    is no moment they have any others: SYSTEM and Administrators on Windows,
+//    mode 0600 elsewhere. The child's (standard) account can't open them.
+// 5. Files are replaced in one step (temporary file, flushed, renamed), so a crash
+//    leaves the old or the new content, never part of it. `write_atomic` does the
+//    same for state that isn't secret.
+//
+// Secrets are never logged, and never passed on a command line.
+
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[cfg(not(windows))]
+pub const MASTER_KEY_PATH: &str = "/var/lib/guardnest/master.key";
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+// Authenticated with every blob sealed by `FileKeyProtector`. Bump the version
+// when the layout changes.
+const SEALED_AAD: &[u8] = b"GUARDNEST-SEALED 1";
+
+// Seals secrets so they can be stored on disk, and opens them again
+pub trait KeyProtector: Send + Sync {
+    // Short name for logs, e.g. "DPAPI"
+    fn name(&self) -> &'static str;
+
+    fn seal(&self, secret: &[u8]) -> io::Result<Vec<u8>>;
+
+    // Fails with `InvalidData` if the blob wasn't sealed by this protector (on
+    // this machine), or was changed
+    fn unseal(&self, sealed: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+// The protector for this platform: the OS keystore where there is one
+pub fn default_protector() -> Arc<dyn KeyProtector> {
+    #[cfg(windows)]
+    {
+        Arc::new(DpapiProtector)
+    }
+    #[cfg(not(windows))]
+    {
+        Arc::new(FileKeyProtector::new(MASTER_KEY_PATH))
+    }
+}
+
+// Seals `secret` and writes it to `path` (see `write_restricted`)
+pub fn write_sealed(path: &Path, protector: &dyn KeyProtector, secret: &[u8]) -> io::Result<()> {
+    write_restricted(path, &protector.seal(secret)?)
+}
+
+// Reads and unseals a secret written by `write_sealed`
+pub fn read_sealed(path: &Path, protector: &dyn KeyProtector) -> io::Result<Vec<u8>> {
+    protector.unseal(&fs::read(path)?)
+}
+
+// Writes `data` to `path`, readable only by administrators (Windows) or the owner.
+// The temporary file is created with those permissions, then filled and renamed,
+// so the data is never readable by anyone else, even briefly.
+pub fn write_restricted(path: &Path, data: &[u8]) -> io::Result<()> {
+    replace_file(path, data, create_restricted)
+}
+
+// Opens `path` for appending. A missing file is created first, restricted like
+// the files of `write_restricted`; an existing one keeps its permissions.
+pub fn append_restricted(path: &Path) -> io::Result<fs::File> {
+    match create_restricted(path) {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e),
+    }
+    fs::OpenOptions::new().append(true).open(path)
+}
+
+// Writes `data` to `path` with the folder's usual permissions, replacing the old
+// content in one step
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    replace_file(path, data, |tmp_path| {
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(tmp_path)
+    })
+}
+
+// Fills a temporary file made by `create`, flushes it to disk and renames it over
+// `path`
+fn replace_file(
+    path: &Path,
+    data: &[u8],
+    create: impl Fn(&Path) -> io::Result<fs::File>,
+) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let tmp_path = path.with_extension("tmp");
+    let _ = fs::remove_file(&tmp_path); // Left over from an interrupted write
+    let write = || -> io::Result<()> {
+        let mut file = create(&tmp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&tmp_path, path)
+    };
+    if let Err(e) = write() {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    Ok(())
+}
+
+// Protected DACL (nothing inherited from C:\ProgramData, where Users can read)
+// granting full access to SYSTEM and Administrators only. Well-known SID aliases
+// rather than names, which are translated on non-English Windows.
+#[cfg(windows)]
+const RESTRICTED_SDDL: windows::core::PCWSTR = windows::core::w!("D:P(A;;FA;;;SY)(A;;FA;;;BA)");
+
+// Creates the file with its final permissions in the same call, so no other
+// account can open it in between (a handle opened before an ACL change outlives it)
+#[cfg(windows)]
+fn create_restricted(path: &Path) -> io::Result<fs::File> {
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::io::FromRawHandle;
+    use windows::core::PCWSTR;
+    use windows::Win32::Foundation::{LocalFree, GENERIC_WRITE, HLOCAL};
+    use windows::Win32::Security::Authorization::{
+        ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1,
+    };
+    use windows::Win32::Security::{PSECURITY_DESCRIPTOR, SECURITY_ATTRIBUTES};
+    use windows::Win32::Storage::FileSystem::{
+        CreateFileW, CREATE_NEW, FILE_ATTRIBUTE_NORMAL, FILE_SHARE_NONE,
+    };
+
+    let mut descriptor = PSECURITY_DESCRIPTOR::default();
+    unsafe {
+        ConvertStringSecurityDescriptorToSecurityDescriptorW(
+            RESTRICTED_SDDL,
+            SDDL_REVISION_1,
+            &mut descriptor,
+            None,
+        )?;
+    }
+    let attributes = SECURITY_ATTRIBUTES {
+        nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+        lpSecurityDescriptor: descriptor.0,
+        bInheritHandle: false.into(),
+    };
+    let wide_path: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+
+    // CREATE_NEW fails with ERROR_FILE_EXISTS (`AlreadyExists`) instead of reusing
+    // a file someone else may have a handle to
+    let handle = unsafe {
+        CreateFileW(
+            PCWSTR(wide_path.as_ptr()),
+            GENERIC_WRITE.0,
+            FILE_SHARE_NONE,
+            Some(&attributes),
+            CREATE_NEW,
+            FILE_ATTRIBUTE_NORMAL,
+            None,
+        )
+    };
+    unsafe {
+        LocalFree(Some(HLOCAL(descriptor.0)));
+    }
+    Ok(unsafe { fs::File::from_raw_handle(handle?.0 as _) })
+}
+
+#[cfg(unix)]
+fn create_restricted(path: &Path) -> io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+}
+
+// ----------------------------------------------------------------------------
+//  DPAPI (Windows)
+// ----------------------------------------------------------------------------
+
+// Seals with CryptProtectData in machine scope: nothing can unseal on another
+// machine, but any account or process of this machine can, the child's included.
+// Only the permissions of the sealed file (see `write_restricted`) keep it out of
+// their hands.
+#[cfg(windows)]
+pub struct DpapiProtector;
+
+#[cfg(windows)]
+impl KeyProtector for DpapiProtector {
+    fn name(&self) -> &'static str {
+        "DPAPI"
+    }
+
+    fn seal(&self, secret: &[u8]) -> io::Result<Vec<u8>> {
+        dpapi::protect(secret).map_err(io::Error::other)
+    }
+
+    fn unseal(&self, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        dpapi::unprotect(sealed).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(windows)]
+mod dpapi {
+    use windows::core::PCWSTR;
+    use windows::Win32::Foundation::{LocalFree, HLOCAL};
+    use windows::Win32::Security::Cryptography::{
+        CryptProtectData, CryptUnprotectData, CRYPTPROTECT_LOCAL_MACHINE,
+        CRYPTPROTECT_UI_FORBIDDEN, CRYPT_INTEGER_BLOB,
+    };
+
+    const FLAGS: u32 = CRYPTPROTECT_LOCAL_MACHINE | CRYPTPROTECT_UI_FORBIDDEN;
+
+    pub fn protect(data: &[u8]) -> windows::core::Result<Vec<u8>> {
+        let input = blob(data);
+        let mut output = CRYPT_INTEGER_BLOB::default();
+        unsafe {
+            CryptProtectData(&input, PCWSTR::null(), None, None, None, FLAGS, &mut output)?;
+            Ok(take(output))
+        }
+    }
+
+    pub fn unprotect(data: &[u8]) -> windows::core::Result<Vec<u8>> {
+        let input = blob(data);
+        let mut output = CRYPT_INTEGER_BLOB::default();
+        unsafe {
+            CryptUnprotectData(&input, None, None, None, None, FLAGS, &mut output)?;
+            Ok(take(output))
+        }
+    }
+
+    fn blob(data: &[u8]) -> CRYPT_INTEGER_BLOB {
+        CRYPT_INTEGER_BLOB {
+            cbData: data.len() as u32,
+            pbData: data.as_ptr() as *mut u8, // Only read by the API
+        }
+    }
+
+    // Copies the output of the API and frees it (LocalAlloc'd by DPAPI)
+    unsafe fn take(output: CRYPT_INTEGER_BLOB) -> Vec<u8> {
+        if output.pbData.is_null() {
+            return Vec::new();
+        }
+        let data = std::slice::from_raw_parts(output.pbData, output.cbData as usize).to_vec();
+        LocalFree(Some(HLOCAL(output.pbData as *mut _)));
+        data
+    }
+}
+
+// ----------------------------------------------------------------------------
+//  MASTER KEY FILE (other platforms)
+// ----------------------------------------------------------------------------
+
+// Seals with AES-256-GCM under a random master key, created on first use in a
+// file only the owner can read. Protects as much as that file's permissions do.
+//
+// Sealed layout: <12-byte nonce><16-byte tag><encrypted secret>
+pub struct FileKeyProtector {
+    key_path: PathBuf,
+}
+
+impl FileKeyProtector {
+    pub fn new(key_path: impl Into<PathBuf>) -> Self {
+        FileKeyProtector {
+            key_path: key_path.into(),
+        }
+    }
+
+    fn master_key(&self) -> io::Result<Vec<u8>> {
+        match fs::read(&self.key_path) {
+            Ok(key) if key.len() == KEY_LEN => return Ok(key),
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Master key file has the wrong size",
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let mut key = vec![0u8; KEY_LEN];
+        rand_bytes(&mut key).map_err(io::Error::other)?;
+        write_restricted(&self.key_path, &key)?;
+        Ok(key)
+    }
+}
+
+impl KeyProtector for FileKeyProtector {
+    fn name(&self) -> &'static str {
+        "master key file"
+    }
+
+    fn seal(&self, secret: &[u8]) -> io::Result<Vec<u8>> {
+        let key = self.master_key()?;
+        let mut nonce = [0u8; NONCE_LEN];
+        rand_bytes(&mut nonce).map_err(io::Error::other)?;
+        let mut tag = [0u8; TAG_LEN];
+        let encrypted = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &key,
+            Some(&nonce),
+            SEALED_AAD,
+            secret,
+            &mut tag,
+        )
+        .map_err(io::Error::other)?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&tag);
+        sealed.extend_from_slice(&encrypted);
+        Ok(sealed)
+    }
+
+    fn unseal(&self, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Can't unseal the secret");
+        if sealed.len() < NONCE_LEN + TAG_LEN {
+            return Err(invalid());
+        }
+        // Unsealing never creates the master key: without it nothing can be opened
+        let key = match fs::read(&self.key_path) {
+            Ok(key) if key.len() == KEY_LEN => key,
+            Ok(_) => return Err(invalid()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(invalid()),
+            Err(e) => return Err(e),
+        };
+
+        let (nonce, rest) = sealed.split_at(NONCE_LEN);
+        let (tag, encrypted) = rest.split_at(TAG_LEN);
+        decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &key,
+            Some(nonce),
+            SEALED_AAD,
+            encrypted,
+            tag,
+        )
+        .map_err(|_| invalid())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn protector_in(dir: &Path) -> FileKeyProtector {
+        FileKeyProtector::new(dir.join("keys").join("master.key"))
+    }
+
+    #[test]
+    fn sealed_secrets_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let protector = protector_in(dir.path());
+        let sealed = protector.seal(b"pfx password").unwrap();
+        assert_eq!(sealed.len(), NONCE_LEN + TAG_LEN + b"pfx password".len());
+        assert!(!sealed
+            .windows(b"pfx password".len())
+            .any(|part| part == b"pfx password"));
+        assert_eq!(protector.unseal(&sealed).unwrap(), b"pfx password");
+
+        // The same master key is used from then on, fresh nonce every time
+        let again = protector.seal(b"pfx password").unwrap();
+        assert_ne!(again, sealed);
+        assert_eq!(protector.unseal(&again).unwrap(), b"pfx password");
+
+        let path = dir.path().join("certificate.secret");
+        write_sealed(&path, &protector, b"leaf cache key").unwrap();
+        assert_eq!(read_sealed(&path, &protector).unwrap(), b"leaf cache key");
+    }
+
+    #[test]
+    fn changed_blobs_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let protector = protector_in(dir.path());
+        let sealed = protector.seal(b"secret").unwrap();
+
+        for position in [0, NONCE_LEN, sealed.len() - 1] {
+            let mut changed = sealed.clone();
+            changed[position] ^= 1;
+            let error = protector.unseal(&changed).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+        let error = protector
+            .unseal(&sealed[..NONCE_LEN + TAG_LEN - 1])
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // Sealed under another master key
+        let other = tempfile::tempdir().unwrap();
+        let error = protector_in(other.path())
+            .seal(b"secret")
+            .and_then(|sealed| protector.unseal(&sealed))
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn master_key_must_be_there_and_complete() {
+        let dir = tempfile::tempdir().unwrap();
+        let protector = protector_in(dir.path());
+
+        // Unsealing never creates the master key
+        let error = protector.unseal(&[0u8; 64]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(!protector.key_path.exists());
+
+        let sealed = protector.seal(b"secret").unwrap();
+        assert_eq!(fs::read(&protector.key_path).unwrap().len(), KEY_LEN);
+
+        // Lost: what was sealed can't be opened, and isn't replaced by a new key
+        fs::remove_file(&protector.key_path).unwrap();
+        let error = protector.unseal(&sealed).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // Cut short
+        fs::write(&protector.key_path, [0u8; KEY_LEN - 1]).unwrap();
+        let error = protector.unseal(&sealed).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let error = protector.seal(b"secret").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn secrets_are_only_readable_by_the_owner() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        let path = dir.path().join("certificate.p12");
+        write_restricted(&path, b"first").unwrap();
+        assert_eq!(mode(&path), 0o600);
+
+        // Replaced in one step, still restricted, no temporary file left behind
+        write_restricted(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(mode(&path), 0o600);
+        assert!(!path.with_extension("tmp").exists());
+
+        let protector = protector_in(dir.path());
+        protector.seal(b"secret").unwrap();
+        assert_eq!(mode(&protector.key_path), 0o600);
+
+        // Appending to a new file creates it restricted; an existing one keeps its mode
+        let log = dir.path().join("activity.log");
+        append_restricted(&log).unwrap().write_all(b"a").unwrap();
+        assert_eq!(mode(&log), 0o600);
+        fs::set_permissions(&log, fs::Permissions::from_mode(0o640)).unwrap();
+        append_restricted(&log).unwrap().write_all(b"b").unwrap();
+        assert_eq!(mode(&log), 0o640);
+        assert_eq!(fs::read(&log).unwrap(), b"ab");
+    }
+
+    #[test]
+    fn interrupted_writes_are_cleaned_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        fs::write(path.with_extension("tmp"), b"half written").unwrap();
+        write_atomic(&path, b"{}").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"{}");
+        assert!(!path.with_extension("tmp").exists());
+    }
+}
@@ -0,0 +1,541 @@
+// ============================================================================
+//  LEAF CERTIFICATE CACHE - REUSING MINTED CERTIFICATES ACROSS TUNNELS
+// ============================================================================
+// Minting a leaf (key generation + signature) for every intercepted tunnel would
+// slow down each new HTTPS connection. The cache keeps the leaves, ready to use as
+// TLS acceptors:
+// 1. Keyed by the name on the certificate. Subdomains share one wildcard leaf:
+//    "www.example.com" and "cdn.example.com" are both served by "*.example.com"
+// 2. Bounded: when full, the least recently used leaf is dropped
+// 3. A leaf that expires soon is treated as missing and minted again
+// 4. Optionally saved to disk, encrypted with a local key, so the leaves survive
+//    a restart instead of being minted again on the first visit of each site. The
+//    key itself is stored sealed by a `KeyProtector` (see key_protector.rs).
+//
+// Cache file layout (`LEAF_CACHE_PATH`):
+//
+//     GUARDNEST-LEAVES 1\n            <- magic + format version
+//     <12-byte nonce><16-byte tag>    <- AES-256-GCM, sealed key in `LEAF_CACHE_KEY_PATH`
+//     <encrypted JSON>                <- CA fingerprint + leaves, most recently used last
+//
+// Leaves signed by another CA than the current one are dropped when loading.
+
+use crate::logger::ProxyLogger;
+use crate::windows::certificate::LeafCertificate;
+use crate::windows::key_protector::{read_sealed, write_sealed, KeyProtector};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use native_tls::Identity;
+use openssl::asn1::Asn1Time;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use openssl::x509::X509;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::{fmt, fs, io};
+use tokio_native_tls::TlsAcceptor;
+
+pub const LEAF_CACHE_PATH: &str = "C:\\ProgramData\\GuardNest\\leaf_cache.bin";
+pub const LEAF_CACHE_KEY_PATH: &str = "C:\\ProgramData\\GuardNest\\leaf_cache.key";
+
+// How many leaves are kept. Each one is a few hundred bytes plus an SSL context.
+pub const DEFAULT_LEAF_CAPACITY: usize = 1024;
+
+// Leaves are valid for 30 days (certificate.rs); one with less than this left is
+// minted again, so the browser never gets a certificate about to expire
+const REFRESH_BEFORE_DAYS: u32 = 7;
+
+// First line of the cache file. Bump the number when the layout changes.
+const CACHE_MAGIC: &[u8] = b"GUARDNEST-LEAVES 1\n";
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+#[derive(Debug)]
+pub enum LeafCacheError {
+    Io(io::Error),     // The file or key can't be read or written
+    BadHeader,         // Not a cache file, or written by another format version
+    Undecryptable,     // Wrong key, or the file was changed
+    Malformed(String), // Decrypted fine but the content can't be used
+    OtherAuthority,    // The leaves were signed by another CA
+}
+
+impl fmt::Display for LeafCacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LeafCacheError::Io(e) => write!(f, "I/O error: {}", e),
+            LeafCacheError::BadHeader => write!(f, "Unknown cache format"),
+            LeafCacheError::Undecryptable => write!(f, "Can't decrypt the cache"),
+            LeafCacheError::Malformed(msg) => write!(f, "Malformed cache: {}", msg),
+            LeafCacheError::OtherAuthority => write!(f, "Leaves signed by another CA"),
+        }
+    }
+}
+
+impl std::error::Error for LeafCacheError {}
+
+// Name a leaf for `host` is issued for. Names three labels deep or more collapse
+// into a wildcard for their parent, which covers all its direct subdomains.
+// Names right under a public suffix ("bbc.co.uk") are kept as they are, browsers
+// refuse wildcards like "*.co.uk". The check is a heuristic: two-letter country
+// code with a short second level, as in "co.uk", "com.au" or "ac.jp".
+pub fn leaf_name(host: &str) -> String {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    if host.parse::<IpAddr>().is_ok() {
+        return host; // IP addresses have no wildcards
+    }
+
+    let labels: Vec<&str> = host.split('.').collect();
+    if labels.len() < 3 || labels.iter().any(|label| label.is_empty() || *label == "*") {
+        return host;
+    }
+
+    let parent = &labels[1..];
+    let under_public_suffix = parent.len() == 2 && parent[1].len() == 2 && parent[0].len() <= 3;
+    if under_public_suffix {
+        return host;
+    }
+    format!("*.{}", parent.join("."))
+}
+
+// A leaf ready to be presented to the browser
+pub struct CachedLeaf {
+    pub leaf: LeafCertificate,
+    pub acceptor: TlsAcceptor, // Sends the leaf followed by the CA certificate
+}
+
+impl CachedLeaf {
+    pub fn new(leaf: LeafCertificate, ca: &X509) -> io::Result<Self> {
+        // Chain sent to the browser: the leaf, then the CA that signed it
+        let mut chain = leaf.cert.to_pem().map_err(io::Error::other)?;
+        chain.extend(ca.to_pem().map_err(io::Error::other)?);
+        let key = leaf
+            .key
+            .private_key_to_pem_pkcs8()
+            .map_err(io::Error::other)?;
+
+        let identity = Identity::from_pkcs8(&chain, &key).map_err(io::Error::other)?;
+        let acceptor = native_tls::TlsAcceptor::new(identity).map_err(io::Error::other)?;
+        Ok(CachedLeaf {
+            leaf,
+            acceptor: TlsAcceptor::from(acceptor),
+        })
+    }
+
+    // Whether the leaf expires within REFRESH_BEFORE_DAYS
+    pub fn expires_soon(&self) -> bool {
+        match Asn1Time::days_from_now(REFRESH_BEFORE_DAYS) {
+            Ok(deadline) => self.leaf.cert.not_after() <= deadline,
+            Err(_) => true,
+        }
+    }
+}
+
+struct Entry {
+    leaf: Arc<CachedLeaf>,
+    last_used: u64, // Value of `LeafCache::clock` when the leaf was last handed out
+}
+
+// Where the cache is saved, if anywhere
+struct LeafStore {
+    path: PathBuf,
+    key_path: PathBuf,
+    protector: Arc<dyn KeyProtector>, // Seals the key at `key_path`
+}
+
+pub struct LeafCache {
+    entries: HashMap<String, Entry>, // Leaf name -> leaf
+    capacity: usize,
+    clock: u64,               // Bumped on every use, orders the entries for eviction
+    store: Option<LeafStore>, // None: in memory only
+    changed: bool,            // Leaves were added or dropped since the last save
+}
+
+// One leaf in the cache file
+#[derive(Serialize, Deserialize)]
+struct StoredLeaf {
+    name: String,
+    cert: String, // Base64 DER
+    key: String,  // Base64 PKCS#8 DER
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredCache {
+    ca_fingerprint: String, // SHA-256 of the CA certificate that signed the leaves
+    leaves: Vec<StoredLeaf>,
+}
+
+impl LeafCache {
+    // Empty in-memory cache holding up to `capacity` leaves
+    pub fn new(capacity: usize) -> Self {
+        LeafCache {
+            entries: HashMap::new(),
+            capacity: capacity.max(1),
+            clock: 0,
+            store: None,
+            changed: false,
+        }
+    }
+
+    // Cache saved to `path`, starting with the leaves already there. A cache file
+    // that can't be used is reported and replaced on the next save.
+    pub fn open(
+        path: &Path,
+        key_path: &Path,
+        protector: Arc<dyn KeyProtector>,
+        ca: &X509,
+        capacity: usize,
+    ) -> Self {
+        let mut cache = LeafCache::new(capacity);
+        if path.exists() {
+            if let Err(e) = cache.load(path, key_path, &*protector, ca) {
+                ProxyLogger::log_error("loading leaf certificate cache", &e);
+                cache.entries.clear();
+            }
+        }
+        cache.store = Some(LeafStore {
+            path: path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            protector,
+        });
+        cache
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // The leaf for `name` (see `leaf_name`), unless it's missing or expires soon
+    pub fn get(&mut self, name: &str) -> Option<Arc<CachedLeaf>> {
+        let expired = self.entries.get(name)?.leaf.expires_soon();
+        if expired {
+            self.entries.remove(name);
+            self.changed = true;
+            return None;
+        }
+
+        self.clock += 1;
+        let entry = self.entries.get_mut(name)?;
+        entry.last_used = self.clock;
+        Some(entry.leaf.clone())
+    }
+
+    // Adds or replaces the leaf for `name`, dropping the least recently used leaf
+    // when the cache is full
+    pub fn insert(&mut self, name: &str, leaf: Arc<CachedLeaf>) {
+        if !self.entries.contains_key(name) && self.entries.len() >= self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(name, _)| name.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.clock += 1;
+        self.entries.insert(
+            name.to_string(),
+            Entry {
+                leaf,
+                last_used: self.clock,
+            },
+        );
+        self.changed = true;
+    }
+
+    // Names of the leaves that expire soon, most recently used first, so they can
+    // be minted again ahead of the next visit
+    pub fn expiring(&self) -> Vec<String> {
+        let mut expiring: Vec<(&String, u64)> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.leaf.expires_soon())
+            .map(|(name, entry)| (name, entry.last_used))
+            .collect();
+        expiring.sort_by_key(|(_, last_used)| std::cmp::Reverse(*last_used));
+        expiring.into_iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    // Writes the cache to disk if it's persistent and changed since the last save.
+    // Written to a temporary file first and renamed, like the blocklist cache.
+    pub fn save(&mut self, ca: &X509) -> Result<(), LeafCacheError> {
+        let store = match &self.store {
+            Some(store) if self.changed => store,
+            _ => return Ok(()),
+        };
+
+        // Step 1: Serialize the leaves, least recently used first
+        let mut entries: Vec<(&String, &Entry)> = self.entries.iter().collect();
+        entries.sort_by_key(|(_, entry)| entry.last_used);
+        let mut leaves = Vec::with_capacity(entries.len());
+        for (name, entry) in entries {
+            let der =
+                |result: Result<Vec<u8>, _>| {
+                    result.map(|der| STANDARD.encode(der)).map_err(
+                        |e: openssl::error::ErrorStack| LeafCacheError::Malformed(e.to_string()),
+                    )
+                };
+            leaves.push(StoredLeaf {
+                name: name.clone(),
+                cert: der(entry.leaf.leaf.cert.to_der())?,
+                key: der(entry.leaf.leaf.key.private_key_to_pkcs8())?,
+            });
+        }
+        let plain = serde_json::to_vec(&StoredCache {
+            ca_fingerprint: fingerprint(ca)?,
+            leaves,
+        })
+        .map_err(|e| LeafCacheError::Malformed(e.to_string()))?;
+
+        // Step 2: Encrypt with the local key
+        let key = read_or_create_key(&store.key_path, &*store.protector)?;
+        let mut nonce = [0u8; NONCE_LEN];
+        rand_bytes(&mut nonce).map_err(|e| LeafCacheError::Io(io::Error::other(e)))?;
+        let mut tag = [0u8; TAG_LEN];
+        let encrypted = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &key,
+            Some(&nonce),
+            CACHE_MAGIC,
+            &plain,
+            &mut tag,
+        )
+        .map_err(|e| LeafCacheError::Io(io::Error::other(e)))?;
+
+        let mut data = CACHE_MAGIC.to_vec();
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&tag);
+        data.extend_from_slice(&encrypted);
+
+        // Step 3: Write atomically
+        let tmp_path = store.path.with_extension("tmp");
+        let write = || -> io::Result<()> {
+            if let Some(dir) = store.path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(&tmp_path, &data)?;
+            fs::rename(&tmp_path, &store.path)
+        };
+        if let Err(e) = write() {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(LeafCacheError::Io(e));
+        }
+
+        self.changed = false;
+        Ok(())
+    }
+
+    fn load(
+        &mut self,
+        path: &Path,
+        key_path: &Path,
+        protector: &dyn KeyProtector,
+        ca: &X509,
+    ) -> Result<(), LeafCacheError> {
+        // Step 1: Check the header and decrypt
+        let data = fs::read(path).map_err(LeafCacheError::Io)?;
+        let rest = data
+            .strip_prefix(CACHE_MAGIC)
+            .ok_or(LeafCacheError::BadHeader)?;
+        if rest.len() < NONCE_LEN + TAG_LEN {
+            return Err(LeafCacheError::BadHeader);
+        }
+        let (nonce, rest) = rest.split_at(NONCE_LEN);
+        let (tag, encrypted) = rest.split_at(TAG_LEN);
+
+        let key = read_sealed(key_path, protector).map_err(|e| match e.kind() {
+            io::ErrorKind::InvalidData => LeafCacheError::Undecryptable,
+            _ => LeafCacheError::Io(e),
+        })?;
+        if key.len() != KEY_LEN {
+            return Err(LeafCacheError::Undecryptable);
+        }
+        let plain = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &key,
+            Some(nonce),
+            CACHE_MAGIC,
+            encrypted,
+            tag,
+        )
+        .map_err(|_| LeafCacheError::Undecryptable)?;
+
+        // Step 2: Only keep leaves signed by the current CA
+        let stored: StoredCache =
+            serde_json::from_slice(&plain).map_err(|e| LeafCacheError::Malformed(e.to_string()))?;
+        if stored.ca_fingerprint != fingerprint(ca)? {
+            return Err(LeafCacheError::OtherAuthority);
+        }
+
+        // Step 3: Rebuild the acceptors, in the saved order so the LRU order survives
+        for stored_leaf in stored.leaves {
+            let decode = |value: &str| {
+                STANDARD
+                    .decode(value)
+                    .map_err(|e| LeafCacheError::Malformed(e.to_string()))
+            };
+            let cert = X509::from_der(&decode(&stored_leaf.cert)?)
+                .map_err(|e| LeafCacheError::Malformed(e.to_string()))?;
+            let key = PKey::private_key_from_pkcs8(&decode(&stored_leaf.key)?)
+                .map_err(|e| LeafCacheError::Malformed(e.to_string()))?;
+            let leaf = CachedLeaf::new(LeafCertificate { cert, key }, ca)
+                .map_err(|e| LeafCacheError::Malformed(e.to_string()))?;
+            if !leaf.expires_soon() {
+                self.insert(&stored_leaf.name, Arc::new(leaf));
+            }
+        }
+        self.changed = false;
+        Ok(())
+    }
+}
+
+fn fingerprint(ca: &X509) -> Result<String, LeafCacheError> {
+    let digest = ca
+        .digest(MessageDigest::sha256())
+        .map_err(|e| LeafCacheError::Malformed(e.to_string()))?;
+    Ok(digest.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+// The key the cache file is encrypted with, created on first use. A new key
+// makes the old cache file unreadable, which only costs minting the leaves again,
+// so a key that can't be unsealed (e.g. a plain key from an older version) is
+// replaced.
+fn read_or_create_key(
+    key_path: &Path,
+    protector: &dyn KeyProtector,
+) -> Result<Vec<u8>, LeafCacheError> {
+    match read_sealed(key_path, protector) {
+        Ok(key) if key.len() == KEY_LEN => return Ok(key),
+        Ok(_) => {}
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::InvalidData
+            ) => {}
+        Err(e) => return Err(LeafCacheError::Io(e)),
+    }
+
+    let mut key = vec![0u8; KEY_LEN];
+    rand_bytes(&mut key).map_err(|e| LeafCacheError::Io(io::Error::other(e)))?;
+    write_sealed(key_path, protector, &key).map_err(LeafCacheError::Io)?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::windows::certificate::CertificateAuthority;
+    use crate::windows::interception::tests::throwaway_ca;
+    use crate::windows::key_protector::FileKeyProtector;
+
+    fn mint(ca: &CertificateAuthority, name: &str) -> Arc<CachedLeaf> {
+        let leaf = ca.issue_leaf(name).unwrap();
+        Arc::new(CachedLeaf::new(leaf, ca.certificate()).unwrap())
+    }
+
+    // The names in the cache, least recently used first
+    fn names_by_use(cache: &LeafCache) -> Vec<String> {
+        let mut entries: Vec<_> = cache.entries.iter().collect();
+        entries.sort_by_key(|(_, entry)| entry.last_used);
+        entries.into_iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    #[test]
+    fn leaf_names() {
+        assert_eq!(leaf_name("example.com"), "example.com");
+        assert_eq!(leaf_name("WWW.Example.com."), "*.example.com");
+        assert_eq!(leaf_name("a.b.example.com"), "*.b.example.com");
+        assert_eq!(leaf_name("bbc.co.uk"), "bbc.co.uk");
+        assert_eq!(leaf_name("www.bbc.co.uk"), "*.bbc.co.uk");
+        assert_eq!(leaf_name("10.0.0.1"), "10.0.0.1");
+        assert_eq!(leaf_name("localhost"), "localhost");
+    }
+
+    #[test]
+    fn least_recently_used_is_dropped() {
+        let ca = throwaway_ca("GuardNest Test CA");
+        let mut cache = LeafCache::new(2);
+        cache.insert("a.com", mint(&ca, "a.com"));
+        cache.insert("b.com", mint(&ca, "b.com"));
+        assert!(cache.get("a.com").is_some()); // b.com is now the oldest
+
+        cache.insert("c.com", mint(&ca, "c.com"));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("b.com").is_none());
+        assert_eq!(names_by_use(&cache), ["a.com", "c.com"]);
+
+        // Replacing a leaf doesn't evict anything
+        cache.insert("a.com", mint(&ca, "a.com"));
+        assert_eq!(names_by_use(&cache), ["c.com", "a.com"]);
+        assert!(cache.expiring().is_empty());
+    }
+
+    #[test]
+    fn encrypted_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("leaf_cache.bin");
+        let key_path = dir.path().join("leaf_cache.key");
+        let protector: Arc<dyn KeyProtector> =
+            Arc::new(FileKeyProtector::new(dir.path().join("master.key")));
+        let ca = throwaway_ca("GuardNest Test CA");
+        let open = |ca: &CertificateAuthority| {
+            LeafCache::open(&path, &key_path, protector.clone(), ca.certificate(), 3)
+        };
+
+        let mut cache = open(&ca);
+        assert!(cache.is_empty());
+        for name in ["a.com", "b.com", "c.com"] {
+            cache.insert(name, mint(&ca, name));
+        }
+        cache.get("a.com");
+        cache.save(ca.certificate()).unwrap();
+
+        // Nothing readable on disk: no names, no keys, and the key file is sealed
+        let data = fs::read(&path).unwrap();
+        assert!(data.starts_with(CACHE_MAGIC));
+        assert!(!String::from_utf8_lossy(&data).contains("b.com"));
+        assert_eq!(read_sealed(&key_path, &*protector).unwrap().len(), KEY_LEN);
+        assert_ne!(fs::read(&key_path).unwrap().len(), KEY_LEN);
+
+        // Loaded with the same leaves, in the same order of use
+        let mut loaded = open(&ca);
+        assert_eq!(names_by_use(&loaded), ["b.com", "c.com", "a.com"]);
+        let saved = loaded.get("c.com").unwrap();
+        assert_eq!(
+            saved.leaf.cert.to_der().unwrap(),
+            cache.get("c.com").unwrap().leaf.cert.to_der().unwrap()
+        );
+        // Unchanged since loading (a lookup isn't a change), so nothing is written
+        fs::remove_file(&path).unwrap();
+        loaded.save(ca.certificate()).unwrap();
+        assert!(!path.exists());
+        cache.changed = true;
+        cache.save(ca.certificate()).unwrap();
+
+        // Leaves of another CA are never presented
+        let other = throwaway_ca("Other CA");
+        assert!(open(&other).is_empty());
+
+        // A changed file or another key only means minting again
+        let mut changed = fs::read(&path).unwrap();
+        let last = changed.len() - 1;
+        changed[last] ^= 1;
+        fs::write(&path, &changed).unwrap();
+        assert!(open(&ca).is_empty());
+        cache.changed = true;
+        cache.save(ca.certificate()).unwrap();
+        fs::remove_file(&key_path).unwrap();
+        assert!(open(&ca).is_empty());
+    }
+}
@@ -0,0 +1,587 @@
+// ============================================================================
+//  LOCAL SITE - PAGES SERVED BY THE PROXY ITSELF AT http://guardnest.local
+// ============================================================================
+// Requests for the reserved host `LOCAL_HOST` never leave the machine: the proxy
+// answers them itself, before the blocklist, so they also work in allowlist mode.
+// The proxy only listens on 127.0.0.1, so these pages are for browsers on this
+// machine, e.g. to install the GuardNest Root CA by hand in one that keeps its
+// own certificate store:
+// - "/"                How to install the root, with its fingerprint
+// - "/ca.pem"          The root certificate, PEM
+// - "/ca.crt"          The root certificate, DER (opens the certificate installer
+//                      on Windows, Android and iOS)
+// - "/ca.der"          Same as "/ca.crt"
+// - "/request-access"  Where the block page's button leads (see block_page.rs):
+//                      GET shows the form asking a parent for `?host=`, or where
+//                      the request stands once sent; POST sends it (host, reason)
+// - "/parent-override" POST with a parent's code (host, code, minutes, scope "host"
+//                      or "all") lifts filtering (see parent_override.rs)
+//
+// Forms are only taken from the local site's own pages: a POST carrying an
+// Origin header from anywhere else is refused, so other pages can't send
+// requests or try codes in the child's name.
+//
+// Only the public certificate (`CaFiles::root_cert`) is read, never the PKCS#12
+// files holding the keys.
+
+use crate::logger::ProxyLogger;
+use crate::windows::access_request::{AccessRequests, AccessStatus, SubmitError};
+use crate::windows::block_page::{
+    access_request_page, parent_override_page, request_access_page, too_many_requests_page,
+};
+use crate::windows::certificate::{load_root_certificate, CaFiles, RootExport};
+use crate::windows::domain_matcher::normalize_host;
+use crate::windows::http_parser::HttpRequestHead;
+use crate::windows::parent_override::{
+    unlock_filtering, OverrideScope, OverrideSource, ParentOverride,
+};
+use crate::windows::policy::FilterPolicy;
+use std::io;
+use std::sync::{Mutex, PoisonError, RwLock};
+
+pub const LOCAL_HOST: &str = "guardnest.local";
+
+// Largest request body the local site reads (the forms)
+pub const MAX_BODY_SIZE: u64 = 8 * 1024;
+
+// A response of the local site
+#[derive(Debug)]
+pub struct LocalResponse {
+    pub status: u16,
+    pub reason: &'static str,
+    pub headers: Vec<(String, String)>, // Besides Content-Length and Connection
+    pub body: Vec<u8>,
+}
+
+impl LocalResponse {
+    pub fn new(status: u16, reason: &'static str, content_type: &str, body: Vec<u8>) -> Self {
+        LocalResponse {
+            status,
+            reason,
+            headers: vec![
+                ("Content-Type".to_string(), content_type.to_string()),
+                // Pages change: the root is rotated, blocks are lifted
+                ("Cache-Control".to_string(), "no-store".to_string()),
+            ],
+            body,
+        }
+    }
+
+    fn text(status: u16, reason: &'static str, body: &str) -> Self {
+        Self::new(status, reason, "text/plain; charset=utf-8", body.into())
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    // The response as sent to the browser. `head_only` leaves the body out (HEAD).
+    pub fn to_bytes(&self, head_only: bool, keep_alive: bool) -> Vec<u8> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, self.reason);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        if keep_alive {
+            head.push_str("Connection: keep-alive\r\n\r\n");
+        } else {
+            head.push_str("Connection: close\r\n\r\n");
+        }
+
+        let mut bytes = head.into_bytes();
+        if !head_only {
+            bytes.extend_from_slice(&self.body);
+        }
+        bytes
+    }
+}
+
+// Whether `host` is the local site
+pub fn is_local_host(host: &str) -> bool {
+    normalize_host(host).is_some_and(|host| host == LOCAL_HOST)
+}
+
+// Answers `request` for `path` (its origin-form, e.g. "/ca.pem?x=1") with its
+// `body`, with the CA stored in `files`, the access requests in `requests`, and
+// the parent overrides in `overrides` (let through `policy`)
+pub fn respond(
+    request: &HttpRequestHead,
+    path: &str,
+    body: &[u8],
+    files: &CaFiles,
+    requests: &Mutex<AccessRequests>,
+    overrides: &Mutex<ParentOverride>,
+    policy: &RwLock<FilterPolicy>,
+) -> LocalResponse {
+    let route = path.split(['?', '#']).next().unwrap_or_default();
+    if route == "/request-access" {
+        return request_access(request, path, body, requests);
+    }
+    if route == "/parent-override" {
+        return parent_override(request, body, overrides, policy);
+    }
+
+    if request.method != "GET" && request.method != "HEAD" {
+        return LocalResponse::text(405, "Method Not Allowed", "Method not allowed\n")
+            .with_header("Allow", "GET, HEAD");
+    }
+    if !matches!(route, "/" | "/ca.pem" | "/ca.crt" | "/ca.der") {
+        return LocalResponse::text(404, "Not Found", "Not found\n");
+    }
+
+    let root = load_root_certificate(files).and_then(|root| {
+        let der = root.to_der().map_err(io::Error::other)?;
+        Ok((der, RootExport::new(&root).map_err(io::Error::other)?))
+    });
+    let (der, export) = match root {
+        Ok(root) => root,
+        Err(e) => {
+            ProxyLogger::log_error("reading the GuardNest Root CA", &e);
+            return LocalResponse::text(
+                503,
+                "Service Unavailable",
+                "The GuardNest Root CA isn't available yet\n",
+            );
+        }
+    };
+
+    match route {
+        "/" => LocalResponse::new(
+            200,
+            "OK",
+            "text/html; charset=utf-8",
+            install_page(&export).into_bytes(),
+        ),
+        "/ca.pem" => LocalResponse::new(200, "OK", "application/x-pem-file", export.pem.into())
+            .with_header(
+                "Content-Disposition",
+                "attachment; filename=\"guardnest-ca.pem\"",
+            )
+            .with_header("X-Certificate-Fingerprint", &export.fingerprint),
+        _ => LocalResponse::new(200, "OK", "application/x-x509-ca-cert", der)
+            .with_header(
+                "Content-Disposition",
+                "attachment; filename=\"guardnest-ca.crt\"",
+            )
+            .with_header("X-Certificate-Fingerprint", &export.fingerprint),
+    }
+}
+
+// "/request-access": the form for a host, where its request stands, or sending it
+fn request_access(
+    request: &HttpRequestHead,
+    path: &str,
+    body: &[u8],
+    requests: &Mutex<AccessRequests>,
+) -> LocalResponse {
+    let accept_language = request.header("accept-language");
+    let requests = || requests.lock().unwrap_or_else(PoisonError::into_inner);
+
+    match request.method.as_str() {
+        "GET" | "HEAD" => {
+            let Some(host) = query_param(path, "host").and_then(|host| normalize_host(&host))
+            else {
+                return LocalResponse::text(400, "Bad Request", "Missing or invalid host\n");
+            };
+            // Asking again is possible once the last request was answered with a
+            // no, or its access ran out
+            match requests().latest_for(&host) {
+                Some(sent)
+                    if matches!(sent.status, AccessStatus::Pending | AccessStatus::Approved) =>
+                {
+                    access_request_page(&sent, accept_language)
+                }
+                previous => request_access_page(&host, previous.as_ref(), accept_language),
+            }
+        }
+        "POST" => {
+            if from_other_origin(request) {
+                return LocalResponse::text(403, "Forbidden", "Cross-origin request refused\n");
+            }
+
+            let form = format!("?{}", String::from_utf8_lossy(body));
+            let host = query_param(&form, "host").unwrap_or_default();
+            let reason = query_param(&form, "reason");
+            let submitted = requests().submit(&host, reason.as_deref());
+            match submitted {
+                // Back to the GET page, so reloading it doesn't send the form again
+                Ok(sent) => {
+                    LocalResponse::new(303, "See Other", "text/plain; charset=utf-8", Vec::new())
+                        .with_header("Location", &format!("/request-access?host={}", sent.host))
+                }
+                Err(SubmitError::InvalidHost) => {
+                    LocalResponse::text(400, "Bad Request", "Missing or invalid host\n")
+                }
+                Err(SubmitError::TooManyPending) => too_many_requests_page(&host, accept_language),
+                Err(SubmitError::Io(e)) => {
+                    ProxyLogger::log_error("queueing an access request", &e);
+                    LocalResponse::text(
+                        500,
+                        "Internal Server Error",
+                        "The request couldn't be saved\n",
+                    )
+                }
+            }
+        }
+        _ => LocalResponse::text(405, "Method Not Allowed", "Method not allowed\n")
+            .with_header("Allow", "GET, HEAD, POST"),
+    }
+}
+
+// "/parent-override": a parent's code typed on one of the pages above
+fn parent_override(
+    request: &HttpRequestHead,
+    body: &[u8],
+    overrides: &Mutex<ParentOverride>,
+    policy: &RwLock<FilterPolicy>,
+) -> LocalResponse {
+    if request.method != "POST" {
+        return LocalResponse::text(405, "Method Not Allowed", "Method not allowed\n")
+            .with_header("Allow", "POST");
+    }
+    if from_other_origin(request) {
+        return LocalResponse::text(403, "Forbidden", "Cross-origin request refused\n");
+    }
+
+    let form = format!("?{}", String::from_utf8_lossy(body));
+    let host = query_param(&form, "host").unwrap_or_default();
+    let code = query_param(&form, "code").unwrap_or_default();
+    let minutes = query_param(&form, "minutes")
+        .and_then(|minutes| minutes.trim().parse().ok())
+        .unwrap_or(0); // Refused as an invalid duration
+    let scope = match query_param(&form, "scope").as_deref() {
+        Some("all") => OverrideScope::All,
+        _ => OverrideScope::Host { host: host.clone() },
+    };
+
+    let result = unlock_filtering(
+        overrides,
+        policy,
+        &code,
+        scope,
+        minutes,
+        OverrideSource::BlockPage,
+    );
+    parent_override_page(&host, &result, request.header("accept-language"))
+}
+
+// Whether `request` carries an Origin header of another site than the local site
+fn from_other_origin(request: &HttpRequestHead) -> bool {
+    let local_origin = format!("http://{}", LOCAL_HOST);
+    request
+        .header("origin")
+        .is_some_and(|origin| !origin.eq_ignore_ascii_case(&local_origin))
+}
+
+// "3f0a…" as "3F:0A:…", how devices show fingerprints
+fn colon_separated(hex: &str) -> String {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| String::from_utf8_lossy(pair).to_ascii_uppercase())
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn install_page(export: &RootExport) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>GuardNest Root CA</title>
+</head>
+<body>
+<h1>GuardNest Root CA</h1>
+<p>Install this certificate as a trusted root in browsers of this computer that
+keep their own certificates, so their secure connections can be filtered.</p>
+<ul>
+<li><a href="/ca.crt">guardnest-ca.crt</a> (DER: Windows, Android, iOS, macOS)</li>
+<li><a href="/ca.pem">guardnest-ca.pem</a> (PEM: Linux, Firefox)</li>
+</ul>
+<p>Before trusting it, check that the browser shows this fingerprint:</p>
+<p>SHA-256<br><code>{}</code></p>
+<p>SHA-1<br><code>{}</code></p>
+</body>
+</html>
+"#,
+        colon_separated(&export.fingerprint),
+        colon_separated(&export.thumbprint)
+    )
+}
+
+// The value of the query parameter `name` in `path` (e.g. "/page?host=a.com"),
+// percent-decoded. Form bodies use the same encoding ("?" + body).
+pub fn query_param(path: &str, name: &str) -> Option<String> {
+    let query = path.split('#').next()?.split_once('?')?.1;
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| decode_query(value))
+}
+
+// Decodes a percent-encoded query string value ("+" is a space). Invalid escapes
+// are kept as they are.
+fn decode_query(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (escaped, bytes[i]) {
+            (Some(byte), _) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (None, b'+') => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (None, byte) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::windows::interception::tests::throwaway_ca;
+    use crate::windows::key_protector::FileKeyProtector;
+    use std::fs;
+    use std::path::Path;
+
+    // The local site with a CA saved in `dir`
+    struct Site {
+        files: CaFiles,
+        requests: Mutex<AccessRequests>,
+        overrides: Mutex<ParentOverride>,
+        policy: RwLock<FilterPolicy>,
+    }
+
+    impl Site {
+        fn new(dir: &Path) -> Self {
+            let files = CaFiles::in_dir(dir);
+            throwaway_ca("GuardNest Test CA")
+                .save_to(&files, &FileKeyProtector::new(dir.join("master.key")))
+                .unwrap();
+            Site {
+                files,
+                requests: Mutex::default(),
+                overrides: Mutex::default(),
+                policy: RwLock::default(),
+            }
+        }
+
+        fn request(
+            &self,
+            method: &str,
+            path: &str,
+            headers: &[(&str, &str)],
+            body: &str,
+        ) -> LocalResponse {
+            let request = HttpRequestHead {
+                method: method.to_string(),
+                target: format!("http://{}{}", LOCAL_HOST, path),
+                version: "HTTP/1.1".to_string(),
+                headers: headers
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+            };
+            respond(
+                &request,
+                path,
+                body.as_bytes(),
+                &self.files,
+                &self.requests,
+                &self.overrides,
+                &self.policy,
+            )
+        }
+
+        fn get(&self, path: &str) -> LocalResponse {
+            self.request("GET", path, &[], "")
+        }
+    }
+
+    fn header<'a>(response: &'a LocalResponse, name: &str) -> Option<&'a str> {
+        response
+            .headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn serves_the_root_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let site = Site::new(dir.path());
+        let der = fs::read(&site.files.root_cert).unwrap();
+        let root = openssl::x509::X509::from_der(&der).unwrap();
+
+        let page = site.get("/");
+        assert_eq!(page.status, 200);
+        assert_eq!(
+            header(&page, "Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        let fingerprint = colon_separated(&RootExport::new(&root).unwrap().fingerprint);
+        assert!(String::from_utf8(page.body).unwrap().contains(&fingerprint));
+
+        let pem = site.get("/ca.pem?download=1");
+        assert_eq!(pem.status, 200);
+        assert_eq!(pem.body, root.to_pem().unwrap());
+        assert!(header(&pem, "Content-Disposition")
+            .unwrap()
+            .contains("guardnest-ca.pem"));
+
+        for path in ["/ca.der", "/ca.crt"] {
+            let response = site.get(path);
+            assert_eq!(response.status, 200);
+            assert_eq!(
+                header(&response, "Content-Type"),
+                Some("application/x-x509-ca-cert")
+            );
+            assert_eq!(response.body, der);
+        }
+
+        // HEAD: the same head, no body
+        let head = site
+            .request("HEAD", "/ca.der", &[], "")
+            .to_bytes(true, false);
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}\r\n", der.len())));
+        assert!(head.ends_with("Connection: close\r\n\r\n"));
+    }
+
+    #[test]
+    fn never_serves_the_private_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let site = Site::new(dir.path());
+        let pfx = fs::read(&site.files.root_pfx).unwrap();
+
+        for path in [
+            "/",
+            "/ca.pem",
+            "/ca.der",
+            "/ca.crt",
+            "/certificate.p12",
+            "/certificate.secret",
+        ] {
+            let response = site.get(path);
+            assert!(!response
+                .body
+                .windows(pfx.len())
+                .any(|part| part == pfx.as_slice()));
+            assert!(!String::from_utf8_lossy(&response.body).contains("PRIVATE KEY"));
+        }
+        assert_eq!(site.get("/certificate.p12").status, 404);
+    }
+
+    #[test]
+    fn unknown_routes_and_methods() {
+        let dir = tempfile::tempdir().unwrap();
+        let site = Site::new(dir.path());
+        assert_eq!(site.get("/nothing").status, 404);
+
+        let response = site.request("POST", "/ca.pem", &[], "");
+        assert_eq!(response.status, 405);
+        assert_eq!(header(&response, "Allow"), Some("GET, HEAD"));
+        let response = site.request("DELETE", "/request-access", &[], "");
+        assert_eq!(response.status, 405);
+        let response = site.get("/parent-override");
+        assert_eq!(
+            (response.status, header(&response, "Allow")),
+            (405, Some("POST"))
+        );
+
+        // No CA yet
+        let empty = tempfile::tempdir().unwrap();
+        let site = Site {
+            files: CaFiles::in_dir(empty.path()),
+            ..site
+        };
+        assert_eq!(site.get("/ca.pem").status, 503);
+    }
+
+    #[test]
+    fn forms_from_other_origins_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let site = Site::new(dir.path());
+        let form = "host=games.example&reason=homework";
+
+        for origin in [
+            "https://evil.example",
+            "null",
+            "http://guardnest.local:8080",
+        ] {
+            let response = site.request("POST", "/request-access", &[("Origin", origin)], form);
+            assert_eq!(response.status, 403);
+            let response = site.request(
+                "POST",
+                "/parent-override",
+                &[("Origin", origin)],
+                "code=123456",
+            );
+            assert_eq!(response.status, 403);
+        }
+        assert!(site.requests.lock().unwrap().requests().is_empty());
+
+        // The local site's own form, or no Origin at all
+        let response = site.request(
+            "POST",
+            "/request-access",
+            &[("Origin", "HTTP://GUARDNEST.LOCAL")],
+            form,
+        );
+        assert_eq!(response.status, 303);
+        assert_eq!(
+            header(&response, "Location"),
+            Some("/request-access?host=games.example")
+        );
+        let sent = site.requests.lock().unwrap().requests();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].reason.as_deref(), Some("homework"));
+    }
+
+    #[test]
+    fn query_parameters() {
+        assert_eq!(
+            query_param("/a?host=x.com&reason=r", "host").as_deref(),
+            Some("x.com")
+        );
+        assert_eq!(
+            query_param("/a?host=x.com&reason=r", "reason").as_deref(),
+            Some("r")
+        );
+        assert_eq!(query_param("/a?flag&host=", "flag").as_deref(), Some(""));
+        assert_eq!(query_param("/a?host=", "host").as_deref(), Some(""));
+        assert_eq!(query_param("/a?hostname=x", "host"), None);
+        assert_eq!(query_param("/a", "host"), None);
+        assert_eq!(query_param("/a#?host=x", "host"), None);
+        assert_eq!(query_param("/a?host=x#y", "host").as_deref(), Some("x"));
+    }
+
+    #[test]
+    fn percent_and_plus_decoding() {
+        assert_eq!(decode_query("for+my%20homework"), "for my homework");
+        assert_eq!(decode_query("a%2Bb%3d%26"), "a+b=&");
+        assert_eq!(decode_query("caf%C3%A9"), "café");
+        // Invalid escapes stay as they are
+        assert_eq!(decode_query("100%"), "100%");
+        assert_eq!(decode_query("%zz%4"), "%zz%4");
+        assert_eq!(decode_query("%%41"), "%A");
+        // Bytes that aren't UTF-8 become U+FFFD
+        assert_eq!(decode_query("%FF"), "\u{FFFD}");
+    }
+}
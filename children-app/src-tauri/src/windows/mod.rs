@@ -1,6 +1,25 @@
+pub mod access_request;
+pub mod activity;
+pub mod activity_store;
 pub mod account;
+pub mod block_page;
+pub mod bypass;
 pub mod certificate;
+pub mod client_hello;
+pub mod domain_matcher;
+pub mod http_framing;
+pub mod http_parser;
 pub mod http_service;
+pub mod interception;
+pub mod key_protector;
+pub mod leaf_cache;
+pub mod local_site;
+pub mod parent_override;
+pub mod policy;
 pub mod proxy;
+pub mod schedule;
 pub mod screen_record;
+pub mod screen_time;
 pub mod system;
+pub mod trust_store;
+pub mod url_matcher;
@@ -0,0 +1,759 @@
+// ============================================================================
+//  PARENT OVERRIDE - OFFLINE UNLOCK CODES (TOTP)
+// ============================================================================
+// A parent standing next to the child can lift filtering without the backend:
+// 1. At enrollment the parent's account and the device share a TOTP secret
+//    (base32). It is kept sealed at `OVERRIDE_SECRET_PATH` (see key_protector.rs),
+//    never in the clear: one left in the device registration by the pairing is
+//    moved there the first time it's read.
+// 2. The parent app shows the current 6-digit code (RFC 6238: HMAC-SHA1, 30 s
+//    steps). Codes of the step before and after are taken too, for clock drift.
+// 3. The code is typed in the child app or on the block page (see local_site.rs),
+//    with how long the unlock lasts and whether it's for one host or every host
+// 4. The override is an exception of the policy until it runs out (see policy.rs)
+//
+// A code works once: after a step was used, only codes of later steps are taken.
+// After `MAX_FAILED_ATTEMPTS` wrong codes within `ATTEMPT_WINDOW`, every code is
+// refused until the window moves on, so codes can't be guessed. The last step
+// taken and the wrong codes are saved with the overrides, so restarting the app
+// resets neither.
+//
+// Every override and every refused code is recorded in the activity log and its
+// history (see activity.rs). Overrides lift the blocklist and allowlist mode, not
+// the screen-time budgets.
+
+use crate::logger::ProxyLogger;
+use crate::windows::activity::{ActivityEvent, ActivityLog, ParentCodeLog};
+use crate::windows::domain_matcher::{normalize_host, DomainMatcher};
+use crate::windows::key_protector::{read_sealed, write_restricted, write_sealed, KeyProtector};
+use crate::windows::policy::{AllowException, FilterPolicy};
+use crate::windows::schedule::{Clock, SystemClock};
+use chrono::{DateTime, Duration as TimeDelta, Utc};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::{fmt, fs, io, path::Path};
+
+pub const PARENT_OVERRIDE_PATH: &str = "C:\\ProgramData\\GuardNest\\parent_override.json";
+
+// The TOTP secret shared at enrollment, sealed
+pub const OVERRIDE_SECRET_PATH: &str = "C:\\ProgramData\\GuardNest\\override.secret";
+
+// Field of the device registration a pairing may have left the secret in
+const REGISTRATION_SECRET_FIELD: &str = "override_secret";
+
+// RFC 6238 parameters, the defaults authenticator apps use
+const STEP_SECONDS: i64 = 30;
+const DIGITS: usize = 6;
+const SKEW_STEPS: i64 = 1; // Steps taken before and after the current one
+
+// Shortest secret accepted, in bytes (RFC 4226 asks for at least 128 bits)
+const MIN_SECRET_LEN: usize = 16;
+
+// Wrong codes allowed within `ATTEMPT_WINDOW` before codes are refused
+const MAX_FAILED_ATTEMPTS: usize = 5;
+const ATTEMPT_WINDOW: TimeDelta = TimeDelta::minutes(5);
+
+// Longest override, in minutes
+pub const MAX_OVERRIDE_MINUTES: u32 = 24 * 60;
+
+// Overrides kept in the history, ended ones included
+const KEEP_HISTORY: usize = 100;
+
+// Time-based one-time passwords from a shared secret
+pub struct Totp {
+    secret: Vec<u8>,
+}
+
+impl Totp {
+    // The secret as shown to authenticator apps: base32 (RFC 4648), case and
+    // spaces ignored, padding optional. None if it isn't valid or too short.
+    pub fn from_base32(secret: &str) -> Option<Totp> {
+        let mut bytes = Vec::new();
+        let mut buffer: u32 = 0;
+        let mut bits = 0;
+        for c in secret.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+            let value = match c.to_ascii_uppercase() {
+                c @ 'A'..='Z' => c as u32 - 'A' as u32,
+                c @ '2'..='7' => c as u32 - '2' as u32 + 26,
+                _ => return None,
+            };
+            buffer = (buffer << 5) | value;
+            bits += 5;
+            if bits >= 8 {
+                bits -= 8;
+                bytes.push((buffer >> bits) as u8);
+                buffer &= (1 << bits) - 1;
+            }
+        }
+        (bytes.len() >= MIN_SECRET_LEN).then_some(Totp { secret: bytes })
+    }
+
+    // The step `at` falls in
+    pub fn step_at(at: DateTime<Utc>) -> i64 {
+        at.timestamp().div_euclid(STEP_SECONDS)
+    }
+
+    // The code of `step`, zero-padded to `DIGITS` digits
+    pub fn code(&self, step: i64) -> Result<String, ErrorStack> {
+        let key = PKey::hmac(&self.secret)?;
+        let mut signer = Signer::new(MessageDigest::sha1(), &key)?;
+        signer.update(&step.to_be_bytes())?;
+        let mac = signer.sign_to_vec()?;
+
+        // Dynamic truncation (RFC 4226, section 5.3)
+        let offset = (mac[mac.len() - 1] & 0x0f) as usize;
+        let value = u32::from_be_bytes([
+            mac[offset] & 0x7f,
+            mac[offset + 1],
+            mac[offset + 2],
+            mac[offset + 3],
+        ]);
+        Ok(format!(
+            "{:0width$}",
+            value % 10u32.pow(DIGITS as u32),
+            width = DIGITS
+        ))
+    }
+
+    // The latest step around `at` whose code is `code`, if any
+    pub fn matching_step(&self, code: &str, at: DateTime<Utc>) -> Result<Option<i64>, ErrorStack> {
+        let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+        if code.len() != DIGITS || !code.bytes().all(|b| b.is_ascii_digit()) {
+            return Ok(None);
+        }
+
+        let now = Self::step_at(at);
+        for step in ((now - SKEW_STEPS)..=(now + SKEW_STEPS)).rev() {
+            if memcmp::eq(self.code(step)?.as_bytes(), code.as_bytes()) {
+                return Ok(Some(step));
+            }
+        }
+        Ok(None)
+    }
+}
+
+// What an override lets through
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "scope", rename_all = "snake_case")]
+pub enum OverrideScope {
+    All,                   // Every host: filtering is off
+    Host { host: String }, // This host and its subdomains
+}
+
+impl fmt::Display for OverrideScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OverrideScope::All => write!(f, "all sites"),
+            OverrideScope::Host { host } => write!(f, "{}", host),
+        }
+    }
+}
+
+// Where the code was typed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverrideSource {
+    App,       // The child app
+    BlockPage, // The local site, from the block page
+}
+
+impl fmt::Display for OverrideSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OverrideSource::App => write!(f, "app"),
+            OverrideSource::BlockPage => write!(f, "block page"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Override {
+    #[serde(flatten)]
+    pub scope: OverrideScope,
+    pub granted_at: DateTime<Utc>,
+    pub until: DateTime<Utc>,
+    pub source: OverrideSource,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OverrideError {
+    NotConfigured,                           // No secret was shared at enrollment
+    RateLimited { retry_at: DateTime<Utc> }, // Too many wrong codes lately
+    WrongCode,                               // Wrong, too old, or used already
+    InvalidDuration,                         // Not within 1..=`MAX_OVERRIDE_MINUTES`
+    InvalidHost,
+    Crypto(String), // The code couldn't be computed
+}
+
+impl fmt::Display for OverrideError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OverrideError::NotConfigured => write!(f, "Parent codes aren't set up on this device"),
+            OverrideError::RateLimited { retry_at } => {
+                write!(f, "Too many wrong codes, try again after {}", retry_at)
+            }
+            OverrideError::WrongCode => write!(f, "Wrong or already used code"),
+            OverrideError::InvalidDuration => write!(
+                f,
+                "The override must last 1 to {} minutes",
+                MAX_OVERRIDE_MINUTES
+            ),
+            OverrideError::InvalidHost => write!(f, "Invalid host"),
+            OverrideError::Crypto(msg) => write!(f, "Crypto error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for OverrideError {}
+
+// Parent codes as shown in the app
+#[derive(Debug, Clone, Serialize)]
+pub struct ParentOverrideStatus {
+    pub configured: bool,       // A secret was shared at enrollment
+    pub active: Vec<Override>,  // Running, newest first
+    pub history: Vec<Override>, // Every override kept, newest first
+}
+
+// Layout of the file at `PARENT_OVERRIDE_PATH`
+#[derive(Serialize, Deserialize, Default)]
+struct SavedOverrides {
+    #[serde(default)]
+    last_step: Option<i64>,
+    #[serde(default)]
+    failures: Vec<DateTime<Utc>>,
+    #[serde(default)]
+    overrides: Vec<Override>,
+}
+
+pub struct ParentOverride {
+    totp: Option<Totp>,
+    last_step: Option<i64>,        // Step of the last code taken
+    failures: Vec<DateTime<Utc>>,  // Wrong codes within `ATTEMPT_WINDOW`
+    overrides: Vec<Override>,      // Oldest first
+    activity: Option<ActivityLog>, // Where codes are recorded, once the log runs
+    clock: Arc<dyn Clock>,
+    dirty: bool, // Changed since the last save
+}
+
+impl Default for ParentOverride {
+    fn default() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+}
+
+impl ParentOverride {
+    // No secret and no overrides, reading the time from `clock` (e.g. a fixed
+    // clock in tests)
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        ParentOverride {
+            totp: None,
+            last_step: None,
+            failures: Vec::new(),
+            overrides: Vec::new(),
+            activity: None,
+            clock,
+            dirty: false,
+        }
+    }
+
+    // Sets the secret codes are checked against (None: codes are refused)
+    pub fn set_totp(&mut self, totp: Option<Totp>) {
+        self.totp = totp;
+    }
+
+    pub fn is_configured(&self) -> bool {
+        self.totp.is_some()
+    }
+
+    // Records every code typed from now on in `activity`
+    pub fn set_activity_log(&mut self, activity: Option<ActivityLog>) {
+        self.activity = activity;
+    }
+
+    // Lifts filtering for `scope` during `minutes` if `code` is the parent's
+    // current code
+    pub fn unlock(
+        &mut self,
+        code: &str,
+        scope: OverrideScope,
+        minutes: u32,
+        source: OverrideSource,
+    ) -> Result<Override, OverrideError> {
+        let at = self.clock.now();
+        let result = self.try_unlock(code, scope.clone(), minutes, source);
+        match &result {
+            Ok(granted) => ProxyLogger::log_parent_override(
+                &granted.scope.to_string(),
+                granted.until,
+                &source.to_string(),
+            ),
+            Err(e) => ProxyLogger::log_parent_code_refused(&source.to_string(), e),
+        }
+
+        if let Some(activity) = &self.activity {
+            let log = ParentCodeLog {
+                source,
+                scope: result
+                    .as_ref()
+                    .map_or(scope, |granted| granted.scope.clone()),
+                minutes,
+                until: result.as_ref().ok().map(|granted| granted.until),
+                refused: result.as_ref().err().map(|e| e.to_string()),
+            };
+            activity.emit(ActivityEvent::parent_code(at, log));
+        }
+        result
+    }
+
+    fn try_unlock(
+        &mut self,
+        code: &str,
+        scope: OverrideScope,
+        minutes: u32,
+        source: OverrideSource,
+    ) -> Result<Override, OverrideError> {
+        let totp = self.totp.as_ref().ok_or(OverrideError::NotConfigured)?;
+        if !(1..=MAX_OVERRIDE_MINUTES).contains(&minutes) {
+            return Err(OverrideError::InvalidDuration);
+        }
+        let scope = match scope {
+            OverrideScope::All => OverrideScope::All,
+            OverrideScope::Host { host } => OverrideScope::Host {
+                host: normalize_host(&host).ok_or(OverrideError::InvalidHost)?,
+            },
+        };
+
+        // Step 1: Refuse every code while too many were wrong lately
+        let now = self.clock.now();
+        self.failures
+            .retain(|failed| now - *failed < ATTEMPT_WINDOW);
+        if self.failures.len() >= MAX_FAILED_ATTEMPTS {
+            return Err(OverrideError::RateLimited {
+                retry_at: self.failures[0] + ATTEMPT_WINDOW,
+            });
+        }
+
+        // Step 2: The code must be current and newer than the last one taken
+        let step = totp
+            .matching_step(code, now)
+            .map_err(|e| OverrideError::Crypto(e.to_string()))?
+            .filter(|step| self.last_step.is_none_or(|last| *step > last));
+        let Some(step) = step else {
+            self.failures.push(now);
+            self.dirty = true;
+            return Err(OverrideError::WrongCode);
+        };
+
+        // Step 3: Record the override
+        let granted = Override {
+            scope,
+            granted_at: now,
+            until: now + TimeDelta::minutes(minutes.into()),
+            source,
+        };
+        self.last_step = Some(step);
+        self.failures.clear();
+        self.overrides.push(granted.clone());
+        if self.overrides.len() > KEEP_HISTORY {
+            self.overrides.drain(..self.overrides.len() - KEEP_HISTORY);
+        }
+        self.dirty = true;
+        Ok(granted)
+    }
+
+    // Ends every override still running. Returns true if there was one.
+    pub fn end_all(&mut self) -> bool {
+        let now = self.clock.now();
+        let mut ended = false;
+        for running in self.overrides.iter_mut().filter(|o| o.until > now) {
+            running.until = now;
+            ended = true;
+        }
+        self.dirty |= ended;
+        ended
+    }
+
+    pub fn status(&self) -> ParentOverrideStatus {
+        ParentOverrideStatus {
+            configured: self.is_configured(),
+            active: self.active(),
+            history: self.history(),
+        }
+    }
+
+    // The overrides still running, newest first
+    pub fn active(&self) -> Vec<Override> {
+        let now = self.clock.now();
+        self.overrides
+            .iter()
+            .rev()
+            .filter(|o| o.until > now)
+            .cloned()
+            .collect()
+    }
+
+    // Every override kept, newest first
+    pub fn history(&self) -> Vec<Override> {
+        self.overrides.iter().rev().cloned().collect()
+    }
+
+    // The overrides still running as policy exceptions. The policy checks the
+    // expiry on every request.
+    pub fn exceptions(&self) -> Vec<AllowException> {
+        self.active()
+            .into_iter()
+            .map(|o| AllowException {
+                rules: match &o.scope {
+                    OverrideScope::All => None,
+                    OverrideScope::Host { host } => {
+                        Some(DomainMatcher::from_rules([host.as_str()]))
+                    }
+                },
+                until: Some(o.until),
+            })
+            .collect()
+    }
+
+    // Restores the last step taken, the wrong codes and the overrides saved by
+    // `save`
+    pub fn load(&mut self, path: &Path) -> io::Result<()> {
+        let data = fs::read(path)?;
+        let saved: SavedOverrides = serde_json::from_slice(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        self.last_step = saved.last_step;
+        self.failures = saved.failures;
+        self.overrides = saved.overrides;
+        self.dirty = false;
+        Ok(())
+    }
+
+    // Writes the last step taken, the wrong codes and the overrides to disk if they
+    // changed since the last save
+    pub fn save(&mut self, path: &Path) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let saved = SavedOverrides {
+            last_step: self.last_step,
+            failures: self.failures.clone(),
+            overrides: self.overrides.clone(),
+        };
+        let data = serde_json::to_vec_pretty(&saved)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        write_restricted(path, &data)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+// Seals the secret shared at enrollment (base32) to `path`
+pub fn save_secret(path: &Path, protector: &dyn KeyProtector, secret: &str) -> io::Result<()> {
+    if Totp::from_base32(secret).is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid parent code secret",
+        ));
+    }
+    write_sealed(path, protector, secret.trim().as_bytes())
+}
+
+// Reads the secret sealed by `save_secret`
+pub fn load_secret(path: &Path, protector: &dyn KeyProtector) -> io::Result<Totp> {
+    let secret = read_sealed(path, protector)?;
+    std::str::from_utf8(&secret)
+        .ok()
+        .and_then(Totp::from_base32)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid parent code secret"))
+}
+
+// Takes a secret the pairing wrote in the clear into the device registration at
+// `registration`: seals it to `path` (see `save_secret`) and rewrites the
+// registration without it. An invalid secret is dropped. Returns true if the
+// registration held one.
+pub fn move_secret_out_of(
+    registration: &Path,
+    path: &Path,
+    protector: &dyn KeyProtector,
+) -> io::Result<bool> {
+    let invalid = |e: serde_json::Error| io::Error::new(io::ErrorKind::InvalidData, e);
+    let data = match fs::read(registration) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false), // Not paired
+        Err(e) => return Err(e),
+    };
+    let mut fields: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_slice(&data).map_err(invalid)?;
+    let Some(secret) = fields.remove(REGISTRATION_SECRET_FIELD) else {
+        return Ok(false);
+    };
+
+    // Sealed first: if rewriting the registration fails, the next try seals it again
+    match secret
+        .as_str()
+        .map(|secret| save_secret(path, protector, secret))
+    {
+        Some(Ok(())) | None => {}
+        Some(Err(e)) if e.kind() == io::ErrorKind::InvalidData => {
+            println!("⚠️ Invalid parent code secret in the device registration, dropped");
+        }
+        Some(Err(e)) => return Err(e),
+    }
+    write_restricted(
+        registration,
+        &serde_json::to_vec_pretty(&fields).map_err(invalid)?,
+    )?;
+    Ok(true)
+}
+
+// Makes the running overrides the policy's overrides
+pub fn apply_overrides(overrides: &Mutex<ParentOverride>, policy: &RwLock<FilterPolicy>) {
+    let exceptions = overrides
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .exceptions();
+    policy
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .set_overrides(exceptions);
+}
+
+// Unlocks with `code` and lets the override through `policy` right away
+pub fn unlock_filtering(
+    overrides: &Mutex<ParentOverride>,
+    policy: &RwLock<FilterPolicy>,
+    code: &str,
+    scope: OverrideScope,
+    minutes: u32,
+    source: OverrideSource,
+) -> Result<Override, OverrideError> {
+    let granted = overrides
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .unlock(code, scope, minutes, source)?;
+    apply_overrides(overrides, policy);
+    Ok(granted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::windows::activity::{ActivitySink, Decision, RequestLog};
+    use crate::windows::key_protector::FileKeyProtector;
+    use crate::windows::schedule::tests::FixedClock;
+    use std::time::{Duration, Instant};
+
+    const SECRET: &str = "JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP";
+
+    fn now() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2026-10-17T08:30:00Z")
+            .unwrap()
+            .to_utc()
+    }
+
+    fn configured(at: DateTime<Utc>) -> ParentOverride {
+        let mut overrides = ParentOverride::with_clock(Arc::new(FixedClock(at)));
+        overrides.set_totp(Totp::from_base32(SECRET));
+        overrides
+    }
+
+    fn current_code(at: DateTime<Utc>) -> String {
+        let totp = Totp::from_base32(SECRET).unwrap();
+        totp.code(Totp::step_at(at)).unwrap()
+    }
+
+    fn host(host: &str) -> OverrideScope {
+        OverrideScope::Host {
+            host: host.to_string(),
+        }
+    }
+
+    // Collects what the activity log's writer thread hands over
+    struct Collect(Arc<Mutex<Vec<ActivityEvent>>>);
+
+    impl ActivitySink for Collect {
+        fn name(&self) -> &'static str {
+            "collect"
+        }
+
+        fn record(&mut self, events: &[ActivityEvent]) -> io::Result<()> {
+            self.0.lock().unwrap().extend_from_slice(events);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn used_code_stays_used_after_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("parent_override.json");
+
+        let code = current_code(now());
+        let mut before = configured(now());
+        before
+            .unlock(&code, host("games.com"), 30, OverrideSource::App)
+            .unwrap();
+        before.save(&path).unwrap();
+
+        let later = now() + TimeDelta::seconds(10);
+        let mut after = configured(later);
+        after.load(&path).unwrap();
+        assert_eq!(after.active().len(), 1);
+        assert_eq!(
+            after.unlock(&code, OverrideScope::All, 30, OverrideSource::App),
+            Err(OverrideError::WrongCode)
+        );
+    }
+
+    #[test]
+    fn limiter_survives_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("parent_override.json");
+
+        let mut before = configured(now());
+        for _ in 0..MAX_FAILED_ATTEMPTS {
+            let refused =
+                before.unlock("000000", OverrideScope::All, 30, OverrideSource::BlockPage);
+            assert_eq!(refused, Err(OverrideError::WrongCode));
+        }
+        before.save(&path).unwrap();
+
+        // Still limited after the restart, even with the right code
+        let later = now() + TimeDelta::seconds(40);
+        let mut after = configured(later);
+        after.load(&path).unwrap();
+        assert_eq!(
+            after.unlock(
+                &current_code(later),
+                OverrideScope::All,
+                30,
+                OverrideSource::App
+            ),
+            Err(OverrideError::RateLimited {
+                retry_at: now() + ATTEMPT_WINDOW
+            })
+        );
+
+        // Until the window has moved on
+        let later = now() + ATTEMPT_WINDOW;
+        let mut after = configured(later);
+        after.load(&path).unwrap();
+        assert!(after
+            .unlock(
+                &current_code(later),
+                OverrideScope::All,
+                30,
+                OverrideSource::App
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn refused_codes_are_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("parent_override.json");
+
+        let mut overrides = configured(now());
+        let _ = overrides.unlock("123456", OverrideScope::All, 30, OverrideSource::App);
+        overrides.save(&path).unwrap();
+
+        let mut loaded = configured(now());
+        loaded.load(&path).unwrap();
+        assert_eq!(loaded.failures, [now()]);
+    }
+
+    #[test]
+    fn secret_is_sealed() {
+        let dir = tempfile::tempdir().unwrap();
+        let registration = dir.path().join("device.json");
+        let path = dir.path().join("override.secret");
+        let protector = FileKeyProtector::new(dir.path().join("master.key"));
+        fs::write(
+            &registration,
+            format!(
+                r#"{{"child_id":7,"device_token":"t","override_secret":"{}"}}"#,
+                SECRET
+            ),
+        )
+        .unwrap();
+
+        assert!(move_secret_out_of(&registration, &path, &protector).unwrap());
+        let left = fs::read_to_string(&registration).unwrap();
+        assert!(!left.contains(SECRET) && !left.contains(REGISTRATION_SECRET_FIELD));
+        assert!(left.contains("device_token"));
+        assert!(!fs::read(&path)
+            .unwrap()
+            .windows(SECRET.len())
+            .any(|window| window == SECRET.as_bytes()));
+
+        let totp = load_secret(&path, &protector).unwrap();
+        assert_eq!(
+            totp.code(1).unwrap(),
+            Totp::from_base32(SECRET).unwrap().code(1).unwrap()
+        );
+
+        // Nothing left to move, or nothing paired
+        assert!(!move_secret_out_of(&registration, &path, &protector).unwrap());
+        assert!(!move_secret_out_of(&dir.path().join("missing.json"), &path, &protector).unwrap());
+
+        // An invalid secret is dropped, not sealed
+        assert!(save_secret(&path, &protector, "too short").is_err());
+        fs::write(&registration, r#"{"child_id":7,"override_secret":"!!"}"#).unwrap();
+        assert!(move_secret_out_of(&registration, &path, &protector).unwrap());
+        assert!(!fs::read_to_string(&registration).unwrap().contains("!!"));
+        assert!(load_secret(&path, &protector).is_ok());
+    }
+
+    #[test]
+    fn codes_are_recorded() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let log = ActivityLog::start(vec![Box::new(Collect(events.clone()))]).unwrap();
+
+        let mut overrides = configured(now());
+        overrides.set_activity_log(Some(log));
+        overrides
+            .unlock(
+                &current_code(now()),
+                host("Games.com"),
+                30,
+                OverrideSource::BlockPage,
+            )
+            .unwrap();
+        let _ = overrides.unlock("000000", OverrideScope::All, 10, OverrideSource::App);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while events.lock().unwrap().len() < 2 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 2);
+
+        assert_eq!(events[0].host, "games.com");
+        assert_eq!(events[0].decision, Decision::Allowed);
+        assert_eq!(events[0].method(), "PARENT-CODE");
+        assert_eq!(events[0].rule.as_deref(), Some("parent code (block page)"));
+        let RequestLog::ParentCode(taken) = &events[0].request else {
+            panic!("not a parent code");
+        };
+        assert_eq!(taken.until, Some(now() + TimeDelta::minutes(30)));
+        assert_eq!(taken.refused, None);
+
+        assert_eq!(events[1].host, "*");
+        assert_eq!(events[1].decision, Decision::Blocked);
+        let RequestLog::ParentCode(refused) = &events[1].request else {
+            panic!("not a parent code");
+        };
+        assert_eq!(refused.until, None);
+        assert_eq!(refused.refused, Some(OverrideError::WrongCode.to_string()));
+
+        // The code itself is never recorded
+        let json = serde_json::to_string(&events[0]).unwrap();
+        assert!(!json.contains(&current_code(now())));
+    }
+}
@@ -0,0 +1,517 @@
+// ============================================================================
+//  FILTER POLICY - BLOCKLIST OR ALLOWLIST ("WHITELIST-ONLY") MODE
+// ============================================================================
+// Decides if the proxy lets a host through:
+// - Blocklist mode: everything is allowed except the domains in the list
+// - Allowlist mode: nothing is allowed except the domains in the list, plus a
+//   built-in set of infrastructure hosts the computer and the allowed sites
+//   need to keep working (OS updates, certificate checks, GuardNest itself, a few
+//   font and library hosts)
+//
+// The mode can be switched at any time; requests already in flight keep the
+// decision they got, every new request uses the new mode. It is saved, so a
+// restart keeps it.
+//
+// Besides the always-on list, rule groups can carry a schedule: they only take
+// part in the decision while the schedule is active (checked per request).
+//
+// Exceptions (approved access requests, see access_request.rs) and parent
+// overrides (see parent_override.rs) come first: a host they cover is let through
+// in either mode until they expire.
+//
+// In blocklist mode, rules with a path (see url_matcher.rs) also block part of a
+// site, for requests whose path the proxy can read. In allowlist mode the list
+// names whole hosts, path rules don't let anything through.
+
+use crate::windows::domain_matcher::{DomainMatcher, DomainRule};
+use crate::windows::http_service::fetch::BlocklistPayload;
+use crate::windows::key_protector::write_restricted;
+use crate::windows::schedule::{Clock, Schedule, ScheduleTimeZone, SystemClock};
+use crate::windows::url_matcher::{UrlMatcher, UrlRule};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::{fs, io, path::Path};
+
+// Where the mode is saved between restarts
+pub const FILTER_MODE_PATH: &str = "C:\\ProgramData\\GuardNest\\filter_mode.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterMode {
+    Blocklist, // Deny the listed domains
+    Allowlist, // Allow only the listed domains (and infrastructure)
+}
+
+impl FilterMode {
+    // Reads the mode written by `save`
+    pub fn load(path: &Path) -> io::Result<Self> {
+        serde_json::from_slice(&fs::read(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // Writes the mode where only administrators can change it, so the child can't
+    // switch allowlist mode off by editing the file
+    pub fn save(self, path: &Path) -> io::Result<()> {
+        write_restricted(path, &serde_json::to_vec(&self)?)
+    }
+}
+
+// Hosts that stay reachable in allowlist mode, as domain rules (see
+// `domain_matcher`): a plain entry also covers its subdomains, "=" only the host.
+// Only hosts run by the service itself belong here. CDNs that serve anyone's
+// content (cloudfront.net, akamaized.net, storage.googleapis.com, jsdelivr.net,
+// ...) would let whole sites through; a parent adds them to the list when an
+// allowed site needs them.
+pub const INFRASTRUCTURE_DOMAINS: &[&str] = &[
+    // GuardNest backend
+    "guardnest.app",
+    // Windows Update and connectivity checks
+    "windowsupdate.com",
+    "update.microsoft.com",
+    "delivery.mp.microsoft.com",
+    "msftconnecttest.com",
+    "msftncsi.com",
+    // Certificate revocation (OCSP / CRL), needed by every HTTPS site
+    "ocsp.digicert.com",
+    "crl3.digicert.com",
+    "crl4.digicert.com",
+    "ocsp.pki.goog",
+    "crl.pki.goog",
+    "o.lencr.org",
+    "ocsp.sectigo.com",
+    "crl.microsoft.com",
+    // Fonts and libraries most sites load, from curated hosts
+    "=fonts.googleapis.com",
+    "=fonts.gstatic.com",
+    "=ajax.googleapis.com",
+    "=cdnjs.cloudflare.com",
+];
+
+// Why a host is refused, e.g. to show on the block page
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockReason {
+    // Blocklist mode: the host matched `rule`, of the scheduled `group` if set
+    Listed {
+        rule: DomainRule,
+        group: Option<String>,
+    },
+    NotAllowed, // Allowlist mode: the host isn't on the list
+    // Blocklist mode: the request's path matched the path rule `rule`
+    ListedPath {
+        rule: UrlRule,
+        group: Option<String>,
+    },
+}
+
+// Rules let through whatever the mode, until `until` (None: for good)
+pub struct AllowException {
+    pub rules: Option<DomainMatcher>, // None: every host
+    pub until: Option<DateTime<Utc>>,
+}
+
+// A group of rules that only applies while its schedule is active
+pub struct ScheduledRules {
+    pub name: String,
+    pub rules: DomainMatcher,
+    pub paths: UrlMatcher,
+    pub schedule: Schedule,
+}
+
+// The rules from the parent's dashboard (blocked or allowed, depending on mode)
+#[derive(Default)]
+pub struct RuleSet {
+    pub domains: DomainMatcher,         // Always active
+    pub paths: UrlMatcher,              // Always active, rules with a path
+    pub scheduled: Vec<ScheduledRules>, // Rule groups with a time dimension
+    pub time_zone: ScheduleTimeZone,    // Time zone the schedules are written in
+}
+
+impl RuleSet {
+    // Builds the tries for a fetched blocklist
+    pub fn from_payload(payload: &BlocklistPayload) -> Self {
+        RuleSet {
+            domains: DomainMatcher::from_rules(&payload.rules),
+            paths: UrlMatcher::from_rules(&payload.rules),
+            scheduled: payload
+                .groups
+                .iter()
+                .map(|group| ScheduledRules {
+                    name: group.name.clone(),
+                    rules: DomainMatcher::from_rules(&group.rules),
+                    paths: UrlMatcher::from_rules(&group.rules),
+                    schedule: group.schedule.clone(),
+                })
+                .collect(),
+            time_zone: payload.time_zone,
+        }
+    }
+}
+
+// The filtering state shared by all client connections.
+pub struct FilterPolicy {
+    mode: FilterMode,
+    rules: RuleSet,
+    exceptions: Vec<AllowException>, // Checked before the rules
+    overrides: Vec<AllowException>,  // Same, set with a parent's code
+    clock: Arc<dyn Clock>,           // Where "now" comes from when checking schedules
+    infrastructure: DomainMatcher,   // Always reachable in allowlist mode
+}
+
+impl Default for FilterPolicy {
+    fn default() -> Self {
+        Self::new(FilterMode::Blocklist)
+    }
+}
+
+impl FilterPolicy {
+    pub fn new(mode: FilterMode) -> Self {
+        Self::with_clock(mode, Arc::new(SystemClock))
+    }
+
+    // Creates a policy that reads the time from `clock` (e.g. a fixed clock in tests)
+    pub fn with_clock(mode: FilterMode, clock: Arc<dyn Clock>) -> Self {
+        FilterPolicy {
+            mode,
+            rules: RuleSet::default(),
+            exceptions: Vec::new(),
+            overrides: Vec::new(),
+            clock,
+            infrastructure: DomainMatcher::from_rules(INFRASTRUCTURE_DOMAINS),
+        }
+    }
+
+    pub fn mode(&self) -> FilterMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: FilterMode) {
+        self.mode = mode;
+    }
+
+    // Replaces the rules (build the `RuleSet` before taking the write lock)
+    pub fn set_rules(&mut self, rules: RuleSet) {
+        self.rules = rules;
+    }
+
+    // Replaces the exceptions, e.g. after a parent approved an access request
+    pub fn set_exceptions(&mut self, exceptions: Vec<AllowException>) {
+        self.exceptions = exceptions;
+    }
+
+    // Replaces the parent overrides
+    pub fn set_overrides(&mut self, overrides: Vec<AllowException>) {
+        self.overrides = overrides;
+    }
+
+    // Replaces the always-on rules after a delta from the server, keeping the
+    // scheduled groups. The tries are built from the complete list the delta
+    // produced, not patched with it: "example.com" and "||example.com" are one
+    // rule, so removing one spelling must not drop the other.
+    pub fn set_always_on(&mut self, domains: DomainMatcher, paths: UrlMatcher) {
+        self.rules.domains = domains;
+        self.rules.paths = paths;
+    }
+
+    // Checks the always-on list and every scheduled group active right now.
+    // Returns the matching rule, and the name of its group if it's scheduled.
+    fn listed(&self, host: &str) -> Option<(DomainRule, Option<&str>)> {
+        if let Some(rule) = self.rules.domains.find(host) {
+            return Some((rule, None));
+        }
+
+        // Read the clock once, so every group is checked against the same instant
+        let local = self.rules.time_zone.local_time(self.clock.now());
+        self.rules
+            .scheduled
+            .iter()
+            .filter(|group| group.schedule.is_active_at(local))
+            .find_map(|group| Some((group.rules.find(host)?, Some(group.name.as_str()))))
+    }
+
+    // Same as `listed`, for the path rules
+    fn listed_path(&self, host: &str, target: &str) -> Option<(UrlRule, Option<&str>)> {
+        if let Some(rule) = self.rules.paths.find(host, target) {
+            return Some((rule.clone(), None));
+        }
+
+        let local = self.rules.time_zone.local_time(self.clock.now());
+        self.rules
+            .scheduled
+            .iter()
+            .filter(|group| !group.paths.is_empty() && group.schedule.is_active_at(local))
+            .find_map(|group| {
+                let rule = group.paths.find(host, target)?;
+                Some((rule.clone(), Some(group.name.as_str())))
+            })
+    }
+
+    // Whether an exception or override that hasn't expired covers `host`
+    fn excepted(&self, host: &str) -> bool {
+        if self.exceptions.is_empty() && self.overrides.is_empty() {
+            return false;
+        }
+        let now = self.clock.now();
+        self.exceptions
+            .iter()
+            .chain(&self.overrides)
+            .any(|exception| {
+                exception.until.is_none_or(|until| now < until)
+                    && exception
+                        .rules
+                        .as_ref()
+                        .is_none_or(|rules| rules.matches(host))
+            })
+    }
+
+    // Decides if a request to `host` must be refused under the current mode.
+    pub fn is_blocked(&self, host: &str) -> bool {
+        self.block_reason(host).is_some()
+    }
+
+    // Same as `is_blocked`, telling why
+    pub fn block_reason(&self, host: &str) -> Option<BlockReason> {
+        if self.excepted(host) {
+            return None;
+        }
+        match self.mode {
+            FilterMode::Blocklist => self.listed(host).map(|(rule, group)| BlockReason::Listed {
+                rule,
+                group: group.map(String::from),
+            }),
+            FilterMode::Allowlist
+                if self.listed(host).is_some() || self.infrastructure.matches(host) =>
+            {
+                None
+            }
+            FilterMode::Allowlist => Some(BlockReason::NotAllowed),
+        }
+    }
+
+    // Same as `block_reason`, for a request whose path is known (plain HTTP, or an
+    // intercepted tunnel). `target` is the request target in origin form, e.g.
+    // "/shorts/abc?x=1".
+    pub fn request_block_reason(&self, host: &str, target: &str) -> Option<BlockReason> {
+        let reason = self.block_reason(host);
+        if reason.is_some() || self.mode != FilterMode::Blocklist || self.excepted(host) {
+            return reason;
+        }
+        self.listed_path(host, target)
+            .map(|(rule, group)| BlockReason::ListedPath {
+                rule,
+                group: group.map(String::from),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(mode: FilterMode, rules: &[&str]) -> FilterPolicy {
+        let mut policy = FilterPolicy::new(mode);
+        policy.set_rules(RuleSet {
+            domains: DomainMatcher::from_rules(rules),
+            ..RuleSet::default()
+        });
+        policy
+    }
+
+    #[test]
+    fn blocklist_mode() {
+        let policy = policy(FilterMode::Blocklist, &["games.com"]);
+        assert!(policy.is_blocked("www.games.com"));
+        assert!(!policy.is_blocked("school.org"));
+        assert_eq!(
+            policy.block_reason("games.com"),
+            Some(BlockReason::Listed {
+                rule: DomainRule::parse("games.com").unwrap(),
+                group: None,
+            })
+        );
+    }
+
+    #[test]
+    fn allowlist_mode() {
+        let policy = policy(FilterMode::Allowlist, &["school.org"]);
+        assert!(!policy.is_blocked("www.school.org"));
+        assert_eq!(
+            policy.block_reason("games.com"),
+            Some(BlockReason::NotAllowed)
+        );
+
+        // Infrastructure the computer needs stays reachable
+        assert!(!policy.is_blocked("fe2.update.microsoft.com"));
+        assert!(!policy.is_blocked("ocsp.digicert.com"));
+        assert!(!policy.is_blocked("fonts.googleapis.com"));
+        // but not CDNs and hosts that serve anyone's content
+        for host in [
+            "storage.googleapis.com",
+            "d1234.cloudfront.net",
+            "cdn.jsdelivr.net",
+            "unpkg.com",
+            "a.akamaized.net",
+            "games.pages.cloudflare.com",
+        ] {
+            assert!(policy.is_blocked(host), "{}", host);
+        }
+    }
+
+    #[test]
+    fn exceptions_come_first() {
+        let mut policy = policy(FilterMode::Blocklist, &["games.com"]);
+        policy.set_exceptions(vec![AllowException {
+            rules: Some(DomainMatcher::from_rules(["games.com"])),
+            until: None,
+        }]);
+        assert!(!policy.is_blocked("games.com"));
+
+        // Expired overrides don't count
+        policy.set_exceptions(Vec::new());
+        policy.set_overrides(vec![AllowException {
+            rules: None,
+            until: Some(Utc::now() - chrono::Duration::minutes(1)),
+        }]);
+        assert!(policy.is_blocked("games.com"));
+    }
+
+    #[test]
+    fn scheduled_groups_follow_the_clock() {
+        use crate::windows::schedule::tests::FixedClock;
+        use crate::windows::schedule::{ScheduleTimeZone, TimeRange};
+        use chrono::{NaiveTime, TimeZone, Weekday};
+
+        let policy_at = |hour| {
+            // Monday 2026-10-12, in UTC+02:00
+            let now = Utc.with_ymd_and_hms(2026, 10, 12, hour, 0, 0).unwrap();
+            let mut policy =
+                FilterPolicy::with_clock(FilterMode::Blocklist, Arc::new(FixedClock(now)));
+            policy.set_rules(RuleSet {
+                domains: DomainMatcher::from_rules(["always.com"]),
+                paths: UrlMatcher::default(),
+                scheduled: vec![ScheduledRules {
+                    name: "school nights".to_string(),
+                    rules: DomainMatcher::from_rules(["games.com"]),
+                    paths: UrlMatcher::default(),
+                    schedule: Schedule {
+                        days: vec![Weekday::Sun, Weekday::Mon],
+                        ranges: vec![TimeRange {
+                            start: NaiveTime::from_hms_opt(19, 0, 0).unwrap(),
+                            end: NaiveTime::from_hms_opt(7, 0, 0).unwrap(),
+                        }],
+                    },
+                }],
+                time_zone: ScheduleTimeZone::UtcOffsetMinutes(120),
+            });
+            policy
+        };
+
+        // 04:00 UTC is 06:00 local: Sunday's range still runs
+        let policy = policy_at(4);
+        assert_eq!(
+            policy.block_reason("www.games.com"),
+            Some(BlockReason::Listed {
+                rule: DomainRule::parse("games.com").unwrap(),
+                group: Some("school nights".to_string()),
+            })
+        );
+        // 05:00 UTC is 07:00 local: over until the evening
+        let policy = policy_at(5);
+        assert!(!policy.is_blocked("games.com"));
+        assert!(policy.is_blocked("always.com"));
+        // 17:00 UTC is 19:00 local
+        assert!(policy_at(17).is_blocked("games.com"));
+
+        // In allowlist mode an active group allows its sites
+        let mut policy = policy_at(17);
+        policy.set_mode(FilterMode::Allowlist);
+        assert!(!policy.is_blocked("games.com"));
+        let mut policy = policy_at(12);
+        policy.set_mode(FilterMode::Allowlist);
+        assert!(policy.is_blocked("games.com"));
+    }
+
+    #[test]
+    fn path_rules() {
+        let mut policy = FilterPolicy::new(FilterMode::Blocklist);
+        policy.set_rules(RuleSet::from_payload(&BlocklistPayload {
+            rules: ["games.com", "youtube.com/shorts"]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            ..BlocklistPayload::default()
+        }));
+
+        // Only requests for the path are blocked, the host itself isn't
+        assert!(!policy.is_blocked("youtube.com"));
+        assert_eq!(
+            policy.request_block_reason("youtube.com", "/watch?v=1"),
+            None
+        );
+        assert_eq!(
+            policy.request_block_reason("www.youtube.com", "/shorts/abc"),
+            Some(BlockReason::ListedPath {
+                rule: UrlRule::parse("youtube.com/shorts").unwrap(),
+                group: None,
+            })
+        );
+        // Host rules still come first
+        assert!(matches!(
+            policy.request_block_reason("games.com", "/"),
+            Some(BlockReason::Listed { .. })
+        ));
+
+        // Deltas replace path rules too
+        let rules = ["games.com", "=example.com/x"];
+        policy.set_always_on(
+            DomainMatcher::from_rules(rules),
+            UrlMatcher::from_rules(rules),
+        );
+        assert_eq!(policy.request_block_reason("youtube.com", "/shorts"), None);
+        assert!(policy.request_block_reason("example.com", "/x/y").is_some());
+        assert!(policy.is_blocked("games.com"));
+
+        // An exception for the host lets every path through
+        policy.set_exceptions(vec![AllowException {
+            rules: Some(DomainMatcher::from_rules(["example.com"])),
+            until: None,
+        }]);
+        assert_eq!(policy.request_block_reason("example.com", "/x"), None);
+
+        // In allowlist mode the list names allowed hosts, path rules don't apply
+        let mut allowlist = FilterPolicy::new(FilterMode::Allowlist);
+        allowlist.set_rules(RuleSet {
+            domains: DomainMatcher::from_rules(["youtube.com"]),
+            paths: UrlMatcher::from_rules(["youtube.com/shorts"]),
+            ..RuleSet::default()
+        });
+        assert_eq!(
+            allowlist.request_block_reason("youtube.com", "/shorts"),
+            None
+        );
+        assert_eq!(
+            allowlist.request_block_reason("games.com", "/"),
+            Some(BlockReason::NotAllowed)
+        );
+    }
+
+    #[test]
+    fn mode_is_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("filter_mode.json");
+        assert_eq!(
+            FilterMode::load(&path).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        FilterMode::Allowlist.save(&path).unwrap();
+        assert_eq!(FilterMode::load(&path).unwrap(), FilterMode::Allowlist);
+        FilterMode::Blocklist.save(&path).unwrap();
+        assert_eq!(FilterMode::load(&path).unwrap(), FilterMode::Blocklist);
+
+        fs::write(&path, "\"off\"").unwrap();
+        assert_eq!(
+            FilterMode::load(&path).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
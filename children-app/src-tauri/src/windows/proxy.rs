@@ -3,18 +3,63 @@
 // ============================================================================
 // This file implements a local proxy server that:
 // 1. Intercepts HTTP/HTTPS requests from the browser
-// 2. Checks domains against a blocklist (malicious sites)
+// 2. Checks domains against a blocklist (malicious sites), or an allowlist in allowlist mode
 // 3. Forwards allowed requests to their destinations
 // 4. Blocks requests to malicious domains
 // 5. Manages Windows system proxy settings automatically
 
 use crate::logger::ProxyLogger;
+use crate::windows::access_request::{AccessRequest, AccessRequests, ACCESS_REQUESTS_PATH};
+use crate::windows::activity::{
+    ActivityEvent, ActivityLog, ActivitySink, Decision, FileSink, Outcome, RequestActivity,
+    StdoutSink, ACTIVITY_LOG_PATH, MAX_LOG_FILE_SIZE,
+};
+use crate::windows::activity_store::{
+    ActivityPage, ActivityStore, PageRequest, Retention, StoreError, TimeRange, ACTIVITY_DB_PATH,
+};
+use crate::windows::block_page::{block_page, BlockCause};
+use crate::windows::bypass::{BypassConfig, BypassStatus, InterceptBypass, INTERCEPT_BYPASS_PATH};
+use crate::windows::certificate::{CaFiles, CertificateAuthority};
+use crate::windows::client_hello::{read_client_hello, HelloError, ACCESS_DENIED_ALERT};
+use crate::windows::domain_matcher::{normalize_host, DomainMatcher};
+use crate::windows::http_framing::{
+    copy_body, has_header_token, header_value, parse_headers, read_head, request_body_length,
+    response_body_length, wants_keep_alive, BodyLength, MAX_HEAD_SIZE,
+};
+use crate::windows::http_parser::{read_request_head, HeadLimits, HeadParseError, HttpRequestHead};
 use crate::windows::http_service;
+use crate::windows::http_service::access::{self, SentRequest};
+use crate::windows::http_service::backoff::Backoff;
+use crate::windows::http_service::cache::{
+    self, CacheError, CachedBlocklist, VersionFloor, BLOCKLIST_CACHE_PATH, VERSION_FLOOR_PATH,
+};
+use crate::windows::http_service::client::{
+    BackendClient, BackendError, DeviceConfig, DEVICE_CONFIG_PATH,
+};
+use crate::windows::http_service::fetch::{BlocklistPayload, SyncOutcome};
+use crate::windows::http_service::report;
+use crate::windows::http_service::signature::{KeySet, SignedBlocklist};
+use crate::windows::interception::{Interceptor, PrefixedStream};
+use crate::windows::key_protector::{default_protector, KeyProtector};
+use crate::windows::leaf_cache::{
+    LeafCache, DEFAULT_LEAF_CAPACITY, LEAF_CACHE_KEY_PATH, LEAF_CACHE_PATH,
+};
+use crate::windows::local_site::{self, is_local_host};
+use crate::windows::parent_override::{
+    self, Override, OverrideScope, OverrideSource, ParentOverride, ParentOverrideStatus,
+    OVERRIDE_SECRET_PATH, PARENT_OVERRIDE_PATH,
+};
+use crate::windows::policy::{FilterMode, FilterPolicy, RuleSet, FILTER_MODE_PATH};
+use crate::windows::screen_time::{
+    ActivityMeter, BudgetCheck, BudgetSet, BudgetUsage, ScreenTime, SCREEN_TIME_PATH,
+};
 use crate::windows::system::WindowsSystemProxy;
-use once_cell::sync::Lazy;
-use std::collections::HashSet;
-use std::sync::{Arc, Mutex, RwLock};
-use std::{io, net::SocketAddr, time::Duration};
+use crate::windows::url_matcher::UrlMatcher;
+use chrono::{Local, Utc};
+use once_cell::sync::{Lazy, OnceCell};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::{io, net::SocketAddr, path::Path, time::Duration};
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::{TcpListener, TcpStream as TokioTcpStream},
@@ -22,9 +67,16 @@ use tokio::{
     task::JoinHandle,
     time::{sleep, timeout},
 };
+use tokio_native_tls::TlsStream;
 
 static PROXY_PORT: u16 = 3000;
 
+// How often the blocklist is synced with the backend while everything works
+const SYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+// Most activity events uploaded in one request
+const ACTIVITY_UPLOAD_BATCH: usize = 500;
+
 // Broadcasts a shutdown signal to all listeners, enabling a graceful exitfrom multiple tasks.
 // The channel is lazily initialized and thread-safe.
 static SHUTDOWN_TX: Lazy<broadcast::Sender<()>> = Lazy::new(|| {
@@ -36,13 +88,169 @@ static SHUTDOWN_TX: Lazy<broadcast::Sender<()>> = Lazy::new(|| {
 // It prevents multiple threads from trying to modify the handle at the same time.
 static PROXY_TASK_HANDLE: Lazy<Mutex<Option<JoinHandle<()>>>> = Lazy::new(|| Mutex::new(None));
 
+// The filtering policy (domain list + blocklist/allowlist mode) used by the proxy.
+// Kept outside `run_proxy` so Tauri commands can switch the mode without a restart.
+static FILTER_POLICY: Lazy<Arc<RwLock<FilterPolicy>>> =
+    Lazy::new(|| Arc::new(RwLock::new(FilterPolicy::default())));
+
+// Daily screen-time budgets and the time used today, shared by all tunnels.
+// Kept outside `run_proxy` so the counters survive a proxy restart.
+static SCREEN_TIME: Lazy<Arc<Mutex<ScreenTime>>> =
+    Lazy::new(|| Arc::new(Mutex::new(ScreenTime::default())));
+
+// Hosts that are tunneled even when TLS interception is on, shared by every
+// interceptor so the learned hosts survive switching interception off and on
+static INTERCEPT_BYPASS: Lazy<Arc<Mutex<InterceptBypass>>> =
+    Lazy::new(|| Arc::new(Mutex::new(InterceptBypass::default())));
+
+// Access requests sent from the block page and the parent's answers. Approved ones
+// are exceptions in `FILTER_POLICY`.
+static ACCESS_REQUESTS: Lazy<Arc<Mutex<AccessRequests>>> =
+    Lazy::new(|| Arc::new(Mutex::new(AccessRequests::default())));
+
+// Filtering lifted with a parent's code. Running overrides are exceptions in
+// `FILTER_POLICY`; wrong codes are counted here, so a restart doesn't reset them.
+static PARENT_OVERRIDE: Lazy<Arc<Mutex<ParentOverride>>> =
+    Lazy::new(|| Arc::new(Mutex::new(ParentOverride::default())));
+
+// TLS interception, off unless switched on with `set_tls_interception`.
+// Connections accepted while it is set decrypt their CONNECT tunnels.
+static INTERCEPTOR: Lazy<RwLock<Option<Arc<Interceptor>>>> = Lazy::new(|| RwLock::new(None));
+
+// What the proxy did with every request (see activity.rs). Started by `run_proxy`
+// and kept across restarts; nothing is recorded before.
+static ACTIVITY_LOG: Lazy<RwLock<Option<ActivityLog>>> = Lazy::new(|| RwLock::new(None));
+
+// The activity history on disk, read by the app and the updater. Opened by the
+// first one that needs it (see `activity_store`).
+static ACTIVITY_STORE: OnceCell<Arc<Mutex<ActivityStore>>> = OnceCell::new();
+
 // The main server function. It creates a TCP listener, starts background tasks,
 // and handles all incoming connections and graceful shutdown.
 pub async fn run_proxy(mut shutdown_rx: broadcast::Receiver<()>) -> io::Result<()> {
 
 
-    // bloclist as a hashset that stores unique values 
-    let blocklist = Arc::new(RwLock::new(HashSet::<String>::new()));
+    // Filtering policy shared with every connection: the domain list (a trie of domain
+    // rules, so subdomains are covered too) and the blocklist/allowlist mode.
+    // It lives in a static so the mode can be switched while the proxy is running.
+    let policy = FILTER_POLICY.clone();
+    match FilterMode::load(Path::new(FILTER_MODE_PATH)) {
+        Ok(mode) => policy
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .set_mode(mode),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {} // Never switched
+        Err(e) => ProxyLogger::log_error("loading filter mode", &e),
+    }
+
+    // Size limits for request heads sent by the browser (defaults allow large cookies)
+    let head_limits = HeadLimits::default();
+
+    // Screen-time counters, restored from disk so a restart doesn't reset today's usage
+    let screen_time = SCREEN_TIME.clone();
+    match screen_time
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .load(Path::new(SCREEN_TIME_PATH))
+    {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {} // First run
+        Err(e) => ProxyLogger::log_error("loading screen time", &e),
+    }
+
+    // Interception bypass settings and the hosts learned as pinned
+    let bypass = INTERCEPT_BYPASS.clone();
+    match bypass
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .load(Path::new(INTERCEPT_BYPASS_PATH))
+    {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {} // First run
+        Err(e) => ProxyLogger::log_error("loading interception bypass list", &e),
+    }
+
+    // Public keys the backend signs rule sets (and access grants) with, and the
+    // child they must have been signed for
+    let keys = KeySet::pinned();
+    let registered_child = DeviceConfig::load(Path::new(DEVICE_CONFIG_PATH))
+        .ok()
+        .map(|config| config.child_id);
+
+    // Access requests and the approvals still letting sites through
+    let access_requests = ACCESS_REQUESTS.clone();
+    match access_requests
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .load(Path::new(ACCESS_REQUESTS_PATH), &keys, registered_child)
+    {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {} // First run
+        Err(e) => ProxyLogger::log_error("loading access requests", &e),
+    }
+    apply_exceptions(&access_requests, &policy);
+
+    // Overrides a parent unlocked with their code, and the secret to check codes with
+    let overrides = PARENT_OVERRIDE.clone();
+    match overrides
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .load(Path::new(PARENT_OVERRIDE_PATH))
+    {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {} // First run
+        Err(e) => ProxyLogger::log_error("loading parent overrides", &e),
+    }
+    load_override_secret(&overrides);
+    parent_override::apply_overrides(&overrides, &policy);
+
+    // Every request, and every parent code, is recorded from here on
+    start_activity_log();
+    overrides
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .set_activity_log(
+            ACTIVITY_LOG
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone(),
+        );
+
+    // Highest rule set version accepted so far, an older cache is a rollback
+    let protector = default_protector();
+    let mut floor = match VersionFloor::load(Path::new(VERSION_FLOOR_PATH), &*protector) {
+        Ok(floor) => floor,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => VersionFloor::default(), // First run
+        Err(e) => {
+            ProxyLogger::log_error("loading blocklist version floor", &e);
+            VersionFloor::default()
+        }
+    };
+
+    // Rules from the last run, loaded before accepting connections so the proxy
+    // filters from the start, even if the backend can't be reached.
+    // A missing, damaged, unsigned or rolled back cache only means we start without
+    // rules until the first sync.
+    let cached = match cache::load(Path::new(BLOCKLIST_CACHE_PATH), &keys, &floor) {
+        Ok(cached) if registered_child.is_some_and(|child| child != cached.blocklist.child_id) => {
+            println!("⚠️ Cached blocklist belongs to another child, ignored");
+            None
+        }
+        Ok(cached) => {
+            raise_version_floor(&mut floor, &cached.blocklist, &*protector);
+            apply_payload(&cached.blocklist.payload, &policy, &screen_time);
+            println!(
+                "📦 Loaded cached blocklist (version {})",
+                cached.blocklist.version
+            );
+            Some(cached)
+        }
+        Err(CacheError::Io(e)) if e.kind() == io::ErrorKind::NotFound => None, // First run
+        Err(e) => {
+            ProxyLogger::log_error("loading blocklist cache", &e);
+            None
+        }
+    };
 
     // Create the address our proxy will listen on (localhost port 3000)
     let proxy_address = SocketAddr::from(([127, 0, 0, 1], PROXY_PORT));
@@ -73,39 +281,177 @@ pub async fn run_proxy(mut shutdown_rx: broadcast::Receiver<()>) -> io::Result<(
 
     // This background task periodically fetches the latest list of domains from our server
 
-    let blocklist_clone = blocklist.clone(); // Create another reference to the same blocklist
+    let policy_clone = policy.clone(); // Create another reference to the same policy
+    let screen_time_clone = screen_time.clone();
+    let bypass_clone = bypass.clone();
+    let access_requests_clone = access_requests.clone();
+    let overrides_clone = overrides.clone();
     let mut updater_shutdown_rx = SHUTDOWN_TX.subscribe(); // Subscribe to shutdown signals
     tokio::spawn(async move {
+        // Version and ETag of the rules we have, so the server only sends what changed.
+        // Resumed from the cache if there is one.
+        let mut cursor = cached
+            .as_ref()
+            .map(CachedBlocklist::cursor)
+            .unwrap_or_default();
+
+        // The verified rules the policy was last built from, kept up to date with every
+        // delta. New rules are checked against them (same child, no older version).
+        let mut current: Option<SignedBlocklist> = cached.map(|cached| cached.blocklist);
+
+        // Client for the GuardNest backend, created once the device is registered
+        let mut backend: Option<BackendClient> = None;
+
+        // Failed fetches are retried sooner than the regular interval, then less and less often
+        let mut backoff = Backoff::new(Duration::from_secs(5), Duration::from_secs(5 * 60));
+
         loop {
             
-            // `spawn_blocking` runs blocking code (like network I/O) on a dedicated thread pool.
-            // This prevents the main async runtime from being blocked, ensuring the proxy remains responsive.
-            let fetch_result =
-                tokio::task::spawn_blocking(http_service::fetch::proxy_fetch_blocklist).await;
-
-            match fetch_result {
-                Ok(new_blocked_addresses) => {
-                    // Obtain a write lock on the blocklist. `unwrap()` is used because a poisoned lock
-                    // indicates a fatal, unrecoverable error.
-                    let mut current = blocklist_clone.write().unwrap();
-
-                    // Only update if the blocklist actually changed (avoid unnecessary work)
-                    if *current != new_blocked_addresses {
-                        *current = new_blocked_addresses; // Replace the old blocklist with the new one
-                        println!("Blocklist updated");
+            // The device may be paired while the proxy is running, so the registration
+            // is read again on every round until it succeeds
+            let client = match backend.take() {
+                Some(client) => Ok(client),
+                None => {
+                    load_override_secret(&overrides_clone);
+                    BackendClient::from_device_config()
+                }
+            };
+
+            // The request runs asynchronously, the proxy keeps serving connections meanwhile
+            let fetch_result = match client {
+                Ok(client) => {
+                    // Rules cached for another child can't be patched, start over
+                    if current.as_ref().map(|current| current.child_id) != Some(client.child_id()) {
+                        cursor.reset();
+                    }
+                    let result = http_service::fetch::proxy_fetch_blocklist(
+                        &client,
+                        &keys,
+                        &mut cursor,
+                        current.as_ref(),
+                    )
+                    .await;
+                    backend = Some(client);
+                    result
+                }
+                Err(e) => Err(e),
+            };
+
+            let wait = match fetch_result {
+                Ok(outcome) => {
+                    backoff.reset();
+                    let blocklist = match outcome {
+                        // Nothing changed since the last sync
+                        SyncOutcome::NotModified => None,
+
+                        // Only the always-on rules changed: rebuild their tries from the
+                        // complete list before taking the lock, so it is only held for a
+                        // moment. The scheduled groups stay as they are.
+                        SyncOutcome::Delta {
+                            blocklist,
+                            added,
+                            removed,
+                        } => {
+                            let rules = &blocklist.payload.rules;
+                            let domains = DomainMatcher::from_rules(rules);
+                            let paths = UrlMatcher::from_rules(rules);
+                            policy_clone
+                                .write()
+                                .unwrap_or_else(PoisonError::into_inner)
+                                .set_always_on(domains, paths);
+                            println!(
+                                "Blocklist patched: {} added, {} removed",
+                                added.len(),
+                                removed.len()
+                            );
+                            Some(blocklist)
+                        }
+
+                        // The complete rule set
+                        SyncOutcome::Full(blocklist) => {
+                            // Only update if the blocklist actually changed (avoid unnecessary work)
+                            if current.as_ref().map(|current| &current.payload)
+                                != Some(&blocklist.payload)
+                            {
+                                apply_payload(
+                                    &blocklist.payload,
+                                    &policy_clone,
+                                    &screen_time_clone,
+                                );
+                                println!("Blocklist updated");
+                            }
+                            Some(blocklist)
+                        }
+                    };
+
+                    // Keep the new rules and sync position for the next start
+                    if let Some(blocklist) = blocklist {
+                        let cached = CachedBlocklist {
+                            blocklist,
+                            etag: cursor.etag.clone(),
+                        };
+                        if let Err(e) = cache::save(Path::new(BLOCKLIST_CACHE_PATH), &cached).await
+                        {
+                            ProxyLogger::log_error("saving blocklist cache", &e);
+                        }
+                        raise_version_floor(&mut floor, &cached.blocklist, &*protector);
+                        current = Some(cached.blocklist);
+                    }
+                    SYNC_INTERVAL
+                }
+                Err(e) => {
+                    // Keep filtering with the rules we already have
+                    if let BackendError::Unauthorized = e {
+                        backend = None; // Re-read the registration, the token may have been renewed
+                        cursor.reset();
                     }
+                    ProxyLogger::log_error("fetching blocklist", &e);
+                    backoff.next_delay()
                 }
-                Err(e) => ProxyLogger::log_error("fetching blocklist", &e.to_string()),
+            };
+
+            // Tell the parent dashboard about hosts learned as pinned and the recorded
+            // activity. Kept for the next round if the backend can't be reached.
+            // Access requests go out the same way, and the parent's answers come back.
+            if let Some(client) = backend.as_ref() {
+                report_pinned_hosts(client, &bypass_clone).await;
+                upload_activity(client).await;
+                sync_access_requests(client, &keys, &access_requests_clone, &policy_clone).await;
             }
 
-            // Wait for either shutdown signal or 30 seconds to pass
+            // Wait for either shutdown signal or the next sync to be due
             tokio::select! {
                 _ = updater_shutdown_rx.recv() => break,  // Shutdown signal - exit loop
-                _ = tokio::time::sleep(Duration::from_secs(30)) => {}  // 30 seconds passed - continue loop
+                _ = tokio::time::sleep(wait) => {}  // Time for the next sync
             }
         }
     });
 
+    // This background task saves the screen-time counters, so at most a minute of usage
+    // is lost if the app is killed. New leaf certificates, hosts learned as pinned,
+    // access requests and parent overrides are saved along with them, and the activity
+    // history is pruned.
+    let screen_time_clone = screen_time.clone();
+    let bypass_clone = bypass.clone();
+    let access_requests_clone = access_requests.clone();
+    let overrides_clone = overrides.clone();
+    let policy_clone = policy.clone();
+    let mut saver_shutdown_rx = SHUTDOWN_TX.subscribe();
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = saver_shutdown_rx.recv() => break,
+                _ = sleep(Duration::from_secs(60)) => {
+                    save_screen_time(&screen_time_clone);
+                    save_leaf_cache();
+                    save_bypass(&bypass_clone);
+                    prune_access_requests(&access_requests_clone, &policy_clone);
+                    save_parent_overrides(&overrides_clone);
+                    prune_activity();
+                }
+            }
+        }
+    });
 
     // This is the core of our proxy server - it continuously accepts new connections
     // and handles them concurrently (multiple connections at the same time)
@@ -117,6 +463,11 @@ pub async fn run_proxy(mut shutdown_rx: broadcast::Receiver<()>) -> io::Result<(
             // OPTION 1: Shutdown signal received
             _ = shutdown_rx.recv() => {
                 println!("Proxy server shutting down.");
+                save_screen_time(&screen_time);
+                save_leaf_cache();
+                save_bypass(&bypass);
+                save_access_requests(&access_requests);
+                save_parent_overrides(&overrides);
                 break;  // Exit the loop and shut down gracefully
             }
 
@@ -128,13 +479,18 @@ pub async fn run_proxy(mut shutdown_rx: broadcast::Receiver<()>) -> io::Result<(
                         let peer_addr = client_stream.peer_addr().unwrap_or(proxy_address);
                         println!("Connection accepted from {}", peer_addr);
 
-                        // Create a new reference to the blocklist for this connection
-                        let blocklist_clone = blocklist.clone();
+                        // Create new references to the policy and screen time for this connection
+                        let policy_clone = policy.clone();
+                        let screen_time_clone = screen_time.clone();
+                        let interceptor = INTERCEPTOR
+                            .read()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .clone();
 
                         // Spawn a new asynchronous task for each client connection.
                         //  This allows to handle multiple connections concurrently without blocking
                         tokio::spawn(async move {
-                            if let Err(e) = handle_client(client_stream, peer_addr, blocklist_clone).await {
+                            if let Err(e) = handle_client(client_stream, peer_addr, policy_clone, screen_time_clone, head_limits, interceptor).await {
                                 ProxyLogger::log_error("client handling", &e);
                             }
                         });
@@ -151,6 +507,300 @@ pub async fn run_proxy(mut shutdown_rx: broadcast::Receiver<()>) -> io::Result<(
     Ok(()) // Return success when the loop exits (shutdown complete)
 }
 
+// Replaces the filtering rules and screen-time budgets with a full payload
+fn apply_payload(
+    payload: &BlocklistPayload,
+    policy: &RwLock<FilterPolicy>,
+    screen_time: &Mutex<ScreenTime>,
+) {
+    // Build the new tries before taking the lock, so lookups aren't held up
+    let rules = RuleSet::from_payload(payload);
+    let budgets = BudgetSet::from_payload(payload);
+
+    // Replace the old blocklist with the new one
+    policy
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .set_rules(rules);
+    screen_time
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .set_budgets(budgets);
+}
+
+// Writes the screen-time counters to disk (only if they changed)
+fn save_screen_time(screen_time: &Mutex<ScreenTime>) {
+    if let Err(e) = screen_time
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .save(Path::new(SCREEN_TIME_PATH))
+    {
+        ProxyLogger::log_error("saving screen time", &e);
+    }
+}
+
+// Writes the interception bypass settings and learned hosts to disk (only if they changed)
+fn save_bypass(bypass: &Mutex<InterceptBypass>) {
+    if let Err(e) = bypass
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .save(Path::new(INTERCEPT_BYPASS_PATH))
+    {
+        ProxyLogger::log_error("saving interception bypass list", &e);
+    }
+}
+
+// Sends the hosts learned as pinned that the parent dashboard hasn't seen yet
+async fn report_pinned_hosts(client: &BackendClient, bypass: &Mutex<InterceptBypass>) {
+    let unreported = bypass
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .unreported();
+    if unreported.is_empty() {
+        return;
+    }
+
+    match report::report_pinned_hosts(client, &unreported).await {
+        Ok(()) => bypass
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .mark_reported(&unreported),
+        Err(e) => ProxyLogger::log_error("reporting pinned hosts", &e),
+    }
+}
+
+// Uploads the activity stored since the last round, in batches. What the
+// backend didn't take stays in the store for the next round.
+async fn upload_activity(client: &BackendClient) {
+    let store = match activity_store() {
+        Ok(store) => store,
+        Err(e) => return ProxyLogger::log_error("opening the activity store", &e),
+    };
+    let lock = || store.lock().unwrap_or_else(PoisonError::into_inner);
+
+    loop {
+        let pending = match lock().pending_upload(ACTIVITY_UPLOAD_BATCH) {
+            Ok(pending) => pending,
+            Err(e) => return ProxyLogger::log_error("reading activity to upload", &e),
+        };
+        let Some(last) = pending.last().map(|stored| stored.id) else {
+            return; // All uploaded
+        };
+        let events: Vec<ActivityEvent> = pending.into_iter().map(|stored| stored.event).collect();
+
+        if let Err(e) = report::report_activity(client, &events).await {
+            return ProxyLogger::log_error("uploading activity", &e);
+        }
+        if let Err(e) = lock().mark_uploaded(last) {
+            return ProxyLogger::log_error("marking activity uploaded", &e);
+        }
+        if events.len() < ACTIVITY_UPLOAD_BATCH {
+            return;
+        }
+    }
+}
+
+// Saves the new floor when `blocklist` is newer than any accepted before
+fn raise_version_floor(
+    floor: &mut VersionFloor,
+    blocklist: &SignedBlocklist,
+    protector: &dyn KeyProtector,
+) {
+    if floor.raise(blocklist) {
+        if let Err(e) = floor.save(Path::new(VERSION_FLOOR_PATH), protector) {
+            ProxyLogger::log_error("saving blocklist version floor", &e);
+        }
+    }
+}
+
+// Sends the access requests the backend doesn't have yet, then asks for the
+// parent's answers to the ones waiting. Approvals let their site through right away.
+async fn sync_access_requests(
+    client: &BackendClient,
+    keys: &KeySet,
+    requests: &Mutex<AccessRequests>,
+    policy: &RwLock<FilterPolicy>,
+) {
+    let lock = || requests.lock().unwrap_or_else(PoisonError::into_inner);
+
+    let unsent: Vec<AccessRequest> = lock().unsent();
+    if !unsent.is_empty() {
+        let sent: Vec<SentRequest> = unsent
+            .iter()
+            .map(|request| SentRequest {
+                id: &request.id,
+                host: &request.host,
+                reason: request.reason.as_deref(),
+                requested_at: request.requested_at,
+            })
+            .collect();
+        match access::send_access_requests(client, &sent).await {
+            Ok(()) => {
+                let ids: Vec<String> = unsent.into_iter().map(|request| request.id).collect();
+                lock().mark_sent(&ids);
+            }
+            Err(e) => ProxyLogger::log_error("sending access requests", &e),
+        }
+    }
+
+    let awaiting = lock().awaiting_decision();
+    if awaiting.is_empty() {
+        return;
+    }
+    match access::fetch_access_decisions(client, keys, &awaiting).await {
+        Ok(decisions) => {
+            if lock().apply_decisions(decisions) {
+                apply_exceptions(requests, policy);
+                println!("✅ Access request approved by a parent");
+            }
+        }
+        Err(e) => ProxyLogger::log_error("fetching access request decisions", &e),
+    }
+}
+
+// Makes the approved access requests the policy's exceptions
+fn apply_exceptions(requests: &Mutex<AccessRequests>, policy: &RwLock<FilterPolicy>) {
+    let exceptions = requests
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .exceptions();
+    policy
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .set_exceptions(exceptions);
+}
+
+// Forgets access requests answered long ago, then saves them (only if they changed)
+fn prune_access_requests(requests: &Mutex<AccessRequests>, policy: &RwLock<FilterPolicy>) {
+    let pruned = requests
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .prune();
+    if pruned {
+        apply_exceptions(requests, policy);
+    }
+    save_access_requests(requests);
+}
+
+fn save_access_requests(requests: &Mutex<AccessRequests>) {
+    if let Err(e) = requests
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .save(Path::new(ACCESS_REQUESTS_PATH))
+    {
+        ProxyLogger::log_error("saving access requests", &e);
+    }
+}
+
+// Reads the parent code secret shared at enrollment, if the device is paired. A
+// secret the pairing left in the registration is sealed first.
+fn load_override_secret(overrides: &Mutex<ParentOverride>) {
+    let protector = default_protector();
+    let secret_path = Path::new(OVERRIDE_SECRET_PATH);
+    match parent_override::move_secret_out_of(
+        Path::new(DEVICE_CONFIG_PATH),
+        secret_path,
+        &*protector,
+    ) {
+        Ok(true) => println!("🔒 Parent code secret sealed with {}", protector.name()),
+        Ok(false) => {}
+        Err(e) => ProxyLogger::log_error("sealing the parent code secret", &e),
+    }
+
+    let totp = match parent_override::load_secret(secret_path, &*protector) {
+        Ok(totp) => Some(totp),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None, // Not paired
+        Err(e) => {
+            ProxyLogger::log_error("loading the parent code secret", &e);
+            None
+        }
+    };
+    overrides
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .set_totp(totp);
+}
+
+fn save_parent_overrides(overrides: &Mutex<ParentOverride>) {
+    if let Err(e) = overrides
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .save(Path::new(PARENT_OVERRIDE_PATH))
+    {
+        ProxyLogger::log_error("saving parent overrides", &e);
+    }
+}
+
+// Starts the activity log and its sinks, unless an earlier run of the proxy did.
+// The console only shows the events in debug builds.
+fn start_activity_log() {
+    let mut log = ACTIVITY_LOG.write().unwrap_or_else(PoisonError::into_inner);
+    if log.is_some() {
+        return;
+    }
+
+    let mut sinks: Vec<Box<dyn ActivitySink>> = vec![Box::new(FileSink::new(
+        ACTIVITY_LOG_PATH,
+        MAX_LOG_FILE_SIZE,
+    ))];
+    match activity_store() {
+        Ok(store) => sinks.push(Box::new(store.clone())),
+        Err(e) => ProxyLogger::log_error("opening the activity store", &e),
+    }
+    if cfg!(debug_assertions) {
+        sinks.push(Box::new(StdoutSink));
+    }
+    match ActivityLog::start(sinks) {
+        Ok(started) => *log = Some(started),
+        Err(e) => ProxyLogger::log_error("starting the activity log", &e),
+    }
+}
+
+// The activity history, opened (and migrated) on first use. A failure to open it
+// is tried again the next time.
+fn activity_store() -> Result<&'static Arc<Mutex<ActivityStore>>, StoreError> {
+    ACTIVITY_STORE.get_or_try_init(|| {
+        let store = ActivityStore::open(Path::new(ACTIVITY_DB_PATH))?;
+        Ok(Arc::new(Mutex::new(store)))
+    })
+}
+
+// Removes the activity that is too old, or beyond the size the history may take
+fn prune_activity() {
+    let Some(store) = ACTIVITY_STORE.get() else {
+        return;
+    };
+    match store
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .prune(Utc::now(), &Retention::default())
+    {
+        Ok(0) => {}
+        Ok(removed) => println!("🧹 Removed {} old activity events", removed),
+        Err(e) => ProxyLogger::log_error("pruning activity", &e),
+    }
+}
+
+// Hands the event of a finished request to the activity log, without waiting.
+// Without an outcome the request broke off with an I/O error.
+fn record_activity(activity: RequestActivity, outcome: Option<Outcome>) {
+    let log = ACTIVITY_LOG.read().unwrap_or_else(PoisonError::into_inner);
+    if let Some(log) = log.as_ref() {
+        log.emit(activity.finish(outcome));
+    }
+}
+
+// Saves the leaf certificates minted since the last save, if interception is on
+fn save_leaf_cache() {
+    let interceptor = INTERCEPTOR
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    if let Some(interceptor) = interceptor {
+        interceptor.save_leaf_cache();
+    }
+}
+
 // The core function for HTTPS tunneling. It creates a bidirectional
 // data pipe, copying data between two streams (e.g., client and server)
 // until the connection closes or a timeout occurs.
@@ -169,6 +819,8 @@ async fn tunnel_stream(
     mut read_half: impl AsyncRead + Unpin, // Where we read data from
     mut write_half: impl AsyncWrite + Unpin, // Where we write data to
     description: &'static str,             // Description for logging (e.g., "client_to_server")
+    meter: Option<&ActivityMeter>, // Counts active time when the site has a screen-time budget
+    transferred: &AtomicU64,       // Bytes copied so far, for the activity log
 ) -> io::Result<()> {
     // Buffer to hold data as we copy it from one stream to another
     // 8192 bytes is a good balance between memory usage and efficiency
@@ -202,171 +854,1133 @@ async fn tunnel_stream(
             ProxyLogger::log_error(&format!("{} write", description), &e);
             return Err(e);
         }
+        transferred.fetch_add(n as u64, Ordering::Relaxed);
+
+        // Data is flowing, so the tunnel is in active use
+        if let Some(meter) = meter {
+            meter.traffic();
+        }
     }
     Ok(()) // Successfully completed the tunnel
 }
-// The core logic for each client connection. It reads the request, checks
-// the blocklist, and either establishes an HTTPS tunnel or returns an error.
+
+// Headers that only apply to a single connection (RFC 9110 section 7.6.1).
+// A proxy must not forward them to the next hop.
+// "Transfer-Encoding" is hop-by-hop too, but we pass chunked bodies through
+// unchanged, so it has to stay with the message. "Upgrade" is put back by the
+// proxy itself when a protocol switch is asked for (see `requested_upgrade`).
+const HOP_BY_HOP_HEADERS: [&str; 7] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "upgrade",
+];
+
+// How long a kept-alive browser connection may sit idle between requests
+const KEEP_ALIVE_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Where a plain HTTP request should be sent, and the path to put in the request line.
+#[derive(Debug, Clone, PartialEq)]
+struct HttpTarget {
+    host: String,
+    port: u16,
+    origin_form: String, // e.g. "/page?x=1"
+}
+
+impl HttpTarget {
+    // "host:port" string to connect to
+    fn address(&self) -> String {
+        join_host_port(&self.host, self.port)
+    }
+}
+
+// "host:port", with brackets around IPv6 literals
+fn join_host_port(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+// A connection to a server: plain TCP, or TLS inside an intercepted tunnel
+trait ServerStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> ServerStream for S {}
+
+// Connection to an HTTP server that is kept open between requests,
+// so a browser sending many requests to one site doesn't reconnect each time.
+struct Upstream {
+    address: String,               // "host:port" this stream is connected to
+    stream: Box<dyn ServerStream>, // The connection to the server
+    buf: Vec<u8>,                  // Bytes read from the server but not forwarded yet
+}
+
+// The server behind an intercepted CONNECT tunnel. Requests read from the tunnel
+// can only go there, over TLS.
+struct TunnelOrigin<'a> {
+    host: &'a str,
+    port: u16,
+    interceptor: &'a Interceptor,
+    client_addr: SocketAddr, // The browser, for the activity log
+}
+
+// Splits "host:port" (or "[::1]:port") into its parts, using `default_port` when none is given.
+// The host is returned lowercased so it can be compared against the blocklist.
+fn split_host_port(authority: &str, default_port: u16) -> Option<(String, u16)> {
+    let authority = authority.trim();
+
+    let (host, port) = if let Some(rest) = authority.strip_prefix('[') {
+        // IPv6 literal: "[::1]:8080"
+        let (host, after) = rest.split_once(']')?;
+        match after.strip_prefix(':') {
+            Some(port) => (host, port.parse().ok()?),
+            None if after.is_empty() => (host, default_port),
+            None => return None,
+        }
+    } else {
+        match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().ok()?),
+            None => (authority, default_port),
+        }
+    };
+
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_ascii_lowercase(), port))
+}
+
+// Resolves the request target of a plain HTTP request.
+// - Absolute-form ("http://example.com:8080/a?b"): the authority comes from the URI
+// - Origin-form ("/a?b"): the authority comes from the Host header
+fn resolve_http_target(uri: &str, host_header: Option<&str>) -> Option<HttpTarget> {
+    let scheme_end = uri.find("://");
+
+    if let Some(idx) = scheme_end {
+        // Only plain http can be forwarded here, https goes through CONNECT
+        if !uri[..idx].eq_ignore_ascii_case("http") {
+            return None;
+        }
+        let rest = &uri[idx + 3..];
+        let path_start = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+        let authority = &rest[..path_start];
+        // Drop any "user:password@" prefix
+        let authority = authority.rsplit_once('@').map_or(authority, |(_, a)| a);
+        let (host, port) = split_host_port(authority, 80)?;
+
+        let mut origin_form = rest[path_start..]
+            .split('#')
+            .next()
+            .unwrap_or("")
+            .to_string();
+        if !origin_form.starts_with('/') {
+            origin_form.insert(0, '/');
+        }
+
+        Some(HttpTarget {
+            host,
+            port,
+            origin_form,
+        })
+    } else if uri.starts_with('/') {
+        let (host, port) = split_host_port(host_header?, 80)?;
+        Some(HttpTarget {
+            host,
+            port,
+            origin_form: uri.to_string(),
+        })
+    } else {
+        None
+    }
+}
+
+// Checks a single host against the shared filter policy, telling why it is blocked.
+// Subdomains are covered too: a rule for "example.com" blocks "www.example.com".
+// When the request target is known (`target`, e.g. "/page?x=1"), path rules apply too.
+fn block_cause(
+    policy: &RwLock<FilterPolicy>,
+    host: &str,
+    target: Option<&str>,
+) -> Option<BlockCause> {
+    // Get a READ lock on the policy (multiple threads can read simultaneously)
+    match policy.read() {
+        Ok(guard) => match target {
+            Some(target) => guard.request_block_reason(host, target),
+            None => guard.block_reason(host),
+        }
+        .map(BlockCause::Policy),
+        Err(poisoned) => {
+            // Handle "poisoned" lock (rare error condition)
+            ProxyLogger::log_error("RwLock read error (poisoned)", &poisoned.to_string());
+            Some(BlockCause::Unavailable) // If we can't read the blocklist, block for safety
+        }
+    }
+}
+
+// Checks a host against the screen-time budgets.
+// A poisoned lock only means a panic while counting, so the counters are still used.
+fn check_budget(screen_time: &Mutex<ScreenTime>, host: &str) -> BudgetCheck {
+    screen_time
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .check(host)
+}
+
+// Counts traffic of a plain HTTP request as activity for a budget category
+fn record_budget_activity(screen_time: &Mutex<ScreenTime>, category: &str) {
+    screen_time
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .record_activity(category);
+}
+
+// Removes hop-by-hop headers, plus any header named in "Connection: foo, bar"
+// (those are connection-specific as well).
+fn end_to_end_headers(headers: &[(String, String)]) -> Vec<&(String, String)> {
+    let connection_tokens: Vec<String> = headers
+        .iter()
+        .filter(|(key, _)| key.eq_ignore_ascii_case("connection"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !token.is_empty())
+        .collect();
+
+    headers
+        .iter()
+        .filter(|(key, _)| {
+            let lower = key.to_ascii_lowercase();
+            !HOP_BY_HOP_HEADERS.contains(&lower.as_str()) && !connection_tokens.contains(&lower)
+        })
+        .collect()
+}
+
+// The protocol the browser asks to switch to, e.g. "websocket": an "Upgrade"
+// header that is also named in "Connection"
+fn requested_upgrade(headers: &[(String, String)]) -> Option<&str> {
+    if !has_header_token(headers, "connection", "upgrade") {
+        return None;
+    }
+    header_value(headers, "upgrade").filter(|protocol| !protocol.is_empty())
+}
+
+// Builds the request head sent to the target server:
+// - the request line is rewritten to origin-form
+// - hop-by-hop headers are removed
+// - "Expect" is removed when the proxy already answered "100 Continue" itself
+// - "Host" is set if the browser didn't send one
+// - "Connection: keep-alive" so the server connection can be reused, or
+//   "Connection: Upgrade" when the browser asks to switch protocols
+fn build_forward_request(
+    method: &str,
+    target: &HttpTarget,
+    version: &str,
+    headers: &[(String, String)],
+    drop_expect: bool,
+    upgrade: Option<&str>,
+) -> String {
+    let mut head = format!("{} {} {}\r\n", method, target.origin_form, version);
+    let mut has_host = false;
+
+    for (key, value) in end_to_end_headers(headers) {
+        if drop_expect && key.eq_ignore_ascii_case("expect") {
+            continue;
+        }
+        has_host |= key.eq_ignore_ascii_case("host");
+        head.push_str(&format!("{}: {}\r\n", key, value));
+    }
+
+    if !has_host {
+        if target.port == 80 {
+            head.push_str(&format!("Host: {}\r\n", target.host));
+        } else {
+            head.push_str(&format!("Host: {}:{}\r\n", target.host, target.port));
+        }
+    }
+
+    match upgrade {
+        Some(protocol) => head.push_str(&format!(
+            "Upgrade: {}\r\nConnection: Upgrade\r\n\r\n",
+            protocol
+        )),
+        None => head.push_str("Connection: keep-alive\r\n\r\n"),
+    }
+    head
+}
+
+// Builds the response head sent back to the browser: same status line and
+// end-to-end headers, with our own "Connection" header for the browser side.
+fn build_forward_response(
+    status_line: &str,
+    headers: &[(String, String)],
+    keep_alive: bool,
+) -> String {
+    let mut head = format!("{}\r\n", status_line);
+    for (key, value) in end_to_end_headers(headers) {
+        head.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    if keep_alive {
+        head.push_str("Connection: keep-alive\r\n\r\n");
+    } else {
+        head.push_str("Connection: close\r\n\r\n");
+    }
+    head
+}
+
+// Builds the "101 Switching Protocols" head sent back to the browser, with the
+// protocol the server switched to
+fn build_switching_response(
+    status_line: &str,
+    headers: &[(String, String)],
+    protocol: &str,
+) -> String {
+    let mut head = format!("{}\r\n", status_line);
+    for (key, value) in end_to_end_headers(headers) {
+        head.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    head.push_str(&format!(
+        "Upgrade: {}\r\nConnection: Upgrade\r\n\r\n",
+        protocol
+    ));
+    head
+}
+
+// Carries a connection that switched protocols (e.g. a WebSocket) both ways
+// until one side closes it, starting with the bytes already read from either
+// side. Returns the bytes sent to the server and to the browser.
+async fn switch_protocols<C: AsyncRead + AsyncWrite + Unpin>(
+    client_stream: &mut C,
+    client_buf: &mut Vec<u8>,
+    mut server: Upstream,
+) -> io::Result<(u64, u64)> {
+    let early_up = client_buf.len() as u64;
+    server.stream.write_all(client_buf).await?;
+    client_buf.clear();
+    let early_down = server.buf.len() as u64;
+    client_stream.write_all(&server.buf).await?;
+
+    let (up, down) = tokio::io::copy_bidirectional(client_stream, &mut server.stream).await?;
+    Ok((early_up + up, early_down + down))
+}
+
+// The core logic for each client connection. It reads requests one after
+// another (keep-alive / pipelining), checks the blocklist for each of them,
+// and either forwards them, establishes an HTTPS tunnel, or returns an error.
 
 /// ## TODO Features:
-/// - Full HTTP proxy support (currently only HTTPS CONNECT is implemented)
-/// - HTTP header parsing and modification
-/// - Connection keep-alive handling
 /// - HTTP method filtering and validation
 pub async fn handle_client(
     mut client_stream: TokioTcpStream, // The connection from the browser
-    _conn_info: SocketAddr,            // Information about who connected (IP address, etc.)
-    blocklist: Arc<RwLock<HashSet<String>>>, // Our list of blocked domains
+    conn_info: SocketAddr,             // Information about who connected (IP address, etc.)
+    policy: Arc<RwLock<FilterPolicy>>, // Our list of blocked (or allowed) domains
+    screen_time: Arc<Mutex<ScreenTime>>, // Daily budgets and the time used today
+    head_limits: HeadLimits,           // Size limits for request heads
+    interceptor: Option<Arc<Interceptor>>, // Set when CONNECT tunnels are decrypted
 ) -> io::Result<()> {
+    // Bytes read from the browser that haven't been handled yet.
+    // With pipelining this may already contain the next request(s).
+    let mut client_buf: Vec<u8> = Vec::new();
 
-    // Step 1: Read the full HTTP request from the client into a buffer.
-    // This is done to parse the request and determine the action (e.g., connect, get).
-    // A timeout is applied to prevent the connection from hanging indefinitely.
-    let mut buf = [0u8; 4096]; // Buffer to hold the request data (4KB should be enough for most requests)
-
-    // TIMEOUT on reading - don't wait forever for the browser to send data
-    let n = match timeout(Duration::from_secs(10), client_stream.read(&mut buf)).await {
-        Ok(Ok(n)) => n,              // Successfully read n bytes
-        Ok(Err(e)) => return Err(e), // Read failed
-        Err(_) => {
-            // Timeout - browser didn't send data within 10 seconds
-            ProxyLogger::log_error(
-                "client read timeout",
-                &io::Error::new(io::ErrorKind::TimedOut, "Read timeout"),
-            );
-            return Ok(()); // Close connection gracefully
-        }
-    };
+    // Server connection reused while the browser keeps talking to the same host
+    let mut upstream: Option<Upstream> = None;
 
-    // If we read 0 bytes, the browser closed the connection
-    if n == 0 {
-        return Ok(());
-    }
+    let mut first_request = true;
 
-    // Convert the raw bytes to a string so we can parse the HTTP request
-    let request = String::from_utf8_lossy(&buf[..n]);
+    loop {
+        // Step 1: Read the next HTTP request head from the client.
+        // A timeout is applied to prevent the connection from hanging indefinitely.
+        // The first request must arrive quickly; later ones may wait for the keep-alive idle time.
+        let wait = if first_request {
+            Duration::from_secs(10)
+        } else {
+            KEEP_ALIVE_IDLE_TIMEOUT
+        };
 
-    // Step 2: Parse the HTTP request line to determine the method (e.g., `CONNECT` or `GET`)
-    // and the target host.
-    if let Some(first_line) = request.lines().next() {
-        // Split the first line into parts: METHOD TARGET VERSION
+        // Step 2: Parse the HTTP request head: method (e.g., `CONNECT` or `GET`),
+        // target, version and headers.
         // Example: "CONNECT google.com:443 HTTP/1.1"
-        let parts: Vec<&str> = first_line.split_whitespace().collect();
-
-        // Step 3: Handle `CONNECT` requests from the client.
-        // This is done to establish a secure HTTPS tunnel to the target server.
-        if parts.len() >= 3 && parts[0] == "CONNECT" {
-            let target = parts[1]; // The target server (e.g., "google.com:443")
-            let host_only = target.split(':').next().unwrap_or(target); // Extract just the domain name
-            println!("CONNECT request to domain: {}", host_only);
-
-            // Step 4: Check if the extracted domain is present in the blocklist.
-            // This is the security core of the proxy, preventing access to malicious sites.
-
-            // Get a READ lock on the blocklist (multiple threads can read simultaneously)
-            let is_blocked = match blocklist.read() {
-                Ok(guard) => guard.contains(host_only), // Check if domain is in our blocklist
-                Err(poisoned) => {
-                    // Handle "poisoned" lock (rare error condition)
-                    ProxyLogger::log_error("RwLock read error (poisoned)", &poisoned.to_string());
-                    true // If we can't read the blocklist, block the request for safety
+        let read = read_request_head(&mut client_stream, &mut client_buf, &head_limits);
+        let request = match timeout(wait, read).await {
+            Ok(Ok(Some(request))) => request, // Got a full request head
+            Ok(Ok(None)) => return Ok(()),    // The browser closed the connection
+            Ok(Err(HeadParseError::Io(e))) => return Err(e), // Read failed
+            Ok(Err(e)) => {
+                // Malformed (400) or too large (431) - answer instead of silently closing
+                ProxyLogger::log_error("parsing request", &e);
+                if let Some(response) = e.response() {
+                    let _ = client_stream.write_all(response).await;
                 }
-            };
-
-            if is_blocked {
-                // Domain is blocked - send 403 Forbidden response and close connection
-                let _ = client_stream
-                    .write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n")
-                    .await;
                 return Ok(());
             }
+            Err(_) => {
+                // Timeout - browser didn't send data in time
+                if first_request {
+                    ProxyLogger::log_error(
+                        "client read timeout",
+                        &io::Error::new(io::ErrorKind::TimedOut, "Read timeout"),
+                    );
+                }
+                return Ok(()); // Close connection gracefully
+            }
+        };
+        first_request = false;
 
-            // Step 5: Connect to the target server after a successful blocklist check.
-            // This connection is then used to create the secure tunnel.
-            match timeout(Duration::from_secs(10), TokioTcpStream::connect(target)).await {
-                Ok(Ok(mut server_stream)) => {
-                    // SUCCESS! We connected to the target server
+        // Step 3: Handle `CONNECT` requests from the client.
+        // After a CONNECT the connection becomes a raw tunnel, so no more requests follow.
+        // Every request ends up in the activity log, whatever happens to it.
+        if request.method == "CONNECT" {
+            let (host, port) = split_host_port(&request.target, 443)
+                .unwrap_or_else(|| (request.target.clone(), 0));
+            let activity = RequestActivity::new(conn_info, &host, port, &request);
+            let outcome = handle_connect(
+                client_stream,
+                client_buf,
+                &request.target,
+                &policy,
+                &screen_time,
+                &head_limits,
+                interceptor.as_deref(),
+            )
+            .await;
+            record_activity(activity, outcome.as_ref().ok().cloned());
+            return outcome.map(drop);
+        }
+
+        // Handle regular HTTP requests (GET, POST, etc.)
+        let (host, port) = resolve_http_target(&request.target, request.header("host"))
+            .map_or_else(|| (request.target.clone(), 0), |t| (t.host, t.port));
+        let activity = RequestActivity::new(conn_info, &host, port, &request);
+        let forwarded = forward_http_request(
+            &mut client_stream,
+            &mut client_buf,
+            &mut upstream,
+            &request,
+            None,
+            &policy,
+            &screen_time,
+        )
+        .await;
+        record_activity(activity, forwarded.as_ref().ok().map(|(_, o)| o.clone()));
+        let (keep_alive, _) = forwarded?;
+
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
 
-                    // Tell the browser "Connection established" - this is the standard HTTP response
+// Handles a CONNECT request: checks the blocklist and establishes the HTTPS tunnel,
+// or decrypts it when TLS interception is on. Returns what happened to the tunnel.
+async fn handle_connect(
+    mut client_stream: TokioTcpStream,
+    pending: Vec<u8>, // Bytes the browser already sent after the CONNECT head
+    target: &str,     // The target server (e.g., "google.com:443")
+    policy: &RwLock<FilterPolicy>,
+    screen_time: &Arc<Mutex<ScreenTime>>,
+    head_limits: &HeadLimits,
+    interceptor: Option<&Interceptor>,
+) -> io::Result<Outcome> {
+    let Some((host, port)) = split_host_port(target, 443) else {
+        let _ = client_stream
+            .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
+            .await;
+        return Ok(Outcome::new(Decision::Failed));
+    };
+    let host_only = host.as_str(); // Just the domain name
+
+    // Step 4: Check if the extracted domain is present in the blocklist.
+    // This is the security core of the proxy, preventing access to malicious sites.
+    if let Some(cause) = block_cause(policy, host_only, None) {
+        // Domain is blocked - refuse the tunnel (with the block page if it can be
+        // intercepted)
+        refuse_connect(
+            client_stream,
+            pending,
+            (host_only, port),
+            policy,
+            screen_time,
+            head_limits,
+            interceptor,
+        )
+        .await?;
+        return Ok(Outcome::blocked(&cause));
+    }
+
+    // Refuse new tunnels once the site's daily screen-time budget is used up.
+    // Tunnels to budgeted sites count their active time towards the budget.
+    let meter = match check_budget(screen_time, host_only) {
+        BudgetCheck::Untracked => None,
+        BudgetCheck::Allowed(category) => Some(ActivityMeter::new(screen_time.clone(), category)),
+        BudgetCheck::Exhausted(category) => {
+            refuse_connect(
+                client_stream,
+                pending,
+                (host_only, port),
+                policy,
+                screen_time,
+                head_limits,
+                interceptor,
+            )
+            .await?;
+            return Ok(Outcome::blocked(&BlockCause::ScreenTime(category)));
+        }
+    };
+
+    // Step 5: Connect to the target server after a successful blocklist check.
+    // This connection is then used to create the secure tunnel.
+    // Hosts on the bypass list (banking, updates, pinned apps) are never decrypted.
+    let interceptor = interceptor.filter(|interceptor| !interceptor.bypasses(host_only));
+    let origin = interceptor.map(|interceptor| TunnelOrigin {
+        host: host_only,
+        port,
+        interceptor,
+        client_addr: client_stream
+            .peer_addr()
+            .unwrap_or(([0, 0, 0, 0], 0).into()),
+    });
+    let outcome = match (
+        timeout(Duration::from_secs(10), TokioTcpStream::connect(target)).await,
+        origin,
+    ) {
+        (Ok(Ok(server_stream)), Some(origin)) => {
+            // Decrypt the tunnel instead of passing it through. TLS to the real server
+            // comes first: a server we can't reach or trust gets a 502, the browser
+            // never sees a forged certificate for it.
+            let handshake = origin
+                .interceptor
+                .connect_upstream(host_only, server_stream);
+            match timeout(Duration::from_secs(10), handshake).await {
+                Ok(Ok(server_tls)) => {
                     let _ = client_stream
                         .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
                         .await;
+                    intercept_tunnel(
+                        PrefixedStream::new(pending, client_stream),
+                        Some(server_tls),
+                        &origin,
+                        policy,
+                        screen_time,
+                        head_limits,
+                        meter.as_ref(),
+                    )
+                    .await?;
+                    // The bytes are counted by the requests' own events
+                    Outcome::allowed(0, 0)
+                }
+                Ok(Err(e)) => {
+                    ProxyLogger::log_error("TLS connection to server", &e);
+                    let _ = client_stream
+                        .write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")
+                        .await;
+                    Outcome::new(Decision::Failed)
+                }
+                Err(_) => {
+                    let _ = client_stream
+                        .write_all(b"HTTP/1.1 504 Gateway Timeout\r\n\r\n")
+                        .await;
+                    Outcome::new(Decision::Timeout)
+                }
+            }
+        }
+        (Ok(Ok(mut server_stream)), None) => {
+            // SUCCESS! We connected to the target server
+
+            // Tell the browser "Connection established" - this is the standard HTTP response
+            let _ = client_stream
+                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                .await;
+
+            // Step 6: Peek at the TLS ClientHello. Its server name (SNI) is the site
+            // the browser really talks to, which may not be the CONNECT host (an IP
+            // address, or another name on the same server).
+            let mut hello_buf = pending;
+            let meter =
+                match check_server_name(&mut client_stream, &mut hello_buf, policy, screen_time)
+                    .await?
+                {
+                    ServerNameCheck::Allowed(Some(sni_meter)) if meter.is_none() => Some(sni_meter),
+                    ServerNameCheck::Allowed(_) => meter,
+                    ServerNameCheck::Refused(outcome) => return Ok(outcome),
+                };
+
+            // The ClientHello and anything read with it goes to the server unchanged
+            if !hello_buf.is_empty() {
+                server_stream.write_all(&hello_buf).await?;
+            }
 
-                    // Step 6: Create the bidirectional tunnel. This copies data
-                    // between the client and the target server, acting as a transparent pipe.
-
-                    // Split both streams into read and write halves so we can handle them separately
-                    let (mut client_read, mut client_write) = client_stream.split();
-                    let (mut server_read, mut server_write) = server_stream.split();
-
-                    // TOKIO::SELECT! for bidirectional tunneling
-                    // We run two tunnel operations simultaneously:
-                    // 1. Copy data from browser to server
-                    // 2. Copy data from server to browser
-                    // Whichever one finishes first (usually when connection closes), we're done
-                    tokio::select! {
-                        _ = tunnel_stream(&mut client_read, &mut server_write, "client_to_server") => {},
-                        _ = tunnel_stream(&mut server_read, &mut client_write, "server_to_client") => {},
-                    }
+            // Step 7: Create the bidirectional tunnel. This copies data
+            // between the client and the target server, acting as a transparent pipe.
+
+            // Split both streams into read and write halves so we can handle them separately
+            let (mut client_read, mut client_write) = client_stream.split();
+            let (mut server_read, mut server_write) = server_stream.split();
+
+            // TOKIO::SELECT! for bidirectional tunneling
+            // We run two tunnel operations simultaneously:
+            // 1. Copy data from browser to server
+            // 2. Copy data from server to browser
+            // Whichever one finishes first (usually when connection closes), we're done
+            let up = AtomicU64::new(hello_buf.len() as u64);
+            let down = AtomicU64::new(0);
+            tokio::select! {
+                _ = tunnel_stream(&mut client_read, &mut server_write, "client_to_server", meter.as_ref(), &up) => {},
+                _ = tunnel_stream(&mut server_read, &mut client_write, "server_to_client", meter.as_ref(), &down) => {},
+            }
+            Outcome::allowed(up.into_inner(), down.into_inner())
+        }
+
+        // Error handling: Failed to connect to target server
+        (Ok(Err(e)), _) => {
+            // Connection to target server failed (server is down, network issue, etc.)
+            ProxyLogger::log_error("server connection", &e);
+            let _ = client_stream
+                .write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n") // Standard HTTP error code
+                .await;
+            Outcome::new(Decision::Failed)
+        }
+        (Err(_), _) => {
+            // Connection attempt timed out (server didn't respond within 10 seconds)
+            let _ = client_stream
+                .write_all(b"HTTP/1.1 504 Gateway Timeout\r\n\r\n") // Standard HTTP timeout code
+                .await;
+            Outcome::new(Decision::Timeout)
+        }
+    };
+
+    Ok(outcome)
+}
+
+// Refuses a CONNECT tunnel to `host:port`. Browsers don't show what a proxy
+// answers to CONNECT, so a tunnel that can be intercepted is decrypted without
+// connecting to the server, and its requests get the block page. Any other tunnel
+// gets a bare 403.
+async fn refuse_connect(
+    mut client_stream: TokioTcpStream,
+    pending: Vec<u8>,
+    (host, port): (&str, u16),
+    policy: &RwLock<FilterPolicy>,
+    screen_time: &Mutex<ScreenTime>,
+    head_limits: &HeadLimits,
+    interceptor: Option<&Interceptor>,
+) -> io::Result<()> {
+    let Some(interceptor) = interceptor.filter(|interceptor| !interceptor.bypasses(host)) else {
+        let _ = client_stream
+            .write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n")
+            .await;
+        return Ok(());
+    };
+
+    let _ = client_stream
+        .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+        .await;
+    let origin = TunnelOrigin {
+        host,
+        port,
+        interceptor,
+        client_addr: client_stream.peer_addr()?,
+    };
+    intercept_tunnel(
+        PrefixedStream::new(pending, client_stream),
+        None,
+        &origin,
+        policy,
+        screen_time,
+        head_limits,
+        None,
+    )
+    .await
+}
+
+// Result of checking a pass-through tunnel's TLS server name
+enum ServerNameCheck {
+    Allowed(Option<ActivityMeter>), // Meter for the server name's budget, if it has one
+    Refused(Outcome), // The browser got an alert and the tunnel is closed (blocked or failed)
+}
+
+// Reads the ClientHello into `buf` and checks its server name like a CONNECT host:
+// blocklist first, then screen time. Tunnels that aren't TLS are let through, there
+// is no name to check; a broken or missing ClientHello closes the tunnel.
+async fn check_server_name(
+    client_stream: &mut TokioTcpStream,
+    buf: &mut Vec<u8>,
+    policy: &RwLock<FilterPolicy>,
+    screen_time: &Arc<Mutex<ScreenTime>>,
+) -> io::Result<ServerNameCheck> {
+    let hello = match timeout(
+        Duration::from_secs(10),
+        read_client_hello(client_stream, buf),
+    )
+    .await
+    {
+        Ok(Ok(Ok(hello))) => hello,
+        Ok(Ok(Err(HelloError::NotTls))) => return Ok(ServerNameCheck::Allowed(None)),
+        // The browser closed or stalled before its ClientHello was complete
+        Ok(Ok(Err(HelloError::Incomplete))) | Err(_) => {
+            return Ok(ServerNameCheck::Refused(Outcome::new(Decision::Failed)))
+        }
+        Ok(Ok(Err(e))) => {
+            ProxyLogger::log_error("reading ClientHello", &e);
+            return Ok(ServerNameCheck::Refused(Outcome::new(Decision::Failed)));
+        }
+        Ok(Err(e)) => return Err(e),
+    };
+
+    let Some(server_name) = hello.server_name else {
+        return Ok(ServerNameCheck::Allowed(None)); // No SNI, the CONNECT host was checked
+    };
+
+    if let Some(cause) = block_cause(policy, &server_name, None) {
+        let _ = client_stream.write_all(ACCESS_DENIED_ALERT).await;
+        return Ok(ServerNameCheck::Refused(Outcome::blocked(&cause)));
+    }
+
+    match check_budget(screen_time, &server_name) {
+        BudgetCheck::Untracked => Ok(ServerNameCheck::Allowed(None)),
+        BudgetCheck::Allowed(category) => Ok(ServerNameCheck::Allowed(Some(ActivityMeter::new(
+            screen_time.clone(),
+            category,
+        )))),
+        BudgetCheck::Exhausted(category) => {
+            let _ = client_stream.write_all(ACCESS_DENIED_ALERT).await;
+            let cause = BlockCause::ScreenTime(category);
+            Ok(ServerNameCheck::Refused(Outcome::blocked(&cause)))
+        }
+    }
+}
+
+// Serves the decrypted side of an intercepted CONNECT tunnel:
+// 1. TLS with the browser, using a leaf certificate minted for the tunnel's host
+// 2. Every request is read, checked and forwarded like plain HTTP, over the
+//    already open TLS connection to the real server (`server_tls`). Without one
+//    (a refused tunnel), blocked requests get the block page and a server
+//    connection is only opened for a request that is allowed by then.
+async fn intercept_tunnel(
+    client_stream: PrefixedStream<TokioTcpStream>,
+    server_tls: Option<TlsStream<TokioTcpStream>>,
+    origin: &TunnelOrigin<'_>,
+    policy: &RwLock<FilterPolicy>,
+    screen_time: &Mutex<ScreenTime>,
+    head_limits: &HeadLimits,
+    meter: Option<&ActivityMeter>,
+) -> io::Result<()> {
+    // Step 1: TLS handshake with the browser. It fails here if the GuardNest Root CA
+    // isn't trusted on this machine, or if the app pins the server's certificate;
+    // a host that keeps failing is tunneled from then on (see bypass.rs).
+    let acceptor = origin.interceptor.acceptor_for(origin.host).await?;
+    let mut client_stream =
+        match timeout(Duration::from_secs(10), acceptor.accept(client_stream)).await {
+            Ok(Ok(stream)) => {
+                origin.interceptor.handshake_succeeded(origin.host);
+                stream
+            }
+            Ok(Err(e)) => {
+                ProxyLogger::log_error("TLS handshake with browser", &e);
+                origin.interceptor.handshake_failed(origin.host);
+                return Ok(());
+            }
+            Err(_) => return Ok(()), // The browser gave up on the handshake
+        };
+
+    // The server connection is reused by every request in this tunnel
+    let mut upstream = server_tls.map(|server_tls| Upstream {
+        address: join_host_port(origin.host, origin.port),
+        stream: Box::new(server_tls),
+        buf: Vec::new(),
+    });
+    let mut client_buf: Vec<u8> = Vec::new();
+
+    // Step 2: Decrypted requests, one after another (keep-alive)
+    loop {
+        let read = read_request_head(&mut client_stream, &mut client_buf, head_limits);
+        let request = match timeout(KEEP_ALIVE_IDLE_TIMEOUT, read).await {
+            Ok(Ok(Some(request))) => request,
+            Ok(Ok(None)) | Err(_) => break, // Closed or idle for too long
+            Ok(Err(HeadParseError::Io(e))) => return Err(e),
+            Ok(Err(e)) => {
+                ProxyLogger::log_error("parsing request", &e);
+                if let Some(response) = e.response() {
+                    let _ = client_stream.write_all(response).await;
                 }
+                break;
+            }
+        };
+
+        let activity = RequestActivity::new(origin.client_addr, origin.host, origin.port, &request);
+
+        // No tunnels inside tunnels
+        if request.method == "CONNECT" {
+            let _ = client_stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
+                .await;
+            record_activity(activity, Some(Outcome::new(Decision::Failed)));
+            break;
+        }
 
-                // Error handling: Failed to connect to target server
+        // Every request counts as activity for the site's screen-time budget
+        if let Some(meter) = meter {
+            meter.traffic();
+        }
+
+        let forwarded = forward_http_request(
+            &mut client_stream,
+            &mut client_buf,
+            &mut upstream,
+            &request,
+            Some(origin),
+            policy,
+            screen_time,
+        )
+        .await;
+        record_activity(activity, forwarded.as_ref().ok().map(|(_, o)| o.clone()));
+        let (keep_alive, _) = forwarded?;
+        if !keep_alive {
+            break;
+        }
+    }
+
+    // Close the TLS session properly (close_notify)
+    let _ = client_stream.shutdown().await;
+    Ok(())
+}
+
+// Opens a new connection to an HTTP server (over TLS for an intercepted tunnel),
+// answering the browser with 502/504 if that fails. The error tells which.
+async fn connect_upstream<C: AsyncWrite + Unpin>(
+    client_stream: &mut C,
+    address: &str,
+    origin: Option<&TunnelOrigin<'_>>,
+) -> Result<Box<dyn ServerStream>, Decision> {
+    match timeout(Duration::from_secs(10), TokioTcpStream::connect(address)).await {
+        Ok(Ok(stream)) => {
+            let Some(origin) = origin else {
+                return Ok(Box::new(stream));
+            };
+            match timeout(
+                Duration::from_secs(10),
+                origin.interceptor.connect_upstream(origin.host, stream),
+            )
+            .await
+            {
+                Ok(Ok(stream)) => Ok(Box::new(stream)),
                 Ok(Err(e)) => {
-                    // Connection to target server failed (server is down, network issue, etc.)
-                    ProxyLogger::log_connection_failed(target, &e.to_string());
+                    ProxyLogger::log_error("TLS connection to server", &e);
                     let _ = client_stream
-                        .write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n") // Standard HTTP error code
+                        .write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")
                         .await;
+                    Err(Decision::Failed)
                 }
                 Err(_) => {
-                    // Connection attempt timed out (server didn't respond within 10 seconds)
-                    ProxyLogger::log_connection_failed(target, "Connection timeout");
                     let _ = client_stream
-                        .write_all(b"HTTP/1.1 504 Gateway Timeout\r\n\r\n") // Standard HTTP timeout code
+                        .write_all(b"HTTP/1.1 504 Gateway Timeout\r\n\r\n")
                         .await;
+                    Err(Decision::Timeout)
+                }
+            }
+        }
+        Ok(Err(e)) => {
+            ProxyLogger::log_error("server connection", &e);
+            let _ = client_stream
+                .write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")
+                .await;
+            Err(Decision::Failed)
+        }
+        Err(_) => {
+            let _ = client_stream
+                .write_all(b"HTTP/1.1 504 Gateway Timeout\r\n\r\n")
+                .await;
+            Err(Decision::Timeout)
+        }
+    }
+}
+
+// Forwards one plain HTTP request (GET, POST, etc.) and its response.
+// For regular HTTP websites (not HTTPS), browsers send requests to the proxy
+// using the absolute-form URI: "GET http://example.com/page HTTP/1.1"
+//
+// Inside an intercepted tunnel (`origin` is set) requests are in origin-form
+// ("GET /page HTTP/1.1") and always go to the tunnel's server.
+//
+// Returns whether the browser connection can be used for another request, and
+// what happened to this one for the activity log.
+async fn forward_http_request<C: AsyncRead + AsyncWrite + Unpin>(
+    client_stream: &mut C,
+    client_buf: &mut Vec<u8>,
+    upstream: &mut Option<Upstream>,
+    request: &HttpRequestHead,
+    origin: Option<&TunnelOrigin<'_>>,
+    policy: &RwLock<FilterPolicy>,
+    screen_time: &Mutex<ScreenTime>,
+) -> io::Result<(bool, Outcome)> {
+    let method = request.method.as_str();
+    let version = request.version.as_str();
+    let headers = &request.headers;
+    let host_header = request.header("host");
+
+    // Step 4: Work out where the request is going and the origin-form path
+    // that the target server expects ("/page" instead of "http://example.com/page")
+    let resolved = match origin {
+        Some(origin) => request.target.starts_with('/').then(|| HttpTarget {
+            host: origin.host.to_string(),
+            port: origin.port,
+            origin_form: request.target.clone(),
+        }),
+        None => resolve_http_target(&request.target, host_header),
+    };
+    let target = match resolved {
+        Some(t) => t,
+        None => {
+            let _ = client_stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
+                .await;
+            return Ok((false, Outcome::new(Decision::Failed)));
+        }
+    };
+    // Pages of the proxy itself (e.g. the CA download, the access request form) are
+    // answered here: never forwarded, and never blocked. Only small bodies with a
+    // Content-Length are read (the form).
+    if origin.is_none() && is_local_host(&target.host) {
+        let mut body = Vec::new();
+        match request_body_length(headers) {
+            Ok(BodyLength::Empty) => {}
+            Ok(BodyLength::ContentLength(length)) if length <= local_site::MAX_BODY_SIZE => {
+                let length = BodyLength::ContentLength(length);
+                copy_body(client_stream, client_buf, &mut body, length).await?;
+            }
+            _ => {
+                let _ = client_stream
+                    .write_all(b"HTTP/1.1 413 Payload Too Large\r\n\r\n")
+                    .await;
+                return Ok((false, Outcome::new(Decision::Failed)));
+            }
+        }
+
+        let keep_alive = wants_keep_alive(version, headers);
+        let response = local_site::respond(
+            request,
+            &target.origin_form,
+            &body,
+            &CaFiles::default(),
+            &ACCESS_REQUESTS,
+            &PARENT_OVERRIDE,
+            policy,
+        );
+        // A code typed on the block page counts at once, even if the app restarts
+        save_parent_overrides(&PARENT_OVERRIDE);
+        let response = response.to_bytes(method == "HEAD", keep_alive);
+        client_stream.write_all(&response).await?;
+        let outcome = Outcome::allowed(body.len() as u64, response.len() as u64);
+        return Ok((keep_alive, outcome));
+    }
+
+    // Step 5: Check both the URI authority and the Host header against the blocklist.
+    // A browser could send a URI for one domain and a Host header for another.
+    // The path is known here (also inside an intercepted tunnel), so path rules apply.
+    // Sites whose daily screen-time budget is used up are refused the same way.
+    let budget = check_budget(screen_time, &target.host);
+    let cause = block_cause(policy, &target.host, Some(&target.origin_form))
+        .or_else(|| {
+            let (host, _) = split_host_port(host_header?, 80)?;
+            block_cause(policy, &host, Some(&target.origin_form))
+        })
+        .or_else(|| match &budget {
+            BudgetCheck::Exhausted(category) => Some(BlockCause::ScreenTime(category.clone())),
+            _ => None,
+        });
+
+    if let Some(cause) = cause {
+        // Domain is blocked - send the block page and close the connection
+        // (any request body is still unread, so the connection can't be reused)
+        let page = block_page(
+            &target.host,
+            &cause,
+            Local::now(),
+            request.header("accept-language"),
+        );
+        let _ = client_stream
+            .write_all(&page.to_bytes(method == "HEAD", false))
+            .await;
+        return Ok((false, Outcome::blocked(&cause)));
+    }
+
+    // Step 6: Find out how the request body is delimited, so we forward exactly
+    // this request and leave the next pipelined one in the buffer
+    let request_body = match request_body_length(headers) {
+        Ok(length) => length,
+        Err(_) => {
+            let _ = client_stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
+                .await;
+            return Ok((false, Outcome::new(Decision::Failed)));
+        }
+    };
+    let client_keep_alive = wants_keep_alive(version, headers);
+    let upgrade = requested_upgrade(headers);
+
+    // A plain request counts as activity for its site's budget when it is sent and
+    // when its response is done (inside a tunnel, the tunnel's meter counts it)
+    let metered = match budget {
+        BudgetCheck::Allowed(category) if origin.is_none() => Some(category),
+        _ => None,
+    };
+    let meter = || {
+        if let Some(category) = &metered {
+            record_budget_activity(screen_time, category);
+        }
+    };
+    meter();
+
+    // Step 7: Send the request, reusing the server connection when possible.
+    // A reused connection may have been closed by the server in the meantime;
+    // requests without a body are then retried once on a fresh connection.
+    let address = target.address();
+    let mut attempts = 0;
+    let mut bytes_up = 0;
+    let mut bytes_down = 0;
+
+    let (status_line, status, response_headers, response_body) = loop {
+        attempts += 1;
+
+        if upstream.as_ref().is_none_or(|u| u.address != address) {
+            *upstream = None;
+            match connect_upstream(client_stream, &address, origin).await {
+                Ok(stream) => {
+                    *upstream = Some(Upstream {
+                        address: address.clone(),
+                        stream,
+                        buf: Vec::new(),
+                    })
                 }
+                Err(decision) => return Ok((false, Outcome::new(decision))),
             }
-        } else if parts.len() >= 3 {
-            // Handle regular HTTP requests (GET, POST, etc.)
-            // For regular HTTP websites (not HTTPS), browsers send GET/POST requests directly
-
-            let mut _host = None;
-
-            // Parse HTTP headers to find the "Host" header
-            // HTTP headers are in the format "Header-Name: Header-Value"
-            for line in request.lines().skip(1) {
-                // Skip the first line (request line)
-                if let Some((key, value)) = line.split_once(':') {
-                    // PERFORMANCE OPTIMIZATION: Case-insensitive comparison without allocation
-                    // eq_ignore_ascii_case is faster than to_lowercase() because it doesn't create a new string
-                    if key.trim().eq_ignore_ascii_case("host") {
-                        _host = Some(value.trim().to_string());
-                        println!("HTTP request to domain: {}", value.trim());
-                        break; // Found the host header, no need to continue
+        }
+        let server = upstream.as_mut().unwrap();
+
+        // "Expect: 100-continue" - the browser waits for permission before sending
+        // the body. We give it ourselves, so we don't have to watch the server for it.
+        let expects_continue = has_header_token(headers, "expect", "100-continue");
+        if expects_continue && request_body != BodyLength::Empty && attempts == 1 {
+            client_stream
+                .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                .await?;
+        }
+
+        let forward_head =
+            build_forward_request(method, &target, version, headers, expects_continue, upgrade);
+        let sent = match server.stream.write_all(forward_head.as_bytes()).await {
+            Ok(()) => {
+                bytes_up = forward_head.len() as u64
+                    + copy_body(client_stream, client_buf, &mut server.stream, request_body)
+                        .await?;
+                true
+            }
+            Err(_) => false,
+        };
+
+        // Step 8: Read the response head, passing interim "1xx" responses on to the browser
+        let mut response = None;
+        if sent {
+            loop {
+                let head = match read_head(&mut server.stream, &mut server.buf, MAX_HEAD_SIZE).await
+                {
+                    Ok(Some(head)) => head,
+                    Ok(None) | Err(_) => break,
+                };
+                let text = String::from_utf8_lossy(&head).to_string();
+                let status_line = text.lines().next().unwrap_or("").to_string();
+                let status: u16 = status_line
+                    .split_whitespace()
+                    .nth(1)
+                    .and_then(|code| code.parse().ok())
+                    .unwrap_or(0);
+
+                if (100..200).contains(&status) && status != 101 {
+                    client_stream.write_all(&head).await?;
+                    client_stream.write_all(b"\r\n\r\n").await?;
+                    bytes_down += head.len() as u64 + 4;
+                    continue;
+                }
+
+                let response_headers = parse_headers(&text);
+                response = Some((status_line, status, response_headers));
+                break;
+            }
+        }
+
+        match response {
+            Some((status_line, status, response_headers)) => {
+                match response_body_length(method, status, &response_headers) {
+                    Ok(length) => break (status_line, status, response_headers, length),
+                    Err(_) => {
+                        *upstream = None;
+                        let _ = client_stream
+                            .write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")
+                            .await;
+                        return Ok((false, Outcome::new(Decision::Failed)));
                     }
                 }
             }
+            None => {
+                // The server closed the connection or sent garbage
+                *upstream = None;
+                if attempts == 1 && request_body == BodyLength::Empty {
+                    continue;
+                }
+                let _ = client_stream
+                    .write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")
+                    .await;
+                return Ok((false, Outcome::new(Decision::Failed)));
+            }
+        }
+    };
 
-            // ============================================================================
-            // TODO: IMPLEMENT FULL HTTP PROXY FUNCTIONALITY
-            // ============================================================================
-            // Currently, we only handle HTTPS CONNECT requests properly
-            // For regular HTTP requests, we would need to:
-            // 1. Check if the host is in our blocklist
-            // 2. If allowed, connect to the target server
-            // 3. Forward the original request to the server
-            // 4. Stream the server's response back to the browser
-            //
-            // This is more complex than HTTPS tunneling because we need to:
-            // - Parse and potentially modify HTTP headers
-            // - Handle different HTTP methods (GET, POST, PUT, DELETE, etc.)
-            // - Manage connection keep-alive
-            // - Handle redirects and other HTTP features
-        }
-    }
-
-    // Connection handling complete
-    Ok(())
+    // Step 9: The server agreed to switch protocols (e.g. a WebSocket): from now on
+    // the connection carries the new protocol, both ways, untouched. A switch the
+    // browser didn't ask for is refused.
+    if status == 101 {
+        let server = upstream.take().unwrap();
+        let switched = upgrade.and(header_value(&response_headers, "upgrade"));
+        let Some(protocol) = switched else {
+            let _ = client_stream
+                .write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")
+                .await;
+            return Ok((false, Outcome::new(Decision::Failed)));
+        };
+        let response_head = build_switching_response(&status_line, &response_headers, protocol);
+        client_stream.write_all(response_head.as_bytes()).await?;
+        let (up, down) = switch_protocols(client_stream, client_buf, server).await?;
+        meter();
+        let bytes_down = bytes_down + response_head.len() as u64 + down;
+        return Ok((false, Outcome::allowed(bytes_up + up, bytes_down)));
+    }
+
+    // Step 10: Stream the response back to the browser.
+    // A body that ends when the server closes can only be forwarded by closing our side too.
+    let server = upstream.as_mut().unwrap();
+    let response_version = status_line.split_whitespace().next().unwrap_or("HTTP/1.1");
+    let delimited = response_body != BodyLength::UntilClose;
+    let server_keep_alive = delimited && wants_keep_alive(response_version, &response_headers);
+    let keep_alive = client_keep_alive && delimited;
+
+    let response_head = build_forward_response(&status_line, &response_headers, keep_alive);
+    client_stream.write_all(response_head.as_bytes()).await?;
+    bytes_down += response_head.len() as u64
+        + copy_body(
+            &mut server.stream,
+            &mut server.buf,
+            client_stream,
+            response_body,
+        )
+        .await?;
+
+    meter();
+
+    if !server_keep_alive {
+        *upstream = None;
+    }
+
+    Ok((keep_alive, Outcome::allowed(bytes_up, bytes_down)))
 }
 // A Tauri command that starts the proxy server and configures the system.
 // It checks if the proxy is already running, spawns a new task, and
@@ -565,3 +2179,723 @@ pub async fn restart_proxy() -> Result<String, String> {
 
     Ok("Proxy restart completed with system proxy configured".to_string())
 }
+
+// A Tauri command that switches between blocklist and allowlist ("whitelist-only") mode.
+// Takes effect on the next request, the proxy keeps running. The mode is saved, so
+// restarting the app doesn't switch it back.
+#[tauri::command]
+pub fn set_filter_mode(mode: FilterMode) -> Result<String, String> {
+    match FILTER_POLICY.write() {
+        Ok(mut policy) => {
+            policy.set_mode(mode);
+            println!("Filter mode set to {:?}", mode);
+        }
+        Err(e) => return Err(format!("Failed to set filter mode: {}", e)),
+    }
+    mode.save(Path::new(FILTER_MODE_PATH))
+        .map_err(|e| format!("Filter mode set to {:?}, but not saved: {}", mode, e))?;
+    Ok(format!("Filter mode set to {:?}", mode))
+}
+
+// A Tauri command that returns the current filter mode ("blocklist" or "allowlist").
+#[tauri::command]
+pub fn get_filter_mode() -> Result<FilterMode, String> {
+    FILTER_POLICY
+        .read()
+        .map(|policy| policy.mode())
+        .map_err(|e| format!("Failed to read filter mode: {}", e))
+}
+
+// A Tauri command that returns today's screen time per budget category.
+#[tauri::command]
+pub fn get_screen_time() -> Result<Vec<BudgetUsage>, String> {
+    SCREEN_TIME
+        .lock()
+        .map(|mut screen_time| screen_time.usage())
+        .map_err(|e| format!("Failed to read screen time: {}", e))
+}
+
+// A Tauri command that returns the access requests sent from the block page, newest
+// first, with their status ("pending", "approved", "denied" or "expired").
+#[tauri::command]
+pub fn get_access_requests() -> Result<Vec<AccessRequest>, String> {
+    ACCESS_REQUESTS
+        .lock()
+        .map(|requests| requests.requests())
+        .map_err(|e| format!("Failed to read access requests: {}", e))
+}
+
+// A Tauri command that lifts filtering with the code shown in the parent's app, for
+// `host` (None: every host) during `minutes`. Takes effect on the next request.
+#[tauri::command]
+pub fn unlock_with_parent_code(
+    code: String,
+    host: Option<String>,
+    minutes: u32,
+) -> Result<Override, String> {
+    // The device may have been paired since the proxy started
+    if !PARENT_OVERRIDE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .is_configured()
+    {
+        load_override_secret(&PARENT_OVERRIDE);
+    }
+
+    let scope = match host {
+        Some(host) => OverrideScope::Host { host },
+        None => OverrideScope::All,
+    };
+    let granted = parent_override::unlock_filtering(
+        &PARENT_OVERRIDE,
+        &FILTER_POLICY,
+        &code,
+        scope,
+        minutes,
+        OverrideSource::App,
+    );
+    // Saved either way: a wrong code counts against the limit after a restart too
+    save_parent_overrides(&PARENT_OVERRIDE);
+    granted.map_err(|e| e.to_string())
+}
+
+// A Tauri command that ends every running parent override, filtering again at once.
+#[tauri::command]
+pub fn end_parent_override() -> Result<String, String> {
+    let ended = PARENT_OVERRIDE
+        .lock()
+        .map_err(|e| format!("Failed to end parent override: {}", e))?
+        .end_all();
+    if !ended {
+        return Ok("No parent override running".to_string());
+    }
+
+    parent_override::apply_overrides(&PARENT_OVERRIDE, &FILTER_POLICY);
+    save_parent_overrides(&PARENT_OVERRIDE);
+    println!("🔒 Parent override ended");
+    Ok("Parent override ended".to_string())
+}
+
+// A Tauri command that returns whether parent codes are set up, and the overrides
+// unlocked with them.
+#[tauri::command]
+pub fn get_parent_overrides() -> Result<ParentOverrideStatus, String> {
+    PARENT_OVERRIDE
+        .lock()
+        .map(|overrides| overrides.status())
+        .map_err(|e| format!("Failed to read parent overrides: {}", e))
+}
+
+// A Tauri command that returns a page of the activity history, newest first.
+// Events can be narrowed to a time range and to a domain and its subdomains.
+#[tauri::command]
+pub fn get_activity(
+    range: TimeRange,
+    domain_filter: Option<String>,
+    page: PageRequest,
+) -> Result<ActivityPage, String> {
+    let domain = match domain_filter.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(filter) => Some(normalize_host(filter).ok_or("Invalid domain")?),
+    };
+    let store = activity_store().map_err(|e| format!("Failed to open activity history: {}", e))?;
+    store
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .query(&range, domain.as_deref(), &page)
+        .map_err(|e| format!("Failed to read activity history: {}", e))
+}
+
+// A Tauri command that switches TLS interception on or off. Switching it on loads
+// the GuardNest Root CA, which must also be trusted by the browser, and the leaf
+// certificates saved by the last session.
+// Takes effect for new connections, open tunnels are left alone.
+#[tauri::command]
+pub fn set_tls_interception(enabled: bool) -> Result<String, String> {
+    // Keep what the current interceptor minted, also for the one replacing it
+    save_leaf_cache();
+
+    let interceptor = if enabled {
+        let ca = CertificateAuthority::load()
+            .map_err(|e| format!("Failed to load the GuardNest Root CA: {}", e))?;
+        let leaves = LeafCache::open(
+            Path::new(LEAF_CACHE_PATH),
+            Path::new(LEAF_CACHE_KEY_PATH),
+            default_protector(),
+            ca.certificate(),
+            DEFAULT_LEAF_CAPACITY,
+        );
+        let interceptor = Interceptor::new(ca)
+            .map_err(|e| format!("Failed to set up TLS interception: {}", e))?
+            .with_leaf_cache(leaves)
+            .with_bypass(INTERCEPT_BYPASS.clone());
+        let interceptor = Arc::new(interceptor);
+
+        // Mint the saved leaves that expire soon in the background, so the next
+        // visit of those sites doesn't wait for it
+        let refresher = interceptor.clone();
+        std::thread::spawn(move || refresher.refresh_expiring());
+        Some(interceptor)
+    } else {
+        None
+    };
+
+    match INTERCEPTOR.write() {
+        Ok(mut current) => {
+            *current = interceptor;
+            println!(
+                "TLS interception {}",
+                if enabled { "enabled" } else { "disabled" }
+            );
+            Ok(format!(
+                "TLS interception {}",
+                if enabled { "enabled" } else { "disabled" }
+            ))
+        }
+        Err(e) => Err(format!("Failed to set TLS interception: {}", e)),
+    }
+}
+
+// A Tauri command that returns whether TLS interception is on.
+#[tauri::command]
+pub fn get_tls_interception() -> Result<bool, String> {
+    INTERCEPTOR
+        .read()
+        .map(|current| current.is_some())
+        .map_err(|e| format!("Failed to read TLS interception: {}", e))
+}
+
+// Switches a running interceptor to the current Root CA, e.g. after it was rotated.
+// Nothing to do while interception is off, the CA is loaded when it's switched on.
+pub fn reload_tls_interception() {
+    if matches!(get_tls_interception(), Ok(true)) {
+        if let Err(e) = set_tls_interception(true) {
+            ProxyLogger::log_error("reloading TLS interception", &e);
+        }
+    }
+}
+
+// A Tauri command that returns the interception bypass list.
+#[tauri::command]
+pub fn get_intercept_bypass() -> Result<BypassStatus, String> {
+    INTERCEPT_BYPASS
+        .lock()
+        .map(|bypass| bypass.status())
+        .map_err(|e| format!("Failed to read the bypass list: {}", e))
+}
+
+// A Tauri command that sets which hosts are never intercepted: built-in categories
+// ("banking", "os_updates", "pinned_apps") plus custom domain rules.
+// Hosts learned as pinned are kept.
+#[tauri::command]
+pub fn set_intercept_bypass(
+    categories: Vec<String>,
+    domains: Vec<String>,
+) -> Result<String, String> {
+    let mut bypass = INTERCEPT_BYPASS
+        .lock()
+        .map_err(|e| format!("Failed to set the bypass list: {}", e))?;
+    bypass.set_config(BypassConfig {
+        categories: categories.into_iter().collect(),
+        domains,
+    })?;
+    bypass
+        .save(Path::new(INTERCEPT_BYPASS_PATH))
+        .map_err(|e| format!("Failed to save the bypass list: {}", e))?;
+    Ok("Bypass list updated".to_string())
+}
+
+// A Tauri command that intercepts a host learned as pinned again, e.g. after it
+// was learned by mistake.
+#[tauri::command]
+pub fn forget_pinned_host(host: String) -> Result<String, String> {
+    let mut bypass = INTERCEPT_BYPASS
+        .lock()
+        .map_err(|e| format!("Failed to update the bypass list: {}", e))?;
+    if !bypass.forget(&host) {
+        return Err(format!("{} is not a learned pinned host", host));
+    }
+    bypass
+        .save(Path::new(INTERCEPT_BYPASS_PATH))
+        .map_err(|e| format!("Failed to save the bypass list: {}", e))?;
+    Ok(format!("{} will be intercepted again", host))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::windows::http_service::fetch::TimeBudget;
+    use crate::windows::interception::tests::{
+        browser, native_certificate, throwaway_ca, tls_origin,
+    };
+    use crate::windows::screen_time::tests::SteppingClock;
+    use chrono::{TimeZone, Utc};
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    // A proxy on a free local port, serving one connection with `handle_client`
+    async fn start_proxy(policy: FilterPolicy, screen_time: Arc<Mutex<ScreenTime>>) -> SocketAddr {
+        start_intercepting_proxy(policy, screen_time, None).await
+    }
+
+    // Same, decrypting tunnels with `interceptor`
+    async fn start_intercepting_proxy(
+        policy: FilterPolicy,
+        screen_time: Arc<Mutex<ScreenTime>>,
+        interceptor: Option<Arc<Interceptor>>,
+    ) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let policy = Arc::new(RwLock::new(policy));
+        tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            let _ = handle_client(
+                stream,
+                peer,
+                policy,
+                screen_time,
+                HeadLimits::default(),
+                interceptor,
+            )
+            .await;
+        });
+        address
+    }
+
+    // Reads a response head, up to and including the blank line
+    async fn read_response_head<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> String {
+        let mut head = String::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            head.push_str(&line);
+            if line == "\r\n" || line.is_empty() {
+                return head;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn plain_http_is_forwarded() {
+        // The origin answers one request and hands back what it got
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_address = origin.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = origin.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let head = read_response_head(&mut stream).await;
+            let mut body = [0u8; 4];
+            stream.read_exact(&mut body).await.unwrap();
+            stream
+                .get_mut()
+                .write_all(
+                    b"HTTP/1.1 201 Created\r\nContent-Length: 5\r\nX-Origin: yes\r\n\r\nhello",
+                )
+                .await
+                .unwrap();
+            (head, body)
+        });
+
+        let proxy = start_proxy(FilterPolicy::default(), Default::default()).await;
+        let mut client = BufReader::new(TokioTcpStream::connect(proxy).await.unwrap());
+        let request = format!(
+            "POST http://{0}/form?x=1 HTTP/1.1\r\nHost: {0}\r\nUser-Agent: test\r\n\
+             Proxy-Connection: keep-alive\r\nProxy-Authorization: Basic eA==\r\n\
+             Content-Length: 4\r\n\r\nname",
+            origin_address
+        );
+        client
+            .get_mut()
+            .write_all(request.as_bytes())
+            .await
+            .unwrap();
+
+        let response = read_response_head(&mut client).await;
+        assert!(
+            response.starts_with("HTTP/1.1 201 Created\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.contains("X-Origin: yes\r\n"));
+        let mut body = [0u8; 5];
+        client.read_exact(&mut body).await.unwrap();
+        assert_eq!(&body, b"hello");
+
+        // Origin-form request line, end-to-end headers only, the body as sent
+        let (forwarded, body) = server.await.unwrap();
+        assert!(
+            forwarded.starts_with("POST /form?x=1 HTTP/1.1\r\n"),
+            "{}",
+            forwarded
+        );
+        assert!(forwarded.contains(&format!("Host: {}\r\n", origin_address)));
+        assert!(forwarded.contains("User-Agent: test\r\n"));
+        assert!(!forwarded.contains("Proxy-"), "{}", forwarded);
+        assert_eq!(&body, b"name");
+    }
+
+    #[tokio::test]
+    async fn blocked_hosts_get_the_block_page() {
+        // Nothing may reach the origin
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_address = origin.local_addr().unwrap();
+        let reached = tokio::spawn(async move { origin.accept().await.is_ok() });
+
+        let mut policy = FilterPolicy::default();
+        policy.set_rules(RuleSet {
+            domains: DomainMatcher::from_rules(["games.test"]),
+            ..RuleSet::default()
+        });
+        let policy = Arc::new(RwLock::new(policy));
+
+        // By the URI, and by a Host header naming a blocked site on an allowed address
+        let requests = [
+            "GET http://www.games.test/play HTTP/1.1\r\nHost: www.games.test\r\n\r\n".to_string(),
+            format!(
+                "GET http://{}/play HTTP/1.1\r\nHost: games.test\r\n\r\n",
+                origin_address
+            ),
+        ];
+        for request in requests {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy = listener.local_addr().unwrap();
+            let policy = policy.clone();
+            tokio::spawn(async move {
+                let (stream, peer) = listener.accept().await.unwrap();
+                let _ = handle_client(
+                    stream,
+                    peer,
+                    policy,
+                    Default::default(),
+                    HeadLimits::default(),
+                    None,
+                )
+                .await;
+            });
+
+            let mut client = BufReader::new(TokioTcpStream::connect(proxy).await.unwrap());
+            client
+                .get_mut()
+                .write_all(request.as_bytes())
+                .await
+                .unwrap();
+            let response = read_response_head(&mut client).await;
+            assert!(response.starts_with("HTTP/1.1 403 "), "{}", response);
+            assert!(response.contains("Connection: close\r\n"), "{}", response);
+            // The page names the rule, then the connection is closed
+            let mut page = Vec::new();
+            client.read_to_end(&mut page).await.unwrap();
+            assert!(String::from_utf8_lossy(&page).contains("games.test"));
+        }
+
+        assert!(!reached.is_finished());
+        reached.abort();
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_share_a_server_connection() {
+        // The origin answers every request with its path, on as many connections
+        // as it gets
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_address = origin.local_addr().unwrap();
+        let connections = Arc::new(AtomicU64::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = origin.accept().await.unwrap();
+                accepted.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    loop {
+                        let head = read_response_head(&mut stream).await;
+                        let Some(path) = head.split_whitespace().nth(1) else {
+                            return;
+                        };
+                        let response = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                            path.len(),
+                            path
+                        );
+                        stream
+                            .get_mut()
+                            .write_all(response.as_bytes())
+                            .await
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        let proxy = start_proxy(FilterPolicy::default(), Default::default()).await;
+        let mut client = BufReader::new(TokioTcpStream::connect(proxy).await.unwrap());
+        let request = |path: &str, extra: &str| {
+            format!(
+                "GET http://{0}{1} HTTP/1.1\r\nHost: {0}\r\n{2}\r\n",
+                origin_address, path, extra
+            )
+        };
+        async fn read_response(client: &mut BufReader<TokioTcpStream>) -> (String, String) {
+            let head = read_response_head(client).await;
+            let length: usize = head
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            let mut body = vec![0u8; length];
+            client.read_exact(&mut body).await.unwrap();
+            (head, String::from_utf8(body).unwrap())
+        }
+
+        // Two requests in one write: answered in order, the connection stays open
+        let pipelined = request("/one", "") + &request("/two?x=1", "");
+        client
+            .get_mut()
+            .write_all(pipelined.as_bytes())
+            .await
+            .unwrap();
+        for path in ["/one", "/two?x=1"] {
+            let (head, body) = read_response(&mut client).await;
+            assert!(head.contains("Connection: keep-alive\r\n"), "{}", head);
+            assert_eq!(body, path);
+        }
+
+        // A later request on the same connection, asking to close it after
+        let last = request("/three", "Connection: close\r\n");
+        client.get_mut().write_all(last.as_bytes()).await.unwrap();
+        let (head, body) = read_response(&mut client).await;
+        assert!(head.contains("Connection: close\r\n"), "{}", head);
+        assert_eq!(body, "/three");
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn responses_ended_by_the_server_close_the_connection() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_address = origin.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = origin.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            read_response_head(&mut stream).await;
+            let _ = stream
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 OK\r\n\r\nuntil the end")
+                .await;
+        });
+
+        let proxy = start_proxy(FilterPolicy::default(), Default::default()).await;
+        let mut client = BufReader::new(TokioTcpStream::connect(proxy).await.unwrap());
+        let request = format!(
+            "GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\n\r\n",
+            origin_address
+        );
+        client
+            .get_mut()
+            .write_all(request.as_bytes())
+            .await
+            .unwrap();
+        let response = read_response_head(&mut client).await;
+        assert!(response.contains("Connection: close\r\n"), "{}", response);
+        let mut body = Vec::new();
+        client.read_to_end(&mut body).await.unwrap();
+        assert_eq!(body, b"until the end");
+    }
+
+    #[tokio::test]
+    async fn websocket_upgrade() {
+        // The origin answers the upgrade, then echoes whatever it gets
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_address = origin.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = origin.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let head = read_response_head(&mut stream).await;
+            stream
+                .get_mut()
+                .write_all(
+                    b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                      Connection: Upgrade\r\nSec-WebSocket-Accept: x\r\n\r\nhello",
+                )
+                .await
+                .unwrap();
+            let mut echo = [0u8; 4];
+            stream.read_exact(&mut echo).await.unwrap();
+            stream.get_mut().write_all(&echo).await.unwrap();
+            head
+        });
+
+        let proxy = start_proxy(FilterPolicy::default(), Default::default()).await;
+        let mut client = BufReader::new(TokioTcpStream::connect(proxy).await.unwrap());
+        let request = format!(
+            "GET http://{0}/chat HTTP/1.1\r\nHost: {0}\r\nConnection: keep-alive, Upgrade\r\n\
+             Upgrade: websocket\r\nSec-WebSocket-Key: k\r\n\r\n",
+            origin_address
+        );
+        client
+            .get_mut()
+            .write_all(request.as_bytes())
+            .await
+            .unwrap();
+
+        let response = read_response_head(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 101 "), "{}", response);
+        assert!(response.contains("Upgrade: websocket\r\n"));
+        assert!(response.contains("Connection: Upgrade\r\n"));
+        assert!(response.contains("Sec-WebSocket-Accept: x\r\n"));
+
+        // Bytes the server sent right after its head, then both ways
+        let mut early = [0u8; 5];
+        client.read_exact(&mut early).await.unwrap();
+        assert_eq!(&early, b"hello");
+        client.get_mut().write_all(b"ping").await.unwrap();
+        let mut echo = [0u8; 4];
+        client.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"ping");
+
+        let forwarded = server.await.unwrap();
+        assert!(forwarded.starts_with("GET /chat HTTP/1.1\r\n"));
+        assert!(forwarded.contains("Upgrade: websocket\r\nConnection: Upgrade\r\n"));
+        assert!(!forwarded.contains("keep-alive"));
+    }
+
+    #[tokio::test]
+    async fn plain_http_is_metered() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_port = origin.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = origin.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            read_response_head(&mut stream).await;
+            let _ = stream
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                .await;
+        });
+
+        let start = Utc.with_ymd_and_hms(2026, 10, 12, 15, 0, 0).unwrap();
+        let mut screen_time = ScreenTime::with_clock(Arc::new(SteppingClock::starting_at(start)));
+        screen_time.set_budgets(BudgetSet::from_payload(&BlocklistPayload {
+            budgets: vec![TimeBudget {
+                category: "local".to_string(),
+                rules: vec!["localhost".to_string()],
+                daily_minutes: 60,
+            }],
+            ..Default::default()
+        }));
+        let screen_time = Arc::new(Mutex::new(screen_time));
+
+        let proxy = start_proxy(FilterPolicy::default(), screen_time.clone()).await;
+        let mut client = BufReader::new(TokioTcpStream::connect(proxy).await.unwrap());
+        let request = format!(
+            "GET http://localhost:{0}/ HTTP/1.1\r\nHost: localhost:{0}\r\n\r\n",
+            origin_port
+        );
+        client
+            .get_mut()
+            .write_all(request.as_bytes())
+            .await
+            .unwrap();
+        let response = read_response_head(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+        let mut body = [0u8; 2];
+        client.read_exact(&mut body).await.unwrap();
+
+        let usage = screen_time
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .usage();
+        assert!(usage[0].used_seconds >= 1, "{:?}", usage);
+    }
+
+    #[tokio::test]
+    async fn path_rules_in_intercepted_tunnels() {
+        let origin_ca = throwaway_ca("Origin Test CA");
+        let origin_port = tls_origin(&origin_ca).await;
+        let ca = throwaway_ca("GuardNest Test CA");
+        let root = ca.certificate().clone();
+        let interceptor =
+            Interceptor::with_upstream_roots(ca, &[native_certificate(origin_ca.certificate())])
+                .unwrap();
+
+        let mut policy = FilterPolicy::default();
+        policy.set_rules(RuleSet {
+            paths: UrlMatcher::from_rules(["localhost/shorts"]),
+            ..RuleSet::default()
+        });
+        let proxy =
+            start_intercepting_proxy(policy, Default::default(), Some(Arc::new(interceptor))).await;
+
+        // The tunnel itself is allowed, the rule only covers some paths
+        let mut client = BufReader::new(TokioTcpStream::connect(proxy).await.unwrap());
+        let connect = format!(
+            "CONNECT localhost:{0} HTTP/1.1\r\nHost: localhost:{0}\r\n\r\n",
+            origin_port
+        );
+        client
+            .get_mut()
+            .write_all(connect.as_bytes())
+            .await
+            .unwrap();
+        let response = read_response_head(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+        let tls = browser(&root)
+            .connect("localhost", client.into_inner())
+            .await
+            .unwrap();
+        let mut tls = BufReader::new(tls);
+
+        let request = format!(
+            "GET /watch?v=1 HTTP/1.1\r\nHost: localhost:{}\r\n\r\n",
+            origin_port
+        );
+        tls.get_mut().write_all(request.as_bytes()).await.unwrap();
+        let response = read_response_head(&mut tls).await;
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+        let mut body = [0u8; 17];
+        tls.read_exact(&mut body).await.unwrap();
+        assert_eq!(&body, b"secure /watch?v=1");
+
+        let request = format!(
+            "GET /shorts/abc HTTP/1.1\r\nHost: localhost:{}\r\n\r\n",
+            origin_port
+        );
+        tls.get_mut().write_all(request.as_bytes()).await.unwrap();
+        let response = read_response_head(&mut tls).await;
+        assert!(response.starts_with("HTTP/1.1 403 "), "{}", response);
+        let mut page = Vec::new();
+        let _ = tls.read_to_end(&mut page).await;
+        assert!(String::from_utf8_lossy(&page).contains("||localhost/shorts"));
+    }
+
+    #[tokio::test]
+    async fn unrequested_switch_refused() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_address = origin.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = origin.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            read_response_head(&mut stream).await;
+            let _ = stream
+                .get_mut()
+                .write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: h2c\r\n\r\n")
+                .await;
+        });
+
+        let proxy = start_proxy(FilterPolicy::default(), Default::default()).await;
+        let mut client = BufReader::new(TokioTcpStream::connect(proxy).await.unwrap());
+        let request = format!(
+            "GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\n\r\n",
+            origin_address
+        );
+        client
+            .get_mut()
+            .write_all(request.as_bytes())
+            .await
+            .unwrap();
+        let response = read_response_head(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 502 "), "{}", response);
+    }
+}
@@ -0,0 +1,238 @@
+// ============================================================================
+//  RULE SCHEDULES - WEEKDAYS AND TIME-OF-DAY RANGES
+// ============================================================================
+// A schedule says when a rule (or group of rules) is active, for example:
+// - "games sites on school nights": Sun-Thu, 19:00-07:00
+// - "social media only on weekends": block on Mon-Fri, 00:00-00:00 (all day)
+//
+// Ranges where the end is before the start cross midnight. They belong to the
+// day they start on: "Sun 19:00-07:00" is active from Sunday 19:00 until
+// Monday 07:00, even if Monday is not in the list of days.
+//
+// Time is read from a `Clock`, so the logic can be checked with a fixed time.
+
+use chrono::{DateTime, Datelike, FixedOffset, Local, NaiveDateTime, NaiveTime, Utc, Weekday};
+use serde::{Deserialize, Serialize};
+
+// Source of the current time. The proxy uses `SystemClock`; a fixed clock can
+// be passed instead to check schedules deterministically.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+// The time zone schedules are written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleTimeZone {
+    #[default]
+    Local, // The computer's time zone setting
+    UtcOffsetMinutes(i32), // A fixed offset, e.g. 60 for UTC+01:00
+}
+
+impl ScheduleTimeZone {
+    // Converts an instant to the wall-clock date and time used by schedules
+    pub fn local_time(&self, now: DateTime<Utc>) -> NaiveDateTime {
+        match self {
+            ScheduleTimeZone::Local => now.with_timezone(&Local).naive_local(),
+            ScheduleTimeZone::UtcOffsetMinutes(minutes) => {
+                match FixedOffset::east_opt(minutes * 60) {
+                    Some(offset) => now.with_timezone(&offset).naive_local(),
+                    None => now.naive_utc(), // Offset out of range, fall back to UTC
+                }
+            }
+        }
+    }
+}
+
+// A time-of-day range, e.g. 19:00 to 07:00. Start is inclusive, end exclusive.
+// start == end means the whole day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeRange {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl TimeRange {
+    pub fn crosses_midnight(&self) -> bool {
+        self.end < self.start
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schedule {
+    pub days: Vec<Weekday>,     // Days a range may start on, e.g. ["Mon", "Tue"]
+    pub ranges: Vec<TimeRange>, // Empty means the whole day
+}
+
+impl Schedule {
+    // Checks if the schedule is active at the given wall-clock date and time.
+    pub fn is_active_at(&self, local: NaiveDateTime) -> bool {
+        let day = local.weekday();
+        let yesterday = day.pred();
+        let time = local.time();
+
+        if self.ranges.is_empty() {
+            return self.days.contains(&day);
+        }
+
+        self.ranges.iter().any(|range| {
+            if range.start == range.end {
+                // Whole day
+                self.days.contains(&day)
+            } else if range.crosses_midnight() {
+                // Evening part belongs to today, early-morning part to yesterday's range
+                (self.days.contains(&day) && time >= range.start)
+                    || (self.days.contains(&yesterday) && time < range.end)
+            } else {
+                self.days.contains(&day) && time >= range.start && time < range.end
+            }
+        })
+    }
+
+    pub fn is_active(&self, clock: &dyn Clock, time_zone: ScheduleTimeZone) -> bool {
+        self.is_active_at(time_zone.local_time(clock.now()))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    // Always the same instant (also used by the tests of the policy and budgets)
+    pub struct FixedClock(pub DateTime<Utc>);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> DateTime<Utc> {
+            self.0
+        }
+    }
+
+    fn time(text: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(text, "%H:%M").unwrap()
+    }
+
+    fn range(start: &str, end: &str) -> TimeRange {
+        TimeRange {
+            start: time(start),
+            end: time(end),
+        }
+    }
+
+    // 2026-10-12 is a Monday
+    fn at(day: u32, hour_minute: &str) -> NaiveDateTime {
+        chrono::NaiveDate::from_ymd_opt(2026, 10, day)
+            .unwrap()
+            .and_time(time(hour_minute))
+    }
+
+    #[test]
+    fn range_within_a_day() {
+        let schedule = Schedule {
+            days: vec![Weekday::Mon, Weekday::Wed],
+            ranges: vec![range("08:00", "15:30")],
+        };
+        assert!(schedule.is_active_at(at(12, "08:00"))); // Start included
+        assert!(schedule.is_active_at(at(14, "15:29")));
+        assert!(!schedule.is_active_at(at(12, "15:30"))); // End excluded
+        assert!(!schedule.is_active_at(at(12, "07:59")));
+        assert!(!schedule.is_active_at(at(13, "10:00"))); // Tuesday
+    }
+
+    #[test]
+    fn range_crossing_midnight() {
+        // School nights: Sun-Thu, 19:00-07:00
+        let schedule = Schedule {
+            days: vec![
+                Weekday::Sun,
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+            ],
+            ranges: vec![range("19:00", "07:00")],
+        };
+        assert!(range("19:00", "07:00").crosses_midnight());
+        assert!(schedule.is_active_at(at(11, "23:00"))); // Sunday evening
+        assert!(schedule.is_active_at(at(12, "06:59"))); // Monday morning, Sunday's range
+        assert!(!schedule.is_active_at(at(12, "07:00")));
+        assert!(!schedule.is_active_at(at(12, "18:59")));
+        assert!(schedule.is_active_at(at(16, "06:00"))); // Friday morning, Thursday's range
+        assert!(!schedule.is_active_at(at(16, "20:00"))); // Friday evening
+        assert!(!schedule.is_active_at(at(17, "06:00"))); // Saturday morning
+    }
+
+    #[test]
+    fn whole_days() {
+        let weekends = Schedule {
+            days: vec![Weekday::Sat, Weekday::Sun],
+            ranges: Vec::new(),
+        };
+        assert!(weekends.is_active_at(at(17, "00:00")));
+        assert!(weekends.is_active_at(at(18, "23:59")));
+        assert!(!weekends.is_active_at(at(19, "00:00")));
+
+        let same_start_and_end = Schedule {
+            days: vec![Weekday::Tue],
+            ranges: vec![range("00:00", "00:00")],
+        };
+        assert!(same_start_and_end.is_active_at(at(13, "12:00")));
+        assert!(!same_start_and_end.is_active_at(at(14, "00:30")));
+
+        let never = Schedule {
+            days: Vec::new(),
+            ranges: vec![range("00:00", "00:00")],
+        };
+        assert!(!never.is_active_at(at(13, "12:00")));
+    }
+
+    #[test]
+    fn time_zones() {
+        let schedule = Schedule {
+            days: vec![Weekday::Mon],
+            ranges: vec![range("08:00", "09:00")],
+        };
+        // Monday 07:30 UTC
+        let clock = FixedClock(Utc.with_ymd_and_hms(2026, 10, 12, 7, 30, 0).unwrap());
+        assert!(!schedule.is_active(&clock, ScheduleTimeZone::UtcOffsetMinutes(0)));
+        assert!(schedule.is_active(&clock, ScheduleTimeZone::UtcOffsetMinutes(60)));
+        assert!(schedule.is_active(&clock, ScheduleTimeZone::UtcOffsetMinutes(75)));
+        assert!(!schedule.is_active(&clock, ScheduleTimeZone::UtcOffsetMinutes(90))); // 09:00, end excluded
+        assert!(!schedule.is_active(&clock, ScheduleTimeZone::UtcOffsetMinutes(-60)));
+
+        // Sunday 23:30 UTC is already Monday east of UTC
+        let clock = FixedClock(Utc.with_ymd_and_hms(2026, 10, 11, 23, 30, 0).unwrap());
+        assert_eq!(
+            ScheduleTimeZone::UtcOffsetMinutes(9 * 60).local_time(clock.now()),
+            at(12, "08:30")
+        );
+        assert!(schedule.is_active(&clock, ScheduleTimeZone::UtcOffsetMinutes(9 * 60)));
+
+        // An offset out of range is read as UTC
+        let utc = Utc.with_ymd_and_hms(2026, 10, 12, 8, 15, 0).unwrap();
+        assert_eq!(
+            ScheduleTimeZone::UtcOffsetMinutes(48 * 60).local_time(utc),
+            at(12, "08:15")
+        );
+    }
+
+    #[test]
+    fn schedule_json() {
+        let schedule: Schedule = serde_json::from_str(
+            r#"{"days":["Sun","Mon"],"ranges":[{"start":"19:00:00","end":"07:00:00"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(schedule.days, [Weekday::Sun, Weekday::Mon]);
+        assert_eq!(schedule.ranges, [range("19:00", "07:00")]);
+        let zone: ScheduleTimeZone = serde_json::from_str(r#"{"utc_offset_minutes":60}"#).unwrap();
+        assert_eq!(zone, ScheduleTimeZone::UtcOffsetMinutes(60));
+    }
+}
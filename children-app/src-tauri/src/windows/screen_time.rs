@@ -0,0 +1,381 @@
+// ============================================================================
+//  SCREEN TIME - DAILY BUDGETS PER DOMAIN CATEGORY
+// ============================================================================
+// Besides hard blocks, a category of sites can get a daily budget, for example
+// "video" (youtube.com, twitch.tv) up to 60 minutes a day:
+// 1. Every HTTPS tunnel and plain HTTP request reports when traffic flows
+// 2. Traffic reports close together count as active time for the category;
+//    longer gaps (a tunnel sitting idle, a paused video) are not counted
+// 3. Once a category has used up its budget, new connections to it are refused
+// 4. The counters start over every day at a configurable local time, and are
+//    saved to disk (in one step, see `write_atomic`) so restarting the app
+//    doesn't reset them
+//
+// Activity is tracked per category, not per tunnel: a page opening six
+// connections to the same site at once still counts the time only once.
+
+use crate::logger::ProxyLogger;
+use crate::windows::domain_matcher::DomainMatcher;
+use crate::windows::http_service::fetch::BlocklistPayload;
+use crate::windows::key_protector::write_atomic;
+use crate::windows::schedule::{Clock, ScheduleTimeZone, SystemClock};
+use chrono::{DateTime, Duration as TimeDelta, NaiveDate, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use std::{fs, io, path::Path};
+
+// Where the counters are saved between restarts
+pub const SCREEN_TIME_PATH: &str = "C:\\ProgramData\\GuardNest\\screen_time.json";
+
+// Two traffic reports further apart than this are not counted as active time
+const ACTIVE_GAP: TimeDelta = TimeDelta::seconds(10);
+
+// How often a tunnel reports traffic (a busy tunnel moves many chunks per second)
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+// A category with its rules and daily limit
+struct Budget {
+    category: String,
+    rules: DomainMatcher,
+    limit: Duration,
+}
+
+// The budgets from the parent's dashboard
+#[derive(Default)]
+pub struct BudgetSet {
+    budgets: Vec<Budget>,
+    reset_time: NaiveTime,       // Local time the budgets start over
+    time_zone: ScheduleTimeZone, // Time zone `reset_time` is written in
+}
+
+impl BudgetSet {
+    // Builds the tries for a fetched blocklist
+    pub fn from_payload(payload: &BlocklistPayload) -> Self {
+        BudgetSet {
+            budgets: payload
+                .budgets
+                .iter()
+                .map(|budget| Budget {
+                    category: budget.category.clone(),
+                    rules: DomainMatcher::from_rules(&budget.rules),
+                    limit: Duration::from_secs(u64::from(budget.daily_minutes) * 60),
+                })
+                .collect(),
+            reset_time: payload.budget_reset,
+            time_zone: payload.time_zone,
+        }
+    }
+}
+
+// Result of checking a host against the budgets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BudgetCheck {
+    Untracked,         // Not in any category, no limit
+    Allowed(String),   // In this category, time left today
+    Exhausted(String), // In this category, budget used up
+}
+
+// Time used and allowed for one category, as shown in the app
+#[derive(Debug, Clone, Serialize)]
+pub struct BudgetUsage {
+    pub category: String,
+    pub used_seconds: u64,
+    pub limit_seconds: u64,
+}
+
+// What is written to `SCREEN_TIME_PATH`
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SavedUsage {
+    day: Option<NaiveDate>,
+    used_seconds: HashMap<String, u64>,
+}
+
+pub struct ScreenTime {
+    budgets: BudgetSet,
+    clock: Arc<dyn Clock>,
+    day: Option<NaiveDate>,          // Budget day the counters belong to
+    used: HashMap<String, Duration>, // Active time per category on `day`
+    last_activity: HashMap<String, DateTime<Utc>>, // Last traffic report per category
+    dirty: bool,                     // Counters changed since the last save
+}
+
+impl Default for ScreenTime {
+    fn default() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+}
+
+impl ScreenTime {
+    // Creates a tracker that reads the time from `clock` (e.g. a fixed clock in tests)
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        ScreenTime {
+            budgets: BudgetSet::default(),
+            clock,
+            day: None,
+            used: HashMap::new(),
+            last_activity: HashMap::new(),
+            dirty: false,
+        }
+    }
+
+    // Replaces the budgets (build the `BudgetSet` before taking the lock).
+    // Time already used today is kept.
+    pub fn set_budgets(&mut self, budgets: BudgetSet) {
+        self.budgets = budgets;
+    }
+
+    // The budget day `now` belongs to. Before the reset time it is still "yesterday".
+    fn budget_day(&self, now: DateTime<Utc>) -> NaiveDate {
+        let local = self.budgets.time_zone.local_time(now);
+        if local.time() < self.budgets.reset_time {
+            local.date().pred_opt().unwrap_or(local.date())
+        } else {
+            local.date()
+        }
+    }
+
+    // Starts a new day if the reset time has passed since the counters were last used
+    fn roll_over(&mut self, now: DateTime<Utc>) {
+        let today = self.budget_day(now);
+        if self.day != Some(today) {
+            if self.day.is_some() {
+                ProxyLogger::log_screen_time_reset(today);
+            }
+            self.day = Some(today);
+            self.used.clear();
+            self.last_activity.clear();
+            self.dirty = true;
+        }
+    }
+
+    // Returns the first budget whose rules cover `host`
+    fn budget_for(&self, host: &str) -> Option<&Budget> {
+        self.budgets
+            .budgets
+            .iter()
+            .find(|budget| budget.rules.matches(host))
+    }
+
+    // Decides if a new connection to `host` may be opened.
+    pub fn check(&mut self, host: &str) -> BudgetCheck {
+        self.roll_over(self.clock.now());
+
+        let Some(budget) = self.budget_for(host) else {
+            return BudgetCheck::Untracked;
+        };
+        let used = self.used.get(&budget.category).copied().unwrap_or_default();
+        if used >= budget.limit {
+            BudgetCheck::Exhausted(budget.category.clone())
+        } else {
+            BudgetCheck::Allowed(budget.category.clone())
+        }
+    }
+
+    // Records that traffic is flowing for `category` right now. The time since the
+    // previous report is added to the category, unless the gap was too long.
+    pub fn record_activity(&mut self, category: &str) {
+        let now = self.clock.now();
+        self.roll_over(now);
+
+        if let Some(last) = self.last_activity.get(category) {
+            let gap = now - *last;
+            if gap > TimeDelta::zero() && gap <= ACTIVE_GAP {
+                *self.used.entry(category.to_string()).or_default() +=
+                    gap.to_std().unwrap_or_default();
+                self.dirty = true;
+            }
+        }
+        self.last_activity.insert(category.to_string(), now);
+    }
+
+    // Time used and allowed today for every category
+    pub fn usage(&mut self) -> Vec<BudgetUsage> {
+        self.roll_over(self.clock.now());
+
+        self.budgets
+            .budgets
+            .iter()
+            .map(|budget| BudgetUsage {
+                category: budget.category.clone(),
+                used_seconds: self
+                    .used
+                    .get(&budget.category)
+                    .map_or(0, |used| used.as_secs()),
+                limit_seconds: budget.limit.as_secs(),
+            })
+            .collect()
+    }
+
+    // Restores the counters saved by `save`. Counters from an earlier day are dropped
+    // the next time the tracker is used.
+    pub fn load(&mut self, path: &Path) -> io::Result<()> {
+        let data = fs::read(path)?;
+        let saved: SavedUsage = serde_json::from_slice(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        self.day = saved.day;
+        self.used = saved
+            .used_seconds
+            .into_iter()
+            .map(|(category, seconds)| (category, Duration::from_secs(seconds)))
+            .collect();
+        self.last_activity.clear();
+        self.dirty = false;
+        Ok(())
+    }
+
+    // Writes the counters to disk if they changed since the last save.
+    pub fn save(&mut self, path: &Path) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let saved = SavedUsage {
+            day: self.day,
+            used_seconds: self
+                .used
+                .iter()
+                .map(|(category, used)| (category.clone(), used.as_secs()))
+                .collect(),
+        };
+        let data = serde_json::to_vec_pretty(&saved)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        write_atomic(path, &data)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+// Reports traffic of one tunnel to the tracker, at most once per `REPORT_INTERVAL`.
+// Shared by both directions of the tunnel.
+pub struct ActivityMeter {
+    screen_time: Arc<Mutex<ScreenTime>>,
+    category: String,
+    last_report: Mutex<Option<Instant>>,
+}
+
+impl ActivityMeter {
+    pub fn new(screen_time: Arc<Mutex<ScreenTime>>, category: String) -> Self {
+        ActivityMeter {
+            screen_time,
+            category,
+            last_report: Mutex::new(None),
+        }
+    }
+
+    // Called every time bytes go through the tunnel
+    pub fn traffic(&self) {
+        let mut last_report = self
+            .last_report
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if last_report.is_some_and(|last| last.elapsed() < REPORT_INTERVAL) {
+            return;
+        }
+        *last_report = Some(Instant::now());
+
+        self.screen_time
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .record_activity(&self.category);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::windows::http_service::fetch::TimeBudget;
+    use chrono::TimeZone;
+
+    // Moves one second forward every time it is read (also used by the proxy tests)
+    pub struct SteppingClock(pub Mutex<DateTime<Utc>>);
+
+    impl SteppingClock {
+        pub fn starting_at(now: DateTime<Utc>) -> Self {
+            SteppingClock(Mutex::new(now))
+        }
+    }
+
+    impl Clock for SteppingClock {
+        fn now(&self) -> DateTime<Utc> {
+            let mut now = self.0.lock().unwrap();
+            *now += TimeDelta::seconds(1);
+            *now
+        }
+    }
+
+    // A "video" budget of `minutes` a day for youtube.com
+    pub fn video_budget(minutes: u32) -> BudgetSet {
+        BudgetSet::from_payload(&BlocklistPayload {
+            budgets: vec![TimeBudget {
+                category: "video".to_string(),
+                rules: vec!["youtube.com".to_string()],
+                daily_minutes: minutes,
+            }],
+            ..Default::default()
+        })
+    }
+
+    fn tracker() -> ScreenTime {
+        let start = Utc.with_ymd_and_hms(2026, 10, 12, 15, 0, 0).unwrap();
+        let mut screen_time = ScreenTime::with_clock(Arc::new(SteppingClock::starting_at(start)));
+        screen_time.set_budgets(video_budget(1));
+        screen_time
+    }
+
+    fn used(screen_time: &mut ScreenTime) -> u64 {
+        screen_time.usage()[0].used_seconds
+    }
+
+    #[test]
+    fn activity_uses_up_the_budget() {
+        let mut screen_time = tracker();
+        assert_eq!(screen_time.check("example.com"), BudgetCheck::Untracked);
+        assert_eq!(
+            screen_time.check("www.youtube.com"),
+            BudgetCheck::Allowed("video".to_string())
+        );
+
+        for _ in 0..30 {
+            screen_time.record_activity("video");
+        }
+        // The first report only starts the count
+        assert_eq!(used(&mut screen_time), 29);
+        for _ in 0..31 {
+            screen_time.record_activity("video");
+        }
+        assert_eq!(
+            screen_time.check("youtube.com"),
+            BudgetCheck::Exhausted("video".to_string())
+        );
+    }
+
+    #[test]
+    fn saved_atomically_and_restored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("screen_time.json");
+
+        let mut screen_time = tracker();
+        for _ in 0..5 {
+            screen_time.record_activity("video");
+        }
+        screen_time.save(&path).unwrap();
+        let files: Vec<_> = fs::read_dir(dir.path()).unwrap().collect();
+        assert_eq!(files.len(), 1, "temporary file left behind");
+
+        // Saving over the old file replaces it
+        screen_time.record_activity("video");
+        screen_time.save(&path).unwrap();
+
+        let mut restored = tracker();
+        restored.load(&path).unwrap();
+        assert_eq!(used(&mut restored), 5);
+
+        fs::write(&path, b"{ not json").unwrap();
+        let error = restored.load(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,614 @@
+// ============================================================================
+//  TRUST STORES - WHERE THE GUARDNEST ROOT CA IS INSTALLED
+// ============================================================================
+// Browsers only accept the leaves minted for intercepted hosts if the GuardNest
+// Root CA is in a store they trust. Which stores depends on the platform:
+// 1. Windows: the LocalMachine Root store, used by Edge, Chrome, and Firefox
+//    (enterprise roots)
+// 2. Linux: the system store, a PEM file in the anchors directory and the
+//    distribution's update command:
+//    - Debian/Ubuntu: /usr/local/share/ca-certificates + update-ca-certificates
+//    - Fedora/RHEL: /etc/pki/ca-trust/source/anchors + update-ca-trust
+// 3. Linux: the NSS databases of Firefox profiles and Chromium (~/.pki/nssdb),
+//    which don't read the system store, through `certutil` from the NSS tools
+//
+// Every backend can install a root, tell which GuardNest roots it holds (so one
+// can be verified by its fingerprint), and uninstall one. Roots are always
+// identified by their SHA-256 fingerprint, see `certificate::fingerprint`.
+
+use crate::windows::certificate::{fingerprint, thumbprint, ROOT_NAME};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use openssl::x509::X509;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+// Prefix of the files and NSS nicknames of installed roots
+const FILE_PREFIX: &str = "guardnest-";
+
+// Fingerprint characters in file names and nicknames, enough to tell roots apart
+const SHORT_FINGERPRINT: usize = 16;
+
+// A certificate store the GuardNest Root CA can be installed in
+pub trait TrustStore: Send + Sync {
+    // For logs, e.g. "Windows Root store"
+    fn name(&self) -> String;
+
+    // The GuardNest roots in the store
+    fn installed(&self) -> io::Result<Vec<X509>>;
+
+    // Adds `root` to the store, replacing it if it's already there
+    fn install(&self, root: &X509) -> io::Result<()>;
+
+    // Removes the GuardNest root with this SHA-256 fingerprint. Returns false if
+    // it wasn't installed.
+    fn uninstall(&self, fingerprint: &str) -> io::Result<bool>;
+
+    // Whether the GuardNest root with this SHA-256 fingerprint is installed
+    fn contains(&self, fingerprint: &str) -> io::Result<bool> {
+        for root in self.installed()? {
+            if fingerprint_of(&root)?.eq_ignore_ascii_case(fingerprint) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+// The stores of this machine the GuardNest Root CA belongs in
+pub fn default_trust_stores() -> Vec<Box<dyn TrustStore>> {
+    let mut stores: Vec<Box<dyn TrustStore>> = Vec::new();
+    #[cfg(windows)]
+    stores.push(Box::new(WindowsRootStore));
+    #[cfg(target_os = "linux")]
+    {
+        let root = Path::new("/");
+        if let Some(system) = LinuxSystemStore::detect(root) {
+            stores.push(Box::new(system));
+        }
+        for db in nss_databases(root) {
+            stores.push(Box::new(NssStore::new(db)));
+        }
+    }
+    stores
+}
+
+fn fingerprint_of(cert: &X509) -> io::Result<String> {
+    fingerprint(cert).map_err(io::Error::other)
+}
+
+// "guardnest-" and the start of the fingerprint, unique per root
+fn short_name(fingerprint: &str) -> String {
+    let end = fingerprint.len().min(SHORT_FINGERPRINT);
+    format!("{}{}", FILE_PREFIX, &fingerprint[..end])
+}
+
+// Runs `command`, failing with its stderr if it doesn't succeed
+fn run(command: &mut Command) -> io::Result<Output> {
+    let output = command.output()?;
+    check_status(command, output)
+}
+
+// Same as `run`, writing `input` to the command's stdin
+fn run_with_input(command: &mut Command, input: &[u8]) -> io::Result<Output> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let written = child.stdin.take().map(|mut stdin| stdin.write_all(input));
+    let output = child.wait_with_output()?;
+    if let Some(Err(e)) = written {
+        if output.status.success() {
+            return Err(e);
+        }
+    }
+    check_status(command, output)
+}
+
+fn check_status(command: &Command, output: Output) -> io::Result<Output> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{} failed with status {:?}: {}",
+            program,
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(output)
+}
+
+// ----------------------------------------------------------------------------
+//  WINDOWS ROOT STORE
+// ----------------------------------------------------------------------------
+
+// The LocalMachine Root store, through PowerShell (listing) and certutil
+pub struct WindowsRootStore;
+
+impl TrustStore for WindowsRootStore {
+    fn name(&self) -> String {
+        "Windows Root store".to_string()
+    }
+
+    fn installed(&self) -> io::Result<Vec<X509>> {
+        let script = format!(
+            "Get-ChildItem Cert:\\LocalMachine\\Root | \
+             Where-Object {{ $_.Subject -like '*{}*' }} | \
+             ForEach-Object {{ [Convert]::ToBase64String($_.RawData) }}",
+            ROOT_NAME
+        );
+        let output = run(Command::new("powershell").args([
+            "-NoProfile",
+            "-NonInteractive",
+            "-ExecutionPolicy",
+            "Bypass",
+            "-Command",
+            &script,
+        ]))?;
+
+        let mut roots = Vec::new();
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let der = STANDARD
+                .decode(line)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            roots.push(X509::from_der(&der).map_err(io::Error::other)?);
+        }
+        Ok(roots)
+    }
+
+    fn install(&self, root: &X509) -> io::Result<()> {
+        // certutil only imports from a file. The certificate is public, but the file
+        // must not be one another account could plant or swap before certutil reads
+        // it: a random name, created exclusively, removed when dropped.
+        let mut file = tempfile::Builder::new()
+            .prefix(&format!("{}-", short_name(&fingerprint_of(root)?)))
+            .suffix(".crt")
+            .tempfile()?;
+        file.write_all(&root.to_der().map_err(io::Error::other)?)?;
+        file.flush()?;
+        run(Command::new("certutil")
+            .args(["-f", "-addstore", "Root"])
+            .arg(file.path()))
+        .map(|_| ())
+    }
+
+    fn uninstall(&self, fingerprint: &str) -> io::Result<bool> {
+        for root in self.installed()? {
+            if fingerprint_of(&root)?.eq_ignore_ascii_case(fingerprint) {
+                // The Root store identifies certificates by their SHA-1 thumbprint
+                let thumbprint = thumbprint(&root).map_err(io::Error::other)?;
+                run(Command::new("certutil").args(["-delstore", "Root", &thumbprint]))?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+// ----------------------------------------------------------------------------
+//  LINUX SYSTEM STORE
+// ----------------------------------------------------------------------------
+
+// The system store of a Linux distribution: one PEM file per root in the anchors
+// directory, merged into the system bundle by the update command
+pub struct LinuxSystemStore {
+    anchors: PathBuf,
+    update: Option<Vec<String>>, // Program and arguments, run after every change
+}
+
+impl LinuxSystemStore {
+    // Debian, Ubuntu and derivatives, under `root` (e.g. "/")
+    pub fn debian(root: &Path) -> Self {
+        LinuxSystemStore {
+            anchors: root.join("usr/local/share/ca-certificates"),
+            update: Some(vec!["update-ca-certificates".to_string()]),
+        }
+    }
+
+    // Fedora, RHEL and derivatives, under `root`
+    pub fn red_hat(root: &Path) -> Self {
+        LinuxSystemStore {
+            anchors: root.join("etc/pki/ca-trust/source/anchors"),
+            update: Some(vec!["update-ca-trust".to_string(), "extract".to_string()]),
+        }
+    }
+
+    // The layout used under `root`, if it's one of the known ones
+    pub fn detect(root: &Path) -> Option<Self> {
+        if root.join("usr/share/ca-certificates").is_dir()
+            || root.join("etc/ca-certificates.conf").is_file()
+        {
+            Some(Self::debian(root))
+        } else if root.join("etc/pki/ca-trust").is_dir() {
+            Some(Self::red_hat(root))
+        } else {
+            None
+        }
+    }
+
+    // Only changes the anchors directory, e.g. for a store under a test directory,
+    // where the update command would rebuild the real system bundle
+    pub fn without_update(self) -> Self {
+        LinuxSystemStore {
+            update: None,
+            ..self
+        }
+    }
+
+    pub fn anchors(&self) -> &Path {
+        &self.anchors
+    }
+
+    // Our anchor files, with the root in each
+    fn anchor_files(&self) -> io::Result<Vec<(PathBuf, X509)>> {
+        let entries = match fs::read_dir(&self.anchors) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut files = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let ours = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(FILE_PREFIX) && name.ends_with(".crt"));
+            if !ours {
+                continue;
+            }
+            match X509::from_pem(&fs::read(&path)?) {
+                Ok(root) => files.push((path, root)),
+                Err(e) => eprintln!("⚠️ Ignoring unreadable anchor {}: {}", path.display(), e),
+            }
+        }
+        Ok(files)
+    }
+
+    fn update(&self) -> io::Result<()> {
+        match &self.update {
+            Some(command) => run(Command::new(&command[0]).args(&command[1..])).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl TrustStore for LinuxSystemStore {
+    fn name(&self) -> String {
+        format!("system store {}", self.anchors.display())
+    }
+
+    fn installed(&self) -> io::Result<Vec<X509>> {
+        Ok(self
+            .anchor_files()?
+            .into_iter()
+            .map(|(_, root)| root)
+            .collect())
+    }
+
+    fn install(&self, root: &X509) -> io::Result<()> {
+        // update-ca-certificates only picks up files ending in ".crt"
+        let path = self
+            .anchors
+            .join(format!("{}.crt", short_name(&fingerprint_of(root)?)));
+        fs::create_dir_all(&self.anchors)?;
+        fs::write(&path, root.to_pem().map_err(io::Error::other)?)?;
+        self.update()
+    }
+
+    fn uninstall(&self, fingerprint: &str) -> io::Result<bool> {
+        let mut removed = false;
+        for (path, root) in self.anchor_files()? {
+            if fingerprint_of(&root)?.eq_ignore_ascii_case(fingerprint) {
+                fs::remove_file(&path)?;
+                removed = true;
+            }
+        }
+        if removed {
+            self.update()?;
+        }
+        Ok(removed)
+    }
+}
+
+// ----------------------------------------------------------------------------
+//  NSS DATABASES (FIREFOX, CHROMIUM)
+// ----------------------------------------------------------------------------
+
+// An NSS certificate database (the directory holding cert9.db), changed with
+// `certutil` from the NSS tools (libnss3-tools, nss-tools). Roots get the
+// nickname "GuardNest Local CA <start of the fingerprint>" and are trusted to
+// issue server certificates ("C,,").
+pub struct NssStore {
+    db: PathBuf,
+    certutil: PathBuf,
+}
+
+impl NssStore {
+    pub fn new(db: impl Into<PathBuf>) -> Self {
+        NssStore {
+            db: db.into(),
+            certutil: PathBuf::from("certutil"),
+        }
+    }
+
+    // Uses another `certutil` program, e.g. one not on the PATH
+    pub fn with_certutil(self, certutil: impl Into<PathBuf>) -> Self {
+        NssStore {
+            certutil: certutil.into(),
+            ..self
+        }
+    }
+
+    fn certutil(&self) -> Command {
+        let mut command = Command::new(&self.certutil);
+        command.arg("-d").arg(format!("sql:{}", self.db.display()));
+        command
+    }
+
+    // Nicknames of our roots in the database
+    fn nicknames(&self) -> io::Result<Vec<String>> {
+        let output = run(self.certutil().arg("-L"))?;
+
+        // A header, then one "<nickname> <trust attributes>" line per certificate
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.trim_end().rsplit_once(char::is_whitespace))
+            .map(|(nickname, _trust)| nickname.trim_end().to_string())
+            .filter(|nickname| nickname.starts_with(ROOT_NAME))
+            .collect())
+    }
+
+    fn export(&self, nickname: &str) -> io::Result<X509> {
+        let output = run(self.certutil().args(["-L", "-a", "-n", nickname]))?;
+        X509::from_pem(&output.stdout).map_err(io::Error::other)
+    }
+}
+
+impl TrustStore for NssStore {
+    fn name(&self) -> String {
+        format!("NSS database {}", self.db.display())
+    }
+
+    fn installed(&self) -> io::Result<Vec<X509>> {
+        self.nicknames()?
+            .iter()
+            .map(|nickname| self.export(nickname))
+            .collect()
+    }
+
+    fn install(&self, root: &X509) -> io::Result<()> {
+        let fingerprint = fingerprint_of(root)?;
+        let nickname = format!("{} {}", ROOT_NAME, &fingerprint[..SHORT_FINGERPRINT]);
+        // Without "-i" certutil reads the certificate from stdin, so there is no
+        // file another user could swap before it's read
+        run_with_input(
+            self.certutil().args(["-A", "-t", "C,,", "-n", &nickname]),
+            &root.to_pem().map_err(io::Error::other)?,
+        )
+        .map(|_| ())
+    }
+
+    fn uninstall(&self, fingerprint: &str) -> io::Result<bool> {
+        for nickname in self.nicknames()? {
+            if fingerprint_of(&self.export(&nickname)?)?.eq_ignore_ascii_case(fingerprint) {
+                run(self.certutil().args(["-D", "-n", &nickname]))?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+// NSS databases of the users under `root` (e.g. "/"): every Firefox profile
+// (also the snap's) and the shared one Chromium uses. Only existing databases,
+// an NSS database created by us would be owned by the wrong user.
+pub fn nss_databases(root: &Path) -> Vec<PathBuf> {
+    let mut homes = vec![root.join("root")];
+    if let Ok(entries) = fs::read_dir(root.join("home")) {
+        homes.extend(entries.flatten().map(|entry| entry.path()));
+    }
+
+    let mut databases = Vec::new();
+    for home in homes {
+        databases.push(home.join(".pki/nssdb"));
+        for profiles in [
+            home.join(".mozilla/firefox"),
+            home.join("snap/firefox/common/.mozilla/firefox"),
+        ] {
+            if let Ok(entries) = fs::read_dir(profiles) {
+                databases.extend(entries.flatten().map(|entry| entry.path()));
+            }
+        }
+    }
+    databases.retain(|db| db.join("cert9.db").is_file());
+    databases.sort();
+    databases
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::windows::interception::tests::throwaway_ca;
+
+    fn root() -> X509 {
+        throwaway_ca(ROOT_NAME).root().clone()
+    }
+
+    // Installs, finds and removes two roots, leaving what isn't ours alone
+    fn exercise(store: &dyn TrustStore) {
+        let (first, second) = (root(), root());
+        let first_fingerprint = fingerprint(&first).unwrap();
+        let second_fingerprint = fingerprint(&second).unwrap();
+
+        assert!(store.installed().unwrap().is_empty());
+        assert!(!store.contains(&first_fingerprint).unwrap());
+
+        store.install(&first).unwrap();
+        store.install(&second).unwrap();
+        store.install(&first).unwrap(); // Already there: replaced, not added again
+        assert_eq!(store.installed().unwrap().len(), 2);
+        assert!(store.contains(&first_fingerprint).unwrap());
+        assert!(store
+            .contains(&second_fingerprint.to_ascii_uppercase())
+            .unwrap());
+
+        assert!(store.uninstall(&first_fingerprint).unwrap());
+        assert!(!store.uninstall(&first_fingerprint).unwrap());
+        assert!(!store.contains(&first_fingerprint).unwrap());
+        assert!(store.contains(&second_fingerprint).unwrap());
+
+        assert!(store.uninstall(&second_fingerprint).unwrap());
+        assert!(store.installed().unwrap().is_empty());
+    }
+
+    #[test]
+    fn detects_the_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(LinuxSystemStore::detect(dir.path()).is_none());
+
+        fs::create_dir_all(dir.path().join("etc/pki/ca-trust")).unwrap();
+        let store = LinuxSystemStore::detect(dir.path()).unwrap();
+        assert_eq!(
+            store.anchors(),
+            dir.path().join("etc/pki/ca-trust/source/anchors")
+        );
+
+        // Debian wins if both are there
+        fs::create_dir_all(dir.path().join("usr/share/ca-certificates")).unwrap();
+        let store = LinuxSystemStore::detect(dir.path()).unwrap();
+        assert_eq!(
+            store.anchors(),
+            dir.path().join("usr/local/share/ca-certificates")
+        );
+    }
+
+    #[test]
+    fn linux_system_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LinuxSystemStore::debian(dir.path()).without_update();
+
+        // Other anchors, and broken files that look like ours, are skipped
+        fs::create_dir_all(store.anchors()).unwrap();
+        let other = store.anchors().join("school.crt");
+        fs::write(&other, root().to_pem().unwrap()).unwrap();
+        fs::write(store.anchors().join("guardnest-broken.crt"), "not PEM").unwrap();
+
+        exercise(&store);
+        assert!(other.is_file());
+        assert!(store.anchors().join("guardnest-broken.crt").is_file());
+    }
+
+    #[test]
+    fn anchors_are_named_for_update_ca_certificates() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LinuxSystemStore::red_hat(dir.path()).without_update();
+        let root = root();
+        store.install(&root).unwrap();
+
+        let name = format!("{}.crt", short_name(&fingerprint(&root).unwrap()));
+        let installed = X509::from_pem(&fs::read(store.anchors().join(&name)).unwrap()).unwrap();
+        assert_eq!(installed.to_der().unwrap(), root.to_der().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failed_update_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LinuxSystemStore {
+            update: Some(vec!["false".to_string()]),
+            ..LinuxSystemStore::debian(dir.path())
+        };
+        assert!(store.install(&root()).is_err());
+    }
+
+    // A stand-in for NSS's certutil keeping each certificate as "<nickname>.pem"
+    // in the database directory, with the same arguments and output format
+    #[cfg(unix)]
+    fn fake_certutil(dir: &Path) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = dir.join("certutil");
+        let script = r#"#!/bin/sh
+db=${2#sql:}
+shift 2
+case "$1" in
+-L)
+    if [ "$2" = "-a" ]; then
+        cat "$db/$4.pem"
+    else
+        echo "Certificate Nickname                              Trust Attributes"
+        echo "                                                  SSL,S/MIME,JAR/XPI"
+        echo
+        for file in "$db"/*.pem; do
+            [ -e "$file" ] && printf '%-50s C,,\n' "$(basename "$file" .pem)"
+        done
+    fi ;;
+-A) cat > "$db/$5.pem" ;;
+-D) rm "$db/$3.pem" ;;
+*) echo "bad arguments" >&2; exit 1 ;;
+esac
+"#;
+        fs::write(&path, script).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn nss_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("nssdb");
+        fs::create_dir(&db).unwrap();
+        let store = NssStore::new(&db).with_certutil(fake_certutil(dir.path()));
+
+        // Someone else's root in the same database
+        let other = throwaway_ca("Other CA").root().clone();
+        fs::write(db.join("Other CA.pem"), other.to_pem().unwrap()).unwrap();
+
+        exercise(&store);
+        assert!(db.join("Other CA.pem").is_file());
+        assert!(store.name().contains("nssdb"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn certutil_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let store =
+            NssStore::new(dir.path().join("missing")).with_certutil(fake_certutil(dir.path()));
+        assert!(store.install(&root()).is_err());
+
+        let store = NssStore::new(dir.path()).with_certutil(dir.path().join("no-such-certutil"));
+        assert!(store.installed().is_err());
+    }
+
+    #[test]
+    fn finds_nss_databases() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = |path: &str| {
+            let path = dir.path().join(path);
+            fs::create_dir_all(&path).unwrap();
+            fs::write(path.join("cert9.db"), "").unwrap();
+            path
+        };
+        let expected = vec![
+            db("home/alex/.mozilla/firefox/abc.default-release"),
+            db("home/alex/.pki/nssdb"),
+            db("home/sam/snap/firefox/common/.mozilla/firefox/xyz.default"),
+            db("root/.pki/nssdb"),
+        ];
+        // Profiles without a database aren't created
+        fs::create_dir_all(dir.path().join("home/sam/.mozilla/firefox/empty")).unwrap();
+        fs::create_dir_all(dir.path().join("home/sam/.pki/nssdb")).unwrap();
+
+        assert_eq!(nss_databases(dir.path()), expected);
+        assert!(nss_databases(&dir.path().join("missing")).is_empty());
+    }
+}
@@ -0,0 +1,234 @@
+// ============================================================================
+//  URL MATCHER - PATH RULES FOR REQUESTS THE PROXY CAN READ
+// ============================================================================
+// Domain rules decide for a whole host. A rule with a path blocks only part of a
+// site, e.g. the short videos on a video site while the rest stays reachable:
+//
+//     youtube.com/shorts     <- "/shorts", "/shorts/abc", "/shorts?feature=x"
+//                               on youtube.com and its subdomains, not "/shortsx"
+//     =m.example.com/games/  <- everything under "/games/" on m.example.com only
+//
+// The part before the first "/" is a domain rule (see domain_matcher.rs), the
+// rest is a path prefix. Paths are case-sensitive, the query string is ignored.
+//
+// Path rules can only be checked where the proxy sees the path: plain HTTP
+// requests and the requests inside intercepted tunnels (see interception.rs). A
+// tunnel that isn't intercepted only shows its host, so path rules don't apply.
+//
+// Sites usually have a handful of path rules at most, so they're kept in a list
+// and checked one by one.
+
+use crate::windows::domain_matcher::{normalize_host, DomainRule};
+use std::fmt;
+
+// A single parsed rule, e.g. `youtube.com/shorts`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UrlRule {
+    pub host: DomainRule,
+    pub path: String, // Starts with "/", percent-encoding of plain characters decoded
+}
+
+impl UrlRule {
+    // Parses a rule line. Returns None for rules without a path (those are domain
+    // rules), comments and invalid domains.
+    pub fn parse(rule: &str) -> Option<UrlRule> {
+        let rule = rule.trim();
+        let slash = rule.find('/')?;
+        let host = rule[..slash].trim_end_matches('^');
+        Some(UrlRule {
+            host: DomainRule::parse(host)?,
+            path: decode_unreserved(rule[slash..].trim_end_matches('^')),
+        })
+    }
+
+    // Whether `path` (the normalized path of a request) is at or below the rule's path
+    fn covers_path(&self, path: &str) -> bool {
+        match path.strip_prefix(self.path.as_str()) {
+            Some(rest) => self.path.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+}
+
+impl fmt::Display for UrlRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.host, self.path)
+    }
+}
+
+// The path rules of a rule set
+#[derive(Debug, Default)]
+pub struct UrlMatcher {
+    rules: Vec<UrlRule>,
+}
+
+impl UrlMatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Builds a matcher from rule lines, skipping the ones without a path.
+    pub fn from_rules<I, S>(rules: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut matcher = Self::new();
+        for rule in rules {
+            matcher.insert(rule.as_ref());
+        }
+        matcher
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    // Adds a rule. Returns false if it has no path, is invalid or already present.
+    pub fn insert(&mut self, rule: &str) -> bool {
+        match UrlRule::parse(rule) {
+            Some(rule) if !self.rules.contains(&rule) => {
+                self.rules.push(rule);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Removes a rule. Returns false if it was not present.
+    pub fn remove(&mut self, rule: &str) -> bool {
+        let Some(rule) = UrlRule::parse(rule) else {
+            return false;
+        };
+        let len = self.rules.len();
+        self.rules.retain(|existing| *existing != rule);
+        self.rules.len() != len
+    }
+
+    // Returns the first rule that covers a request for `target` (origin form,
+    // e.g. "/shorts/abc?x=1") on `host`.
+    pub fn find(&self, host: &str, target: &str) -> Option<&UrlRule> {
+        if self.rules.is_empty() {
+            return None;
+        }
+        let host = normalize_host(host)?;
+        let path = target.split(['?', '#']).next().unwrap_or("");
+        let path = decode_unreserved(path);
+
+        self.rules
+            .iter()
+            .find(|rule| rule.host.covers(&host) && rule.covers_path(&path))
+    }
+}
+
+// Decodes the percent-escapes of characters that never need one (letters, digits,
+// "-", ".", "_", "~"), so "/%73horts" can't slip past a rule for "/shorts".
+// Escapes of other characters ("%2F") change what the path means and are kept.
+fn decode_unreserved(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = String::with_capacity(path.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(byte) = hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+                    decoded.push(byte as char);
+                    i += 3;
+                    continue;
+                }
+            }
+        }
+        // Copy up to the next escape unchanged
+        let next = bytes[i + 1..]
+            .iter()
+            .position(|&b| b == b'%')
+            .map_or(bytes.len(), |n| i + 1 + n);
+        decoded.push_str(&path[i..next]);
+        i = next;
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rules() {
+        let rule = UrlRule::parse(" YouTube.com/shorts ").unwrap();
+        assert_eq!(rule.host, DomainRule::parse("youtube.com").unwrap());
+        assert_eq!(rule.path, "/shorts");
+        assert_eq!(rule.to_string(), "||youtube.com/shorts");
+        assert_eq!(
+            UrlRule::parse("||example.com/ads^").unwrap().to_string(),
+            "||example.com/ads"
+        );
+        assert_eq!(
+            UrlRule::parse("=m.example.com/%67ames/")
+                .unwrap()
+                .to_string(),
+            "=m.example.com/games/"
+        );
+
+        // Domain rules, comments and broken hosts aren't path rules
+        for rule in ["youtube.com", "# youtube.com/shorts", "/shorts", "a..b/x"] {
+            assert_eq!(UrlRule::parse(rule), None, "{}", rule);
+        }
+        // and path rules aren't domain rules
+        assert_eq!(DomainRule::parse("youtube.com/shorts"), None);
+    }
+
+    #[test]
+    fn matches_paths() {
+        let matcher =
+            UrlMatcher::from_rules(["youtube.com/shorts", "=example.com/games/", "news.org"]);
+        assert_eq!(matcher.len(), 2);
+
+        let covered = |host, target| matcher.find(host, target).is_some();
+        assert!(covered("youtube.com", "/shorts"));
+        assert!(covered("www.youtube.com", "/shorts/abc"));
+        assert!(covered("WWW.YouTube.com.", "/shorts?feature=share"));
+        assert!(covered("youtube.com", "/shorts#top"));
+        assert!(!covered("youtube.com", "/shortsx"));
+        assert!(!covered("youtube.com", "/watch?v=shorts"));
+        assert!(!covered("youtube.com", "/Shorts"));
+        assert!(!covered("notyoutube.com", "/shorts"));
+
+        assert!(covered("example.com", "/games/"));
+        assert!(covered("example.com", "/games/chess"));
+        assert!(!covered("example.com", "/games"));
+        assert!(!covered("www.example.com", "/games/chess"));
+    }
+
+    #[test]
+    fn escapes_are_normalized() {
+        let matcher = UrlMatcher::from_rules(["youtube.com/shorts"]);
+        assert!(matcher.find("youtube.com", "/%73horts").is_some());
+        assert!(matcher.find("youtube.com", "/sh%6Frts/x").is_some());
+        // An escaped "/" is part of a name, not a separator
+        assert!(matcher.find("youtube.com", "/shorts%2Fx").is_none());
+        // Broken escapes and non-ASCII paths are compared as they are
+        assert!(matcher.find("youtube.com", "/%zz/shorts").is_none());
+        assert!(matcher.find("youtube.com", "/vídeo%").is_none());
+        assert_eq!(decode_unreserved("/a%2fb%41%"), "/a%2fbA%");
+    }
+
+    #[test]
+    fn insert_and_remove() {
+        let mut matcher = UrlMatcher::new();
+        assert!(matcher.insert("youtube.com/shorts"));
+        assert!(!matcher.insert("||youtube.com/shorts"));
+        assert!(!matcher.insert("youtube.com"));
+        assert_eq!(matcher.len(), 1);
+
+        assert!(!matcher.remove("youtube.com/watch"));
+        assert!(matcher.remove("youtube.com/shorts"));
+        assert!(matcher.is_empty());
+        assert!(matcher.find("youtube.com", "/shorts").is_none());
+    }
+}